@@ -0,0 +1,100 @@
+//! Throughput of the builder on synthetic programs of several sizes: instructions built per second, the
+//! time taken by `AsmBuilder::finish` and `Asm::finish`, and the most memory held at once while building and
+//! emitting, which is printed before the timings.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod common;
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// Tracks the bytes allocated, and the most allocated at once since the last reset.
+struct PeakAlloc {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = self.current.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(current, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc {
+    current: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+/// The most memory held at once while running `f`, beyond what was held before.
+fn peak_memory(f: impl FnOnce()) -> usize {
+    let before = ALLOC.current.load(Ordering::Relaxed);
+    ALLOC.peak.store(before, Ordering::Relaxed);
+    f();
+    ALLOC.peak.load(Ordering::Relaxed) - before
+}
+
+fn memory(_: &mut Criterion) {
+    for functions in SIZES {
+        let peak = peak_memory(|| {
+            let text = common::program(functions).finish().finish();
+            assert!(!text.is_empty());
+        });
+        println!("memory/{functions} functions: peak {} KiB", peak / 1024);
+    }
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    for functions in SIZES {
+        let instructions = common::program(functions).finish().stats().instructions();
+        group.throughput(Throughput::Elements(instructions as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(functions),
+            &functions,
+            |b, &functions| b.iter(|| common::program(functions).finish()),
+        );
+    }
+    group.finish();
+}
+
+fn finish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("finish");
+    for functions in SIZES {
+        group.bench_with_input(
+            BenchmarkId::new("AsmBuilder", functions),
+            &functions,
+            |b, &functions| {
+                b.iter_batched(
+                    || common::program(functions),
+                    minivm_asm_rs::AsmBuilder::finish,
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("Asm", functions),
+            &functions,
+            |b, &functions| {
+                b.iter_batched(
+                    || common::program(functions).finish(),
+                    minivm_asm_rs::asm::Asm::finish,
+                    BatchSize::LargeInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, memory, build, finish);
+criterion_main!(benches);
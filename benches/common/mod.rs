@@ -0,0 +1,27 @@
+//! Synthetic programs shared by the benchmarks.
+
+use minivm_asm_rs::{AsmBuilder, BuildInstruction};
+
+/// An unfinished program of `functions` functions, each with a sub-label, calling the next one.
+pub fn program(functions: usize) -> AsmBuilder {
+    let mut builder = AsmBuilder::new();
+    for index in 0..functions {
+        let name = format!("f{index}");
+        let next = format!("f{}", index + 1);
+        builder.label(&name, |f_builder| {
+            f_builder
+                .integer(1, 1)
+                .add(1, 2, 1)
+                .branch_boolean(1, format!("{name}.done"), format!("{name}.call"))
+                .sub_label("call", |call_builder| {
+                    if index + 1 < functions {
+                        call_builder.label_call(&next, &[1], 0);
+                    }
+                    call_builder.return_(0)
+                })
+                .sub_label("done", |done_builder| done_builder.return_(1))
+        });
+    }
+    builder.main(|main_builder| main_builder.label_call("f0", &[], 0).exit());
+    builder
+}
@@ -0,0 +1,102 @@
+//! Emitting large programs, as one `String` and streamed to a writer, against appending each label to a
+//! growing `String`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use minivm_asm_rs::{
+    asm::Asm,
+    instr::{Instruction, Operand},
+};
+use std::fmt::Write;
+use std::io;
+
+mod common;
+
+fn emit(c: &mut Criterion) {
+    let asm = common::program(10_000).finish();
+    let mut group = c.benchmark_group("emit 10k functions");
+    let text = asm.to_string();
+    assert_eq!(push_per_label(&asm), text);
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    // Both take the program by value, as `finish` does, so both times include dropping it.
+    group.bench_function("push_str per label", |b| {
+        b.iter_batched(
+            || asm.clone(),
+            |asm| push_per_label(&asm),
+            BatchSize::LargeInput,
+        );
+    });
+    group.bench_function("finish", |b| {
+        b.iter_batched(|| asm.clone(), Asm::finish, BatchSize::LargeInput);
+    });
+    group.bench_function("to_string", |b| b.iter(|| asm.to_string()));
+    group.bench_function("write_to", |b| {
+        b.iter(|| asm.write_to(io::BufWriter::new(io::sink())).unwrap());
+    });
+    group.finish();
+}
+
+/// How programs were emitted before their length was measured up front: each label formatted on its own and
+/// appended to a `String` that grows as it goes.
+fn push_per_label(asm: &Asm) -> String {
+    let mut out = String::new();
+    let mut separator = "";
+    if !asm.is_library() {
+        out.push_str(&asm.entry().to_string());
+        separator = "\n\n";
+    }
+    for label in asm.iter() {
+        out.push_str(separator);
+        out.push_str(&label.to_string());
+        separator = "\n\n";
+    }
+    out
+}
+
+/// How instructions were formatted before registers and integers were written by hand.
+fn write_with_format(out: &mut String, instr: &Instruction) {
+    if let Some(dest) = instr.dest {
+        write!(out, "r{dest} <- ").unwrap();
+    }
+    out.push_str(instr.op.mnemonic());
+    for operand in &instr.operands {
+        match operand {
+            Operand::Reg(reg) => write!(out, " r{reg}"),
+            Operand::Int(value) => write!(out, " {value}"),
+            operand => write!(out, " {operand}"),
+        }
+        .unwrap();
+    }
+}
+
+fn format_instructions(c: &mut Criterion) {
+    let asm = common::program(10_000).finish();
+    let instructions: Vec<&Instruction> = asm
+        .iter()
+        .flat_map(|label| label.blocks())
+        .flat_map(|block| block.instructions())
+        .collect();
+    let mut group = c.benchmark_group("format instructions");
+    group.throughput(Throughput::Elements(instructions.len() as u64));
+    group.bench_function("display", |b| {
+        let mut out = String::new();
+        b.iter(|| {
+            out.clear();
+            for instr in &instructions {
+                write!(out, "{instr}").unwrap();
+            }
+        });
+    });
+    group.bench_function("write!", |b| {
+        let mut out = String::new();
+        b.iter(|| {
+            out.clear();
+            for instr in &instructions {
+                write_with_format(&mut out, instr);
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, emit, format_instructions);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+/* The C interface to minivm-asm-rs, built with the `capi` feature. See `src/capi.rs` for the details of
+ * every function. */
+
+#ifndef MINIVM_ASM_H
+#define MINIVM_ASM_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct MvbBuilder MvbBuilder;
+
+MvbBuilder *mvb_new(void);
+void mvb_free(MvbBuilder *builder);
+
+int mvb_label_begin(MvbBuilder *builder, const char *name);
+int mvb_sub_label_begin(MvbBuilder *builder, const char *name);
+
+int mvb_emit_int(MvbBuilder *builder, int64_t value, uint8_t to);
+int mvb_emit_str(MvbBuilder *builder, const char *text, uint8_t to);
+int mvb_emit(MvbBuilder *builder, const char *line);
+
+char *mvb_finish(MvbBuilder *builder, char **error);
+void mvb_string_free(char *text);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
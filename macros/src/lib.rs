@@ -0,0 +1,163 @@
+//! `include_minivm!`, for bundling hand-written MiniVM files that are checked when the crate is built.
+
+#![warn(clippy::pedantic)]
+
+use minivm_asm_rs::{
+    asm::{Label, LabelImpl},
+    instr::Instruction,
+    parse,
+};
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Embed the MiniVM file at a path relative to the including crate's `Cargo.toml`, as an expression
+/// evaluating to its `Asm`, like `include_minivm!("runtime/main.minivm")`.
+///
+/// The file is parsed when the crate is built, and the build fails if it isn't valid MiniVM or refers to a
+/// label it doesn't define. Writing `library` before the path parses a library of functions named after
+/// the file, as in `include_minivm!(library "runtime/putn.minivm")`. A library may call functions it doesn't
+/// define, to be found when it is linked, but not jump to their sub-labels.
+#[proc_macro]
+pub fn include_minivm(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(tokens) => tokens,
+        Err((message, span)) => compile_error(&message, span),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, (String, Span)> {
+    let usage = || r#"expected a path, like `include_minivm!("runtime/main.minivm")`"#.to_string();
+    let mut tokens = input.into_iter().peekable();
+    let library =
+        matches!(tokens.peek(), Some(TokenTree::Ident(ident)) if ident.to_string() == "library");
+    if library {
+        tokens.next();
+    }
+    let literal = match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(literal)), None) => literal,
+        (Some(token), _) => return Err((usage(), token.span())),
+        (None, _) => return Err((usage(), Span::call_site())),
+    };
+    let span = literal.span();
+    let relative = string_value(&literal).ok_or_else(|| (usage(), span))?;
+
+    let dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| ("`CARGO_MANIFEST_DIR` is not set".to_string(), span))?;
+    let path = Path::new(&dir).join(&relative);
+    let full_path = path
+        .to_str()
+        .ok_or_else(|| (format!("`{relative}` is not valid UTF-8"), span))?;
+    let text = std::fs::read_to_string(&path)
+        .map_err(|error| (format!("couldn't read `{relative}`: {error}"), span))?;
+    let id = library.then(|| {
+        path.file_stem()
+            .map_or(relative.clone(), |stem| stem.to_string_lossy().into_owned())
+    });
+    check(&text, id.as_deref()).map_err(|message| (format!("{relative}: {message}"), span))?;
+
+    let parse = match id {
+        Some(id) => format!("parse_library({id:?}, "),
+        None => "parse(".to_string(),
+    };
+    // `include_str!` makes the crate rebuild when the file changes.
+    let expr = format!(
+        "::minivm_asm_rs::parse::{parse}include_str!({full_path:?}))\
+         .expect(\"checked when the crate was built\")"
+    );
+    Ok(expr.parse().expect("generated a valid expression"))
+}
+
+/// The contents of a string literal, as long as it has no escapes.
+fn string_value(literal: &Literal) -> Option<String> {
+    let text = literal.to_string();
+    if let Some(raw) = text.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        return raw
+            .get(hashes + 1..raw.len().checked_sub(hashes + 1)?)
+            .map(str::to_string);
+    }
+    text.strip_prefix('"')?
+        .strip_suffix('"')
+        .filter(|value| !value.contains('\\'))
+        .map(str::to_string)
+}
+
+/// Parse `text`, as a library named `library` if given, and check every label it refers to is defined.
+fn check(text: &str, library: Option<&str>) -> Result<(), String> {
+    let asm = match library {
+        Some(id) => parse::parse_library(id, text),
+        None => parse::parse(text),
+    }
+    .map_err(|error| error.to_string())?;
+
+    let functions: HashSet<&str> = asm.iter().map(|label| label.name()).collect();
+    let defined: HashSet<&str> = asm
+        .iter()
+        .flat_map(Label::blocks)
+        .map(LabelImpl::name)
+        .collect();
+    let entry = Some(asm.entry()).filter(|_| !asm.is_library());
+    for block in entry.into_iter().chain(asm.iter().flat_map(Label::blocks)) {
+        for target in block.instructions().flat_map(Instruction::targets) {
+            let local = !asm.is_library()
+                || target
+                    .split_once('.')
+                    .is_some_and(|(function, _)| functions.contains(function));
+            if local && !defined.contains(target) {
+                return Err(format!(
+                    "`{}` refers to undefined label `{target}`",
+                    block.name()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let tokens = [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::Literal(Literal::string(message)).into(),
+        )),
+    ];
+    tokens
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let putn = r"func putn
+    bb r1 putn.ret putn.digit
+@putn.digit
+    r0 <- call div10 r1
+@putn.ret
+    ret r1
+end";
+        assert_eq!(check(putn, Some("putn")), Ok(()));
+        assert_eq!(
+            check(putn, None).unwrap_err(),
+            "line 7: `main` is not defined"
+        );
+        assert_eq!(
+            check("func f\n    jump f.done\nend", Some("f")).unwrap_err(),
+            "`f` refers to undefined label `f.done`"
+        );
+        assert_eq!(
+            check("func main\n    r0 <- call putn\n    exit\nend", None).unwrap_err(),
+            "`main` refers to undefined label `putn`"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use minivm_asm_macros::include_minivm;
+
+#[test]
+fn test_include_minivm() {
+    let asm = include_minivm!("tests/fixtures/main.minivm");
+    assert!(!asm.is_library());
+    assert_eq!(
+        asm.to_string(),
+        r"@__entry
+    r0 <- call main
+    exit
+
+func done
+    r1 <- int 10
+    putchar r1
+    ret r1
+end
+
+func main
+    r1 <- int 72
+    putchar r1
+    r0 <- call done
+    exit
+end"
+    );
+}
+
+#[test]
+fn test_include_minivm_library() {
+    let asm = include_minivm!(library "tests/fixtures/putn.minivm");
+    assert!(asm.is_library());
+    assert_eq!(
+        asm.iter().map(|label| label.name()).collect::<Vec<_>>(),
+        ["putn"]
+    );
+}
+
+#[cfg(feature = "float")]
+#[test]
+fn test_include_minivm_float() {
+    let asm = include_minivm!(library "tests/fixtures/half.minivm");
+    assert_eq!(
+        asm.to_string(),
+        r"func half
+    r2 <- fint 0.5
+    r1 <- fmul r1 r2
+    ret r1
+end"
+    );
+}
@@ -0,0 +1,1344 @@
+//! Analyses over the structured instruction IR.
+
+use crate::{
+    asm::{Asm, Label, LabelImpl, Line},
+    builder::Reg,
+    instr::{Instruction, OpCode, Operand},
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+/// A set of registers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RegSet([u64; 4]);
+
+impl RegSet {
+    #[must_use]
+    pub fn new() -> RegSet {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, reg: Reg) {
+        self.0[usize::from(reg / 64)] |= 1 << (reg % 64);
+    }
+
+    pub fn remove(&mut self, reg: Reg) {
+        self.0[usize::from(reg / 64)] &= !(1 << (reg % 64));
+    }
+
+    #[must_use]
+    pub fn contains(self, reg: Reg) -> bool {
+        self.0[usize::from(reg / 64)] & (1 << (reg % 64)) != 0
+    }
+
+    #[must_use]
+    pub fn len(self) -> usize {
+        self.0.iter().map(|bits| bits.count_ones() as usize).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    #[must_use]
+    pub fn union(mut self, other: RegSet) -> RegSet {
+        for i in 0..4 {
+            self.0[i] |= other.0[i];
+        }
+        self
+    }
+
+    /// Registers in `self` that aren't in `other`.
+    #[must_use]
+    pub fn difference(mut self, other: RegSet) -> RegSet {
+        for i in 0..4 {
+            self.0[i] &= !other.0[i];
+        }
+        self
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Reg> {
+        (0..=Reg::MAX).filter(move |&reg| self.contains(reg))
+    }
+}
+
+impl FromIterator<Reg> for RegSet {
+    fn from_iter<I: IntoIterator<Item = Reg>>(iter: I) -> RegSet {
+        let mut set = RegSet::new();
+        for reg in iter {
+            set.insert(reg);
+        }
+        set
+    }
+}
+
+/// Which registers hold values that may still be read, before and after every instruction of a function.
+///
+/// Instructions are numbered in the order of [`Label::blocks`], skipping raw lines, which are assumed
+/// to neither read nor write registers.
+#[derive(Clone, Debug)]
+pub struct LivenessInfo {
+    before: Vec<RegSet>,
+    after: Vec<RegSet>,
+}
+
+impl LivenessInfo {
+    /// The number of instructions in the function.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.before.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.before.is_empty()
+    }
+
+    /// Registers live just before instruction `index`. Panics if there is no such instruction.
+    #[must_use]
+    pub fn live_at(&self, index: usize) -> RegSet {
+        self.before[index]
+    }
+
+    /// Registers live just after instruction `index`. Panics if there is no such instruction.
+    #[must_use]
+    pub fn live_after(&self, index: usize) -> RegSet {
+        self.after[index]
+    }
+
+    /// Registers that may be read before being written, i.e. that may hold arguments.
+    #[must_use]
+    pub fn live_on_entry(&self) -> RegSet {
+        self.before.first().copied().unwrap_or_default()
+    }
+
+    /// The ranges of instructions before which `reg` is live.
+    #[must_use]
+    pub fn live_ranges(&self, reg: Reg) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (index, live) in self.before.iter().enumerate() {
+            if !live.contains(reg) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == index => range.end += 1,
+                _ => ranges.push(index..index + 1),
+            }
+        }
+        ranges
+    }
+
+    /// The largest number of registers live at once, just before or just after any instruction.
+    #[must_use]
+    pub fn max_pressure(&self) -> usize {
+        self.before
+            .iter()
+            .chain(&self.after)
+            .map(|live| live.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Compute the liveness of the registers of `label`.
+///
+/// Calls start a new frame, so only their arguments are read. A `jump` to another function or a `djump`
+/// shares the frame with its target, so every register the function writes is assumed to be read there.
+#[must_use]
+pub fn liveness(label: &Label) -> LivenessInfo {
+    let cfg = Cfg::new(label);
+    let written: RegSet = label
+        .blocks()
+        .flat_map(LabelImpl::instructions)
+        .filter_map(|instr| instr.dest)
+        .collect();
+    let live_out = |i: usize, live_in: &[RegSet]| {
+        let live = if cfg.leaves_function(i) {
+            written
+        } else {
+            RegSet::new()
+        };
+        cfg.successors(i)
+            .fold(live, |live, succ| live.union(live_in[succ]))
+    };
+
+    let mut live_in = vec![RegSet::new(); cfg.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..cfg.len()).rev() {
+            let live = transfer(cfg.block(i), live_out(i, &live_in));
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut info = LivenessInfo {
+        before: Vec::new(),
+        after: Vec::new(),
+    };
+    for i in 0..cfg.len() {
+        let mut live = live_out(i, &live_in);
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for instr in cfg
+            .block(i)
+            .instructions()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            after.push(live);
+            live = step(instr, live);
+            before.push(live);
+        }
+        info.before.extend(before.into_iter().rev());
+        info.after.extend(after.into_iter().rev());
+    }
+    info
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Jump,
+    BranchTrue,
+    BranchFalse,
+    Fallthrough,
+}
+
+/// Where control goes along an [`Edge`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target<'a> {
+    /// A block of the same function, by index.
+    Block(usize),
+    /// A label outside the function, sharing the frame with it.
+    External(&'a str),
+    /// The address in a register, from a `djump`.
+    Dynamic,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Edge<'a> {
+    pub from: usize,
+    pub to: Target<'a>,
+    pub kind: EdgeKind,
+}
+
+/// The control-flow graph of a function, whose nodes are the function's [blocks](Label::blocks).
+#[derive(Clone, Debug)]
+pub struct Cfg<'a> {
+    name: &'a str,
+    blocks: Vec<&'a LabelImpl>,
+    edges: Vec<Edge<'a>>,
+}
+
+impl<'a> Cfg<'a> {
+    #[must_use]
+    pub fn new(label: &'a Label) -> Cfg<'a> {
+        let blocks: Vec<&LabelImpl> = label.blocks().collect();
+        let index: HashMap<&str, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.name(), i))
+            .collect();
+        let target = |name: &'a str| match index.get(name) {
+            Some(&i) => Target::Block(i),
+            None => Target::External(name),
+        };
+
+        let mut edges = Vec::new();
+        for (from, block) in blocks.iter().enumerate() {
+            for instr in block.instructions() {
+                let mut targets = instr.targets();
+                match instr.op {
+                    OpCode::Jump => edges.extend(targets.map(|name| Edge {
+                        from,
+                        to: target(name),
+                        kind: EdgeKind::Jump,
+                    })),
+                    OpCode::DJump => edges.push(Edge {
+                        from,
+                        to: Target::Dynamic,
+                        kind: EdgeKind::Jump,
+                    }),
+                    op if op.is_branch() => {
+                        let kinds = [EdgeKind::BranchFalse, EdgeKind::BranchTrue];
+                        for kind in kinds {
+                            if let Some(name) = targets.next() {
+                                edges.push(Edge {
+                                    from,
+                                    to: target(name),
+                                    kind,
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if !ends_in_terminator(block) && from + 1 < blocks.len() {
+                edges.push(Edge {
+                    from,
+                    to: Target::Block(from + 1),
+                    kind: EdgeKind::Fallthrough,
+                });
+            }
+        }
+
+        Self {
+            name: label.name(),
+            blocks,
+            edges,
+        }
+    }
+
+    /// The number of blocks.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    #[must_use]
+    pub fn block(&self, index: usize) -> &'a LabelImpl {
+        self.blocks[index]
+    }
+
+    #[must_use]
+    pub fn edges(&self) -> &[Edge<'a>] {
+        &self.edges
+    }
+
+    /// Blocks of the function that control may go to directly from block `index`.
+    pub fn successors(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |edge| edge.from == index)
+            .filter_map(|edge| match edge.to {
+                Target::Block(to) => Some(to),
+                _ => None,
+            })
+    }
+
+    /// Blocks of the function that may go to block `index` directly.
+    pub fn predecessors(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |edge| edge.to == Target::Block(index))
+            .map(|edge| edge.from)
+    }
+
+    /// For every block, the blocks that every path from the start of the function to it goes through, itself
+    /// included. Blocks that can't be reached have none.
+    #[must_use]
+    pub fn dominators(&self) -> Vec<BTreeSet<usize>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut reachable = vec![false; self.len()];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if !std::mem::replace(&mut reachable[index], true) {
+                pending.extend(self.successors(index));
+            }
+        }
+        let all: BTreeSet<usize> = (0..self.len()).filter(|&i| reachable[i]).collect();
+        let mut dominators: Vec<BTreeSet<usize>> = (0..self.len())
+            .map(|i| match i {
+                0 => BTreeSet::from([0]),
+                _ if reachable[i] => all.clone(),
+                _ => BTreeSet::new(),
+            })
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (1..self.len()).filter(|&i| reachable[i]) {
+                let mut dominated_by = self
+                    .predecessors(i)
+                    .filter(|&pred| reachable[pred])
+                    .map(|pred| dominators[pred].clone())
+                    .reduce(|lhs, rhs| &lhs & &rhs)
+                    .unwrap_or_default();
+                dominated_by.insert(i);
+                if dominated_by != dominators[i] {
+                    dominators[i] = dominated_by;
+                    changed = true;
+                }
+            }
+        }
+        dominators
+    }
+
+    /// Whether block `index` may jump out of the function.
+    #[must_use]
+    pub fn leaves_function(&self, index: usize) -> bool {
+        self.edges
+            .iter()
+            .any(|edge| edge.from == index && !matches!(edge.to, Target::Block(_)))
+    }
+
+    /// Render the graph in Graphviz DOT, with the instructions of every block.
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec![
+            format!("digraph \"{}\" {{", escape_dot(self.name)),
+            "    node [shape=box, fontname=monospace];".to_string(),
+        ];
+        for (i, block) in self.blocks.iter().enumerate() {
+            let text: String = std::iter::once(format!("{}:", block.name()))
+                .chain(block.lines().iter().map(|line| format!("    {line}")))
+                .map(|line| escape_dot(&line) + "\\l")
+                .collect();
+            lines.push(format!("    {i} [label=\"{text}\"];"));
+        }
+        // Targets outside the function are declared before the first edge to them.
+        let mut declared = HashSet::new();
+        for edge in &self.edges {
+            let to = match edge.to {
+                Target::Block(to) => to.to_string(),
+                Target::External(name) => {
+                    let name = format!("\"{}\"", escape_dot(name));
+                    if declared.insert(name.clone()) {
+                        lines.push(format!("    {name} [shape=plain];"));
+                    }
+                    name
+                }
+                Target::Dynamic => {
+                    if declared.insert("dynamic".to_string()) {
+                        lines.push("    dynamic [shape=plain, label=\"djump\"];".to_string());
+                    }
+                    "dynamic".to_string()
+                }
+            };
+            let attributes = match edge.kind {
+                EdgeKind::Jump => "",
+                EdgeKind::BranchTrue => " [label=\"true\"]",
+                EdgeKind::BranchFalse => " [label=\"false\"]",
+                EdgeKind::Fallthrough => " [style=dashed]",
+            };
+            lines.push(format!("    {} -> {to}{attributes};", edge.from));
+        }
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+}
+
+/// Escape `text` for use inside a quoted DOT string.
+pub(crate) fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Functions of a program that can't keep all their live values in a limited number of registers.
+#[derive(Clone, Debug)]
+pub struct PressureReport {
+    pub budget: u16,
+    /// Functions needing more than `budget` registers, most pressured first.
+    pub functions: Vec<FunctionPressure>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FunctionPressure {
+    pub name: String,
+    pub max_pressure: usize,
+    /// Where the function could be split so that each over-budget stretch starts a new function.
+    pub split_points: Vec<SplitPoint>,
+}
+
+/// Splitting before instruction `index` passes the registers in `live` to the new function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplitPoint {
+    pub index: usize,
+    pub live: RegSet,
+}
+
+/// Report the functions of `asm` that would have to spill under a budget of `budget` registers.
+///
+/// For every stretch of instructions over budget, the suggested split point is the one with the fewest
+/// live registers since the previous stretch, ignoring the start of the function.
+#[must_use]
+pub fn pressure_report(asm: &Asm, budget: u16) -> PressureReport {
+    let budget_len = usize::from(budget);
+    let mut functions: Vec<FunctionPressure> = asm
+        .iter()
+        .filter_map(|label| {
+            let info = liveness(label);
+            let max_pressure = info.max_pressure();
+            if max_pressure <= budget_len {
+                return None;
+            }
+
+            let over = |index: usize| {
+                info.live_at(index).len() > budget_len || info.live_after(index).len() > budget_len
+            };
+            let mut split_points = Vec::new();
+            let mut since = 1;
+            let mut index = 0;
+            while index < info.len() {
+                if !over(index) {
+                    index += 1;
+                    continue;
+                }
+                let best = (since..=index)
+                    .filter(|&i| i > 0)
+                    .min_by_key(|&i| (info.live_at(i).len(), usize::MAX - i));
+                if let Some(best) = best {
+                    split_points.push(SplitPoint {
+                        index: best,
+                        live: info.live_at(best),
+                    });
+                }
+                while index < info.len() && over(index) {
+                    index += 1;
+                }
+                since = index + 1;
+            }
+
+            Some(FunctionPressure {
+                name: label.name().to_string(),
+                max_pressure,
+                split_points,
+            })
+        })
+        .collect();
+    functions.sort_by_key(|function| std::cmp::Reverse(function.max_pressure));
+    PressureReport { budget, functions }
+}
+
+impl fmt::Display for PressureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for function in &self.functions {
+            write!(
+                f,
+                "{}: {} live registers, budget {}",
+                function.name, function.max_pressure, self.budget
+            )?;
+            for point in &function.split_points {
+                write!(f, "\n    split before instruction {} passing", point.index)?;
+                for reg in point.live.iter() {
+                    write!(f, " r{reg}")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which functions each function of a program calls, jumps to, or takes the address of.
+///
+/// Jumps within a function are left out, and so are `djump` and `dcall`, whose targets aren't known. A
+/// target that isn't defined by the program, like a function of another module, is named as it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallGraph<'a> {
+    edges: BTreeMap<&'a str, BTreeSet<&'a str>>,
+    /// The edges that push a frame: calls, and addresses taken, which are usually called.
+    calls: BTreeSet<(&'a str, &'a str)>,
+    /// Functions referred to by the entry block.
+    roots: BTreeSet<&'a str>,
+}
+
+/// Build the [`CallGraph`] of `asm`. Raw lines are ignored.
+#[must_use]
+pub fn call_graph<'a>(asm: &'a Asm) -> CallGraph<'a> {
+    let owner: HashMap<&str, &str> = asm
+        .iter()
+        .flat_map(|label| label.blocks().map(|block| (block.name(), label.name())))
+        .collect();
+    // Jumps and branches only count when they leave the function.
+    let refers_to = |caller: &str, block: &'a LabelImpl| -> Vec<(&'a str, bool)> {
+        let mut callees = Vec::new();
+        for instr in block.instructions() {
+            let calls = matches!(instr.op, OpCode::Call | OpCode::Addr);
+            let jumps = instr.op == OpCode::Jump || instr.op.is_branch();
+            for target in instr.targets() {
+                let callee = owner.get(target).copied().unwrap_or(target);
+                if calls || (jumps && callee != caller) {
+                    callees.push((callee, calls));
+                }
+            }
+        }
+        callees
+    };
+
+    let mut edges = BTreeMap::new();
+    let mut calls = BTreeSet::new();
+    for label in asm.iter() {
+        let callees: &mut BTreeSet<&str> = edges.entry(label.name()).or_default();
+        for block in label.blocks() {
+            for (callee, call) in refers_to(label.name(), block) {
+                callees.insert(callee);
+                if call {
+                    calls.insert((label.name(), callee));
+                }
+            }
+        }
+    }
+    let roots = if asm.is_library() {
+        BTreeSet::new()
+    } else {
+        refers_to(asm.entry().name(), asm.entry())
+            .into_iter()
+            .map(|(callee, _)| callee)
+            .collect()
+    };
+    CallGraph {
+        edges,
+        calls,
+        roots,
+    }
+}
+
+impl<'a> CallGraph<'a> {
+    /// Every function of the program, in alphabetical order.
+    pub fn functions(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.edges.keys().copied()
+    }
+
+    /// The functions `function` refers to, in alphabetical order.
+    pub fn callees(&self, function: &str) -> impl Iterator<Item = &'a str> + '_ {
+        self.edges.get(function).into_iter().flatten().copied()
+    }
+
+    /// `function` and every function it can lead to, directly or not.
+    #[must_use]
+    pub fn reachable_from(&self, function: &'a str) -> BTreeSet<&'a str> {
+        self.reachable_from_all([function])
+    }
+
+    /// Every function the program can reach from its entry block, which usually just calls `main`. Nothing
+    /// is reachable in a library.
+    #[must_use]
+    pub fn reachable(&self) -> BTreeSet<&'a str> {
+        self.reachable_from_all(self.roots.iter().copied())
+    }
+
+    /// The groups of functions that can call themselves, through each other or not, in alphabetical order.
+    /// Calls through a function of another module are not seen.
+    #[must_use]
+    pub fn recursive_cycles(&self) -> Vec<Vec<&'a str>> {
+        let mut cycles: Vec<Vec<&str>> = self
+            .components()
+            .into_iter()
+            .filter(|component| self.is_recursive(component))
+            .map(|component| component.into_iter().collect())
+            .collect();
+        cycles.sort_unstable();
+        cycles
+    }
+
+    /// How many frames the call stack holds at most when running the program, `main`'s included, or `None`
+    /// if a recursive function can be reached so there is no bound. Calls to functions of other modules and
+    /// `dcall`s are not counted.
+    #[must_use]
+    pub fn estimate_max_call_depth(&self) -> Option<usize> {
+        let components = self.components();
+        let component_of: HashMap<&str, usize> = components
+            .iter()
+            .enumerate()
+            .flat_map(|(index, component)| component.iter().map(move |&function| (function, index)))
+            .collect();
+        // Components come out callees first, so each one's depth can be found from those already known.
+        let mut depths: Vec<Option<usize>> = Vec::with_capacity(components.len());
+        for component in &components {
+            let depth = if self.is_recursive(component) {
+                None
+            } else {
+                let mut depth = Some(0);
+                for &caller in component {
+                    for callee in self.callees(caller) {
+                        let Some(&index) = component_of.get(callee) else {
+                            continue;
+                        };
+                        let frame = usize::from(self.calls.contains(&(caller, callee)));
+                        let callee_depth = if index == depths.len() {
+                            Some(0)
+                        } else {
+                            depths[index].map(|callee_depth| callee_depth + frame)
+                        };
+                        depth = depth
+                            .zip(callee_depth)
+                            .map(|(depth, callee)| depth.max(callee));
+                    }
+                }
+                depth
+            };
+            depths.push(depth);
+        }
+        self.roots
+            .iter()
+            .filter_map(|root| component_of.get(root))
+            .try_fold(0, |max, &index| Some(max.max(depths[index]? + 1)))
+    }
+
+    /// Whether the functions of `component` call one of themselves.
+    fn is_recursive(&self, component: &BTreeSet<&'a str>) -> bool {
+        self.calls
+            .iter()
+            .any(|(caller, callee)| component.contains(caller) && component.contains(callee))
+    }
+
+    /// The strongly connected components of the graph of defined functions, callees before callers.
+    fn components(&self) -> Vec<BTreeSet<&'a str>> {
+        struct Tarjan<'g, 'a> {
+            graph: &'g CallGraph<'a>,
+            index: HashMap<&'a str, usize>,
+            low: HashMap<&'a str, usize>,
+            stack: Vec<&'a str>,
+            components: Vec<BTreeSet<&'a str>>,
+        }
+
+        impl<'a> Tarjan<'_, 'a> {
+            fn visit(&mut self, function: &'a str) {
+                let index = self.index.len();
+                self.index.insert(function, index);
+                self.low.insert(function, index);
+                self.stack.push(function);
+                for callee in self.graph.callees(function) {
+                    if !self.graph.edges.contains_key(callee) {
+                        continue;
+                    }
+                    if !self.index.contains_key(callee) {
+                        self.visit(callee);
+                        let low = self.low[function].min(self.low[callee]);
+                        self.low.insert(function, low);
+                    } else if self.stack.contains(&callee) {
+                        let low = self.low[function].min(self.index[callee]);
+                        self.low.insert(function, low);
+                    }
+                }
+                if self.low[function] == index {
+                    let start = self.stack.iter().rposition(|&f| f == function).unwrap();
+                    self.components.push(self.stack.drain(start..).collect());
+                }
+            }
+        }
+
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        };
+        for function in self.functions() {
+            if !tarjan.index.contains_key(function) {
+                tarjan.visit(function);
+            }
+        }
+        tarjan.components
+    }
+
+    fn reachable_from_all(&self, roots: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+        let mut reached = BTreeSet::new();
+        let mut pending: Vec<&str> = roots.into_iter().collect();
+        while let Some(function) = pending.pop() {
+            if reached.insert(function) {
+                pending.extend(self.callees(function));
+            }
+        }
+        reached
+    }
+}
+
+/// A natural loop of a function, found by [`loops`], with its blocks numbered as by [`Cfg`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    /// The block every iteration starts at, which dominates the other blocks of the loop.
+    pub header: usize,
+    /// The blocks of the loop, the header included.
+    pub blocks: BTreeSet<usize>,
+    /// The blocks that go back to the header.
+    pub latches: BTreeSet<usize>,
+}
+
+/// Find the natural loops of `label`, in the order of their headers. A loop is made of a header, a block
+/// going back to it that the header dominates, and every block that can reach that block without going
+/// through the header. Loops with the same header are merged, so nested loops have different headers.
+#[must_use]
+pub fn loops(label: &Label) -> Vec<Loop> {
+    let cfg = Cfg::new(label);
+    let dominators = cfg.dominators();
+    let mut loops: BTreeMap<usize, Loop> = BTreeMap::new();
+    for edge in cfg.edges() {
+        let Target::Block(header) = edge.to else {
+            continue;
+        };
+        if !dominators[edge.from].contains(&header) {
+            continue;
+        }
+        let found = loops.entry(header).or_insert_with(|| Loop {
+            header,
+            blocks: BTreeSet::from([header]),
+            latches: BTreeSet::new(),
+        });
+        found.latches.insert(edge.from);
+        let mut pending = vec![edge.from];
+        while let Some(block) = pending.pop() {
+            if found.blocks.insert(block) {
+                pending.extend(
+                    cfg.predecessors(block)
+                        .filter(|&pred| !dominators[pred].is_empty()),
+                );
+            }
+        }
+    }
+    loops.into_values().collect()
+}
+
+/// Whether calling a function can do anything other than compute its return value, as found by [`purity`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Purity {
+    /// Calls to the function can be removed if their result isn't read, and merged or reordered if they
+    /// pass the same arguments. The function may still allocate arrays, read those it is passed, and trap.
+    Pure,
+    /// The function writes a character, writes to an array it didn't allocate, calls the host, exits, or
+    /// calls or jumps to a function that may do any of these.
+    Impure,
+}
+
+/// Find the [`Purity`] of every function of `asm`.
+///
+/// Writes are only known to go to an array the function allocated if the array was allocated in the same
+/// block. Functions that aren't defined by the program, `dcall`, `djump`, raw lines and functions that fall
+/// off their end are assumed to be impure, as what they do isn't known.
+#[must_use]
+pub fn purity(asm: &Asm) -> HashMap<&str, Purity> {
+    let owner: HashMap<&str, &str> = asm
+        .iter()
+        .flat_map(|label| label.blocks().map(|block| (block.name(), label.name())))
+        .collect();
+    let mut impure = BTreeSet::new();
+    let mut callers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for label in asm.iter() {
+        let mut has_effects = false;
+        for block in label.blocks() {
+            // Registers holding an array allocated in this block, which nothing else can have seen yet.
+            let mut fresh = RegSet::new();
+            for line in block.lines() {
+                let Some(instr) = line.as_instruction() else {
+                    has_effects = true;
+                    continue;
+                };
+                has_effects |= match instr.op {
+                    OpCode::PutChar
+                    | OpCode::ExternCall
+                    | OpCode::Exit
+                    | OpCode::DCall
+                    | OpCode::DJump => true,
+                    OpCode::Set => !instr
+                        .operands
+                        .first()
+                        .and_then(Operand::as_reg)
+                        .is_some_and(|array| fresh.contains(array)),
+                    _ => false,
+                };
+                if instr.op == OpCode::Call || instr.op == OpCode::Jump || instr.op.is_branch() {
+                    for target in instr.targets() {
+                        match owner.get(target) {
+                            Some(&callee) if callee != label.name() || instr.op == OpCode::Call => {
+                                callers.entry(callee).or_default().push(label.name());
+                            }
+                            Some(_) => {}
+                            None => has_effects = true,
+                        }
+                    }
+                }
+                if let Some(dest) = instr.dest {
+                    if matches!(instr.op, OpCode::Arr | OpCode::Str) {
+                        fresh.insert(dest);
+                    } else {
+                        fresh.remove(dest);
+                    }
+                }
+            }
+        }
+        // Running off the end carries on into whichever function is emitted next.
+        has_effects |= !label.blocks().last().is_some_and(ends_in_terminator);
+        if has_effects {
+            impure.insert(label.name());
+        }
+    }
+
+    // Functions are impure if anything they call or jump to is.
+    let mut pending: Vec<&str> = impure.iter().copied().collect();
+    while let Some(callee) = pending.pop() {
+        for &caller in callers.get(callee).into_iter().flatten() {
+            if impure.insert(caller) {
+                pending.push(caller);
+            }
+        }
+    }
+    asm.iter()
+        .map(|label| {
+            let purity = if impure.contains(label.name()) {
+                Purity::Impure
+            } else {
+                Purity::Pure
+            };
+            (label.name(), purity)
+        })
+        .collect()
+}
+
+/// The `arr` instructions of `label`, numbered as by [`liveness`], whose arrays never leave the function:
+/// they aren't returned, stored into another array, or passed to a call.
+///
+/// Registers are followed through moves over the whole function, whatever order the instructions run in,
+/// so an array escapes if any register it may be in is passed on. Every array escapes if the function may
+/// jump out of itself, which takes its registers along, or has raw lines.
+#[must_use]
+pub fn array_escape(label: &Label) -> Vec<usize> {
+    let cfg = Cfg::new(label);
+    let has_raw = label.blocks().any(|block| {
+        block
+            .lines()
+            .iter()
+            .any(|line| line.as_instruction().is_none())
+    });
+    let falls_off_end = !label.blocks().last().is_some_and(ends_in_terminator);
+    if has_raw || falls_off_end || (0..cfg.len()).any(|i| cfg.leaves_function(i)) {
+        return Vec::new();
+    }
+
+    let instrs: Vec<&Instruction> = label.blocks().flat_map(LabelImpl::instructions).collect();
+    // The allocations each register may hold, by the index of their `arr`.
+    let mut holds: HashMap<Reg, BTreeSet<usize>> = HashMap::new();
+    for (index, instr) in instrs.iter().enumerate() {
+        if let (OpCode::Arr, Some(dest)) = (instr.op, instr.dest) {
+            holds.entry(dest).or_default().insert(index);
+        }
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for instr in &instrs {
+            let (OpCode::Reg, Some(dest), Some(Operand::Reg(from))) =
+                (instr.op, instr.dest, instr.operands.first())
+            else {
+                continue;
+            };
+            let from = holds.get(from).cloned().unwrap_or_default();
+            let to = holds.entry(dest).or_default();
+            for index in from {
+                changed |= to.insert(index);
+            }
+        }
+    }
+
+    let mut escaped: BTreeSet<usize> = BTreeSet::new();
+    for instr in &instrs {
+        let passed: Vec<Reg> = match instr.op {
+            OpCode::Ret | OpCode::Call | OpCode::DCall | OpCode::ExternCall => {
+                instr.uses().collect()
+            }
+            OpCode::Set => instr
+                .operands
+                .get(2)
+                .and_then(Operand::as_reg)
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        };
+        for reg in passed {
+            escaped.extend(holds.get(&reg).into_iter().flatten());
+        }
+    }
+    instrs
+        .iter()
+        .enumerate()
+        .filter(|&(index, instr)| instr.op == OpCode::Arr && !escaped.contains(&index))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+pub(crate) fn ends_in_terminator(block: &LabelImpl) -> bool {
+    block
+        .lines()
+        .last()
+        .and_then(Line::as_instruction)
+        .is_some_and(|instr| instr.op.is_terminator())
+}
+
+fn transfer(block: &LabelImpl, live_out: RegSet) -> RegSet {
+    block
+        .instructions()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .fold(live_out, |live, instr| step(instr, live))
+}
+
+/// Registers live before `instr`, given those live after it.
+fn step(instr: &Instruction, mut live: RegSet) -> RegSet {
+    if let Some(dest) = instr.dest {
+        live.remove(dest);
+    }
+    live.union(instr.uses().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{builder::LabelBuilder, AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_liveness() {
+        let mut builder = LabelBuilder::new("fib");
+        builder
+            .integer(2, 0)
+            .branch_less_than(1, 0, "fib.then", "fib.else")
+            .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
+            .sub_label("else", |fib_else_builder| {
+                fib_else_builder
+                    .integer(1, 0)
+                    .sub(1, 0, 1)
+                    .sub(1, 0, 0)
+                    .label_call("fib", &[1], 1)
+                    .label_call("fib", &[0], 0)
+                    .add(0, 1, 0)
+                    .return_(0)
+            });
+        let info = liveness(&builder.finish());
+
+        assert_eq!(info.len(), 10);
+        assert_eq!(info.live_on_entry().iter().collect::<Vec<_>>(), [1]);
+        assert_eq!(info.live_at(1).iter().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(info.live_at(3).iter().collect::<Vec<_>>(), [1]);
+        assert_eq!(info.live_after(4).iter().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(info.live_ranges(0), [1..2, 4..10]);
+        assert_eq!(info.max_pressure(), 2);
+    }
+
+    #[test]
+    fn test_liveness_jump_out_of_function() {
+        let mut builder = LabelBuilder::new("swap");
+        builder.tail_call("pair", &[2, 1]);
+        let info = liveness(&builder.finish());
+
+        assert_eq!(info.live_on_entry().iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(info.live_after(3).iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .main(|main_builder| {
+                main_builder
+                    .label_address("show", 1)
+                    .label_call("putn", &[1], 0)
+                    .exit()
+            })
+            .label("putn", |putn_builder| {
+                putn_builder
+                    .branch_boolean(1, "putn.digit", "putn.done")
+                    .sub_label("digit", |digit_builder| {
+                        digit_builder
+                            .label_call("putn", &[1], 0)
+                            .label_jump("putn.done")
+                    })
+                    .sub_label("done", |done_builder| done_builder.label_jump("show"))
+            })
+            .label("show", |show_builder| show_builder.put_char(1).return_(1))
+            .label("unused", |unused_builder| {
+                unused_builder.label_call("missing", &[], 0).return_(0)
+            });
+        let asm = builder.finish();
+        let graph = call_graph(&asm);
+
+        assert_eq!(
+            graph.functions().collect::<Vec<_>>(),
+            ["main", "putn", "show", "unused"]
+        );
+        assert_eq!(graph.callees("putn").collect::<Vec<_>>(), ["putn", "show"]);
+        assert_eq!(graph.callees("unused").collect::<Vec<_>>(), ["missing"]);
+        assert_eq!(
+            graph.reachable().into_iter().collect::<Vec<_>>(),
+            ["main", "putn", "show"]
+        );
+        assert_eq!(
+            graph
+                .reachable_from("unused")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["missing", "unused"]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .integer(0, 2)
+            .sub_label("outer", |outer_builder| outer_builder.integer(0, 3))
+            .sub_label("inner", |inner_builder| {
+                inner_builder
+                    .add(3, 2, 3)
+                    .branch_less_than(3, 1, "f.inner", "f.next")
+            })
+            .sub_label("next", |next_builder| {
+                next_builder
+                    .add(2, 1, 2)
+                    .branch_less_than(2, 1, "f.outer", "f.done")
+            })
+            .sub_label("done", |done_builder| done_builder.return_(2));
+        let label = builder.finish();
+
+        assert_eq!(
+            format!("{:?}", loops(&label)),
+            "[Loop { header: 1, blocks: {1, 2, 3}, latches: {3} }, \
+             Loop { header: 2, blocks: {2}, latches: {2} }]"
+        );
+        assert_eq!(
+            Cfg::new(&label).dominators()[3],
+            BTreeSet::from([0, 1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn test_array_escape() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .array(1, 2)
+            .integer(0, 3)
+            .set_array_index(2, 3, 1)
+            .array(1, 4)
+            .register_move(4, 5)
+            .array(1, 6)
+            .set_array_index(2, 3, 6)
+            .array(1, 7)
+            .label_call("g", &[7], 0)
+            .array(1, 8)
+            .array_length(8, 0)
+            .return_(5);
+        assert_eq!(array_escape(&builder.finish()), [0, 9]);
+
+        let mut builder = LabelBuilder::new("f");
+        builder.array(1, 2).label_jump("g");
+        assert!(array_escape(&builder.finish()).is_empty());
+    }
+
+    #[test]
+    fn test_purity() {
+        let asm = crate::parse::parse(
+            r"func square
+    r1 <- mul r1 r1
+    ret r1
+end
+
+func fill
+    r2 <- arr r1
+    r3 <- int 0
+    set r2 r3 r1
+    ret r2
+end
+
+func poke
+    set r1 r2 r3
+    ret r1
+end
+
+func show
+    putchar r1
+    ret r1
+end
+
+func even
+    bb r1 even.more even.done
+@even.more
+    r0 <- int 1
+    r1 <- sub r1 r0
+    jump odd
+@even.done
+    ret r1
+end
+
+func odd
+    r0 <- call square r1
+    bb r1 odd.more odd.done
+@odd.more
+    r0 <- int 1
+    r1 <- sub r1 r0
+    jump even
+@odd.done
+    ret r0
+end
+
+func loud
+    r1 <- call square r1
+    jump show
+end
+
+func slide
+    r1 <- mul r1 r1
+end
+
+func foreign
+    r0 <- call elsewhere
+    ret r0
+end
+
+func main
+    r0 <- call even r1
+    exit
+end",
+        )
+        .unwrap();
+        let purity: BTreeMap<_, _> = purity(&asm).into_iter().collect();
+        let purity: Vec<String> = purity
+            .into_iter()
+            .map(|(name, purity)| format!("{name}: {purity:?}"))
+            .collect();
+        assert_eq!(
+            purity.join("\n"),
+            r"even: Pure
+fill: Pure
+foreign: Impure
+loud: Impure
+main: Impure
+odd: Pure
+poke: Impure
+show: Impure
+slide: Impure
+square: Pure"
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let build = |recurse: bool| {
+            let mut builder = AsmBuilder::new();
+            builder
+                .main(|main_builder| {
+                    if recurse {
+                        main_builder.label_call("even", &[1], 0);
+                    }
+                    main_builder.label_call("a", &[], 0).exit()
+                })
+                .label("a", |a_builder| {
+                    a_builder.label_call("b", &[], 0).return_(0)
+                })
+                .label("b", |b_builder| b_builder.label_jump("c"))
+                .label("c", |c_builder| {
+                    c_builder.label_call("d", &[], 0).return_(0)
+                })
+                .label("d", |d_builder| d_builder.return_(0))
+                .label("even", |even_builder| {
+                    even_builder.label_call("odd", &[1], 0).return_(0)
+                })
+                .label("odd", |odd_builder| odd_builder.label_jump("even"))
+                .label("fact", |fact_builder| {
+                    fact_builder.label_call("fact", &[1], 0).return_(0)
+                });
+            builder.finish()
+        };
+
+        let asm = build(false);
+        let graph = call_graph(&asm);
+        assert_eq!(
+            graph.recursive_cycles(),
+            [vec!["even", "odd"], vec!["fact"]]
+        );
+        assert_eq!(graph.estimate_max_call_depth(), Some(4));
+
+        let asm = build(true);
+        assert_eq!(call_graph(&asm).estimate_max_call_depth(), None);
+    }
+
+    #[test]
+    fn test_pressure_report() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(1, 1)
+                .integer(2, 2)
+                .add(1, 2, 3)
+                .integer(3, 4)
+                .integer(4, 5)
+                .add(3, 4, 4)
+                .add(4, 5, 0)
+                .return_(0)
+        });
+        builder.label("small", |small_builder| {
+            small_builder.add(1, 2, 0).return_(0)
+        });
+
+        assert_eq!(
+            pressure_report(&builder.finish(), 2).to_string(),
+            "main: 3 live registers, budget 2
+    split before instruction 3 passing r3
+"
+        );
+    }
+
+    #[test]
+    fn test_cfg_to_dot() {
+        let mut builder = LabelBuilder::new("count");
+        builder
+            .branch_boolean(1, "count.done", "count.loop")
+            .sub_label("loop", |count_loop_builder| {
+                count_loop_builder.string("\"", 0).sub(1, 2, 1)
+            })
+            .sub_label("test", |count_test_builder| {
+                count_test_builder.branch_boolean(1, "count.done", "count.loop")
+            })
+            .sub_label("done", |count_done_builder| {
+                count_done_builder.tail_call("exit", &[])
+            });
+        let label = builder.finish();
+        let cfg = Cfg::new(&label);
+
+        assert_eq!(cfg.predecessors(1).collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(
+            cfg.to_dot(),
+            r#"digraph "count" {
+    node [shape=box, fontname=monospace];
+    0 [label="count:\l    bb r1 count.loop count.done\l"];
+    1 [label="count.loop:\l    r0 <- str :\"\l    r1 <- sub r1 r2\l"];
+    2 [label="count.test:\l    bb r1 count.loop count.done\l"];
+    3 [label="count.done:\l    jump exit\l"];
+    0 -> 1 [label="false"];
+    0 -> 3 [label="true"];
+    1 -> 2 [style=dashed];
+    2 -> 1 [label="false"];
+    2 -> 3 [label="true"];
+    "exit" [shape=plain];
+    3 -> "exit";
+}"#
+        );
+    }
+
+    #[test]
+    fn test_cfg_to_dot_declares_external_targets_once() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .branch_boolean(1, "f.done", "f.more")
+            .sub_label("more", |f_more_builder| f_more_builder.tail_call("g", &[]))
+            .sub_label("done", |f_done_builder| f_done_builder.tail_call("g", &[]));
+        let label = builder.finish();
+
+        assert_eq!(
+            Cfg::new(&label).to_dot(),
+            r#"digraph "f" {
+    node [shape=box, fontname=monospace];
+    0 [label="f:\l    bb r1 f.more f.done\l"];
+    1 [label="f.more:\l    jump g\l"];
+    2 [label="f.done:\l    jump g\l"];
+    0 -> 1 [label="false"];
+    0 -> 2 [label="true"];
+    "g" [shape=plain];
+    1 -> "g";
+    2 -> "g";
+}"#
+        );
+    }
+}
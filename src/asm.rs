@@ -1,59 +1,819 @@
+#![allow(clippy::missing_panics_doc)]
+
+use crate::analysis::escape_dot;
+use crate::emit::Dialect;
+use crate::instr::{Instruction, OpCode, Operand};
+use crate::stats::AsmStats;
+use crate::target::Version;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::io;
 use std::ops::{Deref, DerefMut, Range};
+use std::panic::Location;
 
 const INDENTED_LINE_START: &str = "\n    ";
 const BLOCK_END: &str = "\nend";
-const ENTRY_POINT: &str = r"@__entry
-    r0 <- call main
-    exit";
+const ENTRY_LABEL: &str = "__entry";
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Asm {
+    library: Option<String>,
+    version: Option<Version>,
+    entry: LabelImpl,
     main: Label,
-    buf: String,
+    labels: Vec<Label>,
+    main_first: bool,
+    size_hint: usize,
+    // Locations in the Rust code that built the program mean nothing outside of the process.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    call_sites: LineTable<&'static Location<'static>>,
+    spans: LineTable<UserSpan>,
 }
 
 impl Asm {
     #[must_use]
     pub fn new() -> Asm {
         let main = Label::new("main");
-        Self {
+        let mut asm = Self {
+            library: None,
+            version: None,
+            entry: LabelImpl::new(format!("@{ENTRY_LABEL}"), 1..1 + ENTRY_LABEL.len()),
             main,
-            buf: ENTRY_POINT.to_string(),
+            labels: Vec::new(),
+            main_first: false,
+            size_hint: 0,
+            call_sites: LineTable::default(),
+            spans: LineTable::default(),
+        };
+        asm.set_entry_point("main");
+        asm
+    }
+
+    /// A fragment of functions to be linked into another program, emitted without the entry block or `main`.
+    /// `id` names the library.
+    #[must_use]
+    pub fn new_library(id: &str) -> Asm {
+        Self {
+            library: Some(id.to_string()),
+            ..Self::new()
         }
     }
 
+    /// The name of the library, if the program is one.
+    #[must_use]
+    pub fn library_id(&self) -> Option<&str> {
+        self.library.as_deref()
+    }
+
+    #[must_use]
+    pub fn is_library(&self) -> bool {
+        self.library.is_some()
+    }
+
+    /// The MiniVM release the program was generated for, if it records one.
+    #[must_use]
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    /// Record that the program was generated for `version`. MiniVM has no comments, so the version isn't
+    /// part of the emitted program; [`finish_with_header`](Asm::finish_with_header) writes it for tools that
+    /// read it back with [`parse`](crate::parse::parse), and
+    /// [`run_with_minivm`](crate::harness::run_with_minivm) checks the VM against it.
+    pub fn set_version(&mut self, version: Option<Version>) {
+        self.version = version;
+    }
+
+    /// Panics if the program is a library.
     #[must_use]
     pub fn main(&mut self) -> &mut Label {
+        assert!(!self.is_library(), "a library has no `main`");
         &mut self.main
     }
 
     pub fn push_label(&mut self, label: Label) {
-        let label = label.finish();
-        self.buf.push_str("\n\n");
-        self.buf.push_str(&label);
+        self.labels.push(label);
+    }
+
+    /// Remove the label `name`, other than `main`, and return it.
+    pub fn remove_label(&mut self, name: &str) -> Option<Label> {
+        let index = self.labels.iter().position(|label| label.name() == name)?;
+        Some(self.labels.remove(index))
+    }
+
+    /// Put `new` in place of the label `name`, `main` included, and return the label it replaces. If there is
+    /// no such label, `new` is pushed instead.
+    pub fn replace_label(&mut self, name: &str, new: Label) -> Option<Label> {
+        let old = if name == "main" && !self.is_library() {
+            &mut self.main
+        } else if let Some(label) = self.labels.iter_mut().find(|label| label.name() == name) {
+            label
+        } else {
+            self.push_label(new);
+            return None;
+        };
+        Some(std::mem::replace(old, new))
+    }
+
+    /// Insert `label` just before the label `name`. Inserting before `main` pushes the label after the
+    /// others, wherever `main` is.
+    ///
+    /// Panics if there is no label `name`.
+    pub fn insert_label_before(&mut self, name: &str, label: Label) {
+        let index = if name == "main" && !self.is_library() {
+            self.labels.len()
+        } else {
+            self.labels
+                .iter()
+                .position(|label| label.name() == name)
+                .unwrap_or_else(|| panic!("no label `{name}` to insert before"))
+        };
+        self.labels.insert(index, label);
+    }
+
+    /// Sort the labels other than `main` with `compare`, keeping labels it finds equal in order.
+    pub fn sort_labels_by(&mut self, compare: impl FnMut(&Label, &Label) -> std::cmp::Ordering) {
+        self.labels.sort_by(compare);
+    }
+
+    /// Emit `main` before the other labels rather than after them.
+    pub fn set_main_first(&mut self, main_first: bool) {
+        self.main_first = main_first;
+    }
+
+    /// Expect the emitted program to take about `bytes` bytes, so that [`Asm::finish`] allocates them up
+    /// front rather than measuring the program first.
+    pub fn set_size_hint(&mut self, bytes: usize) {
+        self.size_hint = bytes;
+    }
+
+    /// Append the labels of `other` other than `main`, along with what is known about their lines. The entry
+    /// block and `main` of `other` are left out, and labels defined by both are kept twice; see
+    /// [`Asm::merge`].
+    pub fn extend(&mut self, mut other: Asm) {
+        let appended: HashSet<String> = other
+            .labels
+            .iter()
+            .flat_map(Label::blocks)
+            .map(|block| block.name().to_string())
+            .collect();
+        other
+            .call_sites
+            .retain_labels(|label| appended.contains(label));
+        other.spans.retain_labels(|label| appended.contains(label));
+        self.labels.append(&mut other.labels);
+        self.size_hint += other.size_hint;
+        self.call_sites.extend(other.call_sites);
+        self.spans.extend(other.spans);
+    }
+
+    /// Like [`Asm::extend`], checking that no label or sub-label of `other` is already defined.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first label of `other` that is already defined.
+    pub fn merge(mut self, other: Asm) -> Result<Asm, MergeError> {
+        let defined: HashSet<&str> = self
+            .iter()
+            .flat_map(Label::blocks)
+            .map(LabelImpl::name)
+            .collect();
+        let collision = other
+            .labels
+            .iter()
+            .flat_map(Label::blocks)
+            .find(|block| defined.contains(block.name()));
+        if let Some(block) = collision {
+            return Err(MergeError::Collision(block.name().to_string()));
+        }
+        self.extend(other);
+        Ok(self)
+    }
+
+    /// The `@__entry` block, where execution starts.
+    #[must_use]
+    pub fn entry(&self) -> &LabelImpl {
+        &self.entry
+    }
+
+    pub fn entry_mut(&mut self) -> &mut LabelImpl {
+        &mut self.entry
+    }
+
+    /// Make the entry block call `name` and exit.
+    pub fn set_entry_point(&mut self, name: &str) {
+        self.entry.lines = entry_lines(name);
+    }
+
+    /// The function the entry block calls before exiting, if that's all it does, as after
+    /// [`set_entry_point`](Asm::set_entry_point). `None` for libraries and other entry blocks.
+    #[must_use]
+    pub fn entry_point(&self) -> Option<&str> {
+        if self.is_library() {
+            return None;
+        }
+        let name = self.entry.instructions().next()?.targets().next()?;
+        (self.entry.lines == entry_lines(name)).then_some(name)
+    }
+
+    /// Whether the entry block only calls `main` and exits, as it does by default.
+    #[must_use]
+    pub fn has_standard_entry(&self) -> bool {
+        self.entry.lines == entry_lines("main")
+    }
+
+    /// Labels other than `main`, in output order.
+    #[must_use]
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn labels_mut(&mut self) -> &mut Vec<Label> {
+        &mut self.labels
+    }
+
+    /// All labels in output order, `main` being last unless the program is a library or
+    /// [`main` comes first](Asm::set_main_first).
+    pub fn iter(&self) -> impl Iterator<Item = &Label> + '_ {
+        self.ordered((!self.is_library()).then_some(&self.main))
+    }
+
+    /// The labels other than `main`, along with `main` where it goes.
+    fn ordered<'a>(&'a self, main: Option<&'a Label>) -> impl Iterator<Item = &'a Label> + 'a {
+        let (first, last) = if self.main_first {
+            (main, None)
+        } else {
+            (None, main)
+        };
+        first.into_iter().chain(&self.labels).chain(last)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Label> + '_ {
+        let main = (!self.is_library()).then_some(&mut self.main);
+        let (first, last) = if self.main_first {
+            (main, None)
+        } else {
+            (None, main)
+        };
+        first.into_iter().chain(&mut self.labels).chain(last)
+    }
+
+    /// Where in the Rust code the instructions of the program were written, if it was built by an
+    /// [`AsmBuilder`](crate::AsmBuilder) [tracking sources](crate::AsmBuilder::track_sources).
+    #[must_use]
+    pub fn call_sites(&self) -> &LineTable<&'static Location<'static>> {
+        &self.call_sites
+    }
+
+    pub fn call_sites_mut(&mut self) -> &mut LineTable<&'static Location<'static>> {
+        &mut self.call_sites
+    }
+
+    /// The spans attached to instructions with [`with_span`](crate::BuildInstruction::with_span).
+    #[must_use]
+    pub fn spans(&self) -> &LineTable<UserSpan> {
+        &self.spans
+    }
+
+    pub fn spans_mut(&mut self) -> &mut LineTable<UserSpan> {
+        &mut self.spans
+    }
+
+    /// Prefix the name of every label and sub-label other than `main`, along with the calls, jumps,
+    /// branches and addresses referring to them, so the program can be linked with others defining the
+    /// same names. Labels only mentioned in raw lines are left as they are.
+    pub fn prefix_labels(&mut self, prefix: &str) {
+        self.rename_functions(|name| Some(format!("{prefix}{name}")));
+    }
+
+    /// Rename the function `old` to `new`, along with its sub-labels and the calls, jumps, branches and
+    /// addresses referring to them. Labels only mentioned in raw lines are left as they are.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `old` is `main`, which the entry block calls, there is no function `old`, or `new`
+    /// or one of its sub-labels is already defined.
+    pub fn rename_label(&mut self, old: &str, new: &str) -> Result<(), RenameError> {
+        if old == "main" && !self.is_library() {
+            return Err(RenameError::Main);
+        }
+        if !self.labels.iter().any(|label| label.name() == old) {
+            return Err(RenameError::Undefined(old.to_string()));
+        }
+        let collision = self
+            .iter()
+            .filter(|label| label.name() != old)
+            .flat_map(Label::blocks)
+            .map(LabelImpl::name)
+            .find(|name| {
+                name.strip_prefix(new)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            });
+        if let Some(name) = collision {
+            return Err(RenameError::Collision(name.to_string()));
+        }
+        self.rename_functions(|name| (name == old).then(|| new.to_string()));
+        Ok(())
+    }
+
+    /// Rename every function other than `main` for which `rename` returns a new name, along with its
+    /// sub-labels and the references to them.
+    pub(crate) fn rename_functions(&mut self, rename: impl Fn(&str) -> Option<String>) {
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        for label in &mut self.labels {
+            let Some(name) = rename(label.name()) else {
+                continue;
+            };
+            let old: Vec<String> = label
+                .blocks()
+                .map(|block| block.name().to_string())
+                .collect();
+            label.rename(&name);
+            renamed.extend(
+                old.into_iter()
+                    .zip(label.blocks().map(|block| block.name().to_string())),
+            );
+        }
+        let rename = |name: &str| renamed.get(name).cloned();
+
+        rename_targets(&mut self.entry.lines, rename);
+        for block in self.iter_mut().flat_map(Label::blocks_mut) {
+            rename_targets(&mut block.lines, rename);
+        }
+        self.call_sites.rename_labels(rename);
+        self.spans.rename_labels(rename);
+    }
+
+    /// Render which functions call, jump to, or take the address of which, in Graphviz DOT.
+    #[must_use]
+    pub fn call_graph_dot(&self) -> String {
+        let owner: HashMap<&str, &str> = self
+            .iter()
+            .flat_map(|label| label.blocks().map(|block| (block.name(), label.name())))
+            .collect();
+        let mut edges: Vec<(&str, &str, &str)> = Vec::new();
+        for label in self.iter() {
+            for instr in label.blocks().flat_map(LabelImpl::instructions) {
+                let kind = match instr.op {
+                    OpCode::Call => "call",
+                    OpCode::Addr => "addr",
+                    op if op == OpCode::Jump || op.is_branch() => "jump",
+                    _ => continue,
+                };
+                for target in instr.targets() {
+                    let callee = owner.get(target).copied().unwrap_or(target);
+                    let edge = (label.name(), callee, kind);
+                    let local = kind == "jump" && callee == label.name();
+                    if !local && !edges.contains(&edge) {
+                        edges.push(edge);
+                    }
+                }
+            }
+        }
+
+        let mut lines = vec!["digraph program {".to_string()];
+        for label in self.iter() {
+            lines.push(format!("    \"{}\";", escape_dot(label.name())));
+        }
+        for (caller, callee, kind) in edges {
+            lines.push(format!(
+                "    \"{}\" -> \"{}\" [label=\"{kind}\"];",
+                escape_dot(caller),
+                escape_dot(callee)
+            ));
+        }
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+
+    /// Where a label or sub-label, or line `index` of its body, is in the emitted program.
+    ///
+    /// Returns `None` if there is no such label or line.
+    #[must_use]
+    pub fn locate(&self, label: &str, index: Option<usize>) -> Option<Span> {
+        let layout = self
+            .layout()
+            .into_iter()
+            .find(|layout| layout.block.name() == label)?;
+        let start = INDENTED_LINE_START.len() - 1;
+        match index {
+            None => Some(Span {
+                line: layout.header,
+                columns: layout.block.name_span.clone(),
+            }),
+            Some(index) => layout.lines.get(index).map(|&(line, width)| Span {
+                line,
+                columns: start..start + width,
+            }),
+        }
+    }
+
+    /// Emit the program, along with which [span](UserSpan) of the frontend's source every line with one
+    /// came from.
+    #[must_use]
+    pub fn finish_with_source_map(self) -> (String, SourceMap) {
+        let mut map = BTreeMap::new();
+        for layout in self.layout() {
+            for (index, &(line, _)) in layout.lines.iter().enumerate() {
+                if let Some(&span) = self.spans.get(layout.block.name(), index) {
+                    map.insert(line, span);
+                }
+            }
+        }
+        (self.emit(), SourceMap(map))
+    }
+
+    /// The line numbers every block and line is emitted at, in output order.
+    fn layout(&self) -> Vec<BlockLayout<'_>> {
+        let mut last_line = 0;
+        let mut layouts = Vec::new();
+        if !self.is_library() {
+            layouts.push(BlockLayout::new(&self.entry, &mut last_line));
+        }
+        for function in self.emitted() {
+            // Functions are separated by an empty line.
+            if !layouts.is_empty() {
+                last_line += 1;
+            }
+            for block in function.blocks() {
+                layouts.push(BlockLayout::new(block, &mut last_line));
+            }
+            // The `end` of the function.
+            last_line += 1;
+        }
+        layouts
+    }
+
+    /// The labels that are emitted: all of them, except for `main` in a library, or an empty `main` that the
+    /// entry block doesn't refer to.
+    pub(crate) fn emitted(&self) -> impl Iterator<Item = &Label> + '_ {
+        let unused_main = self.is_library()
+            || self.main.lines.is_empty()
+                && self.main.sub_labels.is_empty()
+                && !self
+                    .entry
+                    .instructions()
+                    .any(|instr| instr.targets().any(|target| target == "main"));
+        self.ordered((!unused_main).then_some(&self.main))
+    }
+
+    /// Describe the structure of the program as JSON, following the schema documented in [`json`](crate::json).
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        crate::json::to_json(self)
     }
 
+    #[must_use]
+    pub fn stats(&self) -> AsmStats {
+        AsmStats::new(self)
+    }
+
+    /// Emit the program into an allocation of the [size hint](Asm::set_size_hint) if there is one, or else
+    /// of exactly its length, measured before writing it out.
     #[must_use]
     pub fn finish(self) -> String {
-        let Asm { main, mut buf } = self;
-        let main = main.finish();
-        buf.push_str("\n\n");
-        buf.push_str(&main);
-        buf
+        self.emit()
+    }
+
+    /// Emit the program after a [header](Version::header) naming its [version](Asm::version), if it has
+    /// one. The header is a comment, which MiniVM itself doesn't accept, so this is only for files read
+    /// back with [`parse`](crate::parse::parse).
+    #[must_use]
+    pub fn finish_with_header(self) -> String {
+        match self.version {
+            Some(version) => format!("{}\n{}", version.header(), self.emit()),
+            None => self.emit(),
+        }
+    }
+
+    /// Stream the program to `out` as it is emitted, without holding all of it in memory. Writes are small,
+    /// so `out` should be buffered.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error from `out`.
+    pub fn write_to(&self, mut out: impl io::Write) -> io::Result<()> {
+        write!(out, "{self}")
+    }
+
+    /// Emit the program with the mnemonics of `dialect`, for a fork of MiniVM.
+    #[must_use]
+    pub fn finish_with(self, dialect: &Dialect) -> String {
+        let program = InDialect(&self, dialect);
+        let mut text = String::with_capacity(formatted_len(&program));
+        write!(text, "{program}").expect("writing to a `String` doesn't fail");
+        text
+    }
+
+    fn emit(&self) -> String {
+        let capacity = if self.size_hint == 0 {
+            formatted_len(self)
+        } else {
+            self.size_hint
+        };
+        let mut text = String::with_capacity(capacity);
+        write!(text, "{self}").expect("writing to a `String` doesn't fail");
+        text
     }
 }
 
+/// The length in bytes of `value` once formatted, from the lengths of the pieces it is written in, without
+/// storing any of them.
+fn formatted_len(value: &impl fmt::Display) -> usize {
+    struct Counter(usize);
+
+    impl fmt::Write for Counter {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            self.0 += text.len();
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    write!(counter, "{value}").expect("counting doesn't fail");
+    counter.0
+}
+
 impl Default for Asm {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// A label or sub-label defined by both programs.
+    Collision(String),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Collision(name) => write!(f, "`{name}` is defined by both programs"),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RenameError {
+    /// `main`, which the entry block calls, can't be renamed.
+    Main,
+    /// There is no function to rename.
+    Undefined(String),
+    /// A label or sub-label already defined under the new name.
+    Collision(String),
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::Main => f.write_str("`main` can't be renamed"),
+            RenameError::Undefined(name) => write!(f, "no function `{name}` to rename"),
+            RenameError::Collision(name) => write!(f, "`{name}` is already defined"),
+        }
+    }
+}
+
+impl std::error::Error for RenameError {}
+
+impl fmt::Display for Asm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_in(f, None)
+    }
+}
+
+impl Asm {
+    /// Write the program with the mnemonics of `dialect`, or the usual ones if there is none.
+    fn fmt_in(&self, f: &mut fmt::Formatter<'_>, dialect: Option<&Dialect>) -> fmt::Result {
+        let mut separator = "";
+        if !self.is_library() {
+            self.entry.fmt_in(f, dialect)?;
+            separator = "\n\n";
+        }
+        for label in self.emitted() {
+            f.write_str(separator)?;
+            label.fmt_in(f, dialect)?;
+            separator = "\n\n";
+        }
+        Ok(())
+    }
+}
+
+/// A program written in a [`Dialect`].
+struct InDialect<'a>(&'a Asm, &'a Dialect);
+
+impl fmt::Display for InDialect<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_in(f, Some(self.1))
+    }
+}
+
+/// The body of an entry block calling `name`.
+fn entry_lines(name: &str) -> Vec<Line> {
+    vec![
+        Line::Instruction(Instruction::new(
+            OpCode::Call,
+            Some(0),
+            vec![Operand::Label(name.into())],
+        )),
+        Line::Instruction(Instruction::new(OpCode::Exit, None, vec![])),
+    ]
+}
+
+/// Rename the label operands of `lines` for which `rename` returns a new name.
+fn rename_targets(lines: &mut [Line], rename: impl Fn(&str) -> Option<String>) {
+    for line in lines {
+        if let Line::Instruction(instr) = line {
+            for operand in &mut instr.operands {
+                if let Operand::Label(target) = operand {
+                    if let Some(renamed) = rename(target.as_str()) {
+                        *target = renamed.into();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Something known about lines of a program, by label or sub-label and line index.
+///
+/// Entries are not updated by passes that move or remove lines.
+#[derive(Clone, Debug)]
+pub struct LineTable<T>(BTreeMap<(String, usize), T>);
+
+impl<T> LineTable<T> {
+    /// What is known about line `index` of `label`.
+    #[must_use]
+    pub fn get(&self, label: &str, index: usize) -> Option<&T> {
+        self.0.get(&(label.to_string(), index))
+    }
+
+    pub fn insert(&mut self, label: &str, index: usize, value: T) {
+        self.0.insert((label.to_string(), index), value);
+    }
+
+    /// Rename the labels of entries for which `rename` returns a new name.
+    pub(crate) fn rename_labels(&mut self, rename: impl Fn(&str) -> Option<String>) {
+        self.0 = std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|((label, index), value)| ((rename(&label).unwrap_or(label), index), value))
+            .collect();
+    }
+
+    pub fn extend(&mut self, other: LineTable<T>) {
+        self.0.extend(other.0);
+    }
+
+    /// Only keep the entries of labels for which `keep` returns `true`.
+    pub(crate) fn retain_labels(&mut self, keep: impl Fn(&str) -> bool) {
+        self.0.retain(|(label, _), _| keep(label));
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T> Default for LineTable<T> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+/// A sequence of `(label, index, value)` entries, since most formats only have string keys.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for LineTable<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.0
+                .iter()
+                .map(|((label, index), value)| (label, index, value)),
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for LineTable<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<(String, usize, T)>::deserialize(deserializer)?;
+        Ok(Self(
+            entries
+                .into_iter()
+                .map(|(label, index, value)| ((label, index), value))
+                .collect(),
+        ))
+    }
+}
+
+/// One `label+index: value` line per entry.
+impl<T: fmt::Display> fmt::Display for LineTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, ((label, index), value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{label}+{index}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A span of a compiler frontend's source, as a byte range of one of its files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UserSpan {
+    /// The frontend's own identifier for the file.
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for UserSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}..{}", self.file, self.start, self.end)
+    }
+}
+
+/// The [`UserSpan`]s of the lines of an emitted program, by 1-based line number.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap(BTreeMap<usize, UserSpan>);
+
+impl SourceMap {
+    #[must_use]
+    pub fn get(&self, line: usize) -> Option<UserSpan> {
+        self.0.get(&line).copied()
+    }
+
+    /// Every line with a span, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, UserSpan)> + '_ {
+        self.0.iter().map(|(&line, &span)| (line, span))
+    }
+}
+
+struct BlockLayout<'a> {
+    block: &'a LabelImpl,
+    /// The line of the block's header.
+    header: usize,
+    /// The line every line of the block starts on, and the width of that line.
+    lines: Vec<(usize, usize)>,
+}
+
+impl<'a> BlockLayout<'a> {
+    /// The layout of `block`, emitted right after `last_line`, which is moved to the block's last line.
+    fn new(block: &'a LabelImpl, last_line: &mut usize) -> BlockLayout<'a> {
+        let header = *last_line + 1;
+        *last_line = header + block.header.matches('\n').count();
+        let mut lines = Vec::with_capacity(block.lines.len());
+        for line in &block.lines {
+            let text = line.to_string();
+            let width = text.lines().next().map_or(0, str::len);
+            lines.push((*last_line + 1, width));
+            *last_line += 1 + text.matches('\n').count();
+        }
+        Self {
+            block,
+            header,
+            lines,
+        }
+    }
+}
+
+/// A part of a line of the emitted program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// The 1-based line number.
+    pub line: usize,
+    /// The byte range within the line.
+    pub columns: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Label {
     inner: LabelImpl,
     sub_labels: Vec<SubLabel>,
+    visibility: Visibility,
+}
+
+/// Whether a function is part of the interface of its module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Visibility {
+    /// Keeps its name when linked, and is never pruned.
+    #[default]
+    Public,
+    /// Only used by its own module, so it may be renamed when linked, or pruned if unused.
+    Private,
 }
 
 impl Label {
@@ -65,11 +825,29 @@ impl Label {
             let end = start + name.len();
             start..end
         };
-        let buf = Self::format_name(name);
+        let header = Self::format_name(name);
 
         Self {
-            inner: LabelImpl::new(buf, name_span),
+            inner: LabelImpl::new(header, name_span),
             sub_labels: Vec::new(),
+            visibility: Visibility::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn set_visibility(&mut self, visibility: Visibility) {
+        self.visibility = visibility;
+    }
+
+    /// Rename the label and its sub-labels, without updating references to them.
+    pub fn rename(&mut self, name: &str) {
+        let len = self.name().len();
+        for block in self.blocks_mut() {
+            block.replace_name_prefix(len, name);
         }
     }
 
@@ -77,19 +855,31 @@ impl Label {
         self.sub_labels.push(sub_label);
     }
 
+    #[must_use]
+    pub fn sub_labels(&self) -> &[SubLabel] {
+        &self.sub_labels
+    }
+
+    pub fn sub_labels_mut(&mut self) -> &mut Vec<SubLabel> {
+        &mut self.sub_labels
+    }
+
+    /// The body of the label followed by the bodies of its sub-labels.
+    pub fn blocks(&self) -> impl Iterator<Item = &LabelImpl> + '_ {
+        std::iter::once(&self.inner).chain(self.sub_labels.iter().map(|sub_label| &sub_label.inner))
+    }
+
+    pub fn blocks_mut(&mut self) -> impl Iterator<Item = &mut LabelImpl> + '_ {
+        std::iter::once(&mut self.inner).chain(
+            self.sub_labels
+                .iter_mut()
+                .map(|sub_label| &mut sub_label.inner),
+        )
+    }
+
     #[must_use]
     pub fn finish(self) -> String {
-        let Label {
-            inner, sub_labels, ..
-        } = self;
-        let mut buf = inner.finish();
-        for sub_label in sub_labels {
-            let asm = sub_label.finish();
-            buf.push('\n');
-            buf.push_str(&asm);
-        }
-        buf.push_str(BLOCK_END);
-        buf
+        self.to_string()
     }
 
     fn format_name(label_name: &str) -> String {
@@ -97,6 +887,23 @@ impl Label {
     }
 }
 
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_in(f, None)
+    }
+}
+
+impl Label {
+    fn fmt_in(&self, f: &mut fmt::Formatter<'_>, dialect: Option<&Dialect>) -> fmt::Result {
+        self.inner.fmt_in(f, dialect)?;
+        for sub_label in &self.sub_labels {
+            f.write_str("\n")?;
+            sub_label.inner.fmt_in(f, dialect)?;
+        }
+        f.write_str(BLOCK_END)
+    }
+}
+
 impl Deref for Label {
     type Target = LabelImpl;
 
@@ -111,7 +918,8 @@ impl DerefMut for Label {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubLabel {
     inner: LabelImpl,
 }
@@ -125,15 +933,15 @@ impl SubLabel {
             let end = start + label.len() + 1 + name.len();
             start..end
         };
-        let buf = Self::format_name(label, name);
+        let header = Self::format_name(label, name);
         Self {
-            inner: LabelImpl::new(buf, name_span),
+            inner: LabelImpl::new(header, name_span),
         }
     }
 
     #[must_use]
     pub fn finish(self) -> String {
-        self.inner.finish()
+        self.to_string()
     }
 
     fn format_name(label_name: &str, sub_label_name: &str) -> String {
@@ -141,6 +949,12 @@ impl SubLabel {
     }
 }
 
+impl fmt::Display for SubLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.inner)
+    }
+}
+
 impl Deref for SubLabel {
     type Target = LabelImpl;
 
@@ -155,40 +969,131 @@ impl DerefMut for SubLabel {
     }
 }
 
-#[derive(Clone)]
+/// A line in the body of a label.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Line {
+    Instruction(Instruction),
+    /// Text that is emitted as-is, and is opaque to any analysis.
+    Raw(String),
+}
+
+impl Line {
+    #[must_use]
+    pub fn as_instruction(&self) -> Option<&Instruction> {
+        match self {
+            Line::Instruction(instr) => Some(instr),
+            Line::Raw(_) => None,
+        }
+    }
+
+    pub fn as_instruction_mut(&mut self) -> Option<&mut Instruction> {
+        match self {
+            Line::Instruction(instr) => Some(instr),
+            Line::Raw(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_in(f, None)
+    }
+}
+
+impl Line {
+    fn fmt_in(&self, f: &mut fmt::Formatter<'_>, dialect: Option<&Dialect>) -> fmt::Result {
+        match self {
+            Line::Instruction(instr) => instr.fmt_in(f, dialect),
+            Line::Raw(raw) => f.write_str(raw),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LabelImpl {
     name_span: Range<usize>,
-    buf: String,
+    header: String,
+    lines: Vec<Line>,
 }
 
 impl LabelImpl {
-    fn new(buf: String, name_span: Range<usize>) -> LabelImpl {
-        Self { name_span, buf }
+    fn new(header: String, name_span: Range<usize>) -> LabelImpl {
+        Self {
+            name_span,
+            header,
+            lines: Vec::new(),
+        }
     }
 
     #[must_use]
     pub fn name(&self) -> &str {
         let name_span = self.name_span.clone();
-        &self.buf[name_span]
+        &self.header[name_span]
     }
 
+    /// Replace the first `len` bytes of the name with `name`.
+    fn replace_name_prefix(&mut self, len: usize, name: &str) {
+        let start = self.name_span.start;
+        self.header.replace_range(start..start + len, name);
+        self.name_span.end = self.name_span.end - len + name.len();
+    }
+
+    /// Append `raw` to the end of the last line, without starting a new one.
     pub fn push_raw<'a>(&mut self, raw: impl Into<Cow<'a, str>>) {
-        self.buf.push_str(raw.into().as_ref());
+        let raw = raw.into();
+        match self.lines.last_mut() {
+            Some(Line::Raw(line)) => line.push_str(&raw),
+            Some(last @ Line::Instruction(_)) => *last = Line::Raw(format!("{last}{raw}")),
+            None => self.header.push_str(&raw),
+        }
     }
 
     pub fn push_line<'a>(&mut self, line: impl Into<Cow<'a, str>>) {
-        self.buf.push_str(INDENTED_LINE_START);
-        self.buf.push_str(line.into().as_ref());
+        self.lines.push(Line::Raw(line.into().into_owned()));
     }
 
-    fn finish(self) -> String {
-        self.buf
+    pub fn push_instruction(&mut self, instr: Instruction) {
+        self.lines.push(Line::Instruction(instr));
+    }
+
+    #[must_use]
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn lines_mut(&mut self) -> &mut Vec<Line> {
+        &mut self.lines
+    }
+
+    /// The structured instructions of the label, skipping raw lines.
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> + '_ {
+        self.lines.iter().filter_map(Line::as_instruction)
+    }
+}
+
+impl fmt::Display for LabelImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_in(f, None)
+    }
+}
+
+impl LabelImpl {
+    fn fmt_in(&self, f: &mut fmt::Formatter<'_>, dialect: Option<&Dialect>) -> fmt::Result {
+        f.write_str(&self.header)?;
+        for line in &self.lines {
+            f.write_str(INDENTED_LINE_START)?;
+            line.fmt_in(f, dialect)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::BuildInstruction;
 
     impl LabelImpl {
         fn push_str(&mut self, s: &str) -> &mut Self {
@@ -370,4 +1275,305 @@ func main
 end",
         );
     }
+
+    #[test]
+    fn test_prefix_labels() {
+        let mut builder = crate::AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .label_address("concat", 1)
+                .label_call("concat", &[1], 0)
+                .exit()
+        });
+        builder.label("concat", |concat_builder| {
+            concat_builder
+                .branch_boolean(1, "concat.some", "concat.none")
+                .sub_label("none", |none_builder| none_builder.return_(1))
+                .sub_label("some", |some_builder| some_builder.label_jump("putn"))
+        });
+        let mut asm = builder.finish();
+        asm.prefix_labels("str_");
+
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func str_concat
+    bb r1 str_concat.none str_concat.some
+@str_concat.none
+    ret r1
+@str_concat.some
+    jump putn
+end
+
+func main
+    r1 <- addr str_concat
+    r0 <- call str_concat r1
+    exit
+end"
+        );
+    }
+
+    #[test]
+    fn test_rename_label() {
+        let mut builder = crate::AsmBuilder::new();
+        builder
+            .label("loop", |loop_builder| {
+                loop_builder
+                    .branch_if(1, "loop.done")
+                    .label_jump("loop")
+                    .sub_label("done", |done_builder| done_builder.return_(1))
+            })
+            .label("other", |other_builder| other_builder.return_(0))
+            .main(|main_builder| {
+                main_builder
+                    .label_address("loop.done", 1)
+                    .label_call("loop", &[1], 0)
+                    .exit()
+            });
+        let mut asm = builder.finish();
+        asm.main().push_line("; loop");
+
+        assert_eq!(asm.rename_label("main", "start"), Err(RenameError::Main));
+        assert_eq!(
+            asm.rename_label("missing", "start"),
+            Err(RenameError::Undefined("missing".to_string()))
+        );
+        assert_eq!(
+            asm.rename_label("other", "loop").unwrap_err().to_string(),
+            "`loop` is already defined"
+        );
+        asm.rename_label("loop", "repeat").unwrap();
+        asm.remove_label("other");
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func repeat
+    bb r1 repeat.cont1 repeat.done
+@repeat.cont1
+    jump repeat
+@repeat.done
+    ret r1
+end
+
+func main
+    r1 <- addr repeat.done
+    r0 <- call repeat r1
+    exit
+    ; loop
+end"
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let program = |name: &str| {
+            let mut builder = crate::AsmBuilder::with_source_tracking();
+            builder
+                .main(|main_builder| main_builder.label_call(name, &[], 0).exit())
+                .label(name, |label_builder| {
+                    label_builder
+                        .label_jump(format!("{name}.done"))
+                        .sub_label("done", |done_builder| done_builder.return_(0))
+                });
+            builder.finish()
+        };
+
+        let merged = program("a").merge(program("b")).unwrap();
+        assert_eq!(
+            merged.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func a
+    jump a.done
+@a.done
+    ret r0
+end
+
+func b
+    jump b.done
+@b.done
+    ret r0
+end
+
+func main
+    r0 <- call a
+    exit
+end"
+        );
+        assert_eq!(merged.call_sites().len(), 6);
+        assert_eq!(
+            merged.merge(program("b")).unwrap_err().to_string(),
+            "`b` is defined by both programs"
+        );
+    }
+
+    #[test]
+    fn test_label_order() {
+        let mut builder = crate::AsmBuilder::new();
+        builder
+            .main(|main_builder| main_builder.exit())
+            .label("c", |c_builder| c_builder.return_(0))
+            .label("a", |a_builder| a_builder.return_(0));
+        let mut asm = builder.finish();
+
+        asm.sort_labels_by(|a, b| a.name().cmp(b.name()));
+        asm.insert_label_before("c", Label::new("b"));
+        asm.insert_label_before("main", Label::new("d"));
+        asm.set_main_first(true);
+        assert_eq!(
+            asm.iter().map(|label| label.name()).collect::<Vec<_>>(),
+            ["main", "a", "b", "c", "d"]
+        );
+        assert!(asm.to_string().starts_with(
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    exit
+end
+
+func a"
+        ));
+    }
+
+    #[test]
+    fn test_replace_label() {
+        let mut builder = crate::AsmBuilder::new();
+        builder
+            .main(|main_builder| main_builder.label_call("putn", &[1], 0).exit())
+            .label("putn", |putn_builder| putn_builder.put_char(1).return_(1))
+            .label("debug", |debug_builder| debug_builder.return_(1));
+        let mut asm = builder.finish();
+
+        let mut fast = Label::new("putn");
+        fast.push_instruction(Instruction::new(OpCode::Ret, None, vec![Operand::Reg(1)]));
+        let slow = asm.replace_label("putn", fast).unwrap();
+        assert_eq!(slow.finish(), "func putn\n    putchar r1\n    ret r1\nend");
+        assert!(asm.remove_label("debug").is_some());
+        assert!(asm.remove_label("debug").is_none());
+        assert!(asm.replace_label("new", Label::new("new")).is_none());
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func putn
+    ret r1
+end
+
+func new
+end
+
+func main
+    r0 <- call putn r1
+    exit
+end"
+        );
+    }
+
+    #[test]
+    fn test_finish_capacity() {
+        let build = |mut builder: crate::AsmBuilder| {
+            builder.label("f", |f_builder| {
+                f_builder
+                    .reserve_lines(2)
+                    .integer(1, 0)
+                    .return_(0)
+                    .sub_label("done", |done_builder| done_builder.exit())
+            });
+            builder.main(|main_builder| main_builder.label_call("f", &[], 0).exit());
+            builder.finish()
+        };
+        let asm = build(crate::AsmBuilder::new());
+        let text = asm.to_string();
+        let mut written = Vec::new();
+        asm.write_to(&mut written).unwrap();
+        assert_eq!(written, text.as_bytes());
+        let finished = asm.finish();
+        assert_eq!(finished, text);
+        assert_eq!(finished.capacity(), finished.len());
+
+        let asm = build(crate::AsmBuilder::with_capacity(4096));
+        assert_eq!(asm.size_hint, 4096);
+        let finished = asm.finish();
+        assert_eq!(finished, text);
+        assert!(finished.capacity() >= 4096);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut builder = crate::AsmBuilder::with_source_tracking();
+        builder
+            .label("f", |f_builder| {
+                f_builder
+                    .private()
+                    .with_span(UserSpan {
+                        file: 1,
+                        start: 2,
+                        end: 3,
+                    })
+                    .integer(-1, 0)
+                    .branch_boolean(0, "f.done", "f.done")
+                    .sub_label("done", |done_builder| done_builder.return_(0))
+            })
+            .main(|main_builder| main_builder.label_call("f", &[], 0).exit());
+        let asm = builder.finish();
+
+        let json = serde_json::to_string(&asm).unwrap();
+        let cached: Asm = serde_json::from_str(&json).unwrap();
+        assert_eq!(cached.to_string(), asm.to_string());
+        assert_eq!(cached.labels()[0].visibility(), Visibility::Private);
+        assert_eq!(
+            cached.spans().to_string(),
+            r"f+0: 1:2..3
+f+1: 1:2..3
+f.done+0: 1:2..3"
+        );
+        assert!(cached.call_sites().is_empty());
+    }
+
+    #[test]
+    fn test_call_graph_dot() {
+        let mut builder = crate::AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .label_address("fib", 1)
+                .label_call("fib", &[1], 0)
+                .exit()
+        });
+        builder.label("fib", |fib_builder| {
+            fib_builder
+                .label_jump("fib.rec")
+                .sub_label("rec", |fib_rec_builder| {
+                    fib_rec_builder
+                        .label_call("fib", &[1], 0)
+                        .tail_call("putn", &[0])
+                })
+        });
+        builder.label("putn", |putn_builder| putn_builder.return_(1));
+
+        assert_eq!(
+            builder.finish().call_graph_dot(),
+            r#"digraph program {
+    "fib";
+    "putn";
+    "main";
+    "fib" -> "fib" [label="call"];
+    "fib" -> "putn" [label="jump"];
+    "main" -> "fib" [label="addr"];
+    "main" -> "fib" [label="call"];
+}"#
+        );
+    }
 }
@@ -0,0 +1,21 @@
+use crate::asm::Asm;
+
+pub mod c;
+
+/// Something that turns a finished program into an output format.
+pub trait Backend {
+    type Output;
+
+    fn emit(&self, asm: &Asm) -> Self::Output;
+}
+
+/// The MiniVM text format; the same output as [`Asm::finish`].
+pub struct MiniVm;
+
+impl Backend for MiniVm {
+    type Output = String;
+
+    fn emit(&self, asm: &Asm) -> String {
+        asm.to_string()
+    }
+}
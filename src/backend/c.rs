@@ -0,0 +1,814 @@
+//! Transpiler from MiniVM programs to portable C.
+//!
+//! Every function becomes a C function operating on a frame of registers. Calls allocate a new frame,
+//! while jumps into another function reuse the current one. Label addresses are indices into a table
+//! of `(function, label)` pairs. Jumps leaving a function, and `djump`s, return the label to run next to
+//! a trampoline rather than calling it, so loops through them run in constant C stack as they do on
+//! MiniVM. Arrays are heap allocated and never freed.
+
+use super::Backend;
+use crate::asm::{Asm, Label, LabelImpl, Line};
+use crate::instr::{Instruction, OpCode, Operand};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#ifdef __GNUC__
+#pragma GCC diagnostic ignored "-Wunused-function"
+#endif
+
+typedef struct mv_array mv_array;
+typedef struct mv_value {
+    int64_t num;
+    mv_array *arr;
+} mv_value;
+struct mv_array {
+    int64_t len;
+    mv_value *data;
+};
+typedef mv_value (*mv_func)(mv_value *r, int entry, int *next);
+
+static void mv_trap(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "minivm: %s\n", msg);
+    exit(1);
+}
+
+static void mv_exit(void) {
+    fflush(stdout);
+    exit(0);
+}
+
+static mv_value mv_fall_off(const char *func) {
+    fflush(stdout);
+    fprintf(stderr, "minivm: fell off the end of `%s`\n", func);
+    exit(1);
+}
+
+static mv_value mv_int(int64_t num) {
+    mv_value v = {num, NULL};
+    return v;
+}
+
+static int64_t mv_num(mv_value v) {
+    if (v.arr != NULL) mv_trap("expected an integer, found an array");
+    return v.num;
+}
+
+static mv_array *mv_arr(mv_value v) {
+    if (v.arr == NULL) mv_trap("expected an array, found an integer");
+    return v.arr;
+}
+
+static mv_value mv_new_array(int64_t len) {
+    mv_array *arr;
+    mv_value v;
+    if (len < 0) mv_trap("negative array length");
+    arr = malloc(sizeof(mv_array));
+    if (arr == NULL) mv_trap("out of memory");
+    arr->len = len;
+    arr->data = calloc(len > 0 ? (size_t) len : 1, sizeof(mv_value));
+    if (arr->data == NULL) mv_trap("out of memory");
+    v.num = 0;
+    v.arr = arr;
+    return v;
+}
+
+static mv_value mv_str(const char *text, int64_t len) {
+    mv_value v = mv_new_array(len);
+    int64_t i;
+    for (i = 0; i < len; i++) v.arr->data[i] = mv_int((unsigned char) text[i]);
+    return v;
+}
+
+static mv_value *mv_index(mv_value arr, mv_value index) {
+    mv_array *a = mv_arr(arr);
+    int64_t i = mv_num(index);
+    if (i < 0 || i >= a->len) mv_trap("array index out of bounds");
+    return &a->data[i];
+}
+
+static int mv_eq(mv_value lhs, mv_value rhs) {
+    return lhs.num == rhs.num && lhs.arr == rhs.arr;
+}
+
+static mv_value mv_neg(mv_value v) {
+    return mv_int((int64_t) (0 - (uint64_t) mv_num(v)));
+}
+
+static mv_value mv_add(mv_value lhs, mv_value rhs) {
+    return mv_int((int64_t) ((uint64_t) mv_num(lhs) + (uint64_t) mv_num(rhs)));
+}
+
+static mv_value mv_sub(mv_value lhs, mv_value rhs) {
+    return mv_int((int64_t) ((uint64_t) mv_num(lhs) - (uint64_t) mv_num(rhs)));
+}
+
+static mv_value mv_mul(mv_value lhs, mv_value rhs) {
+    return mv_int((int64_t) ((uint64_t) mv_num(lhs) * (uint64_t) mv_num(rhs)));
+}
+
+static mv_value mv_div(mv_value lhs, mv_value rhs) {
+    int64_t a = mv_num(lhs), b = mv_num(rhs);
+    if (b == 0) mv_trap("division by zero");
+    if (b == -1) return mv_neg(lhs);
+    return mv_int(a / b);
+}
+
+static mv_value mv_mod(mv_value lhs, mv_value rhs) {
+    int64_t a = mv_num(lhs), b = mv_num(rhs);
+    if (b == 0) mv_trap("division by zero");
+    if (b == -1) return mv_int(0);
+    return mv_int(a % b);
+}
+
+static mv_value mv_band(mv_value lhs, mv_value rhs) {
+    return mv_int(mv_num(lhs) & mv_num(rhs));
+}
+
+static mv_value mv_bor(mv_value lhs, mv_value rhs) {
+    return mv_int(mv_num(lhs) | mv_num(rhs));
+}
+
+static mv_value mv_bxor(mv_value lhs, mv_value rhs) {
+    return mv_int(mv_num(lhs) ^ mv_num(rhs));
+}
+
+static mv_value mv_shl(mv_value lhs, mv_value rhs) {
+    return mv_int((int64_t) ((uint64_t) mv_num(lhs) << (mv_num(rhs) & 63)));
+}
+
+static mv_value mv_shr(mv_value lhs, mv_value rhs) {
+    return mv_int(mv_num(lhs) >> (mv_num(rhs) & 63));
+}
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranspileError {
+    /// Raw lines cannot be translated, as their meaning is unknown.
+    RawLine { label: String, line: String },
+    /// An instruction whose operands don't match its opcode.
+    MalformedInstruction { label: String, instr: String },
+    /// A jump, call, or address of a label that isn't defined in the program.
+    UnknownLabel { label: String, target: String },
+    /// A library, which has no entry point for the C `main` to call.
+    Library,
+    /// An `xcall`, whose function only the host running the program knows.
+    ExternCall { label: String, instr: String },
+    /// A float instruction, which the C runtime has no values for.
+    #[cfg(feature = "float")]
+    Float { label: String, instr: String },
+}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranspileError::RawLine { label, line } => {
+                write!(f, "cannot transpile raw line `{line}` in `{label}`")
+            }
+            TranspileError::MalformedInstruction { label, instr } => {
+                write!(f, "malformed instruction `{instr}` in `{label}`")
+            }
+            TranspileError::UnknownLabel { label, target } => {
+                write!(f, "unknown label `{target}` referenced in `{label}`")
+            }
+            TranspileError::Library => f.write_str("cannot transpile a library"),
+            TranspileError::ExternCall { label, instr } => {
+                write!(f, "cannot transpile extern call `{instr}` in `{label}`")
+            }
+            #[cfg(feature = "float")]
+            TranspileError::Float { label, instr } => {
+                write!(
+                    f,
+                    "cannot transpile float instruction `{instr}` in `{label}`"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TranspileError {}
+
+/// Emits a C program equivalent to the MiniVM program.
+pub struct Transpiler;
+
+impl Backend for Transpiler {
+    type Output = Result<String, TranspileError>;
+
+    fn emit(&self, asm: &Asm) -> Self::Output {
+        if asm.is_library() {
+            return Err(TranspileError::Library);
+        }
+        Program::new(asm).emit()
+    }
+}
+
+struct Target {
+    func: usize,
+    id: usize,
+}
+
+struct Program<'a> {
+    asm: &'a Asm,
+    /// Every label and sub-label, in order, along with the index of its function.
+    labels: Vec<(&'a str, usize)>,
+    by_name: HashMap<&'a str, usize>,
+}
+
+impl<'a> Program<'a> {
+    fn new(asm: &'a Asm) -> Program<'a> {
+        let mut labels = Vec::new();
+        for (func, label) in asm.iter().enumerate() {
+            labels.push((label.name(), func));
+            for sub_label in label.sub_labels() {
+                labels.push((sub_label.name(), func));
+            }
+        }
+        let by_name = labels
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (*name, id))
+            .collect();
+        Self {
+            asm,
+            labels,
+            by_name,
+        }
+    }
+
+    fn emit(&self) -> Result<String, TranspileError> {
+        let mut out = String::from(PRELUDE);
+        let _ = writeln!(out, "\n#define MV_NREGS {}", self.frame_size());
+
+        out.push('\n');
+        for (func, label) in self.asm.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "static mv_value mv_fn_{func}(mv_value *r, int entry, int *next); /* {} */",
+                comment(label.name())
+            );
+        }
+
+        out.push_str(
+            "\nstatic const struct {\n    mv_func func;\n    int entry;\n} mv_labels[] = {\n",
+        );
+        for (id, (_, func)) in self.labels.iter().enumerate() {
+            let _ = writeln!(out, "    {{mv_fn_{func}, {id}}},");
+        }
+        out.push_str("};\n");
+        out.push_str(
+            r#"
+static int mv_addr(mv_value addr) {
+    int64_t id = mv_num(addr);
+    if (id < 0 || id >= (int64_t) (sizeof(mv_labels) / sizeof(mv_labels[0]))) mv_trap("invalid label address");
+    return (int) id;
+}
+
+static mv_value mv_run(mv_value *r, int id) {
+    mv_value v = mv_int(0);
+    while (id >= 0) v = mv_labels[id].func(r, id, &id);
+    return v;
+}
+"#,
+        );
+
+        for (func, label) in self.asm.iter().enumerate() {
+            out.push('\n');
+            self.emit_function(&mut out, func, label)?;
+        }
+
+        let start = if self.asm.has_standard_entry() {
+            let main = self.target("__entry", "main")?;
+            format!("mv_run(f, {});", main.id)
+        } else {
+            out.push('\n');
+            self.emit_entry(&mut out)?;
+            "int next;\n    mv_entry(f, &next);\n    mv_run(f, next);".to_string()
+        };
+        let _ = write!(
+            out,
+            r"
+int main(void) {{
+    mv_value f[MV_NREGS] = {{{{0}}}};
+    {start}
+    mv_exit();
+    return 0;
+}}
+"
+        );
+        Ok(out)
+    }
+
+    /// Emit a custom entry block as `mv_entry`, which is never the target of a label address.
+    fn emit_entry(&self, out: &mut String) -> Result<(), TranspileError> {
+        let entry = self.asm.entry();
+        let name = entry.name();
+        let _ = writeln!(
+            out,
+            "/* {} */\nstatic mv_value mv_entry(mv_value *r, int *next) {{\n    *next = -1;",
+            comment(name)
+        );
+        for line in entry.lines() {
+            match line {
+                Line::Instruction(instr) => {
+                    let stmt = self.statement(usize::MAX, name, instr)?;
+                    let _ = writeln!(out, "    {stmt}");
+                }
+                Line::Raw(raw) => {
+                    return Err(TranspileError::RawLine {
+                        label: name.to_string(),
+                        line: raw.clone(),
+                    })
+                }
+            }
+        }
+        let _ = writeln!(out, "    return mv_fall_off({});\n}}", c_string(name));
+        Ok(())
+    }
+
+    fn frame_size(&self) -> usize {
+        let instructions = || {
+            std::iter::once(self.asm.entry())
+                .chain(self.asm.iter().flat_map(|label| {
+                    std::iter::once(&**label)
+                        .chain(label.sub_labels().iter().map(|sub_label| &**sub_label))
+                }))
+                .flat_map(LabelImpl::instructions)
+        };
+        let max_reg = instructions()
+            .flat_map(|instr| instr.dest.into_iter().chain(instr.uses()))
+            .max()
+            .unwrap_or(0);
+        // Calls write their arguments to `f[1]` onwards, which may be past the highest register they name.
+        let max_args = instructions()
+            .filter(|instr| matches!(instr.op, OpCode::Call | OpCode::DCall))
+            .map(|instr| instr.operands.len().saturating_sub(1))
+            .max()
+            .unwrap_or(0);
+        usize::from(max_reg).max(max_args) + 1
+    }
+
+    fn target(&self, label: &str, target: &str) -> Result<Target, TranspileError> {
+        match self.by_name.get(target) {
+            Some(&id) => Ok(Target {
+                func: self.labels[id].1,
+                id,
+            }),
+            None => Err(TranspileError::UnknownLabel {
+                label: label.to_string(),
+                target: target.to_string(),
+            }),
+        }
+    }
+
+    fn emit_function(
+        &self,
+        out: &mut String,
+        func: usize,
+        label: &Label,
+    ) -> Result<(), TranspileError> {
+        let name = label.name();
+        let _ = writeln!(
+            out,
+            "/* {} */\nstatic mv_value mv_fn_{func}(mv_value *r, int entry, int *next) {{",
+            comment(name)
+        );
+
+        let start = self.by_name[name];
+        out.push_str("    *next = -1;\n");
+        // Only `djump` comes back to the switch, and C compilers warn about unused labels.
+        let djumps = label
+            .blocks()
+            .flat_map(LabelImpl::instructions)
+            .any(|instr| instr.op == OpCode::DJump);
+        if djumps {
+            out.push_str("mv_switch:\n");
+        }
+        out.push_str("    switch (entry) {\n");
+        let _ = writeln!(out, "    case {start}: goto mv_L{start};");
+        for sub_label in label.sub_labels() {
+            let id = self.by_name[sub_label.name()];
+            let _ = writeln!(out, "    case {id}: goto mv_L{id};");
+        }
+        out.push_str("    default: break;\n    }\n");
+
+        let blocks = std::iter::once((start, &**label)).chain(
+            label
+                .sub_labels()
+                .iter()
+                .map(|sub_label| (self.by_name[sub_label.name()], &**sub_label)),
+        );
+        for (id, block) in blocks {
+            let _ = writeln!(out, "mv_L{id}:;");
+            for line in block.lines() {
+                match line {
+                    Line::Instruction(instr) => {
+                        let stmt = self.statement(func, name, instr)?;
+                        let _ = writeln!(out, "    {stmt}");
+                    }
+                    Line::Raw(raw) => {
+                        return Err(TranspileError::RawLine {
+                            label: name.to_string(),
+                            line: raw.clone(),
+                        })
+                    }
+                }
+            }
+        }
+
+        let _ = writeln!(out, "    return mv_fall_off({});\n}}", c_string(name));
+        Ok(())
+    }
+
+    fn jump(&self, func: usize, label: &str, target: &str) -> Result<String, TranspileError> {
+        let target = self.target(label, target)?;
+        if target.func == func {
+            Ok(format!("goto mv_L{};", target.id))
+        } else {
+            Ok(format!("{{ *next = {}; return r[0]; }}", target.id))
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn statement(
+        &self,
+        func: usize,
+        label: &str,
+        instr: &Instruction,
+    ) -> Result<String, TranspileError> {
+        let malformed = || TranspileError::MalformedInstruction {
+            label: label.to_string(),
+            instr: instr.to_string(),
+        };
+        let reg = |i: usize| match instr.operands.get(i) {
+            Some(Operand::Reg(reg)) => Ok(*reg),
+            _ => Err(malformed()),
+        };
+        let lbl = |i: usize| match instr.operands.get(i) {
+            Some(Operand::Label(target)) => Ok(target.as_str()),
+            _ => Err(malformed()),
+        };
+        let dest = || instr.dest.ok_or_else(malformed);
+        let arity = |n: usize| {
+            if instr.operands.len() == n {
+                Ok(())
+            } else {
+                Err(malformed())
+            }
+        };
+        let frame = |args: &[Operand]| -> Result<String, TranspileError> {
+            let mut buf = String::from("mv_value f[MV_NREGS] = {{0}}; ");
+            for (i, arg) in args.iter().enumerate() {
+                let arg = arg.as_reg().ok_or_else(malformed)?;
+                let _ = write!(buf, "f[{}] = r[{arg}]; ", i + 1);
+            }
+            Ok(buf)
+        };
+
+        let stmt = match instr.op {
+            OpCode::Exit => {
+                arity(0)?;
+                "mv_exit();".to_string()
+            }
+            OpCode::Reg => {
+                arity(1)?;
+                format!("r[{}] = r[{}];", dest()?, reg(0)?)
+            }
+            OpCode::Jump => {
+                arity(1)?;
+                self.jump(func, label, lbl(0)?)?
+            }
+            OpCode::Call => {
+                let target = self.target(label, lbl(0)?)?;
+                format!(
+                    "{{ {}r[{}] = mv_run(f, {}); }}",
+                    frame(&instr.operands[1..])?,
+                    dest()?,
+                    target.id
+                )
+            }
+            OpCode::Addr => {
+                arity(1)?;
+                let target = self.target(label, lbl(0)?)?;
+                format!("r[{}] = mv_int({});", dest()?, target.id)
+            }
+            OpCode::DJump => {
+                arity(1)?;
+                let addr = reg(0)?;
+                if func == usize::MAX {
+                    format!("{{ *next = mv_addr(r[{addr}]); return r[0]; }}")
+                } else {
+                    // Stay in this function when the address is one of its own labels.
+                    format!(
+                        "{{ int id = mv_addr(r[{addr}]); if (mv_labels[id].func == mv_fn_{func}) {{ entry = id; goto mv_switch; }} *next = id; return r[0]; }}"
+                    )
+                }
+            }
+            OpCode::DCall => {
+                let addr = reg(0)?;
+                format!(
+                    "{{ {}r[{}] = mv_run(f, mv_addr(r[{addr}])); }}",
+                    frame(&instr.operands[1..])?,
+                    dest()?
+                )
+            }
+            OpCode::ExternCall => {
+                return Err(TranspileError::ExternCall {
+                    label: label.to_string(),
+                    instr: instr.to_string(),
+                })
+            }
+            OpCode::Ret => {
+                arity(1)?;
+                format!("return r[{}];", reg(0)?)
+            }
+            OpCode::Int => {
+                arity(1)?;
+                let value = instr.operands[0].as_int().ok_or_else(malformed)?;
+                let value = if value == i64::MIN {
+                    "INT64_C(-9223372036854775807) - 1".to_string()
+                } else {
+                    format!("INT64_C({value})")
+                };
+                format!("r[{}] = mv_int({value});", dest()?)
+            }
+            OpCode::Neg => {
+                arity(1)?;
+                format!("r[{}] = mv_neg(r[{}]);", dest()?, reg(0)?)
+            }
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Mod
+            | OpCode::BAnd
+            | OpCode::BOr
+            | OpCode::BXor
+            | OpCode::Shl
+            | OpCode::Shr => {
+                arity(2)?;
+                format!(
+                    "r[{}] = mv_{}(r[{}], r[{}]);",
+                    dest()?,
+                    instr.op.mnemonic(),
+                    reg(0)?,
+                    reg(1)?
+                )
+            }
+            OpCode::Bb => {
+                arity(3)?;
+                format!(
+                    "if (mv_num(r[{}]) != 0) {} else {}",
+                    reg(0)?,
+                    self.jump(func, label, lbl(2)?)?,
+                    self.jump(func, label, lbl(1)?)?
+                )
+            }
+            OpCode::Beq | OpCode::Blt => {
+                arity(4)?;
+                let (lhs, rhs) = (reg(0)?, reg(1)?);
+                let cond = if instr.op == OpCode::Beq {
+                    format!("mv_eq(r[{lhs}], r[{rhs}])")
+                } else {
+                    format!("mv_num(r[{lhs}]) < mv_num(r[{rhs}])")
+                };
+                format!(
+                    "if ({cond}) {} else {}",
+                    self.jump(func, label, lbl(3)?)?,
+                    self.jump(func, label, lbl(2)?)?
+                )
+            }
+            OpCode::Str => {
+                arity(1)?;
+                let Some(Operand::Str(text)) = instr.operands.first() else {
+                    return Err(malformed());
+                };
+                format!(
+                    "r[{}] = mv_str({}, {});",
+                    dest()?,
+                    c_string(text),
+                    text.len()
+                )
+            }
+            OpCode::Arr => {
+                arity(1)?;
+                format!("r[{}] = mv_new_array(mv_num(r[{}]));", dest()?, reg(0)?)
+            }
+            OpCode::Set => {
+                arity(3)?;
+                format!(
+                    "*mv_index(r[{}], r[{}]) = r[{}];",
+                    reg(0)?,
+                    reg(1)?,
+                    reg(2)?
+                )
+            }
+            OpCode::Get => {
+                arity(2)?;
+                format!(
+                    "r[{}] = *mv_index(r[{}], r[{}]);",
+                    dest()?,
+                    reg(0)?,
+                    reg(1)?
+                )
+            }
+            OpCode::Len => {
+                arity(1)?;
+                format!("r[{}] = mv_int(mv_arr(r[{}])->len);", dest()?, reg(0)?)
+            }
+            OpCode::Type => {
+                arity(1)?;
+                format!("r[{}] = mv_int(r[{}].arr != NULL);", dest()?, reg(0)?)
+            }
+            OpCode::PutChar => {
+                arity(1)?;
+                format!("putchar((int) mv_num(r[{}]));", reg(0)?)
+            }
+            #[cfg(feature = "float")]
+            OpCode::FInt
+            | OpCode::FAdd
+            | OpCode::FSub
+            | OpCode::FMul
+            | OpCode::FDiv
+            | OpCode::FBeq
+            | OpCode::FBlt => {
+                return Err(TranspileError::Float {
+                    label: label.to_string(),
+                    instr: instr.to_string(),
+                })
+            }
+        };
+        Ok(stmt)
+    }
+}
+
+fn comment(text: &str) -> String {
+    text.replace("*/", "* /")
+}
+
+fn c_string(text: &str) -> String {
+    let mut buf = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => buf.push_str("\\\""),
+            b'\\' => buf.push_str("\\\\"),
+            b' '..=b'~' => buf.push(char::from(byte)),
+            _ => {
+                let _ = write!(buf, "\\{byte:03o}");
+            }
+        }
+    }
+    buf.push('"');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    fn fib() -> Asm {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .integer(10, 0)
+                .label_call("fib", &[0], 0)
+                .integer(48, 1)
+                .add(0, 1, 0)
+                .put_char(0)
+                .exit()
+        });
+
+        builder.label("fib", |fib_builder| {
+            fib_builder
+                .integer(2, 0)
+                .branch_less_than(1, 0, "fib.then", "fib.else")
+                .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
+                .sub_label("else", |fib_else_builder| {
+                    fib_else_builder
+                        .integer(1, 0)
+                        .sub(1, 0, 1)
+                        .sub(1, 0, 0)
+                        .label_call("fib", &[1], 1)
+                        .label_call("fib", &[0], 0)
+                        .add(0, 1, 0)
+                        .return_(0)
+                })
+        });
+
+        builder.finish()
+    }
+
+    #[test]
+    fn test_transpile_functions() {
+        let c = Transpiler.emit(&fib()).unwrap();
+
+        assert!(c.contains("#define MV_NREGS 2\n"));
+        assert!(c.contains(
+            r#"/* fib */
+static mv_value mv_fn_0(mv_value *r, int entry, int *next) {
+    *next = -1;
+    switch (entry) {
+    case 0: goto mv_L0;
+    case 1: goto mv_L1;
+    case 2: goto mv_L2;
+    default: break;
+    }
+mv_L0:;
+    r[0] = mv_int(INT64_C(2));
+    if (mv_num(r[1]) < mv_num(r[0])) goto mv_L1; else goto mv_L2;
+mv_L1:;
+    return r[1];
+mv_L2:;
+    r[0] = mv_int(INT64_C(1));
+    r[1] = mv_sub(r[1], r[0]);
+    r[0] = mv_sub(r[1], r[0]);
+    { mv_value f[MV_NREGS] = {{0}}; f[1] = r[1]; r[1] = mv_run(f, 0); }
+    { mv_value f[MV_NREGS] = {{0}}; f[1] = r[0]; r[0] = mv_run(f, 0); }
+    r[0] = mv_add(r[0], r[1]);
+    return r[0];
+    return mv_fall_off("fib");
+}"#
+        ));
+        assert!(c.contains("    mv_run(f, 3);\n"));
+    }
+
+    #[test]
+    fn test_transpile_frame_holds_all_arguments() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(1, 1)
+                .label_call("f", &[1, 1, 1], 1)
+                .exit()
+        });
+        builder.label("f", |f_builder| f_builder.return_(1));
+        let c = Transpiler.emit(&builder.finish()).unwrap();
+
+        assert!(c.contains("#define MV_NREGS 4\n"));
+        assert!(c.contains("f[1] = r[1]; f[2] = r[1]; f[3] = r[1];"));
+    }
+
+    #[test]
+    fn test_transpile_djump_loop() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .main(|main_builder| {
+                main_builder
+                    .integer(1_000_000, 1)
+                    .label_address("main.loop", 2)
+                    .label_address("done", 3)
+                    .sub_label("loop", |loop_builder| {
+                        loop_builder
+                            .integer(1, 4)
+                            .sub(1, 4, 1)
+                            .branch_if(1, "main.again")
+                            .dynamic_jump(3)
+                    })
+                    .sub_label("again", |again_builder| again_builder.dynamic_jump(2))
+            })
+            .label("done", |done_builder| done_builder.label_jump("finish"))
+            .label("finish", |finish_builder| finish_builder.exit());
+        let c = Transpiler.emit(&builder.finish()).unwrap();
+
+        // A `djump` to a label of its own function loops inside it, and one leaving it goes through the
+        // trampoline, so neither grows the C stack.
+        assert!(c.contains(
+            "    { int id = mv_addr(r[2]); if (mv_labels[id].func == mv_fn_2) { entry = id; goto mv_switch; } *next = id; return r[0]; }\n"
+        ));
+        assert!(c.contains("    { *next = 1; return r[0]; }\n"));
+        assert!(!c.contains("return mv_fn_"));
+    }
+
+    #[test]
+    fn test_transpile_rejects_raw_lines() {
+        let mut asm = fib();
+        asm.main().push_line("nop");
+
+        assert_eq!(
+            Transpiler.emit(&asm),
+            Err(TranspileError::RawLine {
+                label: "main".to_string(),
+                line: "nop".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_transpile_rejects_unknown_labels() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.label_jump("missing"));
+
+        assert_eq!(
+            Transpiler.emit(&builder.finish()),
+            Err(TranspileError::UnknownLabel {
+                label: "main".to_string(),
+                target: "missing".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! `minivm-asm`, for working with hand-written `.minivm` files.
+
+#![warn(clippy::pedantic)]
+
+use minivm_asm_rs::{asm::Asm, interp, lint, opt, parse};
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: minivm-asm <command> <file>
+
+Reads the file, or stdin if it is `-`, and prints the result to stdout.
+
+commands:
+    check   parse the file and report lints
+    fmt     print the file in its normal form
+    gc      run the file and print what a model of the garbage collector did
+    opt     run the optimization passes and print the result
+    run     run the file with the built-in interpreter and print its output
+    view    browse the file and step through it, with the `tui` feature";
+
+/// Why a command failed, along with what it printed before failing, like the output of a program up to a
+/// trap.
+#[derive(Debug)]
+struct Failure {
+    output: Vec<u8>,
+    message: String,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure {
+            output: Vec::new(),
+            message,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (output, result) = match execute(&args) {
+        Ok(output) => (output, ExitCode::SUCCESS),
+        Err(Failure { output, message }) => {
+            eprintln!("{message}");
+            (output, ExitCode::FAILURE)
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    // There is nowhere left to report a failure to write the output.
+    let _ = stdout.write_all(&output);
+    result
+}
+
+/// Run the command of `args`, returning what it prints or the error to report.
+fn execute(args: &[String]) -> Result<Vec<u8>, Failure> {
+    let [command, path] = args else {
+        return Err(USAGE.to_string().into());
+    };
+    let asm = read(path)?;
+    let output = match command.as_str() {
+        "check" => {
+            let diagnostics = lint::check(&asm);
+            if diagnostics.is_empty() {
+                String::new()
+            } else {
+                format!("{}\n", lint::render(&asm, &diagnostics))
+            }
+        }
+        "fmt" => format!("{asm}\n"),
+        "gc" => {
+            let config = interp::Config {
+                gc: Some(interp::gc::GcConfig {
+                    model_fragmentation: true,
+                    ..interp::gc::GcConfig::default()
+                }),
+                ..interp::Config::default()
+            };
+            let result = interp::run(&asm, &config);
+            if let Err(trap) = result.result {
+                return Err(format!("{path}: {trap}").into());
+            }
+            format!("{}\n", result.gc.unwrap_or_default())
+        }
+        "opt" => {
+            let mut asm = asm;
+            pipeline().run(&mut asm);
+            format!("{asm}\n")
+        }
+        "run" => {
+            let result = interp::run(&asm, &interp::Config::default());
+            if let Err(trap) = result.result {
+                return Err(Failure {
+                    output: result.output,
+                    message: format!("{path}: {trap}"),
+                });
+            }
+            return Ok(result.output);
+        }
+        #[cfg(feature = "tui")]
+        "view" => {
+            minivm_asm_rs::tui::run(&asm)
+                .map_err(|error| format!("couldn't run the viewer: {error}"))?;
+            String::new()
+        }
+        _ => return Err(format!("unknown command `{command}`\n\n{USAGE}").into()),
+    };
+    Ok(output.into_bytes())
+}
+
+fn read(path: &str) -> Result<Asm, String> {
+    let text = if path == "-" {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|error| format!("couldn't read stdin: {error}"))?;
+        text
+    } else {
+        std::fs::read_to_string(path).map_err(|error| format!("couldn't read `{path}`: {error}"))?
+    };
+    parse::parse(&text).map_err(|error| format!("{path}: {error}"))
+}
+
+/// The passes of `opt`, rerun until the program stops changing.
+fn pipeline() -> opt::PassManager {
+    let peephole = opt::Peephole::new();
+    let mut passes = opt::PassManager::new();
+    passes
+        .label_pass("propagate_copies", opt::propagate_copies)
+        .label_pass("coalesce_moves", opt::coalesce_moves)
+        .label_pass("fold_constants", opt::fold_constants)
+        .label_pass("simplify_branches", opt::simplify_branches)
+        .label_pass("peephole", move |label| peephole.run(label))
+        .to_fixpoint(8);
+    passes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let path = std::env::temp_dir().join(format!("minivm-asm-{}.minivm", std::process::id()));
+        std::fs::write(
+            &path,
+            "@__entry\n  r0 <- call main\n  exit\nfunc main\n  r1 <- int 72\n  r2 <- reg r1\n  putchar r2\n  \
+             r1 <- int 105\n  putchar r1\n  exit\nend",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let execute = |command: &str| {
+            execute(&[command.to_string(), path.clone()])
+                .map(|output| String::from_utf8(output).unwrap())
+        };
+
+        assert_eq!(execute("run").unwrap(), "Hi");
+        assert_eq!(execute("check").unwrap(), "");
+        assert_eq!(
+            execute("fmt").unwrap(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 72
+    r2 <- reg r1
+    putchar r2
+    r1 <- int 105
+    putchar r1
+    exit
+end
+"
+        );
+        assert_eq!(
+            execute("opt").unwrap(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 72
+    putchar r1
+    r1 <- int 105
+    putchar r1
+    exit
+end
+"
+        );
+        assert_eq!(
+            execute("gc").unwrap(),
+            "0 arrays of 0 elements allocated, 0 collections freed 0 elements, at most 0 elements in use, \
+             heap grew to 0 elements\n"
+        );
+        assert!(execute("build")
+            .unwrap_err()
+            .message
+            .starts_with("unknown command `build`"));
+
+        // What the program printed before it trapped is kept.
+        std::fs::write(
+            &path,
+            "func main\n  r1 <- int 72\n  putchar r1\n  r2 <- int 0\n  r1 <- div r1 r2\n  exit\nend",
+        )
+        .unwrap();
+        let failure = execute("run").unwrap_err();
+        assert_eq!(failure.output, b"H");
+        assert_eq!(
+            failure.message,
+            format!(
+                "{path}: division by zero at main+3\n    in `r1 <- div r1 r2`\n    with r1 = 72"
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
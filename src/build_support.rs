@@ -0,0 +1,108 @@
+//! Generating programs from a build script, to be embedded with `include_str!`.
+//!
+//! A build script calls [`generate`] with the `OUT_DIR` Cargo gives it, and the crate embeds the program
+//! with `include_str!(concat!(env!("OUT_DIR"), "/{name}.minivm"))`.
+
+use crate::{AsmBuilder, BuildError};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum GenerateError {
+    /// The program couldn't be finished.
+    Build(BuildError),
+    /// The file couldn't be written.
+    Io(io::Error),
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerateError::Build(error) => error.fmt(f),
+            GenerateError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {}
+
+/// Build a program with `build` and write it to `{out_dir}/{name}.minivm`, returning the path.
+///
+/// The file is only written if its contents change, so crates embedding it aren't rebuilt needlessly. Cargo
+/// is told to rerun the build script when `build.rs` changes; use [`rerun_if_changed`] for any other file
+/// the program is generated from.
+///
+/// # Errors
+///
+/// Returns an error if the program can't be finished, as reported by [`AsmBuilder::finish_checked`], or
+/// the file can't be written.
+pub fn generate<F>(
+    out_dir: impl AsRef<Path>,
+    name: &str,
+    build: F,
+) -> Result<PathBuf, GenerateError>
+where
+    F: FnOnce(&mut AsmBuilder) -> &mut AsmBuilder,
+{
+    let mut builder = AsmBuilder::new();
+    build(&mut builder);
+    let text = builder
+        .finish_checked()
+        .map_err(GenerateError::Build)?
+        .finish();
+    let path = out_dir.as_ref().join(format!("{name}.minivm"));
+    write_if_changed(&path, &text).map_err(GenerateError::Io)?;
+    rerun_if_changed("build.rs");
+    Ok(path)
+}
+
+/// Tell Cargo to rerun the build script when `path` changes.
+pub fn rerun_if_changed(path: impl AsRef<Path>) {
+    println!("cargo:rerun-if-changed={}", path.as_ref().display());
+}
+
+fn write_if_changed(path: &Path, text: &str) -> io::Result<()> {
+    if fs::read_to_string(path).is_ok_and(|old| old == text) {
+        return Ok(());
+    }
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BuildInstruction;
+
+    #[test]
+    fn test_generate() {
+        let out_dir = std::env::temp_dir().join(format!("build-support-{}", std::process::id()));
+        fs::create_dir_all(&out_dir).unwrap();
+        let path = generate(&out_dir, "hello", |builder| {
+            builder.main(|main_builder| main_builder.integer(72, 1).put_char(1).exit())
+        })
+        .unwrap();
+        assert_eq!(path, out_dir.join("hello.minivm"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 72
+    putchar r1
+    exit
+end"
+        );
+
+        let error = generate(&out_dir, "broken", |builder| {
+            builder.main(|main_builder| main_builder.tail_call("missing", &[]))
+        })
+        .unwrap_err();
+        assert!(matches!(error, GenerateError::Build(_)));
+        assert!(!out_dir.join("broken.minivm").exists());
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
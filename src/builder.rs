@@ -1,17 +1,253 @@
-#![allow(clippy::module_name_repetitions)]
-
-use crate::{asm, Int};
-use std::borrow::Cow;
+#![allow(clippy::module_name_repetitions, clippy::missing_panics_doc)]
+
+use crate::{
+    asm::{self, LineTable, UserSpan, Visibility},
+    instr::{Instruction, Interner, OpCode, Operand},
+    parse::{self, ParseErrorKind},
+    randomize,
+    regalloc::VirtualRegBuilder,
+    runtime::{RequireRuntime, Runtime},
+    target::{self, Target, TargetError},
+    template::{Bindings, Template},
+    verify::{self, BlockError, VerifyError},
+    Int,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::{Arc, Mutex};
 
 pub type Lbl<'a> = &'a str;
 pub type Reg = u8;
 
+/// The name of a function and how many arguments it takes, [declared](AsmBuilder::declare) so calls to it
+/// can be checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FnSig {
+    pub name: String,
+    pub arity: usize,
+}
+
+impl FnSig {
+    #[must_use]
+    pub fn new(name: &str, arity: usize) -> FnSig {
+        Self {
+            name: name.to_string(),
+            arity,
+        }
+    }
+}
+
+/// Sees every instruction written by the label builders of an [`AsmBuilder`] before it is recorded, as
+/// [added](AsmBuilder::add_hook). Hooks are shared with [detached](AsmBuilder::detached_label_builder) label
+/// builders, so they may run on other threads.
+pub trait EmitHook: Send {
+    /// The lines to record in place of `instr`, written to the label or sub-label `block`: usually `instr`
+    /// itself, maybe changed, but any lines will do, or none to drop it.
+    fn emit(&mut self, block: &str, instr: Instruction) -> Vec<asm::Line>;
+}
+
+/// A string [interned](AsmBuilder::intern_string) by a builder, to be loaded with
+/// [`load_interned`](crate::RuntimeBuilderExt::load_interned).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DataRef(usize);
+
+impl DataRef {
+    /// The function building the array of every interned string.
+    pub const LABEL: &'static str = "__data";
+
+    /// The index of the string in the array of interned strings.
+    #[must_use]
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The function building the array of interned strings, required by the builders that intern or load them.
+pub(crate) struct Data;
+
+impl Runtime for Data {
+    fn name(&self) -> &str {
+        DataRef::LABEL
+    }
+
+    fn inject(&self, builder: &mut AsmBuilder) {
+        builder.inject_interned();
+    }
+}
+
+/// A function provided by the host, for [`extern_call`](BuildInstruction::extern_call).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Extern<'a> {
+    /// The function registered under this name.
+    Name(&'a str),
+    /// The function registered at this position, counting from 0.
+    Index(usize),
+}
+
+impl<'a> From<&'a str> for Extern<'a> {
+    fn from(name: &'a str) -> Self {
+        Extern::Name(name)
+    }
+}
+
+impl From<usize> for Extern<'_> {
+    fn from(index: usize) -> Self {
+        Extern::Index(index)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// A [`tail_call`](BuildInstruction::tail_call) to a function that isn't defined.
+    UndefinedTailCall(String),
+    /// An [exported](AsmBuilder::export) function that isn't defined.
+    UndefinedExport(String),
+    /// A constant read with [`integer_const`](BuildInstruction::integer_const) that isn't
+    /// [defined](AsmBuilder::define_const).
+    UndefinedConst(String),
+    /// A jump, branch, call or address, in the named label or sub-label, to a sub-label of a function of the
+    /// program that the function doesn't have.
+    UndefinedSubLabel { label: String, target: String },
+    /// A sub-label defined more than once in a function, as when a sub-label takes the name
+    /// [`branch_if`](BuildInstruction::branch_if) gave the one it falls through to.
+    DuplicateSubLabel(String),
+    /// A [`string`](BuildInstruction::string) that the `str` instruction can't hold, in the named label or
+    /// sub-label.
+    UnrepresentableString { label: String, text: String },
+    /// A call from the named label or sub-label passing a different number of arguments than the callee
+    /// was [declared](AsmBuilder::declare) with.
+    ArityMismatch {
+        label: String,
+        callee: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An instruction or entry block that the [target](AsmBuilder::target) doesn't accept.
+    Target(TargetError),
+    /// A raw line, in the named label or sub-label, that isn't an instruction, in a
+    /// [strict](AsmBuilder::strict) program.
+    InvalidRawLine {
+        label: String,
+        line: String,
+        error: ParseErrorKind,
+    },
+    /// A block of a [strict](AsmBuilder::strict) program that doesn't end in a terminator, or has an
+    /// instruction after one, as checked by [`verify::check_blocks`].
+    Block(BlockError),
+    /// An instruction, in the named label or sub-label, without the operands its opcode takes, as checked
+    /// by [`verify::check_instruction`].
+    InvalidInstruction {
+        label: String,
+        instr: String,
+        error: VerifyError,
+    },
+    /// A program with more instructions than its [limit](AsmBuilder::limit_size), along with how many
+    /// each function has, largest first.
+    TooLarge {
+        limit: usize,
+        size: usize,
+        functions: Vec<(String, usize)>,
+    },
+    /// A function with more instructions than its [limit](AsmBuilder::limit_function_size).
+    FunctionTooLarge {
+        function: String,
+        limit: usize,
+        size: usize,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::UndefinedTailCall(name) => {
+                write!(f, "tail call to undefined function `{name}`")
+            }
+            BuildError::UndefinedExport(name) => {
+                write!(f, "export of undefined function `{name}`")
+            }
+            BuildError::UndefinedConst(name) => write!(f, "undefined constant `{name}`"),
+            BuildError::UndefinedSubLabel { label, target } => {
+                write!(
+                    f,
+                    "reference to undefined sub-label `{target}` in `{label}`"
+                )
+            }
+            BuildError::DuplicateSubLabel(name) => {
+                write!(f, "sub-label `{name}` is defined more than once")
+            }
+            BuildError::UnrepresentableString { label, text } => {
+                write!(
+                    f,
+                    "string {text:?} in `{label}` can't be written with `str`"
+                )
+            }
+            BuildError::ArityMismatch {
+                label,
+                callee,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{callee}` takes {expected} arguments, but the call in `{label}` passes {found}"
+            ),
+            BuildError::Target(error) => error.fmt(f),
+            BuildError::Block(error) => error.fmt(f),
+            BuildError::InvalidRawLine { label, line, error } => {
+                write!(f, "raw line `{line}` in `{label}` is invalid: {error}")
+            }
+            BuildError::InvalidInstruction {
+                label,
+                instr,
+                error,
+            } => {
+                write!(f, "`{instr}` in `{label}` is invalid: {error}")
+            }
+            BuildError::TooLarge {
+                limit,
+                size,
+                functions,
+            } => {
+                write!(
+                    f,
+                    "the program has {size} instructions, over the limit of {limit}:"
+                )?;
+                for (function, size) in functions {
+                    write!(f, "\n    {function}: {size}")?;
+                }
+                Ok(())
+            }
+            BuildError::FunctionTooLarge {
+                function,
+                limit,
+                size,
+            } => write!(
+                f,
+                "`{function}` has {size} instructions, over the limit of {limit}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
 pub struct AsmBuilder {
     asm: asm::Asm,
     main: LabelBuilder,
     built_main: bool,
     unfinished: Option<LabelBuilder>,
+    deferred: Deferred,
+    injected_runtime: HashSet<String>,
+    exports: Vec<String>,
+    consts: HashMap<String, Int>,
+    sigs: HashMap<String, usize>,
+    interned: Vec<String>,
+    layout_seed: Option<u64>,
+    target: Option<Target>,
+    size_limit: Option<usize>,
+    function_size_limits: HashMap<String, usize>,
+    strict: bool,
 }
 
 impl AsmBuilder {
@@ -22,28 +258,163 @@ impl AsmBuilder {
             main: LabelBuilder::new("main"),
             built_main: false,
             unfinished: None,
+            deferred: Deferred::default(),
+            injected_runtime: HashSet::new(),
+            exports: Vec::new(),
+            consts: HashMap::new(),
+            sigs: HashMap::new(),
+            interned: Vec::new(),
+            layout_seed: None,
+            target: None,
+            size_limit: None,
+            function_size_limits: HashMap::new(),
+            strict: false,
+        }
+    }
+
+    /// Build a library: a fragment of functions to be linked into another program, without the entry block
+    /// or `main`. `id` names the library.
+    #[must_use]
+    pub fn new_library(id: &str) -> AsmBuilder {
+        Self {
+            asm: asm::Asm::new_library(id),
+            ..Self::new()
+        }
+    }
+
+    /// A builder [tracking sources](AsmBuilder::track_sources).
+    #[must_use]
+    pub fn with_source_tracking() -> AsmBuilder {
+        let mut builder = Self::new();
+        builder.track_sources();
+        builder
+    }
+
+    /// A builder with a [capacity hint](AsmBuilder::capacity_hint) of `bytes`.
+    #[must_use]
+    pub fn with_capacity(bytes: usize) -> AsmBuilder {
+        let mut builder = Self::new();
+        builder.capacity_hint(bytes);
+        builder
+    }
+
+    /// A builder for [`target`](AsmBuilder::target).
+    #[must_use]
+    pub fn with_target(target: Target) -> AsmBuilder {
+        let mut builder = Self::new();
+        builder.target(target);
+        builder
+    }
+
+    /// A builder with a [size limit](AsmBuilder::limit_size) of `max_instructions`.
+    #[must_use]
+    pub fn with_size_limit(max_instructions: usize) -> AsmBuilder {
+        let mut builder = Self::new();
+        builder.limit_size(max_instructions);
+        builder
+    }
+
+    /// Record where in the Rust code every instruction is written, in
+    /// [`Asm::call_sites`](asm::Asm::call_sites). Only labels built from now on are tracked, `main` included
+    /// if it hasn't been built yet.
+    ///
+    /// Only the call site of the [`BuildInstruction`] or extension method is recorded, so instructions
+    /// written by a helper of your own are attributed to the helper, unless it is `#[track_caller]`
+    /// too. Functions with virtual registers are not tracked.
+    pub fn track_sources(&mut self) -> &mut Self {
+        self.deferred.call_sites = Some(LineTable::default());
+        if !self.built_main {
+            self.main = self.label_builder("main");
+        }
+        self
+    }
+
+    /// Expect the program to take about `bytes` bytes once emitted, so that
+    /// [`Asm::finish`](asm::Asm::finish) allocates them up front.
+    pub fn capacity_hint(&mut self, bytes: usize) -> &mut Self {
+        self.asm.set_size_hint(bytes);
+        self
+    }
+
+    /// Build the program for `target`, checking when it is finished that the target accepts every
+    /// instruction and the entry block. A MiniVM release is recorded as the [version](asm::Asm::version) of
+    /// the program.
+    pub fn target(&mut self, target: Target) -> &mut Self {
+        if let Target::MiniVm(version) = target {
+            self.asm.set_version(Some(version));
         }
+        self.target = Some(target);
+        self
+    }
+
+    /// Allow the program at most `max_instructions` instructions, checking when it is finished. Every line
+    /// of every function counts, raw lines included, but not the entry block.
+    pub fn limit_size(&mut self, max_instructions: usize) -> &mut Self {
+        self.size_limit = Some(max_instructions);
+        self
+    }
+
+    fn label_builder(&self, name: &str) -> LabelBuilder {
+        let mut builder = LabelBuilder::new(name);
+        builder.deferred = self.deferred.inherit();
+        builder
     }
 
     fn build_main_check(&mut self) {
+        assert!(!self.asm.is_library(), "a library has no `main`");
         assert!(!self.built_main, "cannot build `main` more than once");
         self.built_main = true;
     }
 
     fn take_unfinished(&mut self) {
         if let Some(prev_builder) = self.unfinished.take() {
-            self.asm.push_label(prev_builder.finish());
+            if !prev_builder.abandoned {
+                self.push_label(prev_builder);
+            }
+        }
+    }
+
+    fn push_label(&mut self, builder: LabelBuilder) {
+        let (label, deferred) = builder.finish_with_deferred();
+        self.deferred.append(deferred);
+        self.asm.push_label(label);
+    }
+
+    fn inject_runtime(&mut self) {
+        // Runtime that other runtime requires is injected in a later round, after everything already
+        // required.
+        while !self.deferred.runtime.is_empty() {
+            for runtime in std::mem::take(&mut self.deferred.runtime) {
+                if self.injected_runtime.insert(runtime.name().to_owned()) {
+                    let injected = self.asm.labels().len();
+                    runtime.inject(self);
+                    self.take_unfinished();
+                    for label in &mut self.asm.labels_mut()[injected..] {
+                        label.set_visibility(Visibility::Private);
+                    }
+                }
+            }
         }
     }
 
-    /// Panics if `main` has already been built.
+    /// Panics if `main` has already been built, or the program is a library.
     #[must_use]
     pub fn build_main(&mut self) -> LabelBuilderGuard<'_> {
         self.build_main_check();
         LabelBuilderGuard::new(&mut self.main)
     }
 
-    /// Panics if `main` has already been built.
+    /// The full name of the sub-label `name` of `main`, like `main.loop` for `loop`, to refer to it from
+    /// other functions.
+    ///
+    /// Panics if the program is a library.
+    #[must_use]
+    pub fn main_ref(&self, name: &str) -> String {
+        assert!(!self.asm.is_library(), "a library has no `main`");
+        format!("{}.{name}", self.main.lbl.name())
+    }
+
+    /// Panics if `main` has already been built, or the program is a library.
     pub fn main<F>(&mut self, f: F) -> &mut Self
     where
         F: for<'a> FnOnce(&'a mut LabelBuilder) -> &'a mut LabelBuilder,
@@ -56,7 +427,7 @@ impl AsmBuilder {
     #[must_use]
     pub fn build_label(&mut self, name: &str) -> LabelBuilderGuard<'_> {
         self.take_unfinished();
-        let builder = LabelBuilder::new(name);
+        let builder = self.label_builder(name);
         let builder = self.unfinished.insert(builder);
         LabelBuilderGuard::new(builder)
     }
@@ -66,19 +437,477 @@ impl AsmBuilder {
         F: for<'a> FnOnce(&'a mut LabelBuilder) -> &'a mut LabelBuilder,
     {
         self.take_unfinished();
-        let mut builder = LabelBuilder::new(name);
+        let mut builder = self.label_builder(name);
+        f(&mut builder);
+        self.push_label(builder);
+        self
+    }
+
+    /// A builder for the label `name`, set up like those of [`AsmBuilder::label`], that can be sent to
+    /// another thread to build the label there. Add the label with [`AsmBuilder::push_label_builder`], in the
+    /// order the labels should be emitted in.
+    #[must_use]
+    pub fn detached_label_builder(&self, name: &str) -> LabelBuilder {
+        self.label_builder(name)
+    }
+
+    /// Add the label built by `builder`, along with any [`Runtime`] it requires, after the labels built so far.
+    pub fn push_label_builder(&mut self, builder: LabelBuilder) -> &mut Self {
+        self.take_unfinished();
+        self.push_label(builder);
+        self
+    }
+
+    /// Add a function written against virtual registers, allocating its registers now.
+    pub fn virtual_label<F>(&mut self, name: &str, arity: u8, f: F) -> &mut Self
+    where
+        F: for<'a> FnOnce(&'a mut VirtualRegBuilder) -> &'a mut VirtualRegBuilder,
+    {
+        self.take_unfinished();
+        let mut builder = VirtualRegBuilder::new(name, arity);
         f(&mut builder);
-        self.asm.push_label(builder.finish());
+        let (label, deferred) = builder.finish_with_deferred();
+        self.deferred.append(deferred);
+        self.asm.push_label(label);
+        self
+    }
+
+    /// Start the program by calling `name` instead of `main`, which is left out unless it's built or called.
+    ///
+    /// Panics if the program is a library.
+    pub fn entry_point(&mut self, name: &str) -> &mut Self {
+        assert!(!self.asm.is_library(), "a library has no entry block");
+        self.asm.set_entry_point(name);
+        self
+    }
+
+    /// Replace the entry block, which only calls `main` and exits by default. It can set up arguments or
+    /// anything else before calling into the program, and should end in `exit`.
+    ///
+    /// Panics if the entry block has sub-labels, or the program is a library.
+    pub fn raw_entry<F>(&mut self, f: F) -> &mut Self
+    where
+        F: for<'a> FnOnce(&'a mut LabelBuilder) -> &'a mut LabelBuilder,
+    {
+        assert!(!self.asm.is_library(), "a library has no entry block");
+        let mut builder = self.label_builder(self.asm.entry().name());
+        f(&mut builder);
+        let (label, deferred) = builder.finish_with_deferred();
+        assert!(
+            label.sub_labels().is_empty(),
+            "the entry block can't have sub-labels"
+        );
+        self.deferred.append(deferred);
+        *self.asm.entry_mut().lines_mut() = label.lines().to_vec();
+        self
+    }
+
+    /// Add `name` to the functions the program exports. Once any function is exported, every other one is
+    /// made [private](Visibility::Private) when the program is finished.
+    pub fn export(&mut self, name: &str) -> &mut Self {
+        self.exports.push(name.to_string());
+        self
+    }
+
+    /// Define the constant `name` as `value`, for [`integer_const`](BuildInstruction::integer_const)
+    /// anywhere in the program. A later definition replaces an earlier one.
+    pub fn define_const(&mut self, name: &str, value: Int) -> &mut Self {
+        self.consts.insert(name.to_string(), value);
+        self
+    }
+
+    /// Declare the signature of a function, so every call to it is checked to pass `sig.arity` arguments when
+    /// the program is finished. The function may be defined by another module. A later declaration replaces
+    /// an earlier one.
+    pub fn declare(&mut self, sig: FnSig) -> &mut Self {
+        self.sigs.insert(sig.name, sig.arity);
+        self
+    }
+
+    /// Check when the program is finished that the function `name` has at most `max_instructions`
+    /// instructions, counted as for [`AsmBuilder::limit_size`]. A later limit replaces an earlier one.
+    pub fn limit_function_size(&mut self, name: &str, max_instructions: usize) -> &mut Self {
+        self.function_size_limits
+            .insert(name.to_string(), max_instructions);
+        self
+    }
+
+    /// Run `hook` on every instruction written from now on, after the hooks added before it. Instructions
+    /// of functions with virtual registers are not hooked.
+    pub fn add_hook(&mut self, hook: impl EmitHook + 'static) -> &mut Self {
+        self.deferred.hooks.lock().unwrap().push(Box::new(hook));
+        self.main.deferred.hooks = Arc::clone(&self.deferred.hooks);
+        self
+    }
+
+    /// Add `text` to the strings of the program, unless it's already there, and refer to it. Every interned
+    /// string is built once, into the array [`load_data`](crate::RuntimeBuilderExt::load_data) stores.
+    /// MiniVM has no globals, so load the array once at the start of `main` and pass it to the functions that
+    /// [load](crate::RuntimeBuilderExt::load_interned) strings from it.
+    pub fn intern_string(&mut self, text: &str) -> DataRef {
+        let index = self
+            .interned
+            .iter()
+            .position(|interned| interned == text)
+            .unwrap_or_else(|| {
+                self.interned.push(text.to_string());
+                self.interned.len() - 1
+            });
+        self.deferred.require_runtime(Box::new(Data));
+        DataRef(index)
+    }
+
+    /// Build the function returning the array of every interned string, which may be empty.
+    fn inject_interned(&mut self) {
+        let interned = std::mem::take(&mut self.interned);
+        self.label(DataRef::LABEL, |data_builder| {
+            let len = Int::try_from(interned.len()).expect("too many interned strings");
+            data_builder.private().integer(len, 1).array(1, 0);
+            for (index, text) in (0..).zip(&interned) {
+                data_builder
+                    .integer(index, 1)
+                    .string(text, 2)
+                    .set_array_index(0, 1, 2);
+            }
+            data_builder.return_(0)
+        });
+    }
+
+    /// Leave out the checks of [`assert_true`](crate::RuntimeBuilderExt::assert_true) from labels built from
+    /// now on, as for a release build.
+    pub fn strip_assertions(&mut self) -> &mut Self {
+        self.deferred.strip_assertions = true;
+        self.main.deferred.strip_assertions = true;
+        self
+    }
+
+    /// Check when the program is finished that every raw line, such as those [hooks](AsmBuilder::add_hook)
+    /// write, is an instruction, rather than leaving MiniVM to reject it. Comments are rejected too, as
+    /// MiniVM has none.
+    ///
+    /// Every function and sub-label must also end in `ret`, `exit`, a jump or a branch, with nothing after
+    /// it, rather than running into whatever comes next, which for the last block of a function is the body
+    /// of the next one.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Randomize the layout of the finished program using [`randomize::layout`], reproducibly from `seed`.
+    pub fn randomize_layout(&mut self, seed: u64) -> &mut Self {
+        self.layout_seed = Some(seed);
         self
     }
 
+    /// Finish the program, injecting every [`Runtime`] required by its labels and substituting constants.
+    /// Functions of the runtime are [private](Visibility::Private).
+    ///
+    /// Panics if the program can't be finished, as reported by [`AsmBuilder::finish_checked`].
     #[must_use]
-    pub fn finish(mut self) -> asm::Asm {
+    pub fn finish(self) -> asm::Asm {
+        self.finish_checked()
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    /// Like [`AsmBuilder::finish`], reporting the first problem rather than panicking.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a [`tail_call`](BuildInstruction::tail_call) targets a function that isn't
+    /// defined, an exported function isn't defined, a constant isn't defined, a sub-label of a function isn't
+    /// defined, a string can't be written with `str`, an instruction doesn't have the operands its opcode
+    /// takes, a call doesn't match the declared arity of its callee, the target doesn't accept the program,
+    /// the program or one of its functions is over its size limit, or a raw line of a strict program isn't an
+    /// instruction or one of its blocks doesn't end in a terminator.
+    pub fn finish_checked(mut self) -> Result<asm::Asm, BuildError> {
         self.take_unfinished();
-        let AsmBuilder { mut asm, main, .. } = self;
-        *asm.main() = main.finish();
-        asm
+        let mut main = std::mem::replace(&mut self.main, LabelBuilder::new("main"));
+        if main.abandoned {
+            main = self.label_builder("main");
+        }
+        let (main, deferred) = main.finish_with_deferred();
+        self.deferred.append(deferred);
+        self.inject_runtime();
+        let AsmBuilder {
+            mut asm,
+            deferred,
+            exports,
+            consts,
+            sigs,
+            layout_seed,
+            target,
+            size_limit,
+            function_size_limits,
+            strict,
+            ..
+        } = self;
+        if !asm.is_library() {
+            *asm.main() = main;
+        }
+        share_label_names(&mut asm, deferred.names);
+        if let Some(call_sites) = deferred.call_sites {
+            asm.call_sites_mut().extend(call_sites);
+        }
+        asm.spans_mut().extend(deferred.spans);
+        for target in deferred.tail_calls {
+            if !asm.iter().any(|label| label.name() == target) {
+                return Err(BuildError::UndefinedTailCall(target));
+            }
+        }
+        for name in &exports {
+            if !asm.iter().any(|label| label.name() == name) {
+                return Err(BuildError::UndefinedExport(name.clone()));
+            }
+        }
+        substitute_consts(&mut asm, &consts)?;
+        check_duplicate_sub_labels(&asm)?;
+        check_sub_labels(&asm)?;
+        if strict {
+            check_raw_lines(&asm)?;
+            verify::check_blocks(&asm).map_err(BuildError::Block)?;
+        }
+        check_strings(&asm)?;
+        check_instructions(&asm)?;
+        check_arities(&asm, &sigs)?;
+        if !exports.is_empty() {
+            for label in asm.labels_mut() {
+                let visibility = if exports.iter().any(|name| name == label.name()) {
+                    Visibility::Public
+                } else {
+                    Visibility::Private
+                };
+                label.set_visibility(visibility);
+            }
+        }
+        if let Some(target) = &target {
+            target::check(&asm, target).map_err(BuildError::Target)?;
+        }
+        check_sizes(&asm, size_limit, &function_size_limits)?;
+        if let Some(seed) = layout_seed {
+            randomize::layout(&mut asm, seed);
+        }
+        Ok(asm)
+    }
+}
+
+/// Make the references to labels throughout `asm` share their names, including those of labels built on their
+/// own and added to the program.
+fn share_label_names(asm: &mut asm::Asm, mut names: Interner) {
+    let mut share = |block: &mut asm::LabelImpl| {
+        for instr in block
+            .lines_mut()
+            .iter_mut()
+            .filter_map(asm::Line::as_instruction_mut)
+        {
+            names.share(instr);
+        }
+    };
+    share(asm.entry_mut());
+    for block in asm.iter_mut().flat_map(asm::Label::blocks_mut) {
+        share(block);
+    }
+}
+
+/// Replace the names written by [`integer_const`](BuildInstruction::integer_const) with their values.
+fn substitute_consts(asm: &mut asm::Asm, consts: &HashMap<String, Int>) -> Result<(), BuildError> {
+    substitute_consts_in(asm.entry_mut().lines_mut(), consts)?;
+    for block in asm.iter_mut().flat_map(asm::Label::blocks_mut) {
+        substitute_consts_in(block.lines_mut(), consts)?;
+    }
+    Ok(())
+}
+
+fn substitute_consts_in(
+    lines: &mut [asm::Line],
+    consts: &HashMap<String, Int>,
+) -> Result<(), BuildError> {
+    for line in lines {
+        let asm::Line::Instruction(instr) = line else {
+            continue;
+        };
+        for operand in &mut instr.operands {
+            if let Operand::Const(name) = operand {
+                let value = consts
+                    .get(name)
+                    .ok_or_else(|| BuildError::UndefinedConst(name.clone()))?;
+                *operand = Operand::Int(*value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Panics if `label` reads a constant, which is only substituted when an [`AsmBuilder`] finishes the program.
+#[track_caller]
+pub(crate) fn assert_no_consts(label: &asm::Label) {
+    let name = label
+        .blocks()
+        .flat_map(asm::LabelImpl::instructions)
+        .flat_map(|instr| &instr.operands)
+        .find_map(|operand| match operand {
+            Operand::Const(name) => Some(name),
+            _ => None,
+        });
+    if let Some(name) = name {
+        panic!(
+            "constant `{name}` in `{}` can only be substituted by `AsmBuilder`",
+            label.name()
+        );
+    }
+}
+
+/// Check that no function defines a sub-label twice, or one named like the function itself.
+fn check_duplicate_sub_labels(asm: &asm::Asm) -> Result<(), BuildError> {
+    for label in asm.iter() {
+        let mut names = HashSet::new();
+        for block in label.blocks() {
+            if !names.insert(block.name()) {
+                return Err(BuildError::DuplicateSubLabel(block.name().to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every reference to a sub-label of a function of the program, `main` included, names one the
+/// function has. References to other labels may be resolved by linking, so are left alone.
+fn check_sub_labels(asm: &asm::Asm) -> Result<(), BuildError> {
+    let functions: HashMap<&str, &asm::Label> =
+        asm.iter().map(|label| (label.name(), label)).collect();
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(asm::Label::blocks)) {
+        for target in block.instructions().flat_map(Instruction::targets) {
+            let Some((function, _)) = target.split_once('.') else {
+                continue;
+            };
+            let Some(function) = functions.get(function) else {
+                continue;
+            };
+            if !function.blocks().any(|block| block.name() == target) {
+                return Err(BuildError::UndefinedSubLabel {
+                    label: block.name().to_string(),
+                    target: target.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the program and its functions are within their limits on the number of instructions.
+fn check_sizes(
+    asm: &asm::Asm,
+    limit: Option<usize>,
+    function_limits: &HashMap<String, usize>,
+) -> Result<(), BuildError> {
+    let mut functions: Vec<(String, usize)> = asm
+        .iter()
+        .map(|label| {
+            let size = label.blocks().map(|block| block.lines().len()).sum();
+            (label.name().to_string(), size)
+        })
+        .collect();
+    for (function, size) in &functions {
+        if let Some(&limit) = function_limits.get(function) {
+            if *size > limit {
+                return Err(BuildError::FunctionTooLarge {
+                    function: function.clone(),
+                    limit,
+                    size: *size,
+                });
+            }
+        }
+    }
+    let size = functions.iter().map(|(_, size)| size).sum();
+    match limit {
+        Some(limit) if size > limit => {
+            functions.sort_by(|(_, a), (_, b)| b.cmp(a));
+            Err(BuildError::TooLarge {
+                limit,
+                size,
+                functions,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that every line of every raw line is an instruction, or empty.
+fn check_raw_lines(asm: &asm::Asm) -> Result<(), BuildError> {
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(asm::Label::blocks)) {
+        for raw in block.lines().iter().filter_map(|line| match line {
+            asm::Line::Raw(raw) => Some(raw),
+            asm::Line::Instruction(_) => None,
+        }) {
+            for line in raw.lines().filter(|line| !line.trim().is_empty()) {
+                parse::parse_instruction(line).map_err(|error| BuildError::InvalidRawLine {
+                    label: block.name().to_string(),
+                    line: line.trim().to_string(),
+                    error,
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every `str` instruction survives being written out and loaded by MiniVM: its text is printable
+/// ASCII, without `#`, and doesn't end in a space, which is trimmed with the end of the line.
+fn check_strings(asm: &asm::Asm) -> Result<(), BuildError> {
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(asm::Label::blocks)) {
+        for instr in block.instructions().filter(|instr| instr.op == OpCode::Str) {
+            if let [Operand::Str(text)] = &instr.operands[..] {
+                let printable = |ch: char| (' '..='~').contains(&ch) && ch != '#';
+                if !text.chars().all(printable) || text.ends_with(' ') {
+                    return Err(BuildError::UnrepresentableString {
+                        label: block.name().to_string(),
+                        text: text.clone(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that every instruction has the operands its opcode takes.
+fn check_instructions(asm: &asm::Asm) -> Result<(), BuildError> {
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(asm::Label::blocks)) {
+        for instr in block.instructions() {
+            verify::check_instruction(instr).map_err(|error| BuildError::InvalidInstruction {
+                label: block.name().to_string(),
+                instr: instr.to_string(),
+                error,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that every call to a declared function passes as many arguments as it takes.
+fn check_arities(asm: &asm::Asm, sigs: &HashMap<String, usize>) -> Result<(), BuildError> {
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(asm::Label::blocks)) {
+        for instr in block
+            .instructions()
+            .filter(|instr| instr.op == OpCode::Call)
+        {
+            let Some(Operand::Label(callee)) = instr.operands.first() else {
+                continue;
+            };
+            let found = instr.operands.len() - 1;
+            match sigs.get(callee.as_str()) {
+                Some(&expected) if expected != found => {
+                    return Err(BuildError::ArityMismatch {
+                        label: block.name().to_string(),
+                        callee: callee.to_string(),
+                        expected,
+                        found,
+                    })
+                }
+                _ => {}
+            }
+        }
     }
+    Ok(())
 }
 
 impl Default for AsmBuilder {
@@ -90,6 +919,14 @@ impl Default for AsmBuilder {
 pub struct LabelBuilder {
     lbl: asm::Label,
     unfinished: Option<SubLabelBuilder>,
+    deferred: Deferred,
+    span: Option<UserSpan>,
+    fallthroughs: usize,
+    /// Whether instructions go to the last sub-label, started by [`branch_if`](BuildInstruction::branch_if)
+    /// or [`branch_unless`](BuildInstruction::branch_unless).
+    in_fallthrough: bool,
+    /// Whether the label was [abandoned](LabelBuilderGuard::abandon), to be dropped rather than added.
+    abandoned: bool,
 }
 
 impl LabelBuilder {
@@ -98,19 +935,42 @@ impl LabelBuilder {
         Self {
             lbl: asm::Label::new(name),
             unfinished: None,
+            deferred: Deferred::default(),
+            span: None,
+            fallthroughs: 0,
+            in_fallthrough: false,
+            abandoned: false,
         }
     }
 
     fn take_unfinished(&mut self) {
         if let Some(prev_builder) = self.unfinished.take() {
-            self.lbl.push_sub_label(prev_builder.finish());
+            if !prev_builder.abandoned {
+                self.push_sub_label(prev_builder);
+            }
+        }
+    }
+
+    fn sub_label_builder(&self, name: &str) -> SubLabelBuilder {
+        let mut builder = SubLabelBuilder::new(self.lbl.name(), name);
+        builder.span = self.span;
+        builder.deferred = self.deferred.inherit();
+        builder
+    }
+
+    fn push_sub_label(&mut self, builder: SubLabelBuilder) {
+        let (sub_labels, deferred) = builder.finish_with_deferred();
+        self.deferred.append(deferred);
+        for sub_label in sub_labels {
+            self.lbl.push_sub_label(sub_label);
         }
     }
 
     #[must_use]
     pub fn build_sub_label(&mut self, name: &str) -> SubLabelBuilderGuard<'_> {
         self.take_unfinished();
-        let builder = SubLabelBuilder::new(self.lbl.name(), name);
+        self.in_fallthrough = false;
+        let builder = self.sub_label_builder(name);
         let builder = self.unfinished.insert(builder);
         BuilderGuard::new(builder)
     }
@@ -120,44 +980,267 @@ impl LabelBuilder {
         F: for<'a> FnOnce(&'a mut SubLabelBuilder) -> &'a mut SubLabelBuilder,
     {
         self.take_unfinished();
-        let mut builder = SubLabelBuilder::new(self.lbl.name(), name);
+        self.in_fallthrough = false;
+        let mut builder = self.sub_label_builder(name);
         f(&mut builder);
-        self.lbl.push_sub_label(builder.finish());
+        self.push_sub_label(builder);
+        self
+    }
+
+    /// Make room for `n` more lines in the body of the label, before its sub-labels.
+    pub fn reserve_lines(&mut self, n: usize) -> &mut Self {
+        self.lbl.lines_mut().reserve(n);
+        self
+    }
+
+    /// Make the function [private](Visibility::Private) to its module.
+    pub fn private(&mut self) -> &mut Self {
+        self.lbl.set_visibility(Visibility::Private);
         self
     }
 
+    /// The full name of the sub-label `name` of the function, like `fib.then` for `then`, to branch to
+    /// without repeating the function's name.
     #[must_use]
-    pub fn finish(mut self) -> asm::Label {
-        self.take_unfinished();
-        self.lbl
+    pub fn local(&self, name: &str) -> String {
+        format!("{}.{name}", self.lbl.name())
     }
 
-    fn write_line<'a>(&mut self, line: impl Into<Cow<'a, str>>) {
-        self.lbl.push_line(line);
+    /// Any [`Runtime`] required by the label is discarded, and tail calls are not checked; use [`AsmBuilder`]
+    /// for both.
+    ///
+    /// Panics if the label reads a [constant](BuildInstruction::integer_const), which only [`AsmBuilder`]
+    /// can substitute.
+    #[must_use]
+    pub fn finish(self) -> asm::Label {
+        let label = self.finish_with_deferred().0;
+        assert_no_consts(&label);
+        label
     }
-}
 
-pub struct SubLabelBuilder {
-    lbl: asm::SubLabel,
-}
+    fn finish_with_deferred(mut self) -> (asm::Label, Deferred) {
+        self.take_unfinished();
+        (self.lbl, self.deferred)
+    }
 
-impl SubLabelBuilder {
-    fn new(label: &str, name: &str) -> SubLabelBuilder {
-        Self {
-            lbl: asm::SubLabel::new(label, name),
+    /// Write the instructions of `template`, expanded with `bindings`.
+    ///
+    /// Panics if the template can't be expanded.
+    #[track_caller]
+    pub fn instantiate(&mut self, template: &Template, bindings: &Bindings) -> &mut Self {
+        let instructions = template
+            .expand(bindings)
+            .unwrap_or_else(|error| panic!("cannot instantiate template: {error}"));
+        for instr in instructions {
+            self.write_instruction(instr.op, instr.dest, instr.operands);
         }
+        self
     }
 
-    fn finish(self) -> asm::SubLabel {
-        self.lbl
+    /// Write `instr` as it is, such as one [parsed](crate::parse::parse_instruction) from text.
+    #[track_caller]
+    pub fn instruction(&mut self, instr: Instruction) -> &mut Self {
+        self.write_instruction(instr.op, instr.dest, instr.operands);
+        self
     }
 
-    fn write_line<'a>(&mut self, line: impl Into<Cow<'a, str>>) {
-        self.lbl.push_line(line);
+    fn set_span(&mut self, span: Option<UserSpan>) {
+        self.span = span;
     }
-}
 
-pub struct BuilderGuard<'a, T> {
+    #[track_caller]
+    fn write_instruction(&mut self, op: OpCode, dest: Option<Reg>, operands: Vec<Operand>) {
+        let block: &mut asm::LabelImpl = match self.lbl.sub_labels_mut().last_mut() {
+            Some(sub_label) if self.in_fallthrough => sub_label,
+            _ => &mut self.lbl,
+        };
+        self.deferred
+            .write(block, self.span, Instruction::new(op, dest, operands));
+    }
+
+    #[track_caller]
+    fn write_tail_call(&mut self, label: Lbl, args: &[Reg]) {
+        for (from, to) in sequential_moves(args) {
+            self.register_move(from, to);
+        }
+        self.label_jump(label);
+        self.deferred.tail_calls.push(label.to_string());
+    }
+
+    /// The name of the next sub-label to fall through to, skipping those of sub-labels already defined.
+    fn fallthrough(&mut self) -> String {
+        self.take_unfinished();
+        loop {
+            self.fallthroughs += 1;
+            let name = format!("{}.cont{}", self.lbl.name(), self.fallthroughs);
+            if !self.lbl.blocks().any(|block| block.name() == name) {
+                return name;
+            }
+        }
+    }
+
+    fn start_fallthrough(&mut self, name: &str) {
+        let (label, name) = name.split_once('.').unwrap();
+        self.lbl.push_sub_label(asm::SubLabel::new(label, name));
+        self.in_fallthrough = true;
+    }
+}
+
+pub struct SubLabelBuilder {
+    lbl: asm::SubLabel,
+    /// Sub-labels started by [`branch_if`](BuildInstruction::branch_if) or
+    /// [`branch_unless`](BuildInstruction::branch_unless), the last of which instructions go to.
+    fallthroughs: Vec<asm::SubLabel>,
+    /// Sub-labels nested in this one, like `fib.else.inner` in `fib.else`, which follow the fallthroughs.
+    sub_labels: Vec<asm::SubLabel>,
+    unfinished: Option<Box<SubLabelBuilder>>,
+    deferred: Deferred,
+    span: Option<UserSpan>,
+    /// Whether the sub-label was [abandoned](SubLabelBuilderGuard::abandon), to be dropped rather than added.
+    abandoned: bool,
+}
+
+impl SubLabelBuilder {
+    fn new(label: &str, name: &str) -> SubLabelBuilder {
+        Self {
+            lbl: asm::SubLabel::new(label, name),
+            fallthroughs: Vec::new(),
+            sub_labels: Vec::new(),
+            unfinished: None,
+            deferred: Deferred::default(),
+            span: None,
+            abandoned: false,
+        }
+    }
+
+    fn take_unfinished(&mut self) {
+        if let Some(prev_builder) = self.unfinished.take() {
+            if !prev_builder.abandoned {
+                self.push_sub_label(*prev_builder);
+            }
+        }
+    }
+
+    fn sub_label_builder(&self, name: &str) -> SubLabelBuilder {
+        let mut builder = SubLabelBuilder::new(self.lbl.name(), name);
+        builder.span = self.span;
+        builder.deferred = self.deferred.inherit();
+        builder
+    }
+
+    fn push_sub_label(&mut self, builder: SubLabelBuilder) {
+        let (sub_labels, deferred) = builder.finish_with_deferred();
+        self.deferred.append(deferred);
+        self.sub_labels.extend(sub_labels);
+    }
+
+    /// Build the sub-label `name` of this one, as in `inner` for `@fib.else.inner`.
+    #[must_use]
+    pub fn build_sub_label(&mut self, name: &str) -> SubLabelBuilderGuard<'_> {
+        self.take_unfinished();
+        let builder = self.sub_label_builder(name);
+        let builder = self.unfinished.insert(Box::new(builder));
+        BuilderGuard::new(builder)
+    }
+
+    /// Build the sub-label `name` of this one, as in `inner` for `@fib.else.inner`.
+    pub fn sub_label<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: for<'a> FnOnce(&'a mut SubLabelBuilder) -> &'a mut SubLabelBuilder,
+    {
+        self.take_unfinished();
+        let mut builder = self.sub_label_builder(name);
+        f(&mut builder);
+        self.push_sub_label(builder);
+        self
+    }
+
+    /// The full name of the sub-label `name` of the function, like `fib.then` for `then`, even from within
+    /// another sub-label such as `fib.else`.
+    #[must_use]
+    pub fn local(&self, name: &str) -> String {
+        let function = self.lbl.name().split('.').next().unwrap_or_default();
+        format!("{function}.{name}")
+    }
+
+    #[cfg(test)]
+    fn finish(self) -> Vec<asm::SubLabel> {
+        self.finish_with_deferred().0
+    }
+
+    fn finish_with_deferred(mut self) -> (Vec<asm::SubLabel>, Deferred) {
+        self.take_unfinished();
+        let sub_labels = std::iter::once(self.lbl)
+            .chain(self.fallthroughs)
+            .chain(self.sub_labels)
+            .collect();
+        (sub_labels, self.deferred)
+    }
+
+    /// Write the instructions of `template`, expanded with `bindings`.
+    ///
+    /// Panics if the template can't be expanded.
+    #[track_caller]
+    pub fn instantiate(&mut self, template: &Template, bindings: &Bindings) -> &mut Self {
+        let instructions = template
+            .expand(bindings)
+            .unwrap_or_else(|error| panic!("cannot instantiate template: {error}"));
+        for instr in instructions {
+            self.write_instruction(instr.op, instr.dest, instr.operands);
+        }
+        self
+    }
+
+    /// Write `instr` as it is, such as one [parsed](crate::parse::parse_instruction) from text.
+    #[track_caller]
+    pub fn instruction(&mut self, instr: Instruction) -> &mut Self {
+        self.write_instruction(instr.op, instr.dest, instr.operands);
+        self
+    }
+
+    fn set_span(&mut self, span: Option<UserSpan>) {
+        self.span = span;
+    }
+
+    #[track_caller]
+    fn write_instruction(&mut self, op: OpCode, dest: Option<Reg>, operands: Vec<Operand>) {
+        let block = self.fallthroughs.last_mut().unwrap_or(&mut self.lbl);
+        self.deferred
+            .write(block, self.span, Instruction::new(op, dest, operands));
+    }
+
+    #[track_caller]
+    fn write_tail_call(&mut self, label: Lbl, args: &[Reg]) {
+        for (from, to) in sequential_moves(args) {
+            self.register_move(from, to);
+        }
+        self.label_jump(label);
+        self.deferred.tail_calls.push(label.to_string());
+    }
+
+    /// The name of the next sub-label to fall through to, skipping those of sub-labels already defined.
+    fn fallthrough(&mut self) -> String {
+        self.take_unfinished();
+        // There are fewer sub-labels than this, so one of the names is free.
+        (1..=self.fallthroughs.len() + self.sub_labels.len() + 1)
+            .map(|n| format!("{}.cont{n}", self.lbl.name()))
+            .find(|name| {
+                !self
+                    .fallthroughs
+                    .iter()
+                    .chain(&self.sub_labels)
+                    .any(|sub_label| sub_label.name() == name)
+            })
+            .expect("some sub-label name is free")
+    }
+
+    fn start_fallthrough(&mut self, name: &str) {
+        let (label, name) = name.split_once('.').unwrap();
+        self.fallthroughs.push(asm::SubLabel::new(label, name));
+    }
+}
+
+pub struct BuilderGuard<'a, T> {
     inner: &'a mut T,
     finished: drop_bomb::DropBomb,
 }
@@ -177,9 +1260,34 @@ impl<'a, T> BuilderGuard<'a, T> {
     pub fn finish(mut self) {
         self.finished.defuse();
     }
+
+    /// Let the guard be dropped without calling [`finish`](BuilderGuard::finish), keeping whatever was
+    /// built, as when returning early with `?`.
+    #[must_use]
+    pub fn auto_finish(mut self) -> Self {
+        self.finished.defuse();
+        self
+    }
+}
+
+impl LabelBuilderGuard<'_> {
+    /// Drop the label instead of adding it to the program, along with any [`Runtime`] it requires. An
+    /// abandoned `main` is left empty.
+    pub fn abandon(mut self) {
+        self.inner.abandoned = true;
+        self.finished.defuse();
+    }
+}
+
+impl SubLabelBuilderGuard<'_> {
+    /// Drop the sub-label instead of adding it to its label, along with any [`Runtime`] it requires.
+    pub fn abandon(mut self) {
+        self.inner.abandoned = true;
+        self.finished.defuse();
+    }
 }
 
-impl<'a, T> Deref for BuilderGuard<'a, T> {
+impl<T> Deref for BuilderGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -187,309 +1295,807 @@ impl<'a, T> Deref for BuilderGuard<'a, T> {
     }
 }
 
-impl<'a, T> DerefMut for BuilderGuard<'a, T> {
+impl<T> DerefMut for BuilderGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.inner
     }
 }
 
-pub trait BuildInstruction {
+/// Work that label builders leave to [`AsmBuilder::finish`], once the whole program is known.
+#[derive(Default)]
+pub(crate) struct Deferred {
+    runtime: Vec<Box<dyn Runtime>>,
+    pub(crate) tail_calls: Vec<String>,
+    /// Where instructions were written, if tracked.
+    call_sites: Option<LineTable<&'static Location<'static>>>,
+    spans: LineTable<UserSpan>,
+    strip_assertions: bool,
+    hooks: Arc<Mutex<Vec<Box<dyn EmitHook>>>>,
+    /// The names of the labels referred to so far, shared by the instructions written.
+    names: Interner,
+}
+
+impl Deferred {
+    /// The deferred work of a builder nested in this one, sharing its settings and hooks.
+    fn inherit(&self) -> Deferred {
+        Deferred {
+            call_sites: self.call_sites.as_ref().map(|_| LineTable::default()),
+            strip_assertions: self.strip_assertions,
+            hooks: Arc::clone(&self.hooks),
+            ..Deferred::default()
+        }
+    }
+
+    pub(crate) fn require_runtime(&mut self, runtime: Box<dyn Runtime>) {
+        if self.runtime.iter().all(|r| r.name() != runtime.name()) {
+            self.runtime.push(runtime);
+        }
+    }
+
+    pub(crate) fn append(&mut self, other: Deferred) {
+        for runtime in other.runtime {
+            self.require_runtime(runtime);
+        }
+        self.tail_calls.extend(other.tail_calls);
+        if let (Some(call_sites), Some(other)) = (&mut self.call_sites, other.call_sites) {
+            call_sites.extend(other);
+        }
+        self.spans.extend(other.spans);
+        self.names.extend(other.names);
+    }
+
+    /// Run `instr` through the hooks, and push what they give to `block`.
+    #[track_caller]
+    fn write(&mut self, block: &mut asm::LabelImpl, span: Option<UserSpan>, instr: Instruction) {
+        let mut lines = vec![asm::Line::Instruction(instr)];
+        for hook in self.hooks.lock().unwrap().iter_mut() {
+            lines = lines
+                .into_iter()
+                .flat_map(|line| match line {
+                    asm::Line::Instruction(instr) => hook.emit(block.name(), instr),
+                    raw @ asm::Line::Raw(_) => vec![raw],
+                })
+                .collect();
+        }
+        for mut line in lines {
+            if let asm::Line::Instruction(instr) = &mut line {
+                self.names.share(instr);
+            }
+            self.record_source(block, span);
+            block.lines_mut().push(line);
+        }
+    }
+
+    /// Record the caller, if call sites are tracked, and `span` as the source of the next line of `block`.
+    #[track_caller]
+    fn record_source(&mut self, block: &asm::LabelImpl, span: Option<UserSpan>) {
+        let index = block.lines().len();
+        if let Some(call_sites) = &mut self.call_sites {
+            call_sites.insert(block.name(), index, Location::caller());
+        }
+        if let Some(span) = span {
+            self.spans.insert(block.name(), index, span);
+        }
+    }
+}
+
+impl RequireRuntime for LabelBuilder {
+    fn require_runtime<R: Runtime + 'static>(&mut self, runtime: R) -> &mut Self {
+        self.deferred.require_runtime(Box::new(runtime));
+        self
+    }
+
+    fn keeps_assertions(&self) -> bool {
+        !self.deferred.strip_assertions
+    }
+}
+
+impl RequireRuntime for SubLabelBuilder {
+    fn require_runtime<R: Runtime + 'static>(&mut self, runtime: R) -> &mut Self {
+        self.deferred.require_runtime(Box::new(runtime));
+        self
+    }
+
+    fn keeps_assertions(&self) -> bool {
+        !self.deferred.strip_assertions
+    }
+}
+
+impl<T: RequireRuntime> RequireRuntime for BuilderGuard<'_, T> {
+    fn require_runtime<R: Runtime + 'static>(&mut self, runtime: R) -> &mut Self {
+        self.inner.require_runtime(runtime);
+        self
+    }
+
+    fn keeps_assertions(&self) -> bool {
+        self.inner.keeps_assertions()
+    }
+}
+
+/// Builder methods for writing instructions over registers of type `R`.
+///
+/// Labels can be given as any kind of string, such as a name made with `format!`.
+pub trait BuildInstruction<R = Reg> {
     /// Return to caller, cleanup GC.
     fn exit(&mut self) -> &mut Self;
 
     /// Move contents of `rY` into `rX`.
-    fn register_move(&mut self, from: Reg, to: Reg) -> &mut Self;
+    fn register_move(&mut self, from: R, to: R) -> &mut Self;
 
     /// Jump to `label.a`.
-    fn label_jump(&mut self, label: Lbl) -> &mut Self;
+    fn label_jump(&mut self, label: impl AsRef<str>) -> &mut Self
+    where
+        Self: Sized;
 
     /// Jump to `label.a`.
     /// Argument in `rA` is moved to `r1`, `rB` to `r2`, `rC` to `r3`, and so on.
     /// Once the function is done, all registers are restored. The return value is put into `rX`.
-    fn label_call(&mut self, label: Lbl, args: &[Reg], to: Reg) -> &mut Self;
+    fn label_call(&mut self, label: impl AsRef<str>, args: &[R], to: R) -> &mut Self
+    where
+        Self: Sized;
 
     /// Store the address of `label.a` in `rX`.
-    fn label_address(&mut self, label: Lbl, to: Reg) -> &mut Self;
+    fn label_address(&mut self, label: impl AsRef<str>, to: R) -> &mut Self
+    where
+        Self: Sized;
 
     /// Jump to the address stored in `rX`. Usually this is obtained from [`label_address`](BuildInstruction::label_address).
-    fn dynamic_jump(&mut self, reg: Reg) -> &mut Self;
+    fn dynamic_jump(&mut self, reg: R) -> &mut Self;
 
     /// Jump to the address stored in `rX`. Usually this is obtained from [`label_address`](BuildInstruction::label_address).
     /// Argument in `rA` is moved to `r1`, `rB` to `r2`, `rC` to `r3`, and so on.
     /// Once the function is done, all registers are restored. The return value is put into `rX`.
-    fn dynamic_call(&mut self, reg: Reg, args: &[Reg], to: Reg) -> &mut Self;
+    fn dynamic_call(&mut self, reg: R, args: &[R], to: R) -> &mut Self;
+
+    /// Call a function provided by the host, by name or by the order it was registered in.
+    /// The arguments are passed as for [`label_call`](BuildInstruction::label_call), and the result is put into `rX`.
+    fn extern_call<'a>(&mut self, function: impl Into<Extern<'a>>, args: &[R], to: R) -> &mut Self
+    where
+        Self: Sized;
 
     /// Store the value stored in `rY` in the `rX` from [`label_call`](BuildInstruction::label_call) or [`dynamic_call`](BuildInstruction::dynamic_call).
-    fn return_(&mut self, reg: Reg) -> &mut Self;
+    fn return_(&mut self, reg: R) -> &mut Self;
 
     /// Store `N` in `rX`.
-    fn integer(&mut self, value: Int, to: Reg) -> &mut Self;
+    fn integer(&mut self, value: Int, to: R) -> &mut Self;
+
+    /// Store the constant `name` in `rX`, once it is [defined](AsmBuilder::define_const) by the time the
+    /// program is finished.
+    fn integer_const(&mut self, name: &str, to: R) -> &mut Self;
 
     /// Store the result of the operation `-rY` into `rX`.
-    fn neg(&mut self, from: Reg, to: Reg) -> &mut Self;
+    fn neg(&mut self, from: R, to: R) -> &mut Self;
 
     /// Store the result of the operation `rY + rZ` into `rX`.
-    fn add(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self;
+    fn add(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
 
     /// Store the result of the operation `rY - rZ` into `rX`.
-    fn sub(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self;
+    fn sub(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
 
     /// Store the result of the operation `rY * rZ` into `rX`.
-    fn mul(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self;
+    fn mul(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
 
     /// Store the result of the operation `rY / rZ` into `rX`.
-    fn div(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self;
+    fn div(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
 
     /// Store the result of the operation `rY % rZ` into `rX`.
-    fn mod_(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self;
+    fn mod_(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the operation `rY & rZ` into `rX`.
+    fn bit_and(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the operation `rY | rZ` into `rX`.
+    fn bit_or(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the operation `rY ^ rZ` into `rX`.
+    fn bit_xor(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the operation `rY << rZ` into `rX`. Only the low 6 bits of `rZ` are used.
+    fn shift_left(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the arithmetic shift `rY >> rZ` into `rX`. Only the low 6 bits of `rZ` are used.
+    fn shift_right(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
 
     /// Jump to `label.a` if the contents of `rX` is zero, otherwise jump to `label.b`.
-    fn branch_boolean(&mut self, reg: Reg, label_true: Lbl, label_false: Lbl) -> &mut Self;
+    fn branch_boolean(
+        &mut self,
+        reg: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized;
+
+    /// Jump to `label.t` if the contents of `rX` isn't zero, otherwise fall through to the instructions
+    /// written next. They go in a new sub-label after the current block, named `cont1`, `cont2` and so on.
+    fn branch_if(&mut self, reg: R, label_true: impl AsRef<str>) -> &mut Self
+    where
+        Self: Sized;
+
+    /// Jump to `label.f` if the contents of `rX` is zero, otherwise fall through to the instructions
+    /// written next, as in [`branch_if`](BuildInstruction::branch_if).
+    fn branch_unless(&mut self, reg: R, label_false: impl AsRef<str>) -> &mut Self
+    where
+        Self: Sized;
 
     /// Jump to `label.t` if the contents of `rX` is equal to the contents of `rY`, otherwise jump to `label.f`.
     fn branch_equal(
         &mut self,
-        reg1: Reg,
-        reg2: Reg,
-        label_true: Lbl,
-        label_false: Lbl,
-    ) -> &mut Self;
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized;
 
     /// Jump to `label.t` if the contents of `rX` is less than the contents of `rY`, otherwise jump to `label.f`.
     fn branch_less_than(
         &mut self,
-        reg1: Reg,
-        reg2: Reg,
-        label_true: Lbl,
-        label_false: Lbl,
-    ) -> &mut Self;
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized;
+
+    /// Jump to `label.t` if the contents of `rX` is at most the contents of `rY`, otherwise jump to `label.f`.
+    /// Written as `blt` with the registers and labels swapped.
+    #[track_caller]
+    fn branch_less_equal(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.branch_less_than(reg2, reg1, label_false, label_true)
+    }
+
+    /// Jump to `label.t` if the contents of `rX` is greater than the contents of `rY`, otherwise jump to
+    /// `label.f`. Written as `blt` with the registers swapped.
+    #[track_caller]
+    fn branch_greater_than(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.branch_less_than(reg2, reg1, label_true, label_false)
+    }
+
+    /// Jump to `label.t` if the contents of `rX` is at least the contents of `rY`, otherwise jump to
+    /// `label.f`. Written as `blt` with the labels swapped.
+    #[track_caller]
+    fn branch_greater_equal(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.branch_less_than(reg1, reg2, label_false, label_true)
+    }
+
+    /// Jump to `label.t` if the contents of `rX` isn't equal to the contents of `rY`, otherwise jump to
+    /// `label.f`. Written as `beq` with the labels swapped.
+    #[track_caller]
+    fn branch_not_equal(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.branch_equal(reg1, reg2, label_false, label_true)
+    }
+
+    /// Store the float `value` into `rX`.
+    #[cfg(feature = "float")]
+    fn float(&mut self, value: f64, to: R) -> &mut Self;
+
+    /// Store the result of the float operation `rY + rZ` into `rX`. Integer operands are converted to floats.
+    #[cfg(feature = "float")]
+    fn float_add(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the float operation `rY - rZ` into `rX`. Integer operands are converted to floats.
+    #[cfg(feature = "float")]
+    fn float_sub(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the float operation `rY * rZ` into `rX`. Integer operands are converted to floats.
+    #[cfg(feature = "float")]
+    fn float_mul(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Store the result of the float operation `rY / rZ` into `rX`. Integer operands are converted to floats.
+    #[cfg(feature = "float")]
+    fn float_div(&mut self, lhs: R, rhs: R, to: R) -> &mut Self;
+
+    /// Jump to `label.t` if the number in `rX` is equal to the number in `rY`, compared as floats,
+    /// otherwise jump to `label.f`.
+    #[cfg(feature = "float")]
+    fn branch_float_equal(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized;
+
+    /// Jump to `label.t` if the number in `rX` is less than the number in `rY`, compared as floats,
+    /// otherwise jump to `label.f`.
+    #[cfg(feature = "float")]
+    fn branch_float_less_than(
+        &mut self,
+        reg1: R,
+        reg2: R,
+        label_true: impl AsRef<str>,
+        label_false: impl AsRef<str>,
+    ) -> &mut Self
+    where
+        Self: Sized;
 
     /// Store an array with the ascii data representing `"text-1"` into `rX`.
-    fn string(&mut self, text: &str, to: Reg) -> &mut Self;
+    ///
+    /// `text` runs to the end of its line and MiniVM reads it as ASCII, so it may only hold printable ASCII
+    /// characters other than `#`, and can't end in a space; the program otherwise fails to
+    /// [finish](AsmBuilder::finish_checked). Use [`string_bytes`](crate::BuilderExt::string_bytes) for other
+    /// text.
+    fn string(&mut self, text: &str, to: R) -> &mut Self;
 
     /// Store an empty array of length `rY` into `rX`.
-    fn array(&mut self, len: Reg, to: Reg) -> &mut Self;
+    fn array(&mut self, len: R, to: R) -> &mut Self;
 
     /// Store `rZ` into `rX` at index `rY`.
-    fn set_array_index(&mut self, array: Reg, index: Reg, value: Reg) -> &mut Self;
+    fn set_array_index(&mut self, array: R, index: R, value: R) -> &mut Self;
 
     /// Store into `rX` the element at index `rZ` of `rY`.
-    fn get_array_index(&mut self, array: Reg, index: Reg, to: Reg) -> &mut Self;
+    fn get_array_index(&mut self, array: R, index: R, to: R) -> &mut Self;
 
     /// Store into `rX` the length of the array in `rY`.
-    fn array_length(&mut self, array: Reg, to: Reg) -> &mut Self;
+    fn array_length(&mut self, array: R, to: R) -> &mut Self;
 
     /// Store `0` into `rX` if the data in `rY` is an integer.
     /// Store `1` into `rX` if the data in `rY` is an array.
-    fn object_type(&mut self, object: Reg, to: Reg) -> &mut Self;
+    fn object_type(&mut self, object: R, to: R) -> &mut Self;
 
     /// Print the character stored in `rX` to stdout.
-    fn put_char(&mut self, ch: Reg) -> &mut Self;
+    fn put_char(&mut self, ch: R) -> &mut Self;
+
+    /// Attach `span` to the instructions written from now on, until the span is changed, for
+    /// [`Asm::finish_with_source_map`](asm::Asm::finish_with_source_map). Sub-labels start with the span
+    /// of their label.
+    ///
+    /// Spans of functions with virtual registers are lost in register allocation.
+    fn with_span(&mut self, span: UserSpan) -> &mut Self;
+
+    /// Stop attaching a span to the instructions written from now on.
+    fn without_span(&mut self) -> &mut Self;
+
+    /// Jump to `label.a` without growing the call stack. Its return value is returned to the caller.
+    /// Argument in `rA` is moved to `r1`, `rB` to `r2`, `rC` to `r3`, and so on.
+    /// Other registers may be overwritten.
+    ///
+    /// [`AsmBuilder::finish`] panics if `label.a` is not a function of the same program.
+    fn tail_call(&mut self, label: impl AsRef<str>, args: &[R]) -> &mut Self
+    where
+        Self: Sized;
 }
 
 macro_rules! impl_build_instruction {
-    [$($ty:ty),*] => {
+    [$($ty:ty => $reg:ty),*] => {
         $(
-        impl BuildInstruction for $ty {
+        impl BuildInstruction<$reg> for $ty {
+            #[track_caller]
             fn exit(&mut self) -> &mut Self {
-                self.write_line("exit");
+                self.write_instruction(OpCode::Exit, None, vec![]);
                 self
             }
 
-            fn register_move(&mut self, from: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- reg r{from}"));
+            #[track_caller]
+            fn register_move(&mut self, from: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Reg, Some(to), vec![Operand::Reg(from)]);
                 self
             }
 
-            fn label_jump(&mut self, label: Lbl) -> &mut Self {
-                self.write_line(format!("jump {label}"));
+            #[track_caller]
+            fn label_jump(&mut self, label: impl AsRef<str>) -> &mut Self {
+                self.write_instruction(OpCode::Jump, None, vec![label_operand(label.as_ref())]);
                 self
             }
 
-            fn label_call(&mut self, label: Lbl, args: &[Reg], to: Reg) -> &mut Self {
-                let mut buf = format!("r{to} <- call {label}");
-                for arg in args {
-                    buf.push(' ');
-                    buf.push('r');
-                    buf.push_str(&arg.to_string());
-                }
-                self.write_line(buf);
+            #[track_caller]
+            fn label_call(&mut self, label: impl AsRef<str>, args: &[$reg], to: $reg) -> &mut Self {
+                let operands = std::iter::once(label_operand(label.as_ref())).chain(reg_operands(args)).collect();
+                self.write_instruction(OpCode::Call, Some(to), operands);
                 self
             }
 
-            fn label_address(&mut self, label: Lbl, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- addr {label}"));
+            #[track_caller]
+            fn label_address(&mut self, label: impl AsRef<str>, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Addr, Some(to), vec![label_operand(label.as_ref())]);
                 self
             }
 
-            fn dynamic_jump(&mut self, reg: Reg) -> &mut Self {
-                self.write_line(format!("djump r{reg}"));
+            #[track_caller]
+            fn dynamic_jump(&mut self, reg: $reg) -> &mut Self {
+                self.write_instruction(OpCode::DJump, None, vec![Operand::Reg(reg)]);
                 self
             }
 
-            fn dynamic_call(&mut self, reg: Reg, args: &[Reg], to: Reg) -> &mut Self {
-                let mut buf = format!("r{to} <- dcall r{reg}");
-                for arg in args {
-                    buf.push(' ');
-                    buf.push('r');
-                    buf.push_str(&arg.to_string());
-                }
-                self.write_line(buf);
+            #[track_caller]
+            fn dynamic_call(&mut self, reg: $reg, args: &[$reg], to: $reg) -> &mut Self {
+                let operands = std::iter::once(Operand::Reg(reg)).chain(reg_operands(args)).collect();
+                self.write_instruction(OpCode::DCall, Some(to), operands);
                 self
             }
 
-            fn return_(&mut self, reg: Reg) -> &mut Self {
-                self.write_line(format!("ret r{reg}"));
+            #[track_caller]
+            fn extern_call<'a>(
+                &mut self,
+                function: impl Into<crate::builder::Extern<'a>>,
+                args: &[$reg],
+                to: $reg,
+            ) -> &mut Self {
+                let function = match function.into() {
+                    crate::builder::Extern::Name(name) => Operand::Extern(name.to_string()),
+                    crate::builder::Extern::Index(index) => {
+                        Operand::Int(Int::try_from(index).expect("extern index is too large"))
+                    }
+                };
+                let operands = std::iter::once(function).chain(reg_operands(args)).collect();
+                self.write_instruction(OpCode::ExternCall, Some(to), operands);
                 self
             }
 
-            fn integer(&mut self, value: Int, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- int {value}"));
+            #[track_caller]
+            fn return_(&mut self, reg: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Ret, None, vec![Operand::Reg(reg)]);
                 self
             }
 
-            fn neg(&mut self, from: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- neg r{from}"));
+            #[track_caller]
+            fn integer(&mut self, value: Int, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Int, Some(to), vec![Operand::Int(value)]);
                 self
             }
 
-            fn add(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- add r{lhs} r{rhs}"));
+            #[track_caller]
+            fn integer_const(&mut self, name: &str, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Int, Some(to), vec![Operand::Const(name.to_string())]);
                 self
             }
 
-            fn sub(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- sub r{lhs} r{rhs}"));
+            #[track_caller]
+            fn neg(&mut self, from: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Neg, Some(to), vec![Operand::Reg(from)]);
                 self
             }
 
-            fn mul(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- mul r{lhs} r{rhs}"));
+            #[track_caller]
+            fn add(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Add, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn div(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- div r{lhs} r{rhs}"));
+            #[track_caller]
+            fn sub(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Sub, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn mod_(&mut self, lhs: Reg, rhs: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- mod r{lhs} r{rhs}"));
+            #[track_caller]
+            fn mul(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Mul, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn branch_boolean(&mut self, reg: Reg, label_true: Lbl, label_false: Lbl) -> &mut Self {
-                self.write_line(format!("bb r{reg} {label_false} {label_true}"));
+            #[track_caller]
+            fn div(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Div, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn branch_equal(&mut self, reg1: Reg, reg2: Reg, label_true: Lbl, label_false: Lbl) -> &mut Self {
-                self.write_line(format!("beq r{reg1} r{reg2} {label_false} {label_true}"));
+            #[track_caller]
+            fn mod_(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Mod, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn branch_less_than(&mut self, reg1: Reg, reg2: Reg, label_true: Lbl, label_false: Lbl) -> &mut Self {
-                self.write_line(format!("blt r{reg1} r{reg2} {label_false} {label_true}"));
+            #[track_caller]
+            fn bit_and(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::BAnd, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn string(&mut self, text: &str, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- str :{text}"));
+            #[track_caller]
+            fn bit_or(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::BOr, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn array(&mut self, len: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- arr r{len}"));
+            #[track_caller]
+            fn bit_xor(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::BXor, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn set_array_index(&mut self, array: Reg, index: Reg, value: Reg) -> &mut Self {
-                self.write_line(format!("set r{array} r{index} r{value}"));
+            #[track_caller]
+            fn shift_left(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Shl, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn get_array_index(&mut self, array: Reg, index: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- get r{array} r{index}"));
+            #[track_caller]
+            fn shift_right(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Shr, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
                 self
             }
 
-            fn array_length(&mut self, array: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- len r{array}"));
+            #[track_caller]
+            fn branch_boolean(&mut self, reg: $reg, label_true: impl AsRef<str>, label_false: impl AsRef<str>) -> &mut Self {
+                let operands = vec![Operand::Reg(reg), label_operand(label_false.as_ref()), label_operand(label_true.as_ref())];
+                self.write_instruction(OpCode::Bb, None, operands);
                 self
             }
 
-            fn object_type(&mut self, object: Reg, to: Reg) -> &mut Self {
-                self.write_line(format!("r{to} <- type r{object}"));
+            #[track_caller]
+            fn branch_if(&mut self, reg: $reg, label_true: impl AsRef<str>) -> &mut Self {
+                let fallthrough = self.fallthrough();
+                self.branch_boolean(reg, label_true, &fallthrough);
+                self.start_fallthrough(&fallthrough);
                 self
             }
 
-            fn put_char(&mut self, ch: Reg) -> &mut Self {
-                self.write_line(format!("putchar r{ch}"));
+            #[track_caller]
+            fn branch_unless(&mut self, reg: $reg, label_false: impl AsRef<str>) -> &mut Self {
+                let fallthrough = self.fallthrough();
+                self.branch_boolean(reg, &fallthrough, label_false);
+                self.start_fallthrough(&fallthrough);
                 self
             }
-        }
-        )*
-    };
-}
 
-impl_build_instruction![
-    LabelBuilder,
-    SubLabelBuilder,
-    LabelBuilderGuard<'_>,
-    SubLabelBuilderGuard<'_>
-];
+            #[track_caller]
+            fn branch_equal(&mut self, reg1: $reg, reg2: $reg, label_true: impl AsRef<str>, label_false: impl AsRef<str>) -> &mut Self {
+                let operands = vec![Operand::Reg(reg1), Operand::Reg(reg2), label_operand(label_false.as_ref()), label_operand(label_true.as_ref())];
+                self.write_instruction(OpCode::Beq, None, operands);
+                self
+            }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            #[track_caller]
+            fn branch_less_than(&mut self, reg1: $reg, reg2: $reg, label_true: impl AsRef<str>, label_false: impl AsRef<str>) -> &mut Self {
+                let operands = vec![Operand::Reg(reg1), Operand::Reg(reg2), label_operand(label_false.as_ref()), label_operand(label_true.as_ref())];
+                self.write_instruction(OpCode::Blt, None, operands);
+                self
+            }
 
-    #[test]
-    fn test_asm_builder_build() {
-        let mut builder = AsmBuilder::new();
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn float(&mut self, value: f64, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::FInt, Some(to), vec![Operand::Float(crate::instr::Float(value))]);
+                self
+            }
 
-        builder.main(|main_builder| {
-            main_builder
-                .integer(35, 0)
-                .label_call("fib", &[0], 0)
-                .label_call("putn", &[0], 0)
-                .integer(10, 0)
-                .put_char(0)
-                .exit()
-        });
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn float_add(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::FAdd, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
+                self
+            }
 
-        builder.label("fib", |fib_builder| {
-            fib_builder
-                .integer(2, 0)
-                .branch_less_than(1, 0, "fib.then", "fib.else")
-                .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
-                .sub_label("else", |fib_else_builder| {
-                    fib_else_builder
-                        .integer(1, 0)
-                        .sub(1, 0, 1)
-                        .sub(1, 0, 0)
-                        .label_call("fib", &[1], 1)
-                        .label_call("fib", &[0], 0)
-                        .add(0, 1, 0)
-                        .return_(0)
-                })
-        });
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn float_sub(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::FSub, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
+                self
+            }
 
-        let mut putn_label = builder.build_label("putn");
-        putn_label
-            .branch_boolean(1, "putn.digit", "putn.ret")
-            .sub_label("digit", |putn_digit_builder| {
-                putn_digit_builder
-                    .integer(10, 0)
-                    .div(1, 0, 0)
-                    .label_call("putn", &[0], 0)
-                    .integer(10, 0)
-                    .mod_(1, 0, 1)
-                    .integer(48, 0)
-                    .add(1, 0, 1)
-                    .put_char(1)
-            })
-            .sub_label("ret", |putn_ret_builder| {
-                putn_ret_builder.integer(0, 0).return_(0)
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn float_mul(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::FMul, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
+                self
+            }
+
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn float_div(&mut self, lhs: $reg, rhs: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::FDiv, Some(to), vec![Operand::Reg(lhs), Operand::Reg(rhs)]);
+                self
+            }
+
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn branch_float_equal(&mut self, reg1: $reg, reg2: $reg, label_true: impl AsRef<str>, label_false: impl AsRef<str>) -> &mut Self {
+                let operands = vec![Operand::Reg(reg1), Operand::Reg(reg2), label_operand(label_false.as_ref()), label_operand(label_true.as_ref())];
+                self.write_instruction(OpCode::FBeq, None, operands);
+                self
+            }
+
+            #[cfg(feature = "float")]
+            #[track_caller]
+            fn branch_float_less_than(&mut self, reg1: $reg, reg2: $reg, label_true: impl AsRef<str>, label_false: impl AsRef<str>) -> &mut Self {
+                let operands = vec![Operand::Reg(reg1), Operand::Reg(reg2), label_operand(label_false.as_ref()), label_operand(label_true.as_ref())];
+                self.write_instruction(OpCode::FBlt, None, operands);
+                self
+            }
+
+            #[track_caller]
+            fn string(&mut self, text: &str, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Str, Some(to), vec![Operand::Str(text.to_string())]);
+                self
+            }
+
+            #[track_caller]
+            fn array(&mut self, len: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Arr, Some(to), vec![Operand::Reg(len)]);
+                self
+            }
+
+            #[track_caller]
+            fn set_array_index(&mut self, array: $reg, index: $reg, value: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Set, None, vec![Operand::Reg(array), Operand::Reg(index), Operand::Reg(value)]);
+                self
+            }
+
+            #[track_caller]
+            fn get_array_index(&mut self, array: $reg, index: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Get, Some(to), vec![Operand::Reg(array), Operand::Reg(index)]);
+                self
+            }
+
+            #[track_caller]
+            fn array_length(&mut self, array: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Len, Some(to), vec![Operand::Reg(array)]);
+                self
+            }
+
+            #[track_caller]
+            fn object_type(&mut self, object: $reg, to: $reg) -> &mut Self {
+                self.write_instruction(OpCode::Type, Some(to), vec![Operand::Reg(object)]);
+                self
+            }
+
+            #[track_caller]
+            fn put_char(&mut self, ch: $reg) -> &mut Self {
+                self.write_instruction(OpCode::PutChar, None, vec![Operand::Reg(ch)]);
+                self
+            }
+
+            #[track_caller]
+            fn tail_call(&mut self, label: impl AsRef<str>, args: &[$reg]) -> &mut Self {
+                self.write_tail_call(label.as_ref(), args);
+                self
+            }
+
+            fn with_span(&mut self, span: UserSpan) -> &mut Self {
+                self.set_span(Some(span));
+                self
+            }
+
+            fn without_span(&mut self) -> &mut Self {
+                self.set_span(None);
+                self
+            }
+        }
+        )*
+    };
+}
+
+pub(crate) use impl_build_instruction;
+
+/// Order the moves of `args` into `r1`, `r2`, ..., so that no argument is overwritten before it is read.
+/// Cycles are broken using a register that isn't otherwise involved.
+pub(crate) fn sequential_moves(args: &[Reg]) -> Vec<(Reg, Reg)> {
+    let mut pending: Vec<(Reg, Reg)> = (1..)
+        .zip(args)
+        .map(|(to, &from)| (from, to))
+        .filter(|(from, to)| from != to)
+        .collect();
+    let mut moves = Vec::with_capacity(pending.len() + 1);
+    while !pending.is_empty() {
+        let free = pending
+            .iter()
+            .position(|&(_, to)| pending.iter().all(|&(from, _)| from != to));
+        if let Some(i) = free {
+            moves.push(pending.remove(i));
+        } else {
+            let (_, to) = pending[0];
+            let temp = (0..=Reg::MAX)
+                .find(|&reg| pending.iter().all(|&(from, to)| reg != from && reg != to))
+                .expect("too many arguments");
+            moves.push((to, temp));
+            for (from, _) in &mut pending {
+                if *from == to {
+                    *from = temp;
+                }
+            }
+        }
+    }
+    moves
+}
+
+pub(crate) fn label_operand<R>(label: Lbl) -> Operand<R> {
+    Operand::Label(label.into())
+}
+
+pub(crate) fn reg_operands<R: Copy>(regs: &[R]) -> impl Iterator<Item = Operand<R>> + '_ {
+    regs.iter().copied().map(Operand::Reg)
+}
+
+impl_build_instruction![
+    LabelBuilder => Reg,
+    SubLabelBuilder => Reg,
+    LabelBuilderGuard<'_> => Reg,
+    SubLabelBuilderGuard<'_> => Reg
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asm_builder_build() {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .integer(35, 0)
+                .label_call("fib", &[0], 0)
+                .label_call("putn", &[0], 0)
+                .integer(10, 0)
+                .put_char(0)
+                .exit()
+        });
+
+        builder.label("fib", |fib_builder| {
+            fib_builder
+                .integer(2, 0)
+                .branch_less_than(1, 0, "fib.then", "fib.else")
+                .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
+                .sub_label("else", |fib_else_builder| {
+                    fib_else_builder
+                        .integer(1, 0)
+                        .sub(1, 0, 1)
+                        .sub(1, 0, 0)
+                        .label_call("fib", &[1], 1)
+                        .label_call("fib", &[0], 0)
+                        .add(0, 1, 0)
+                        .return_(0)
+                })
+        });
+
+        let mut putn_label = builder.build_label("putn");
+        putn_label
+            .branch_boolean(1, "putn.digit", "putn.ret")
+            .sub_label("digit", |putn_digit_builder| {
+                putn_digit_builder
+                    .integer(10, 0)
+                    .div(1, 0, 0)
+                    .label_call("putn", &[0], 0)
+                    .integer(10, 0)
+                    .mod_(1, 0, 1)
+                    .integer(48, 0)
+                    .add(1, 0, 1)
+                    .put_char(1)
+            })
+            .sub_label("ret", |putn_ret_builder| {
+                putn_ret_builder.integer(0, 0).return_(0)
             });
         putn_label.finish();
 
@@ -595,7 +2201,7 @@ end",
             .return_(0);
 
         assert_eq!(
-            builder.finish().finish(),
+            builder.finish().remove(0).finish(),
             r"@fib.else
     r0 <- int 1
     r1 <- sub r1 r0
@@ -607,8 +2213,832 @@ end",
         );
     }
 
+    #[test]
+    fn test_nested_sub_labels_build() {
+        let mut builder = LabelBuilder::new("fib");
+        builder.sub_label("else", |else_builder| {
+            else_builder
+                .branch_if(1, "fib.else.inner")
+                .return_(1)
+                .sub_label("inner", |inner_builder| {
+                    inner_builder
+                        .return_(2)
+                        .sub_label("deepest", |deepest_builder| deepest_builder.return_(3))
+                })
+        });
+        let mut after_builder = builder.build_sub_label("after");
+        let mut nested_builder = after_builder.build_sub_label("nested");
+        nested_builder.return_(4);
+        nested_builder.finish();
+        after_builder.finish();
+
+        let text = builder.finish().finish();
+        assert_eq!(
+            text,
+            r"func fib
+@fib.else
+    bb r1 fib.else.cont1 fib.else.inner
+@fib.else.cont1
+    ret r1
+@fib.else.inner
+    ret r2
+@fib.else.inner.deepest
+    ret r3
+@fib.after
+@fib.after.nested
+    ret r4
+end"
+        );
+        assert_eq!(
+            crate::parse::parse(&format!("{text}\nfunc main\nend")).map(|_| ()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_pseudo_branches_build() {
+        let mut builder = SubLabelBuilder::new("f", "test");
+        builder
+            .branch_less_equal(1, 2, "f.yes", "f.no")
+            .branch_greater_than(1, 2, "f.yes", "f.no")
+            .branch_greater_equal(1, 2, "f.yes", "f.no")
+            .branch_not_equal(1, 2, "f.yes", "f.no");
+
+        assert_eq!(
+            builder.finish().remove(0).finish(),
+            r"@f.test
+    blt r2 r1 f.yes f.no
+    blt r2 r1 f.no f.yes
+    blt r1 r2 f.yes f.no
+    beq r1 r2 f.yes f.no"
+        );
+    }
+
+    #[test]
+    fn test_branch_if_build() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .branch_if(1, "f.done")
+            .put_char(1)
+            .branch_unless(2, "f.done")
+            .put_char(2)
+            .sub_label("done", |done_builder| {
+                done_builder.branch_if(3, "f").return_(1)
+            });
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func f
+    bb r1 f.cont1 f.done
+@f.cont1
+    putchar r1
+    bb r2 f.done f.cont2
+@f.cont2
+    putchar r2
+@f.done
+    bb r3 f.done.cont1 f
+@f.done.cont1
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_branch_if_skips_defined_sub_labels() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .sub_label("cont1", |cont_builder| cont_builder.put_char(1))
+                .branch_if(1, "main.cont1")
+                .exit()
+        });
+        let asm = builder.finish_checked().unwrap();
+        assert_eq!(
+            asm.iter().last().unwrap().to_string(),
+            r"func main
+    bb r1 main.cont2 main.cont1
+@main.cont1
+    putchar r1
+@main.cont2
+    exit
+end"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .branch_if(1, "main.done")
+                .exit()
+                .sub_label("cont1", |cont_builder| cont_builder.exit())
+                .sub_label("done", |done_builder| done_builder.exit())
+        });
+        assert_eq!(
+            builder.finish_checked().unwrap_err(),
+            BuildError::DuplicateSubLabel("main.cont1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_computed_label_names() {
+        let mut builder = LabelBuilder::new("switch");
+        for i in 0..2 {
+            builder.integer(i, 2).branch_equal(
+                1,
+                2,
+                format!("switch.case_{i}"),
+                String::from("switch.next"),
+            );
+        }
+        builder.label_jump(crate::instr::LabelId::from("switch.next"));
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func switch
+    r2 <- int 0
+    beq r1 r2 switch.next switch.case_0
+    r2 <- int 1
+    beq r1 r2 switch.next switch.case_1
+    jump switch.next
+end"
+        );
+    }
+
+    #[test]
+    fn test_tail_call_build() {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .integer(1, 1)
+                .integer(2, 2)
+                .label_call("swap", &[1, 2], 0)
+                .exit()
+        });
+
+        builder.label("swap", |swap_builder| {
+            swap_builder.tail_call("pair", &[2, 1])
+        });
+
+        builder.label("pair", |pair_builder| {
+            pair_builder.tail_call("pair", &[3, 1, 1]).return_(1)
+        });
+
+        assert_eq!(
+            builder.finish().main().lines()[2].to_string(),
+            "r0 <- call swap r1 r2"
+        );
+    }
+
+    #[test]
+    fn test_tail_call_moves_arguments() {
+        let mut builder = LabelBuilder::new("swap");
+        builder
+            .tail_call("pair", &[2, 1])
+            .tail_call("pair", &[3, 1, 1]);
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func swap
+    r0 <- reg r1
+    r1 <- reg r2
+    r2 <- reg r0
+    jump pair
+    r2 <- reg r1
+    r0 <- reg r1
+    r1 <- reg r3
+    r3 <- reg r0
+    jump pair
+end"
+        );
+    }
+
+    #[test]
+    fn test_source_tracking() {
+        let build = |mut builder: AsmBuilder| {
+            builder.main(|main_builder| main_builder.integer(1, 0).exit());
+            let mut f_builder = builder.build_label("f");
+            f_builder.sub_label("then", |then_builder| then_builder.tail_call("main", &[0]));
+            f_builder.finish();
+            builder.finish()
+        };
+        let line = line!();
+        let asm = build(AsmBuilder::with_source_tracking());
+        let call_sites = asm.call_sites();
+        assert_eq!(call_sites.len(), 4);
+        let main_exit = call_sites.get("main", 1).unwrap();
+        assert_eq!((main_exit.file(), main_exit.line()), (file!(), line - 6));
+        // Both the argument move and the jump of the tail call come from the same call.
+        let line_of = |index| call_sites.get("f.then", index).map(|site| site.line());
+        assert_eq!(line_of(0), Some(line - 4));
+        assert_eq!(line_of(1), Some(line - 4));
+
+        assert!(build(AsmBuilder::new()).call_sites().is_empty());
+    }
+
+    #[test]
+    fn test_source_map() {
+        let span = |start, end| UserSpan {
+            file: 0,
+            start,
+            end,
+        };
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .with_span(span(0, 5))
+                .integer(1, 0)
+                .branch_boolean(0, "main.then", "main.else")
+                .sub_label("then", |then_builder| {
+                    then_builder.put_char(0).with_span(span(8, 9)).exit()
+                })
+                .sub_label("else", |else_builder| else_builder.without_span().exit())
+        });
+
+        let (text, source_map) = builder.finish().finish_with_source_map();
+        let annotated: Vec<String> = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| match source_map.get(i + 1) {
+                Some(span) => format!("{line:<24}; {span}"),
+                None => line.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            annotated.join("\n"),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r0 <- int 1         ; 0:0..5
+    bb r0 main.else main.then; 0:0..5
+@main.then
+    putchar r0          ; 0:0..5
+    exit                ; 0:8..9
+@main.else
+    exit
+end"
+        );
+    }
+
+    #[test]
+    fn test_custom_entry() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .entry_point("start")
+            .label("start", |start_builder| start_builder.put_char(1).exit());
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call start
+    exit
+
+func start
+    putchar r1
+    exit
+end"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder
+            .raw_entry(|entry_builder| {
+                entry_builder
+                    .integer(72, 1)
+                    .put_char(1)
+                    .integer(105, 1)
+                    .label_call("main", &[1], 0)
+                    .exit()
+            })
+            .main(|main_builder| main_builder.put_char(1).return_(1));
+        let asm = builder.finish();
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r1 <- int 72
+    putchar r1
+    r1 <- int 105
+    r0 <- call main r1
+    exit
+
+func main
+    putchar r1
+    ret r1
+end"
+        );
+
+        #[cfg(feature = "interp")]
+        {
+            let result = crate::interp::run(&asm, &crate::interp::Config::default());
+            assert_eq!(result.result, Ok(()));
+            assert_eq!(result.output_string(), "Hi");
+        }
+    }
+
+    #[test]
+    fn test_library_build() {
+        let mut builder = AsmBuilder::new_library("util");
+        builder
+            .label("id", |id_builder| id_builder.return_(1))
+            .label("twice", |twice_builder| {
+                twice_builder.add(1, 1, 1).return_(1)
+            });
+        let asm = builder.finish();
+        assert_eq!(asm.library_id(), Some("util"));
+        assert_eq!(
+            asm.to_string(),
+            r"func id
+    ret r1
+end
+
+func twice
+    r1 <- add r1 r1
+    ret r1
+end"
+        );
+        assert_eq!(asm.locate("twice", Some(0)).unwrap().line, 6);
+    }
+
+    #[test]
+    fn test_consts() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .main(|main_builder| {
+                main_builder
+                    .integer_const("BUF_SIZE", 1)
+                    .array(1, 1)
+                    .label_call("fill", &[1], 0)
+                    .exit()
+            })
+            .label("fill", |fill_builder| {
+                fill_builder
+                    .integer_const("FILL", 2)
+                    .integer_const("BUF_SIZE", 3)
+                    .return_(1)
+            })
+            .define_const("BUF_SIZE", 1024)
+            .define_const("FILL", -1);
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func fill
+    r2 <- int -1
+    r3 <- int 1024
+    ret r1
+end
+
+func main
+    r1 <- int 1024
+    r1 <- arr r1
+    r0 <- call fill r1
+    exit
+end"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.integer_const("MISSING", 1).exit());
+        assert_eq!(
+            builder.finish_checked().unwrap_err(),
+            BuildError::UndefinedConst("MISSING".to_string())
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder.define_const("FILL", 7);
+        let mut detached = builder.detached_label_builder("fill");
+        detached.integer_const("FILL", 1).return_(1);
+        builder
+            .push_label_builder(detached)
+            .main(|main_builder| main_builder.exit());
+        let asm = builder.finish();
+        let fill = asm.iter().find(|label| label.name() == "fill").unwrap();
+        assert_eq!(fill.lines()[0].to_string(), "r1 <- int 7");
+    }
+
+    #[test]
+    #[should_panic(expected = "constant `BUF` in `f` can only be substituted by `AsmBuilder`")]
+    fn test_const_outside_builder() {
+        let mut builder = LabelBuilder::new("f");
+        builder.integer_const("BUF", 1).return_(1);
+        let _ = builder.finish();
+    }
+
+    #[test]
+    fn test_emit_hooks() {
+        struct Count(Arc<Mutex<usize>>);
+
+        impl EmitHook for Count {
+            fn emit(&mut self, _: &str, instr: Instruction) -> Vec<asm::Line> {
+                *self.0.lock().unwrap() += 1;
+                vec![asm::Line::Instruction(instr)]
+            }
+        }
+
+        /// Drops `putchar`, and notes every call.
+        struct Quiet;
+
+        impl EmitHook for Quiet {
+            fn emit(&mut self, block: &str, instr: Instruction) -> Vec<asm::Line> {
+                match instr.op {
+                    OpCode::PutChar => vec![],
+                    OpCode::Call => vec![
+                        asm::Line::Raw(format!("; call from {block}")),
+                        asm::Line::Instruction(instr),
+                    ],
+                    _ => vec![asm::Line::Instruction(instr)],
+                }
+            }
+        }
+
+        let count = Arc::new(Mutex::new(0));
+        let mut builder = AsmBuilder::new();
+        builder
+            .add_hook(Count(Arc::clone(&count)))
+            .add_hook(Quiet)
+            .main(|main_builder| {
+                main_builder
+                    .integer(65, 1)
+                    .put_char(1)
+                    .label_call("f", &[1], 0)
+                    .exit()
+            })
+            .label("f", |f_builder| {
+                f_builder
+                    .label_jump("f.done")
+                    .sub_label("done", |done_builder| done_builder.put_char(1).return_(1))
+            });
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func f
+    jump f.done
+@f.done
+    ret r1
+end
+
+func main
+    r1 <- int 65
+    ; call from main
+    r0 <- call f r1
+    exit
+end"
+        );
+        assert_eq!(*count.lock().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_strict() {
+        /// Writes `text` before every `exit`.
+        struct Before(&'static str);
+
+        impl EmitHook for Before {
+            fn emit(&mut self, _: &str, instr: Instruction) -> Vec<asm::Line> {
+                if instr.op == OpCode::Exit {
+                    vec![
+                        asm::Line::Raw(self.0.to_string()),
+                        asm::Line::Instruction(instr),
+                    ]
+                } else {
+                    vec![asm::Line::Instruction(instr)]
+                }
+            }
+        }
+
+        let build = |text: &'static str| {
+            let mut builder = AsmBuilder::new();
+            builder
+                .strict(true)
+                .add_hook(Before(text))
+                .main(|main_builder| main_builder.exit());
+            builder.finish_checked().map(|_| ())
+        };
+        assert_eq!(build("r1 <- int 1\n\n    putchar r1"), Ok(()));
+        assert_eq!(
+            build("r1 <- int 1\n; done").unwrap_err().to_string(),
+            "raw line `; done` in `main` is invalid: unknown opcode `;`"
+        );
+    }
+
+    #[test]
+    fn test_detached_label_builders() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send::<LabelBuilder>();
+        assert_send_sync::<asm::Asm>();
+
+        let build = |builder: &mut LabelBuilder, n: Int| {
+            builder
+                .integer(n, 1)
+                .require_runtime(crate::runtime::Stack)
+                .return_(1);
+        };
+        let mut sequential = AsmBuilder::new();
+        for n in 0..4 {
+            sequential.label(&format!("f{n}"), |f_builder| {
+                build(f_builder, n);
+                f_builder
+            });
+        }
+
+        let mut parallel = AsmBuilder::new();
+        let builders: Vec<LabelBuilder> = (0..4)
+            .map(|n| parallel.detached_label_builder(&format!("f{n}")))
+            .collect();
+        let finished: Vec<LabelBuilder> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..)
+                .zip(builders)
+                .map(|(n, mut builder)| {
+                    scope.spawn(move || {
+                        build(&mut builder, n);
+                        builder
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        for builder in finished {
+            parallel.push_label_builder(builder);
+        }
+        assert_eq!(
+            parallel.finish().to_string(),
+            sequential.finish().to_string()
+        );
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .declare(FnSig::new("add", 2))
+            .main(|main_builder| {
+                main_builder
+                    .label_call("add", &[1, 2], 0)
+                    .label_call("putn", &[0], 0)
+                    .label_call("add", &[1], 0)
+                    .exit()
+            })
+            .label("add", |add_builder| add_builder.add(1, 2, 0).return_(0));
+        assert_eq!(
+            builder.finish_checked().unwrap_err().to_string(),
+            "`add` takes 2 arguments, but the call in `main` passes 1"
+        );
+    }
+
+    #[test]
+    fn test_unrepresentable_string() {
+        let build = |text: &'static str| {
+            let mut builder = AsmBuilder::new();
+            builder.main(|main_builder| main_builder.string(text, 1).exit());
+            builder.finish_checked().map(|asm| asm.to_string())
+        };
+        assert!(build(" a: b").is_ok());
+        assert_eq!(
+            build("two\nlines").unwrap_err().to_string(),
+            r#"string "two\nlines" in `main` can't be written with `str`"#
+        );
+        assert_eq!(
+            build("trailing ").unwrap_err(),
+            BuildError::UnrepresentableString {
+                label: "main".to_string(),
+                text: "trailing ".to_string()
+            }
+        );
+        for text in [
+            "caf\u{e9}",
+            "nul\0",
+            "tab\there",
+            "escape\x1b[0m",
+            "delete\x7f",
+            "not a # comment",
+        ] {
+            assert!(
+                matches!(build(text), Err(BuildError::UnrepresentableString { .. })),
+                "{text:?}"
+            );
+        }
+        assert!(build("~!\"$%&'()*+,-./09:;<=>?@AZ[\\]^_`az{|}").is_ok());
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let mut builder = LabelBuilder::new("fib");
+        let done = builder.local("done");
+        builder
+            .branch_less_than(1, 2, builder.local("small"), &done)
+            .sub_label("small", |small_builder| {
+                small_builder
+                    .label_jump(small_builder.local("done"))
+                    .sub_label("inner", |inner_builder| {
+                        inner_builder.label_jump(inner_builder.local("small"))
+                    })
+            })
+            .sub_label("done", |done_builder| done_builder.return_(1));
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func fib
+    blt r1 r2 fib.done fib.small
+@fib.small
+    jump fib.done
+@fib.small.inner
+    jump fib.small
+@fib.done
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_main_sub_labels() {
+        let mut builder = AsmBuilder::new();
+        let main_loop = builder.main_ref("loop");
+        builder
+            .label("restart", |restart_builder| {
+                restart_builder.label_address(&main_loop, 1).return_(1)
+            })
+            .main(|main_builder| {
+                main_builder
+                    .label_call("restart", &[], 1)
+                    .sub_label("loop", |loop_builder| {
+                        loop_builder
+                            .put_char(2)
+                            .branch_if(2, "main.loop")
+                            .dynamic_jump(1)
+                    })
+            });
+        assert_eq!(
+            builder.finish_checked().map(|asm| asm.to_string()),
+            Ok(r"@__entry
+    r0 <- call main
+    exit
+
+func restart
+    r1 <- addr main.loop
+    ret r1
+end
+
+func main
+    r1 <- call restart
+@main.loop
+    putchar r2
+    bb r2 main.loop.cont1 main.loop
+@main.loop.cont1
+    djump r1
+end"
+            .to_string())
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder
+            .label("f", |f_builder| f_builder.label_jump("main.missing"))
+            .main(|main_builder| main_builder.label_jump("elsewhere.loop").exit());
+        assert_eq!(
+            builder.finish_checked().unwrap_err().to_string(),
+            "reference to undefined sub-label `main.missing` in `f`"
+        );
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let build = |mut builder: AsmBuilder| {
+            builder
+                .label("f", |f_builder| {
+                    f_builder
+                        .integer(1, 1)
+                        .sub_label("done", |done_builder| done_builder.return_(1))
+                })
+                .main(|main_builder| main_builder.label_call("f", &[], 0).exit());
+            builder.finish_checked().map(|asm| asm.to_string())
+        };
+        assert!(build(AsmBuilder::with_size_limit(4)).is_ok());
+        assert_eq!(
+            build(AsmBuilder::with_size_limit(3))
+                .unwrap_err()
+                .to_string(),
+            r"the program has 4 instructions, over the limit of 3:
+    f: 2
+    main: 2"
+        );
+        // Options combine with each other and with libraries.
+        let mut library = AsmBuilder::new_library("lib");
+        library
+            .target(Target::MiniVm(target::Version::new(0, 1)))
+            .limit_size(1)
+            .label("f", |f_builder| f_builder.integer(1, 1).return_(1));
+        assert_eq!(
+            library.finish_checked().unwrap_err().to_string(),
+            r"the program has 2 instructions, over the limit of 1:
+    f: 2"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder.limit_function_size("f", 1);
+        assert_eq!(
+            build(builder).unwrap_err(),
+            BuildError::FunctionTooLarge {
+                function: "f".to_string(),
+                limit: 1,
+                size: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_abandon() {
+        let mut builder = AsmBuilder::new();
+        let mut f_builder = builder.build_label("f");
+        f_builder
+            .integer(1, 1)
+            .require_runtime(crate::runtime::Stack)
+            .return_(1);
+        f_builder.abandon();
+        let mut g_builder = builder.build_label("g");
+        let mut done_builder = g_builder.build_sub_label("done");
+        done_builder.return_(0);
+        done_builder.abandon();
+        g_builder.return_(1);
+        g_builder.finish();
+        let mut main_builder = builder.build_main();
+        main_builder.label_call("g", &[], 0);
+        main_builder.abandon();
+
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func g
+    ret r1
+end
+
+func main
+end"
+        );
+    }
+
+    #[test]
+    fn test_auto_finish() {
+        fn build_f(builder: &mut AsmBuilder, value: &str) -> Result<(), std::num::ParseIntError> {
+            let mut f_builder = builder.build_label("f").auto_finish();
+            f_builder.integer(1, 0);
+            f_builder.integer(value.parse()?, 1).return_(1);
+            Ok(())
+        }
+
+        let mut builder = AsmBuilder::new();
+        assert!(build_f(&mut builder, "x").is_err());
+        builder.main(|main_builder| main_builder.label_call("f", &[], 0).exit());
+
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func f
+    r0 <- int 1
+end
+
+func main
+    r0 <- call f
+    exit
+end"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "builder must be marked as finished")]
+    fn test_label_builder_panics_without_finish() {
+        fn build_f(builder: &mut AsmBuilder, value: &str) -> Result<(), std::num::ParseIntError> {
+            let mut f_builder = builder.build_label("f");
+            f_builder.integer(value.parse()?, 1).return_(1);
+            f_builder.finish();
+            Ok(())
+        }
+
+        let mut builder = AsmBuilder::new();
+        let _ = build_f(&mut builder, "x");
+    }
+
+    #[test]
+    #[should_panic(expected = "tail call to undefined function `missing`")]
+    fn test_tail_call_to_undefined_function_panics() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.tail_call("missing", &[]));
+        let _ = builder.finish();
+    }
+
     #[test]
     #[should_panic]
+    #[allow(clippy::should_panic_without_expect)]
     fn test_sub_label_builder_panics_without_finish() {
         let mut label = LabelBuilder::new("test");
         let mut sub_label = label.build_sub_label("0");
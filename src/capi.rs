@@ -0,0 +1,301 @@
+//! A C interface to the builder, for compilers not written in Rust. The declarations are in
+//! `include/minivm_asm.h`, and a library to link against is built with
+//! `cargo rustc --release --features capi --crate-type staticlib`.
+//!
+//! A program is built one label at a time: [`mvb_label_begin`] starts a function, [`mvb_sub_label_begin`]
+//! starts a sub-label of it, and instructions are written to whichever was started last.
+
+use crate::{
+    builder::{LabelBuilder, Reg},
+    instr::{Instruction, OpCode, Operand},
+    parse, AsmBuilder, Int,
+};
+use std::ffi::{c_char, c_int, CStr, CString};
+
+/// A program being built, owned by the C side between [`mvb_new`] and [`mvb_finish`] or [`mvb_free`].
+pub struct MvbBuilder {
+    builder: AsmBuilder,
+    label: Option<PendingLabel>,
+    built_main: bool,
+}
+
+/// The label being written, whose instructions are only handed to the builder once it is done.
+struct PendingLabel {
+    name: String,
+    body: Vec<Instruction>,
+    sub_labels: Vec<(String, Vec<Instruction>)>,
+}
+
+impl MvbBuilder {
+    fn finish_label(&mut self) {
+        let Some(label) = self.label.take() else {
+            return;
+        };
+        let write = |label_builder: &mut LabelBuilder| {
+            for instr in label.body {
+                label_builder.instruction(instr);
+            }
+            for (name, instructions) in label.sub_labels {
+                label_builder.sub_label(&name, |sub_label_builder| {
+                    for instr in instructions {
+                        sub_label_builder.instruction(instr);
+                    }
+                    sub_label_builder
+                });
+            }
+        };
+        if label.name == "main" {
+            self.builder.main(|main_builder| {
+                write(main_builder);
+                main_builder
+            });
+        } else {
+            self.builder.label(&label.name, |label_builder| {
+                write(label_builder);
+                label_builder
+            });
+        }
+    }
+
+    /// The block instructions are written to, if a label was started.
+    fn block(&mut self) -> Option<&mut Vec<Instruction>> {
+        let label = self.label.as_mut()?;
+        Some(match label.sub_labels.last_mut() {
+            Some((_, instructions)) => instructions,
+            None => &mut label.body,
+        })
+    }
+}
+
+/// Start building a program.
+#[no_mangle]
+pub extern "C" fn mvb_new() -> *mut MvbBuilder {
+    Box::into_raw(Box::new(MvbBuilder {
+        builder: AsmBuilder::new(),
+        label: None,
+        built_main: false,
+    }))
+}
+
+/// Free a builder without finishing it.
+///
+/// # Safety
+///
+/// `builder` must come from [`mvb_new`], and not have been finished or freed. It may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_free(builder: *mut MvbBuilder) {
+    if !builder.is_null() {
+        drop(Box::from_raw(builder));
+    }
+}
+
+/// Finish the current label and start the function `name`, which may be `main`. Returns 0, or -1 if `name`
+/// isn't UTF-8 or `main` was already started.
+///
+/// # Safety
+///
+/// `builder` must be a live builder, and `name` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_label_begin(builder: *mut MvbBuilder, name: *const c_char) -> c_int {
+    let builder = &mut *builder;
+    let Ok(name) = CStr::from_ptr(name).to_str() else {
+        return -1;
+    };
+    if name == "main" {
+        if builder.built_main {
+            return -1;
+        }
+        builder.built_main = true;
+    }
+    builder.finish_label();
+    builder.label = Some(PendingLabel {
+        name: name.to_string(),
+        body: Vec::new(),
+        sub_labels: Vec::new(),
+    });
+    0
+}
+
+/// Start the sub-label `name` of the current function, as in `loop` for `@f.loop`. Returns 0, or -1 if
+/// `name` isn't UTF-8 or no function was started.
+///
+/// # Safety
+///
+/// `builder` must be a live builder, and `name` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_sub_label_begin(
+    builder: *mut MvbBuilder,
+    name: *const c_char,
+) -> c_int {
+    let builder = &mut *builder;
+    let (Ok(name), Some(label)) = (CStr::from_ptr(name).to_str(), builder.label.as_mut()) else {
+        return -1;
+    };
+    label.sub_labels.push((name.to_string(), Vec::new()));
+    0
+}
+
+/// Write `rX <- int value`, where `X` is `to`. Returns 0, or -1 if no function was started.
+///
+/// # Safety
+///
+/// `builder` must be a live builder.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_emit_int(builder: *mut MvbBuilder, value: Int, to: Reg) -> c_int {
+    let Some(block) = (*builder).block() else {
+        return -1;
+    };
+    block.push(Instruction::new(
+        OpCode::Int,
+        Some(to),
+        vec![Operand::Int(value)],
+    ));
+    0
+}
+
+/// Write `rX <- str :text`, where `X` is `to`. Returns 0, or -1 if `text` isn't UTF-8 or no function was
+/// started. Text that `str` can't hold is reported when the program is finished.
+///
+/// # Safety
+///
+/// `builder` must be a live builder, and `text` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_emit_str(
+    builder: *mut MvbBuilder,
+    text: *const c_char,
+    to: Reg,
+) -> c_int {
+    match (CStr::from_ptr(text).to_str(), (*builder).block()) {
+        (Ok(text), Some(block)) => {
+            block.push(Instruction::new(
+                OpCode::Str,
+                Some(to),
+                vec![Operand::Str(text.to_string())],
+            ));
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// Write any instruction, in the text format, like `r0 <- call fib r1`. Returns 0, or -1 if `line` isn't an
+/// instruction or no function was started.
+///
+/// # Safety
+///
+/// `builder` must be a live builder, and `line` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_emit(builder: *mut MvbBuilder, line: *const c_char) -> c_int {
+    let instr = CStr::from_ptr(line)
+        .to_str()
+        .ok()
+        .and_then(|line| parse::parse_instruction(line).ok());
+    match (instr, (*builder).block()) {
+        (Some(instr), Some(block)) => {
+            block.push(instr);
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// Finish the program, freeing `builder`, and return its text, to be freed with [`mvb_string_free`].
+///
+/// If the program can't be finished, as reported by [`AsmBuilder::finish_checked`], returns null instead,
+/// and if `error` isn't null, points it at a message saying why, also to be freed with [`mvb_string_free`].
+///
+/// # Safety
+///
+/// `builder` must be a live builder, and `error` null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_finish(
+    builder: *mut MvbBuilder,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    let mut builder = Box::from_raw(builder);
+    builder.finish_label();
+    match builder.builder.finish_checked() {
+        Ok(asm) => into_c_string(&asm.finish()),
+        Err(message) => {
+            if !error.is_null() {
+                *error = into_c_string(&message.to_string());
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a string returned by [`mvb_finish`].
+///
+/// # Safety
+///
+/// `text` must come from [`mvb_finish`], and not have been freed. It may be null.
+#[no_mangle]
+pub unsafe extern "C" fn mvb_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+fn into_c_string(text: &str) -> *mut c_char {
+    // Programs and errors never hold NUL, other than in the text of a `str`, where it would end it anyway.
+    let text = text.replace('\0', "");
+    CString::new(text).expect("NULs were removed").into_raw()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capi() {
+        unsafe {
+            let builder = mvb_new();
+            assert_eq!(mvb_emit_int(builder, 1, 1), -1);
+            assert_eq!(mvb_label_begin(builder, c"main".as_ptr()), 0);
+            assert_eq!(mvb_emit_int(builder, 72, 1), 0);
+            assert_eq!(mvb_emit(builder, c"r0 <- call show r1".as_ptr()), 0);
+            assert_eq!(mvb_emit(builder, c"exit".as_ptr()), 0);
+            assert_eq!(mvb_emit(builder, c"r0 <- nop".as_ptr()), -1);
+            assert_eq!(mvb_label_begin(builder, c"show".as_ptr()), 0);
+            assert_eq!(mvb_emit(builder, c"putchar r1".as_ptr()), 0);
+            assert_eq!(mvb_emit(builder, c"jump show.done".as_ptr()), 0);
+            assert_eq!(mvb_sub_label_begin(builder, c"done".as_ptr()), 0);
+            assert_eq!(mvb_emit(builder, c"ret r1".as_ptr()), 0);
+            assert_eq!(mvb_label_begin(builder, c"main".as_ptr()), -1);
+
+            let text = mvb_finish(builder, std::ptr::null_mut());
+            assert_eq!(
+                CStr::from_ptr(text).to_str().unwrap(),
+                r"@__entry
+    r0 <- call main
+    exit
+
+func show
+    putchar r1
+    jump show.done
+@show.done
+    ret r1
+end
+
+func main
+    r1 <- int 72
+    r0 <- call show r1
+    exit
+end"
+            );
+            mvb_string_free(text);
+
+            let builder = mvb_new();
+            mvb_label_begin(builder, c"main".as_ptr());
+            mvb_emit_str(builder, c"two\nlines".as_ptr(), 1);
+            let mut error = std::ptr::null_mut();
+            assert!(mvb_finish(builder, &raw mut error).is_null());
+            assert_eq!(
+                CStr::from_ptr(error).to_str().unwrap(),
+                "string \"two\\nlines\" in `main` can't be written with `str`"
+            );
+            mvb_string_free(error);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Bulk processing of directories of hand-written or previously generated programs.
+
+use crate::{
+    asm::Asm,
+    parse::{self, ParseError},
+    stats::{AsmStats, SizeDelta},
+    testing,
+};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The extension of the files [`process`] reads.
+pub const EXTENSION: &str = "vasm";
+
+/// What [`process`] does with files that the processed program differs from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Leave them untouched, and report the difference.
+    #[default]
+    Check,
+    /// Replace them with the processed program.
+    Rewrite,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// Every file that was read, sorted by path.
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub outcome: Result<FileStats, ParseError>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStats {
+    pub before: AsmStats,
+    pub after: AsmStats,
+    /// Whether the emitted program differed from the file.
+    pub changed: bool,
+    /// Whether the emitted program replaced the file, as it does when it changed in [`Mode::Rewrite`].
+    pub rewritten: bool,
+    /// How the file would change, in [`Mode::Check`], or `None` if it only differs in whitespace.
+    pub diff: Option<String>,
+}
+
+impl Report {
+    /// Files that could not be parsed, along with why.
+    pub fn failures(&self) -> impl Iterator<Item = (&Path, &ParseError)> + '_ {
+        self.files
+            .iter()
+            .filter_map(|file| Some((file.path.as_path(), file.outcome.as_ref().err()?)))
+    }
+
+    /// The instruction count of every parsed file, before and after processing.
+    #[must_use]
+    pub fn total(&self) -> SizeDelta {
+        self.processed().fold(
+            SizeDelta {
+                baseline: 0,
+                current: 0,
+            },
+            |total, stats| SizeDelta {
+                baseline: total.baseline + stats.before.instructions(),
+                current: total.current + stats.after.instructions(),
+            },
+        )
+    }
+
+    fn processed(&self) -> impl Iterator<Item = &FileStats> + '_ {
+        self.files
+            .iter()
+            .filter_map(|file| file.outcome.as_ref().ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "processed {} files, {} changed, {} rewritten, {} failed: {} instructions",
+            self.files.len(),
+            self.processed().filter(|stats| stats.changed).count(),
+            self.processed().filter(|stats| stats.rewritten).count(),
+            self.failures().count(),
+            self.total()
+        )?;
+        for (path, error) in self.failures() {
+            write!(f, "\n    {}: {error}", path.display())?;
+        }
+        for file in &self.files {
+            if let Ok(FileStats {
+                diff: Some(diff), ..
+            }) = &file.outcome
+            {
+                let diff = diff.replace('\n', "\n        ");
+                write!(f, "\n    {}:\n        {diff}", file.path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse every `.vasm` file directly inside `dir` and apply `transform` to it. Files the result differs from
+/// are reported with a diff, or in [`Mode::Rewrite`], replaced by it.
+///
+/// Files that fail to parse are reported and left untouched.
+///
+/// # Errors
+///
+/// Returns an error if the directory or one of its files can't be read or written.
+pub fn process(
+    dir: impl AsRef<Path>,
+    mode: Mode,
+    mut transform: impl FnMut(&mut Asm),
+) -> io::Result<Report> {
+    let mut files = Vec::new();
+    for path in files_with_extension(dir.as_ref(), EXTENSION)? {
+        let text = fs::read_to_string(&path)?;
+        let outcome = match parse::parse(&text) {
+            Ok(mut asm) => {
+                let before = asm.stats();
+                transform(&mut asm);
+                let after = asm.stats();
+                let output = asm.finish();
+                let changed = output != text;
+                let rewritten = changed && mode == Mode::Rewrite;
+                let diff = if changed && mode == Mode::Check {
+                    testing::diff(&text, &output, false)
+                } else {
+                    None
+                };
+                if rewritten {
+                    fs::write(&path, output)?;
+                }
+                Ok(FileStats {
+                    before,
+                    after,
+                    changed,
+                    rewritten,
+                    diff,
+                })
+            }
+            Err(error) => Err(error),
+        };
+        files.push(FileReport { path, outcome });
+    }
+    Ok(Report { files })
+}
+
+/// The files directly inside `dir` with the extension `extension`, sorted by path.
+pub(crate) fn files_with_extension(dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == extension) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opt;
+
+    #[test]
+    fn test_process() {
+        let dir = std::env::temp_dir().join(format!("minivm-corpus-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = "func main\n    r1 <- int 2\n    r0 <- add r1 r1\n    exit\nend";
+        fs::write(dir.join("a.vasm"), main).unwrap();
+        fs::write(dir.join("b.vasm"), "func main\n    nop\nend").unwrap();
+        fs::write(dir.join("c.txt"), main).unwrap();
+
+        let fold = |asm: &mut Asm| asm.iter_mut().for_each(opt::fold_constants);
+        let report = process(&dir, Mode::default(), fold).unwrap();
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "processed 2 files, 1 changed, 0 rewritten, 1 failed: 3 -> 3 (+0) instructions
+    {}: line 2: unknown opcode `nop`
+    {}:
+        + @__entry
+        + r0 <- call main
+        + exit
+          func main
+          r1 <- int 2
+        - r0 <- add r1 r1
+        + r0 <- int 4
+          exit
+          end",
+                dir.join("b.vasm").display(),
+                dir.join("a.vasm").display()
+            )
+        );
+        assert_eq!(fs::read_to_string(dir.join("a.vasm")).unwrap(), main);
+
+        let report = process(&dir, Mode::Rewrite, fold).unwrap();
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "processed 2 files, 1 changed, 1 rewritten, 1 failed: 3 -> 3 (+0) instructions
+    {}: line 2: unknown opcode `nop`",
+                dir.join("b.vasm").display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("a.vasm")).unwrap(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 2
+    r0 <- int 4
+    exit
+end"
+        );
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).unwrap(), main);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
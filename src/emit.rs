@@ -0,0 +1,71 @@
+//! Spellings of the instructions, for forks of MiniVM that name some of them differently.
+
+use crate::instr::OpCode;
+use std::collections::BTreeMap;
+
+/// The mnemonic each opcode is written with, for [`Asm::finish_with`](crate::asm::Asm::finish_with). Only
+/// the mnemonics differ between dialects; operands are written as usual.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dialect {
+    mnemonics: BTreeMap<OpCode, String>,
+}
+
+impl Dialect {
+    /// The dialect of MiniVM itself, spelling every opcode as [`OpCode::mnemonic`] does.
+    #[must_use]
+    pub fn new() -> Dialect {
+        Self::default()
+    }
+
+    /// Write `op` as `mnemonic` instead.
+    #[must_use]
+    pub fn with_mnemonic(mut self, op: OpCode, mnemonic: &str) -> Dialect {
+        self.mnemonics.insert(op, mnemonic.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn mnemonic(&self, op: OpCode) -> &str {
+        self.mnemonics
+            .get(&op)
+            .map_or_else(|| op.mnemonic(), String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_finish_with() {
+        let dialect = Dialect::new()
+            .with_mnemonic(OpCode::Bb, "bz")
+            .with_mnemonic(OpCode::Get, "load")
+            .with_mnemonic(OpCode::Set, "store");
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .get_array_index(1, 2, 3)
+                .set_array_index(1, 2, 3)
+                .branch_boolean(3, "main.done", "main.done")
+                .sub_label("done", |done_builder| done_builder.exit())
+        });
+        let asm = builder.finish();
+        assert_eq!(asm.clone().finish_with(&Dialect::new()), asm.to_string());
+        assert_eq!(
+            asm.finish_with(&dialect),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r3 <- load r1 r2
+    store r1 r2 r3
+    bz r3 main.done main.done
+@main.done
+    exit
+end"
+        );
+    }
+}
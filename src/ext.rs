@@ -1,14 +1,411 @@
 #![allow(clippy::module_name_repetitions)]
 
 use crate::{
-    builder::{BuildInstruction, Reg},
-    Char,
+    builder::{BuildInstruction, Data, DataRef, Reg},
+    runtime::{Assert, Closure, RequireRuntime, Runtime},
+    Char, Int,
 };
 
 pub trait BuilderExt: BuildInstruction {
+    #[track_caller]
     fn char(&mut self, ch: Char, to: Reg) -> &mut Self {
         self.integer(i64::from(ch), to)
     }
+
+    /// Store the ASCII character `ch` into `rX`.
+    ///
+    /// Panics if `ch` isn't ASCII, as it wouldn't fit in one byte.
+    #[track_caller]
+    fn char_const(&mut self, ch: char, to: Reg) -> &mut Self {
+        self.char(ascii(ch), to)
+    }
+
+    /// Print the ASCII character `ch`, through `rX`.
+    ///
+    /// Panics if `ch` isn't ASCII, as it wouldn't fit in one byte.
+    #[track_caller]
+    fn put_str_char(&mut self, ch: char, via: Reg) -> &mut Self {
+        self.char(ascii(ch), via).put_char(via)
+    }
+
+    /// Print `text` one byte at a time, through `rX`.
+    #[track_caller]
+    fn put_str(&mut self, text: &str, via: Reg) -> &mut Self {
+        for byte in text.bytes() {
+            self.integer(Int::from(byte), via).put_char(via);
+        }
+        self
+    }
+
+    /// Store into `rX` an array holding `bytes`, through `rA` and `rB`. Unlike [`string`](BuildInstruction::string),
+    /// any bytes can be stored, spaces and colons included, and each element takes a line of its own.
+    ///
+    /// Panics if `rX` is `rA` or `rB`, or if `rA` and `rB` are the same register.
+    #[track_caller]
+    fn string_bytes(&mut self, bytes: &[u8], to: Reg, via: [Reg; 2]) -> &mut Self {
+        assert!(
+            !via.contains(&to),
+            "cannot store a string into one of the registers it is built through"
+        );
+        assert!(
+            via[0] != via[1],
+            "cannot build a string through the same register twice"
+        );
+        let [index, byte] = via;
+        let len = Int::try_from(bytes.len()).expect("string is too long");
+        self.integer(len, index).array(index, to);
+        let mut last = None;
+        for (i, &value) in (0..).zip(bytes) {
+            // Runs of the same byte only load it once.
+            if last != Some(value) {
+                self.char(value, byte);
+                last = Some(value);
+            }
+            self.integer(i, index).set_array_index(to, index, byte);
+        }
+        self
+    }
 }
 
-impl<T: BuildInstruction> BuilderExt for T {}
\ No newline at end of file
+impl<T: BuildInstruction> BuilderExt for T {}
+
+/// Helpers that call functions of a [`Runtime`], or the function building the
+/// [interned strings](crate::AsmBuilder::intern_string), which are injected when the program is finished.
+pub trait RuntimeBuilderExt: BuilderExt + RequireRuntime {
+    /// Store into `rX` the array of every [interned](crate::AsmBuilder::intern_string) string, building it.
+    /// Load it once, in `main`, and pass it along to the functions that need it.
+    #[track_caller]
+    fn load_data(&mut self, to: Reg) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.label_call(DataRef::LABEL, &[], to)
+            .require_runtime(Data)
+    }
+
+    /// Store into `rX` the interned string `data`, from the array of interned strings in `rA`. The string is
+    /// the one in the array, not a copy.
+    ///
+    /// Panics if `rX` is `rA`.
+    #[track_caller]
+    fn load_interned(&mut self, data: DataRef, strings: Reg, to: Reg) -> &mut Self {
+        assert!(
+            to != strings,
+            "cannot load an interned string into the register holding the strings"
+        );
+        let index = Int::try_from(data.index()).expect("too many interned strings");
+        self.integer(index, to).get_array_index(strings, to, to)
+    }
+
+    /// Check at runtime that the contents of `rX` isn't zero. Otherwise, print `message` after
+    /// [`Assert::MARKER`] on a line of its own and exit. `rX` is left unchanged.
+    ///
+    /// Writes nothing if assertions are [stripped](crate::AsmBuilder::strip_assertions).
+    #[track_caller]
+    fn assert_true(&mut self, cond: Reg, message: &str) -> &mut Self
+    where
+        Self: Sized,
+    {
+        if !self.keeps_assertions() {
+            return self;
+        }
+        let assert = Assert::new(message);
+        let name = assert.name().to_string();
+        self.label_call(&name, &[cond], cond)
+            .require_runtime(assert)
+    }
+
+    /// Store into `rX` a closure calling `label.a` with the contents of `rA`, `rB`, `rC`, and so on captured.
+    /// Call it using [`call_closure`](RuntimeBuilderExt::call_closure).
+    ///
+    /// Panics if `rX` is one of the captured registers.
+    #[track_caller]
+    fn make_closure(&mut self, label: impl AsRef<str>, captured: &[Reg], to: Reg) -> &mut Self
+    where
+        Self: Sized,
+    {
+        assert!(
+            !captured.contains(&to),
+            "cannot store a closure into one of its captured registers"
+        );
+        let closure = Closure::new(captured.len());
+        let mut args = Vec::with_capacity(captured.len() + 1);
+        args.push(to);
+        args.extend_from_slice(captured);
+        self.label_address(label, to)
+            .label_call(closure.name(), &args, to)
+            .require_runtime(closure)
+    }
+
+    /// Call the closure in `rY`, built with [`make_closure`](RuntimeBuilderExt::make_closure).
+    /// The closure itself is moved to `r1`, and the argument in `rA` to `r2`, `rB` to `r3`, and so on.
+    /// Captured values are at index 1 onwards of the closure. The return value is put into `rX`.
+    ///
+    /// Panics if `rX` is the closure's register or one of the argument registers.
+    #[track_caller]
+    fn call_closure(&mut self, closure: Reg, args: &[Reg], to: Reg) -> &mut Self {
+        assert!(
+            closure != to && !args.contains(&to),
+            "cannot store the result of a closure call into the closure or argument registers"
+        );
+        let mut call_args = Vec::with_capacity(args.len() + 1);
+        call_args.push(closure);
+        call_args.extend_from_slice(args);
+        self.integer(0, to)
+            .get_array_index(closure, to, to)
+            .dynamic_call(to, &call_args, to)
+    }
+}
+
+impl<T: BuildInstruction + RequireRuntime> RuntimeBuilderExt for T {}
+
+#[track_caller]
+fn ascii(ch: char) -> Char {
+    assert!(ch.is_ascii(), "{ch:?} is not an ASCII character");
+    ch as Char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsmBuilder;
+
+    #[test]
+    fn test_assert_true_build() {
+        let build = |strip: bool| {
+            let mut builder = AsmBuilder::new();
+            if strip {
+                builder.strip_assertions();
+            }
+            builder.main(|main_builder| {
+                main_builder
+                    .integer(0, 1)
+                    .assert_true(1, "r1 is set")
+                    .exit()
+            });
+            builder.finish()
+        };
+
+        let mut asm = build(false);
+        assert_eq!(asm.labels().len(), 1);
+        assert_eq!(
+            asm.main().to_string(),
+            r"func main
+    r1 <- int 0
+    r1 <- call __assert_0b4fc76379cd0cac r1
+    exit
+end",
+        );
+        #[cfg(feature = "interp")]
+        assert_eq!(
+            crate::interp::run(&asm, &crate::interp::Config::default()).output_string(),
+            "assertion failed: r1 is set\n"
+        );
+
+        assert_eq!(
+            build(true).to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 0
+    exit
+end",
+        );
+    }
+
+    #[test]
+    fn test_string_bytes_build() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.string_bytes(b"a: ", 1, [2, 3]).exit());
+        assert_eq!(
+            builder.finish().main().to_string(),
+            r"func main
+    r2 <- int 3
+    r1 <- arr r2
+    r3 <- int 97
+    r2 <- int 0
+    set r1 r2 r3
+    r3 <- int 58
+    r2 <- int 1
+    set r1 r2 r3
+    r3 <- int 32
+    r2 <- int 2
+    set r1 r2 r3
+    exit
+end"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot build a string through the same register twice")]
+    fn test_string_bytes_same_via_panics() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.string_bytes(b"hi", 1, [2, 2]));
+    }
+
+    #[test]
+    fn test_char_const_build() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.char_const('A', 1).put_str_char('\n', 2).exit());
+        assert_eq!(
+            builder.finish().main().to_string(),
+            r"func main
+    r1 <- int 65
+    r2 <- int 10
+    putchar r2
+    exit
+end"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "'é' is not an ASCII character")]
+    fn test_char_const_non_ascii_panics() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.char_const('é', 1));
+    }
+
+    #[test]
+    fn test_interned_string_build() {
+        let mut builder = AsmBuilder::new();
+        let greeting = builder.intern_string("hi");
+        assert_eq!(builder.intern_string("hi"), greeting);
+        let name = builder.intern_string("you");
+        builder
+            .main(|main_builder| {
+                main_builder
+                    .load_data(1)
+                    .load_interned(greeting, 1, 2)
+                    .label_call("greet", &[1], 0)
+                    .exit()
+            })
+            .label("greet", |greet_builder| {
+                greet_builder
+                    .load_interned(greeting, 1, 2)
+                    .load_interned(name, 1, 3)
+                    .return_(3)
+            });
+        let asm = builder.finish();
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func greet
+    r2 <- int 0
+    r2 <- get r1 r2
+    r3 <- int 1
+    r3 <- get r1 r3
+    ret r3
+end
+
+func __data
+    r1 <- int 2
+    r0 <- arr r1
+    r1 <- int 0
+    r2 <- str :hi
+    set r0 r1 r2
+    r1 <- int 1
+    r2 <- str :you
+    set r0 r1 r2
+    ret r0
+end
+
+func main
+    r1 <- call __data
+    r2 <- int 0
+    r2 <- get r1 r2
+    r0 <- call greet r1
+    exit
+end"
+        );
+        #[cfg(feature = "interp")]
+        {
+            let mut machine = crate::interp::Machine::new(&asm);
+            machine.resume().unwrap();
+            // The only arrays are the array of strings and the strings themselves.
+            assert_eq!(machine.array(3), None);
+        }
+
+        // Loading the strings builds an empty array when none are interned.
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.load_data(1).exit());
+        let asm = builder.finish_checked().unwrap();
+        assert!(asm.iter().any(|label| label.name() == DataRef::LABEL));
+        #[cfg(feature = "interp")]
+        assert_eq!(
+            crate::interp::run(&asm, &crate::interp::Config::default()).result,
+            Ok(())
+        );
+    }
+
+    #[test]
+    #[should_panic = "cannot load an interned string into the register holding the strings"]
+    fn test_load_interned_into_strings() {
+        let mut builder = AsmBuilder::new();
+        let text = builder.intern_string("text");
+        builder.main(|main_builder| main_builder.load_data(1).load_interned(text, 1, 1));
+    }
+
+    #[test]
+    fn test_closure_build() {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .integer(40, 1)
+                .make_closure("add_captured", &[1], 0)
+                .integer(2, 2)
+                .call_closure(0, &[2], 3)
+                .put_char(3)
+                .exit()
+        });
+
+        builder.label("add_captured", |add_captured_builder| {
+            add_captured_builder
+                .integer(1, 0)
+                .get_array_index(1, 0, 0)
+                .add(0, 2, 0)
+                .return_(0)
+        });
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func add_captured
+    r0 <- int 1
+    r0 <- get r1 r0
+    r0 <- add r0 r2
+    ret r0
+end
+
+func closure_new_1
+    r3 <- int 2
+    r0 <- arr r3
+    r3 <- int 0
+    set r0 r3 r1
+    r3 <- int 1
+    set r0 r3 r2
+    ret r0
+end
+
+func main
+    r1 <- int 40
+    r0 <- addr add_captured
+    r0 <- call closure_new_1 r0 r1
+    r2 <- int 2
+    r3 <- int 0
+    r3 <- get r0 r3
+    r3 <- dcall r3 r0 r2
+    putchar r3
+    exit
+end",
+        );
+    }
+}
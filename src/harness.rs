@@ -0,0 +1,201 @@
+//! Running programs on a real MiniVM build, to compare against the [interpreter](crate::interp) or the
+//! [C backend](crate::backend::c).
+
+use crate::{asm::Asm, target::Version};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Programs that print `Y` on a MiniVM that accepts the instructions added in a release, newest first.
+const PROBES: [((u32, u32), &str); 3] = [
+    (
+        (0, 3),
+        "func main
+    r1 <- fint 1.5
+    r2 <- fint 1.5
+    fbeq r1 r2 main.no main.yes
+@main.yes
+    r3 <- int 89
+    putchar r3
+@main.no
+    exit
+end",
+    ),
+    (
+        (0, 2),
+        "func main
+    r1 <- int 88
+    r2 <- int 1
+    r1 <- bor r1 r2
+    putchar r1
+    exit
+end",
+    ),
+    (
+        (0, 1),
+        "func main
+    r1 <- int 89
+    putchar r1
+    exit
+end",
+    ),
+];
+
+/// Write `asm` to a temporary file and run the `minivm` executable at `minivm_path` on it, with `stdin` as
+/// its input. The file is removed once the VM exits.
+///
+/// If the program records the [version](Asm::version) it was generated for, the VM is first
+/// [probed](minivm_version) for the release it implements, so that a program isn't run on a VM too old
+/// for it.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be written or the executable can't be run, or an error of kind
+/// [`Unsupported`](io::ErrorKind::Unsupported) if the VM is older than the program. A program that traps
+/// isn't an error; its exit status and stderr are in the output.
+pub fn run_with_minivm(
+    asm: &Asm,
+    minivm_path: impl AsRef<Path>,
+    stdin: &[u8],
+) -> io::Result<Output> {
+    let minivm_path = minivm_path.as_ref();
+    if let Some(version) = asm.version() {
+        let found = minivm_version(minivm_path)?;
+        if found.is_none_or(|found| found < version) {
+            let found = found.map_or("none".to_string(), |found| found.to_string());
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "the program is for MiniVM {version}, but `{}` implements {found}",
+                    minivm_path.display()
+                ),
+            ));
+        }
+    }
+    run_text(&asm.to_string(), minivm_path, stdin)
+}
+
+/// The newest MiniVM release whose instructions the executable at `minivm_path` accepts, found by running
+/// a small program using the instructions each release added. `None` if it doesn't run even the oldest.
+///
+/// # Errors
+///
+/// Returns an error if a program can't be written or the executable can't be run.
+pub fn minivm_version(minivm_path: impl AsRef<Path>) -> io::Result<Option<Version>> {
+    for ((major, minor), probe) in PROBES {
+        let output = run_text(probe, minivm_path.as_ref(), b"")?;
+        if output.status.success() && output.stdout == b"Y" {
+            return Ok(Some(Version::new(major, minor)));
+        }
+    }
+    Ok(None)
+}
+
+fn run_text(text: &str, minivm_path: &Path, stdin: &[u8]) -> io::Result<Output> {
+    let file = TempFile::new(text)?;
+    let mut child = Command::new(minivm_path)
+        .arg(&file.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let input = child.stdin.take();
+    let stdin = stdin.to_vec();
+    // Write from another thread, so a VM that doesn't read its input can't block on a full output pipe.
+    let writer = thread::spawn(
+        move || match input.map(|mut input| input.write_all(&stdin)) {
+            Some(Err(err)) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+            _ => Ok(()),
+        },
+    );
+    let output = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| io::Error::other("writing to the VM's stdin panicked"))??;
+    Ok(output)
+}
+
+/// A uniquely named file in the system's temporary directory, removed on drop.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(contents: &str) -> io::Result<TempFile> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "minivm-asm-{}-{}.vasm",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents)?;
+        Ok(TempFile(path))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_run_with_minivm() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.exit());
+        let asm = builder.finish();
+
+        // `cat` stands in for the VM, echoing the program it is given.
+        let output = run_with_minivm(&asm, "cat", b"ignored").unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), asm.to_string());
+        assert!(output.stderr.is_empty());
+
+        assert!(run_with_minivm(&asm, "/nonexistent/minivm", b"").is_err());
+
+        // `cat` runs none of the probes, so it can't run a program for any release.
+        assert_eq!(minivm_version("cat").unwrap(), None);
+        let mut versioned = asm.clone();
+        versioned.set_version(Some(Version::new(0, 2)));
+        let error = run_with_minivm(&versioned, "cat", b"").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            error.to_string(),
+            "the program is for MiniVM 0.2, but `cat` implements none"
+        );
+    }
+
+    #[test]
+    fn test_minivm_version() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for a MiniVM 0.2 build: it prints `Y` for programs without float instructions.
+        let vm = std::env::temp_dir().join(format!("minivm-asm-fake-vm-{}", std::process::id()));
+        std::fs::write(&vm, "#!/bin/sh\ngrep -q fint \"$1\" && exit 1\nprintf Y\n").unwrap();
+        std::fs::set_permissions(&vm, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(minivm_version(&vm).unwrap(), Some(Version::new(0, 2)));
+
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.exit());
+        let mut asm = builder.finish();
+        asm.set_version(Some(Version::new(0, 2)));
+        assert_eq!(run_with_minivm(&asm, &vm, b"").unwrap().stdout, b"Y");
+        asm.set_version(Some(Version::new(0, 3)));
+        assert_eq!(
+            run_with_minivm(&asm, &vm, b"").unwrap_err().to_string(),
+            format!(
+                "the program is for MiniVM 0.3, but `{}` implements 0.2",
+                vm.display()
+            )
+        );
+
+        std::fs::remove_file(&vm).unwrap();
+    }
+}
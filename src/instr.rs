@@ -0,0 +1,641 @@
+use crate::{builder::Reg, emit::Dialect, Int};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum OpCode {
+    Exit,
+    Reg,
+    Jump,
+    Call,
+    Addr,
+    DJump,
+    DCall,
+    ExternCall,
+    Ret,
+    Int,
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Bb,
+    Beq,
+    Blt,
+    Str,
+    Arr,
+    Set,
+    Get,
+    Len,
+    Type,
+    PutChar,
+    #[cfg(feature = "float")]
+    FInt,
+    #[cfg(feature = "float")]
+    FAdd,
+    #[cfg(feature = "float")]
+    FSub,
+    #[cfg(feature = "float")]
+    FMul,
+    #[cfg(feature = "float")]
+    FDiv,
+    #[cfg(feature = "float")]
+    FBeq,
+    #[cfg(feature = "float")]
+    FBlt,
+}
+
+impl OpCode {
+    pub const ALL: &'static [OpCode] = &[
+        OpCode::Exit,
+        OpCode::Reg,
+        OpCode::Jump,
+        OpCode::Call,
+        OpCode::Addr,
+        OpCode::DJump,
+        OpCode::DCall,
+        OpCode::ExternCall,
+        OpCode::Ret,
+        OpCode::Int,
+        OpCode::Neg,
+        OpCode::Add,
+        OpCode::Sub,
+        OpCode::Mul,
+        OpCode::Div,
+        OpCode::Mod,
+        OpCode::BAnd,
+        OpCode::BOr,
+        OpCode::BXor,
+        OpCode::Shl,
+        OpCode::Shr,
+        OpCode::Bb,
+        OpCode::Beq,
+        OpCode::Blt,
+        OpCode::Str,
+        OpCode::Arr,
+        OpCode::Set,
+        OpCode::Get,
+        OpCode::Len,
+        OpCode::Type,
+        OpCode::PutChar,
+        #[cfg(feature = "float")]
+        OpCode::FInt,
+        #[cfg(feature = "float")]
+        OpCode::FAdd,
+        #[cfg(feature = "float")]
+        OpCode::FSub,
+        #[cfg(feature = "float")]
+        OpCode::FMul,
+        #[cfg(feature = "float")]
+        OpCode::FDiv,
+        #[cfg(feature = "float")]
+        OpCode::FBeq,
+        #[cfg(feature = "float")]
+        OpCode::FBlt,
+    ];
+
+    #[must_use]
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCode::Exit => "exit",
+            OpCode::Reg => "reg",
+            OpCode::Jump => "jump",
+            OpCode::Call => "call",
+            OpCode::Addr => "addr",
+            OpCode::DJump => "djump",
+            OpCode::DCall => "dcall",
+            OpCode::ExternCall => "xcall",
+            OpCode::Ret => "ret",
+            OpCode::Int => "int",
+            OpCode::Neg => "neg",
+            OpCode::Add => "add",
+            OpCode::Sub => "sub",
+            OpCode::Mul => "mul",
+            OpCode::Div => "div",
+            OpCode::Mod => "mod",
+            OpCode::BAnd => "band",
+            OpCode::BOr => "bor",
+            OpCode::BXor => "bxor",
+            OpCode::Shl => "shl",
+            OpCode::Shr => "shr",
+            OpCode::Bb => "bb",
+            OpCode::Beq => "beq",
+            OpCode::Blt => "blt",
+            OpCode::Str => "str",
+            OpCode::Arr => "arr",
+            OpCode::Set => "set",
+            OpCode::Get => "get",
+            OpCode::Len => "len",
+            OpCode::Type => "type",
+            OpCode::PutChar => "putchar",
+            #[cfg(feature = "float")]
+            OpCode::FInt => "fint",
+            #[cfg(feature = "float")]
+            OpCode::FAdd => "fadd",
+            #[cfg(feature = "float")]
+            OpCode::FSub => "fsub",
+            #[cfg(feature = "float")]
+            OpCode::FMul => "fmul",
+            #[cfg(feature = "float")]
+            OpCode::FDiv => "fdiv",
+            #[cfg(feature = "float")]
+            OpCode::FBeq => "fbeq",
+            #[cfg(feature = "float")]
+            OpCode::FBlt => "fblt",
+        }
+    }
+
+    #[must_use]
+    pub fn from_mnemonic(mnemonic: &str) -> Option<OpCode> {
+        OpCode::ALL
+            .iter()
+            .copied()
+            .find(|op| op.mnemonic() == mnemonic)
+    }
+
+    /// Whether control never continues to the next instruction.
+    #[must_use]
+    pub fn is_terminator(self) -> bool {
+        matches!(
+            self,
+            OpCode::Exit | OpCode::Jump | OpCode::DJump | OpCode::Ret
+        ) || self.is_branch()
+    }
+
+    #[must_use]
+    pub fn is_branch(self) -> bool {
+        match self {
+            OpCode::Bb | OpCode::Beq | OpCode::Blt => true,
+            #[cfg(feature = "float")]
+            OpCode::FBeq | OpCode::FBlt => true,
+            _ => false,
+        }
+    }
+}
+
+/// The result of the bitwise operation `op` on `lhs` and `rhs`. Shifts only use the low 6 bits of `rhs`,
+/// and `shr` is arithmetic.
+pub(crate) fn bitwise(op: OpCode, lhs: Int, rhs: Int) -> Int {
+    let shift = u32::try_from(rhs & 63).unwrap();
+    match op {
+        OpCode::BAnd => lhs & rhs,
+        OpCode::BOr => lhs | rhs,
+        OpCode::BXor => lhs ^ rhs,
+        OpCode::Shl => lhs << shift,
+        OpCode::Shr => lhs >> shift,
+        _ => unreachable!("`{op}` is not a bitwise operation"),
+    }
+}
+
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+/// A register that instructions can refer to, written `{PREFIX}{index}`.
+pub trait Register: Copy + Eq + fmt::Debug {
+    const PREFIX: char;
+
+    fn index(self) -> u32;
+}
+
+impl Register for Reg {
+    const PREFIX: char = 'r';
+
+    fn index(self) -> u32 {
+        u32::from(self)
+    }
+}
+
+/// The name of a label or sub-label. Cloning an id shares its name rather than copying it, and the builder and
+/// parser intern the ids they write, so every reference to a label in a program shares one copy of its name.
+#[derive(Clone)]
+pub struct LabelId(Arc<str>);
+
+impl LabelId {
+    /// An id holding its own copy of `name`.
+    #[must_use]
+    pub fn new(name: &str) -> LabelId {
+        LabelId(name.into())
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The names of the labels referred to by one program or builder, so that references to the same label share
+/// one copy of its name. Each program has its own, so unrelated programs don't contend for it.
+#[derive(Default)]
+pub(crate) struct Interner {
+    names: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    /// Make the label operands of `instr` share their names with earlier ones.
+    pub(crate) fn share<R: Register>(&mut self, instr: &mut Instruction<R>) {
+        for target in instr.targets_mut() {
+            match self.names.get(&target.0) {
+                Some(name) => target.0 = Arc::clone(name),
+                None => {
+                    self.names.insert(Arc::clone(&target.0));
+                }
+            }
+        }
+    }
+
+    /// Take on the names of `other`, keeping those this already has.
+    pub(crate) fn extend(&mut self, other: Interner) {
+        for name in other.names {
+            if !self.names.contains(&name) {
+                self.names.insert(name);
+            }
+        }
+    }
+}
+
+impl PartialEq for LabelId {
+    fn eq(&self, other: &LabelId) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for LabelId {}
+
+impl std::hash::Hash for LabelId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl From<&str> for LabelId {
+    fn from(name: &str) -> LabelId {
+        LabelId::new(name)
+    }
+}
+
+impl From<String> for LabelId {
+    fn from(name: String) -> LabelId {
+        LabelId::new(&name)
+    }
+}
+
+impl AsRef<str> for LabelId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for LabelId {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+/// Serialized as the name.
+#[cfg(feature = "serde")]
+impl serde::Serialize for LabelId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for LabelId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = std::borrow::Cow::<str>::deserialize(deserializer)?;
+        Ok(LabelId::new(&name))
+    }
+}
+
+impl fmt::Debug for LabelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for LabelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The value of `fint`, compared and hashed by its bits, so `NaN` equals itself and `0.0` doesn't equal
+/// `-0.0`.
+#[cfg(feature = "float")]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Float(pub f64);
+
+#[cfg(feature = "float")]
+impl PartialEq for Float {
+    fn eq(&self, other: &Float) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+#[cfg(feature = "float")]
+impl Eq for Float {}
+
+#[cfg(feature = "float")]
+impl std::hash::Hash for Float {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// Always written with a decimal point or exponent, so it can't be read back as an integer.
+#[cfg(feature = "float")]
+impl fmt::Display for Float {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Operand<R = Reg> {
+    Reg(R),
+    Int(Int),
+    Label(LabelId),
+    Str(String),
+    /// The name of a function provided by the host, called with `xcall`.
+    Extern(String),
+    #[cfg(feature = "float")]
+    Float(Float),
+    /// A constant read with [`integer_const`](crate::BuildInstruction::integer_const), which
+    /// [`AsmBuilder`](crate::AsmBuilder) replaces with its value when the program is finished. Written as
+    /// `{name}`, which no VM accepts.
+    Const(String),
+}
+
+impl<R: Register> Operand<R> {
+    #[must_use]
+    pub fn as_reg(&self) -> Option<R> {
+        match self {
+            Operand::Reg(reg) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_int(&self) -> Option<Int> {
+        match self {
+            Operand::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "float")]
+    #[must_use]
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Operand::Float(value) => Some(value.0),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_label(&self) -> Option<&str> {
+        match self {
+            Operand::Label(label) => Some(label.as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl<R: Register> fmt::Display for Operand<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write_reg(f, *reg),
+            Operand::Int(value) => write_int(f, *value),
+            Operand::Label(label) => f.write_str(label.as_str()),
+            Operand::Str(text) => write!(f, ":{text}"),
+            Operand::Extern(name) => f.write_str(name),
+            #[cfg(feature = "float")]
+            Operand::Float(value) => write!(f, "{value}"),
+            Operand::Const(name) => write!(f, "{{{name}}}"),
+        }
+    }
+}
+
+/// A single MiniVM instruction, `[rX <- ]op operand...`.
+///
+/// Operands are stored in the order they are written, so branches hold their false target before their true target.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instruction<R = Reg> {
+    pub op: OpCode,
+    pub dest: Option<R>,
+    pub operands: Vec<Operand<R>>,
+}
+
+impl Instruction {
+    #[must_use]
+    pub fn new(op: OpCode, dest: Option<Reg>, operands: Vec<Operand>) -> Instruction {
+        Self { op, dest, operands }
+    }
+}
+
+impl<R: Register> Instruction<R> {
+    /// Registers read by the instruction.
+    pub fn uses(&self) -> impl Iterator<Item = R> + '_ {
+        self.operands.iter().filter_map(Operand::as_reg)
+    }
+
+    /// Labels referenced by the instruction.
+    pub fn targets(&self) -> impl Iterator<Item = &str> + '_ {
+        self.operands.iter().filter_map(Operand::as_label)
+    }
+
+    pub fn targets_mut(&mut self) -> impl Iterator<Item = &mut LabelId> + '_ {
+        self.operands
+            .iter_mut()
+            .filter_map(|operand| match operand {
+                Operand::Label(label) => Some(label),
+                _ => None,
+            })
+    }
+
+    /// Replace every register of the instruction, read or written, with `f(reg)`.
+    #[must_use]
+    pub fn map_registers<S: Register>(self, mut f: impl FnMut(R) -> S) -> Instruction<S> {
+        let operands = self
+            .operands
+            .into_iter()
+            .map(|operand| match operand {
+                Operand::Reg(reg) => Operand::Reg(f(reg)),
+                Operand::Int(value) => Operand::Int(value),
+                Operand::Label(label) => Operand::Label(label),
+                Operand::Str(text) => Operand::Str(text),
+                Operand::Extern(name) => Operand::Extern(name),
+                #[cfg(feature = "float")]
+                Operand::Float(value) => Operand::Float(value),
+                Operand::Const(name) => Operand::Const(name),
+            })
+            .collect();
+        Instruction {
+            op: self.op,
+            dest: self.dest.map(f),
+            operands,
+        }
+    }
+}
+
+impl<R: Register> fmt::Display for Instruction<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_in(f, None)
+    }
+}
+
+impl<R: Register> Instruction<R> {
+    /// Write the instruction with the mnemonic of `dialect`, or the usual one if there is none.
+    pub(crate) fn fmt_in(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        dialect: Option<&Dialect>,
+    ) -> fmt::Result {
+        if let Some(dest) = self.dest {
+            write_reg(f, dest)?;
+            f.write_str(" <- ")?;
+        }
+        match dialect {
+            Some(dialect) => f.write_str(dialect.mnemonic(self.op))?,
+            None => f.write_str(self.op.mnemonic())?,
+        }
+        for operand in &self.operands {
+            f.write_str(" ")?;
+            fmt::Display::fmt(operand, f)?;
+        }
+        Ok(())
+    }
+}
+
+// Programs are mostly registers and small integers, so they are formatted by hand rather than with `write!`,
+// which is much slower for so little output.
+
+fn write_reg<R: Register>(f: &mut fmt::Formatter<'_>, reg: R) -> fmt::Result {
+    let mut buf = [0; 4];
+    f.write_str(R::PREFIX.encode_utf8(&mut buf))?;
+    write_int(f, reg.index().into())
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_int(f: &mut fmt::Formatter<'_>, value: Int) -> fmt::Result {
+    // Enough for `i64::MIN`.
+    let mut buf = [0; 20];
+    let mut start = buf.len();
+    let mut rest = value.unsigned_abs();
+    loop {
+        start -= 1;
+        // The remainder is a single digit.
+        buf[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+    f.write_str(std::str::from_utf8(&buf[start..]).expect("digits are ASCII"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_to_string() {
+        let call = Instruction::new(
+            OpCode::Call,
+            Some(0),
+            vec![
+                Operand::Label("fib".into()),
+                Operand::Reg(1),
+                Operand::Reg(2),
+            ],
+        );
+        assert_eq!(call.to_string(), "r0 <- call fib r1 r2");
+
+        let set = Instruction::new(
+            OpCode::Set,
+            None,
+            vec![Operand::Reg(0), Operand::Reg(1), Operand::Reg(2)],
+        );
+        assert_eq!(set.to_string(), "set r0 r1 r2");
+
+        let string = Instruction::new(
+            OpCode::Str,
+            Some(3),
+            vec![Operand::Str("hello".to_string())],
+        );
+        assert_eq!(string.to_string(), "r3 <- str :hello");
+
+        let int = |value| Instruction::new(OpCode::Int, Some(255), vec![Operand::Int(value)]);
+        assert_eq!(int(0).to_string(), "r255 <- int 0");
+        assert_eq!(int(-70).to_string(), "r255 <- int -70");
+        assert_eq!(
+            int(Int::MIN).to_string(),
+            "r255 <- int -9223372036854775808"
+        );
+        assert_eq!(int(Int::MAX).to_string(), "r255 <- int 9223372036854775807");
+
+        assert_eq!(
+            Instruction::new(OpCode::Exit, None, vec![]).to_string(),
+            "exit"
+        );
+    }
+
+    #[test]
+    fn test_opcode_mnemonic_round_trip() {
+        for &op in OpCode::ALL {
+            assert_eq!(OpCode::from_mnemonic(op.mnemonic()), Some(op));
+        }
+        assert_eq!(OpCode::from_mnemonic("nop"), None);
+    }
+
+    #[test]
+    fn test_label_id() {
+        let id = LabelId::new("fib.loop");
+        assert_eq!(LabelId::from("fib.loop".to_string()), id);
+        assert_ne!(LabelId::new("fib.done"), id);
+        assert_eq!(id.as_str(), "fib.loop");
+        assert_eq!(format!("{id:?}"), r#""fib.loop""#);
+
+        let mut names = Interner::default();
+        let mut jumps: Vec<Instruction> = (0..2)
+            .map(|_| Instruction::new(OpCode::Jump, None, vec![Operand::Label(id.clone())]))
+            .collect();
+        jumps.push(Instruction::new(
+            OpCode::Jump,
+            None,
+            vec![Operand::Label(LabelId::new("fib.loop"))],
+        ));
+        for jump in &mut jumps {
+            names.share(jump);
+        }
+        let names: Vec<&Arc<str>> = jumps
+            .iter_mut()
+            .flat_map(Instruction::targets_mut)
+            .map(|target| &target.0)
+            .collect();
+        assert!(names.iter().all(|name| Arc::ptr_eq(name, names[0])));
+
+        let jump = Instruction::new(OpCode::Jump, None, vec![Operand::Label(id)]);
+        assert_eq!(jump.targets().collect::<Vec<_>>(), ["fib.loop"]);
+    }
+}
@@ -0,0 +1,314 @@
+//! Instrumentation that makes programs report on what they do as they run.
+//!
+//! Injected code only uses registers that no function of the program uses, so it doesn't disturb the
+//! program, even across `jump`s between functions.
+
+#![allow(clippy::missing_panics_doc)]
+
+use crate::{
+    asm::{Asm, Label, LabelImpl, Line},
+    builder::{LabelBuilder, Reg},
+    instr::{Instruction, OpCode, Operand},
+    BuildInstruction, Int,
+};
+
+const DUMP_COUNTERS: &str = "__profile_dump";
+const PUT_NUMBER: &str = "__profile_putn";
+
+/// Print `> name` on a line of its own whenever a function is entered, and if `log_returns` is set,
+/// `< name` whenever it returns.
+///
+/// Panics if the program uses every register.
+pub fn add_call_tracing(asm: &mut Asm, log_returns: bool) {
+    let scratch = scratch_register(asm);
+    for label in asm.iter_mut() {
+        let name = label.name().to_string();
+        let prologue = print(&format!("> {name}\n"), scratch);
+        label.lines_mut().splice(0..0, prologue);
+        if log_returns {
+            let epilogue = print(&format!("< {name}\n"), scratch);
+            for block in label.blocks_mut() {
+                let lines = std::mem::take(block.lines_mut());
+                for line in lines {
+                    if matches!(&line, Line::Instruction(instr) if instr.op == OpCode::Ret) {
+                        block.lines_mut().extend(epilogue.iter().cloned());
+                    }
+                    block.lines_mut().push(line);
+                }
+            }
+        }
+    }
+}
+
+/// Count how many times every function is entered, and if `per_block` is set, every sub-label too. The
+/// counts are printed as `name: count` lines before every `exit`.
+///
+/// The counters are an array allocated at the start of the entry block, which is passed to every function
+/// as an extra argument.
+///
+/// Panics if the program doesn't leave three registers free, above the arguments of every call.
+pub fn add_counters(asm: &mut Asm, per_block: bool) {
+    let max_args = blocks(asm)
+        .flat_map(LabelImpl::instructions)
+        .filter(|instr| matches!(instr.op, OpCode::Call | OpCode::DCall))
+        .map(|instr| instr.operands.len() - 1)
+        .max()
+        .unwrap_or(0);
+    let counters = Reg::try_from(usize::from(scratch_register(asm)).max(max_args + 1))
+        .ok()
+        .filter(|&counters| counters <= Reg::MAX - 2)
+        .expect("not enough registers are free for instrumentation");
+    let (index, value) = (counters + 1, counters + 2);
+    let slots: Vec<String> = asm
+        .iter()
+        .flat_map(|label| label.blocks().take(if per_block { usize::MAX } else { 1 }))
+        .map(|block| block.name().to_string())
+        .collect();
+
+    let line = |op, dest, operands| Line::Instruction(Instruction::new(op, dest, operands));
+    let int = |value: usize| Operand::Int(Int::try_from(value).expect("too many counters"));
+    let dump = line(
+        OpCode::Call,
+        Some(index),
+        vec![Operand::Label(DUMP_COUNTERS.into()), Operand::Reg(counters)],
+    );
+    let pass_counters = |lines: Vec<Line>| -> Vec<Line> {
+        let mut out = Vec::new();
+        for mut line in lines {
+            if let Line::Instruction(instr) = &mut line {
+                match instr.op {
+                    OpCode::Call | OpCode::DCall => {
+                        // Pad the arguments so the counters land in the same register of the callee.
+                        let args = instr.operands.len() - 1;
+                        for _ in args + 1..usize::from(counters) {
+                            instr.operands.push(Operand::Reg(0));
+                        }
+                        instr.operands.push(Operand::Reg(counters));
+                    }
+                    OpCode::Exit => out.push(dump.clone()),
+                    _ => {}
+                }
+            }
+            out.push(line);
+        }
+        out
+    };
+
+    let entry = std::mem::take(asm.entry_mut().lines_mut());
+    let mut lines = vec![
+        line(OpCode::Int, Some(counters), vec![int(slots.len())]),
+        line(OpCode::Arr, Some(counters), vec![Operand::Reg(counters)]),
+    ];
+    lines.extend(pass_counters(entry));
+    *asm.entry_mut().lines_mut() = lines;
+
+    let mut slot = 0;
+    for label in asm.iter_mut() {
+        for (i, block) in label.blocks_mut().enumerate() {
+            let old = std::mem::take(block.lines_mut());
+            let lines = block.lines_mut();
+            if per_block || i == 0 {
+                let reg = Operand::Reg;
+                lines.extend([
+                    line(OpCode::Int, Some(index), vec![int(slot)]),
+                    line(OpCode::Get, Some(value), vec![reg(counters), reg(index)]),
+                    line(OpCode::Int, Some(index), vec![int(1)]),
+                    line(OpCode::Add, Some(value), vec![reg(value), reg(index)]),
+                    line(OpCode::Int, Some(index), vec![int(slot)]),
+                    line(
+                        OpCode::Set,
+                        None,
+                        vec![reg(counters), reg(index), reg(value)],
+                    ),
+                ]);
+                slot += 1;
+            }
+            lines.extend(pass_counters(old));
+        }
+    }
+
+    for label in counter_functions(&slots) {
+        asm.push_label(label);
+    }
+}
+
+/// The functions printing the counters of `slots`, as `name: count` lines.
+fn counter_functions(slots: &[String]) -> [Label; 2] {
+    let mut dump = LabelBuilder::new(DUMP_COUNTERS);
+    for (slot, name) in slots.iter().enumerate() {
+        for byte in format!("{name}: ").bytes() {
+            dump.integer(Int::from(byte), 0).put_char(0);
+        }
+        dump.integer(Int::try_from(slot).expect("too many counters"), 2)
+            .get_array_index(1, 2, 2)
+            .label_call(PUT_NUMBER, &[2], 0)
+            .integer(10, 0)
+            .put_char(0);
+    }
+    dump.return_(0);
+
+    let mut put_number = LabelBuilder::new(PUT_NUMBER);
+    put_number
+        .integer(10, 0)
+        .branch_less_than(1, 0, "__profile_putn.digit", "__profile_putn.rest")
+        .sub_label("rest", |rest| {
+            rest.div(1, 0, 2).label_call(PUT_NUMBER, &[2], 2)
+        })
+        .sub_label("digit", |digit| {
+            digit
+                .integer(10, 0)
+                .mod_(1, 0, 1)
+                .integer(48, 0)
+                .add(1, 0, 1)
+                .put_char(1)
+                .return_(1)
+        });
+    [dump.finish(), put_number.finish()]
+}
+
+/// Every block of the program, starting with the entry block.
+fn blocks(asm: &Asm) -> impl Iterator<Item = &LabelImpl> + '_ {
+    std::iter::once(asm.entry()).chain(asm.iter().flat_map(Label::blocks))
+}
+
+/// The lowest register above every register the program uses, including in the entry block.
+///
+/// Panics if the program uses every register.
+fn scratch_register(asm: &Asm) -> Reg {
+    blocks(asm)
+        .flat_map(LabelImpl::instructions)
+        .flat_map(|instr| instr.dest.into_iter().chain(instr.uses()))
+        .max()
+        .map_or(Some(0), |highest| highest.checked_add(1))
+        .expect("no register is free for instrumentation")
+}
+
+/// Lines that print `text` one byte at a time through `scratch`.
+fn print(text: &str, scratch: Reg) -> Vec<Line> {
+    text.bytes()
+        .flat_map(|byte| {
+            [
+                Instruction::new(
+                    OpCode::Int,
+                    Some(scratch),
+                    vec![Operand::Int(Int::from(byte))],
+                ),
+                Instruction::new(OpCode::PutChar, None, vec![Operand::Reg(scratch)]),
+            ]
+        })
+        .map(Line::Instruction)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+
+    #[test]
+    fn test_add_call_tracing() {
+        let mut asm = parse(
+            r"func id
+    ret r1
+end
+
+func main
+    r1 <- int 7
+    r0 <- call id r1
+    exit
+end",
+        )
+        .unwrap();
+        add_call_tracing(&mut asm, true);
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func id
+    r2 <- int 62
+    putchar r2
+    r2 <- int 32
+    putchar r2
+    r2 <- int 105
+    putchar r2
+    r2 <- int 100
+    putchar r2
+    r2 <- int 10
+    putchar r2
+    r2 <- int 60
+    putchar r2
+    r2 <- int 32
+    putchar r2
+    r2 <- int 105
+    putchar r2
+    r2 <- int 100
+    putchar r2
+    r2 <- int 10
+    putchar r2
+    ret r1
+end
+
+func main
+    r2 <- int 62
+    putchar r2
+    r2 <- int 32
+    putchar r2
+    r2 <- int 109
+    putchar r2
+    r2 <- int 97
+    putchar r2
+    r2 <- int 105
+    putchar r2
+    r2 <- int 110
+    putchar r2
+    r2 <- int 10
+    putchar r2
+    r1 <- int 7
+    r0 <- call id r1
+    exit
+end"
+        );
+
+        #[cfg(feature = "interp")]
+        {
+            let result = crate::interp::run(&asm, &crate::interp::Config::default());
+            assert_eq!(result.output_string(), "> main\n> id\n< id\n");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "interp")]
+    fn test_add_counters() {
+        let mut asm = parse(
+            r"func count
+    r0 <- int 1
+    r1 <- sub r1 r0
+    bb r1 count.done count.loop
+@count.loop
+    jump count
+@count.done
+    ret r1
+end
+
+func main
+    r1 <- int 12
+    r0 <- call count r1
+    r1 <- int 3
+    r0 <- call count r1
+    r0 <- int 10
+    putchar r0
+    exit
+end",
+        )
+        .unwrap();
+        add_counters(&mut asm, true);
+        let result = crate::interp::run(&asm, &crate::interp::Config::default());
+        assert_eq!(result.result, Ok(()));
+        assert_eq!(
+            result.output_string(),
+            "\ncount: 15\ncount.loop: 13\ncount.done: 2\nmain: 1\n"
+        );
+    }
+}
@@ -0,0 +1,1275 @@
+//! An interpreter for the structured instructions of a program, to test generated code without a MiniVM
+//! build.
+//!
+//! Semantics follow the [C backend](crate::backend::c): registers start out as the integer 0, calls get a
+//! fresh frame while jumps into another function share the current one, arithmetic wraps, and label
+//! addresses are indices into the list of every label and sub-label in output order.
+
+#![allow(clippy::missing_panics_doc)]
+
+mod compile;
+pub mod gc;
+
+#[cfg(feature = "float")]
+use crate::instr::Float;
+use crate::{
+    asm::{Asm, LabelImpl, Line},
+    builder::Reg,
+    instr::{bitwise, Instruction, OpCode, Operand},
+    Int,
+};
+use compile::Function;
+use gc::{Gc, GcConfig, GcStats};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Options for [`run`] and [`Machine::with_config`]. There are no limits by default.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// How many instructions may be executed.
+    pub max_steps: Option<u64>,
+    /// How many array elements may be allocated over the whole run, strings included.
+    pub max_array_elements: Option<usize>,
+    /// How many frames the call stack may hold, `main`'s included.
+    pub max_call_depth: Option<usize>,
+    /// The functions `xcall` can call, in the order they were registered.
+    pub externs: Vec<HostFunction>,
+    /// How to model the garbage collector, if at all.
+    pub gc: Option<GcConfig>,
+    /// Compile functions that only compute with integers into Rust closures, and run calls to them that pass
+    /// only integers with those instead. Output, steps and traps are the same either way, but breakpoints
+    /// are not checked inside compiled calls.
+    pub compile: bool,
+}
+
+impl Config {
+    /// Let `xcall` call `function` by `name`, or by its position among the registered functions.
+    pub fn register_extern(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[Int]) -> Int + 'static,
+    ) -> &mut Self {
+        self.externs.push(HostFunction {
+            name: name.to_string(),
+            function: Rc::new(function),
+        });
+        self
+    }
+}
+
+/// A function provided by the host, taking the integers passed by `xcall` and giving its result.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub name: String,
+    function: Rc<ExternFn>,
+}
+
+type ExternFn = dyn Fn(&[Int]) -> Int;
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(Int),
+    /// An index into the arrays allocated by the program. Arrays are equal only if they are the same array.
+    Array(usize),
+    /// `type` gives `2` for a float.
+    #[cfg(feature = "float")]
+    Float(Float),
+}
+
+/// Arrays are written by their index among the arrays allocated by the program, as in `array #3`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{value}"),
+            Value::Array(array) => write!(f, "array #{array}"),
+            #[cfg(feature = "float")]
+            Value::Float(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Why a program stopped before exiting, and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    /// The instruction that trapped. For [`TrapKind::FellOffEnd`], this is just past the last line of the
+    /// function.
+    pub location: Location,
+    /// The text of the instruction that trapped, if it is one.
+    pub instruction: Option<String>,
+    /// The registers of the trapping frame that don't hold the integer 0 they start out as.
+    pub registers: Vec<(Reg, Value)>,
+}
+
+/// Written as the kind and location, then the instruction and the registers, each on a line of its own.
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.location)?;
+        if let Some(instr) = &self.instruction {
+            write!(f, "\n    in `{instr}`")?;
+        }
+        if !self.registers.is_empty() {
+            f.write_str("\n    with ")?;
+            for (n, (reg, value)) in self.registers.iter().enumerate() {
+                let separator = if n == 0 { "" } else { ", " };
+                write!(f, "{separator}r{reg} = {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Trap {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrapKind {
+    DivisionByZero,
+    IndexOutOfBounds {
+        index: Int,
+        len: usize,
+    },
+    NegativeArrayLength(Int),
+    ExpectedInt,
+    ExpectedArray,
+    /// A float where only integers and arrays are allowed.
+    #[cfg(feature = "float")]
+    UnexpectedFloat,
+    /// A `djump` or `dcall` to a number that isn't a label address.
+    InvalidAddress(Int),
+    UnknownLabel(String),
+    /// An `xcall` to a function that isn't [registered](Config::register_extern).
+    UnknownExtern(String),
+    /// Control reached the end of the named function without a `ret`, `jump`, or `exit`.
+    FellOffEnd(String),
+    /// Raw lines can't be executed, as their meaning is unknown.
+    RawLine(String),
+    /// An instruction whose operands don't match its opcode.
+    MalformedInstruction(String),
+    /// The program ran for [`Config::max_steps`] instructions.
+    StepLimit,
+    /// The program tried to allocate more than [`Config::max_array_elements`] array elements.
+    AllocationLimit,
+    /// The program tried to call more than [`Config::max_call_depth`] functions deep.
+    CallDepthLimit,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapKind::DivisionByZero => f.write_str("division by zero"),
+            TrapKind::IndexOutOfBounds { index, len } => {
+                write!(f, "index {index} out of bounds of array of length {len}")
+            }
+            TrapKind::NegativeArrayLength(len) => write!(f, "negative array length {len}"),
+            TrapKind::ExpectedInt => f.write_str("expected an integer, found an array"),
+            TrapKind::ExpectedArray => f.write_str("expected an array, found an integer"),
+            #[cfg(feature = "float")]
+            TrapKind::UnexpectedFloat => f.write_str("expected an integer or array, found a float"),
+            TrapKind::InvalidAddress(addr) => write!(f, "invalid label address {addr}"),
+            TrapKind::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            TrapKind::UnknownExtern(name) => write!(f, "unknown extern function `{name}`"),
+            TrapKind::FellOffEnd(function) => write!(f, "fell off the end of `{function}`"),
+            TrapKind::RawLine(line) => write!(f, "cannot execute raw line `{line}`"),
+            TrapKind::MalformedInstruction(instr) => write!(f, "malformed instruction `{instr}`"),
+            TrapKind::StepLimit => f.write_str("step limit reached"),
+            TrapKind::AllocationLimit => f.write_str("allocation limit reached"),
+            TrapKind::CallDepthLimit => f.write_str("call depth limit reached"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunResult {
+    /// Everything written with `putchar`.
+    pub output: Vec<u8>,
+    /// `Ok` if the program exited, either with `exit` or by returning from `main`.
+    pub result: Result<(), Trap>,
+    /// How many instructions were executed.
+    pub steps: u64,
+    /// What the garbage collector did, if [`Config::gc`] was set.
+    pub gc: Option<GcStats>,
+}
+
+impl RunResult {
+    /// The output as text, with invalid UTF-8 replaced.
+    #[must_use]
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+/// Run `asm` from its entry point until it exits or traps.
+///
+/// Panics if the program is a library.
+#[must_use]
+pub fn run(asm: &Asm, config: &Config) -> RunResult {
+    let mut machine = Machine::with_config(asm, config.clone());
+    let result = machine.resume().map(|_| ());
+    RunResult {
+        gc: machine.gc_stats().cloned(),
+        output: machine.output,
+        result,
+        steps: machine.steps,
+    }
+}
+
+/// Where execution is, as the instruction about to be executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The label or sub-label, like `fib` or `fib.else`.
+    pub label: String,
+    /// The index of the instruction among the lines of the label.
+    pub index: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", self.label, self.index)
+    }
+}
+
+/// Where [`Machine::resume`] should stop, before the instruction is executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// The first instruction of a label or sub-label.
+    Label(String),
+    /// An instruction of a label or sub-label, by its index among the lines of the label.
+    Instruction { label: String, index: usize },
+}
+
+impl Breakpoint {
+    fn matches(&self, label: &str, index: usize) -> bool {
+        match self {
+            Breakpoint::Label(name) => name == label && index == 0,
+            Breakpoint::Instruction {
+                label: name,
+                index: at,
+            } => name == label && *at == index,
+        }
+    }
+}
+
+/// Why [`Machine::resume`] returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Exited,
+    Breakpoint(Location),
+}
+
+struct Frame {
+    registers: Vec<Value>,
+    block: usize,
+    index: usize,
+    /// The caller's register the return value goes to.
+    return_to: Option<Reg>,
+}
+
+/// A program being executed, one instruction at a time.
+pub struct Machine<'a> {
+    /// Every label and sub-label, in output order, along with the index of its function.
+    blocks: Vec<(usize, &'a LabelImpl)>,
+    by_name: HashMap<&'a str, usize>,
+    functions: Vec<&'a str>,
+    frame_size: usize,
+    frames: Vec<Frame>,
+    arrays: Vec<Vec<Value>>,
+    output: Vec<u8>,
+    steps: u64,
+    allocated: usize,
+    gc: Option<Gc>,
+    breakpoints: Vec<Breakpoint>,
+    /// The compiled functions, by their first block.
+    compiled: HashMap<usize, Function>,
+    compiled_calls: u64,
+    config: Config,
+}
+
+impl<'a> Machine<'a> {
+    /// A machine about to execute the first instruction of `main`, or of the entry block if it does more than
+    /// call `main` and exit.
+    #[must_use]
+    pub fn new(asm: &'a Asm) -> Machine<'a> {
+        Self::with_config(asm, Config::default())
+    }
+
+    /// Like [`Machine::new`], with the limits of `config`.
+    ///
+    /// Panics if the program is a library, which has nowhere to start.
+    #[must_use]
+    pub fn with_config(asm: &'a Asm, config: Config) -> Machine<'a> {
+        assert!(!asm.is_library(), "a library can't be run");
+        let mut blocks: Vec<(usize, &LabelImpl)> = asm
+            .iter()
+            .enumerate()
+            .flat_map(|(func, label)| label.blocks().map(move |block| (func, block)))
+            .collect();
+        let mut functions: Vec<&str> = asm.iter().map(|label| label.name()).collect();
+        // A custom entry block runs as a function of its own, after every other block so addresses are
+        // the same either way.
+        if !asm.has_standard_entry() {
+            blocks.push((functions.len(), asm.entry()));
+            functions.push(asm.entry().name());
+        }
+        let by_name = blocks
+            .iter()
+            .enumerate()
+            .map(|(id, (_, block))| (block.name(), id))
+            .collect();
+        let max_reg = blocks
+            .iter()
+            .flat_map(|(_, block)| block.instructions())
+            .flat_map(|instr| instr.dest.into_iter().chain(instr.uses()))
+            .max()
+            .unwrap_or(0);
+        let frame_size = usize::from(max_reg) + 1;
+        let compiled = if config.compile {
+            compile_functions(&blocks, &by_name, frame_size)
+        } else {
+            HashMap::new()
+        };
+        let mut machine = Self {
+            blocks,
+            by_name,
+            functions,
+            frame_size,
+            frames: Vec::new(),
+            arrays: Vec::new(),
+            output: Vec::new(),
+            steps: 0,
+            allocated: 0,
+            gc: config.gc.clone().map(Gc::new),
+            breakpoints: Vec::new(),
+            compiled,
+            compiled_calls: 0,
+            config,
+        };
+        let start = if asm.has_standard_entry() {
+            machine.by_name["main"]
+        } else {
+            machine.blocks.len() - 1
+        };
+        let frame = machine.new_frame(start, Vec::new(), None);
+        machine.frames.push(frame);
+        machine
+    }
+
+    /// Execute the call about to be executed with its compiled function, returning whether it did. The
+    /// interpreter executes calls the function can't, which then trap at the same instruction.
+    fn call_compiled(&mut self) -> bool {
+        if self.compiled.is_empty() {
+            return false;
+        }
+        let Ok(Some(instr)) = self.current() else {
+            return false;
+        };
+        let (OpCode::Call, Some(dest)) = (instr.op, instr.dest) else {
+            return false;
+        };
+        let Some(Ok(block)) = instr
+            .operands
+            .first()
+            .and_then(Operand::as_label)
+            .map(|label| self.lookup(label))
+        else {
+            return false;
+        };
+        let registers = self.registers();
+        let args: Option<Vec<Int>> = instr.operands[1..]
+            .iter()
+            .map(|arg| match registers.get(usize::from(arg.as_reg()?)) {
+                Some(&Value::Int(value)) => Some(value),
+                _ => None,
+            })
+            .collect();
+        let (Some(function), Some(args)) = (self.compiled.get(&block), args) else {
+            return false;
+        };
+        if self
+            .config
+            .max_call_depth
+            .is_some_and(|max| self.frames.len() >= max)
+        {
+            return false;
+        }
+        // The call itself is a step too.
+        let max_steps = self.config.max_steps.map_or(Some(u64::MAX), |max| {
+            max.saturating_sub(self.steps).checked_sub(1)
+        });
+        let Some((value, steps)) = max_steps.and_then(|max| function.call(&args, max)) else {
+            return false;
+        };
+        self.steps += 1 + steps;
+        self.top().index += 1;
+        self.set(dest, Value::Int(value));
+        self.compiled_calls += 1;
+        true
+    }
+
+    /// Execute one instruction, returning whether the program is still running.
+    ///
+    /// # Errors
+    ///
+    /// Returns the trap if the instruction traps. The machine is left as it was before the instruction.
+    pub fn step(&mut self) -> Result<bool, Trap> {
+        let instr = match self.current() {
+            Ok(Some(instr)) => instr,
+            Ok(None) => return Ok(false),
+            Err(kind) => return Err(self.trap(kind)),
+        };
+        if self.config.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(self.trap(TrapKind::StepLimit));
+        }
+        self.steps += 1;
+        self.top().index += 1;
+        if let Err(kind) = self.execute(instr) {
+            self.steps -= 1;
+            self.top().index -= 1;
+            return Err(self.trap(kind));
+        }
+        Ok(!self.frames.is_empty())
+    }
+
+    fn trap(&self, kind: TrapKind) -> Trap {
+        let (block, index) = self.position().expect("no frame to trap in");
+        let location = Location {
+            label: self.blocks[block].1.name().to_string(),
+            index,
+        };
+        let instruction = self.blocks[block]
+            .1
+            .lines()
+            .get(index)
+            .and_then(Line::as_instruction)
+            .map(Instruction::to_string);
+        let registers = self.frames.last().map_or_else(Vec::new, |frame| {
+            (0..=Reg::MAX)
+                .zip(&frame.registers)
+                .filter(|&(_, value)| *value != Value::Int(0))
+                .map(|(reg, &value)| (reg, value))
+                .collect()
+        });
+        Trap {
+            kind,
+            location,
+            instruction,
+            registers,
+        }
+    }
+
+    /// Execute instructions until the program exits or reaches a breakpoint. The instruction execution is
+    /// at is always executed, even if it has a breakpoint.
+    ///
+    /// # Errors
+    ///
+    /// Returns the trap if an instruction traps.
+    pub fn resume(&mut self) -> Result<Stop, Trap> {
+        loop {
+            let compiled = self.breakpoints.is_empty() && self.call_compiled();
+            if !compiled && !self.step()? {
+                return Ok(Stop::Exited);
+            }
+            let Some(location) = self.location() else {
+                continue;
+            };
+            let hit = self
+                .breakpoints
+                .iter()
+                .any(|breakpoint| breakpoint.matches(&location.label, location.index));
+            if hit {
+                return Ok(Stop::Breakpoint(location));
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> &mut Self {
+        self.breakpoints.push(breakpoint);
+        self
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// The instruction about to be executed, or `None` once the program has exited.
+    #[must_use]
+    pub fn location(&self) -> Option<Location> {
+        let (block, index) = self.position()?;
+        Some(Location {
+            label: self.blocks[block].1.name().to_string(),
+            index,
+        })
+    }
+
+    /// The registers of the current frame, or nothing once the program has exited.
+    #[must_use]
+    pub fn registers(&self) -> &[Value] {
+        self.frames
+            .last()
+            .map_or(&[], |frame| frame.registers.as_slice())
+    }
+
+    /// The elements of the array with the id in a [`Value::Array`]. Arrays the garbage collector collected
+    /// are empty.
+    #[must_use]
+    pub fn array(&self, id: usize) -> Option<&[Value]> {
+        self.arrays.get(id).map(Vec::as_slice)
+    }
+
+    /// The number of frames on the call stack, 1 while in `main`, or in a custom entry block.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Everything written with `putchar` so far.
+    #[must_use]
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// How many instructions have been executed.
+    #[must_use]
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// How many calls ran compiled, if [`Config::compile`] was set.
+    #[must_use]
+    pub fn compiled_calls(&self) -> u64 {
+        self.compiled_calls
+    }
+
+    /// What the garbage collector has done so far, if [`Config::gc`] was set.
+    #[must_use]
+    pub fn gc_stats(&self) -> Option<&GcStats> {
+        self.gc.as_ref().map(Gc::stats)
+    }
+
+    /// The block and line about to be executed, falling through to the next block of the function if the
+    /// current one has ended.
+    fn position(&self) -> Option<(usize, usize)> {
+        let frame = self.frames.last()?;
+        let (mut block, mut index) = (frame.block, frame.index);
+        while index >= self.blocks[block].1.lines().len()
+            && self
+                .blocks
+                .get(block + 1)
+                .is_some_and(|next| next.0 == self.blocks[block].0)
+        {
+            block += 1;
+            index = 0;
+        }
+        Some((block, index))
+    }
+
+    fn current(&mut self) -> Result<Option<&'a Instruction>, TrapKind> {
+        let Some((block, index)) = self.position() else {
+            return Ok(None);
+        };
+        let frame = self.top();
+        frame.block = block;
+        frame.index = index;
+        let (func, block) = self.blocks[block];
+        match block.lines().get(index) {
+            Some(Line::Instruction(instr)) => Ok(Some(instr)),
+            Some(Line::Raw(raw)) => Err(TrapKind::RawLine(raw.clone())),
+            None => Err(TrapKind::FellOffEnd(self.functions[func].to_string())),
+        }
+    }
+
+    fn top(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("no frame to execute in")
+    }
+
+    fn get(&mut self, reg: Reg) -> Value {
+        self.top().registers[usize::from(reg)]
+    }
+
+    fn set(&mut self, reg: Reg, value: Value) {
+        self.top().registers[usize::from(reg)] = value;
+    }
+
+    fn int(&mut self, reg: Reg) -> Result<Int, TrapKind> {
+        match self.get(reg) {
+            Value::Int(value) => Ok(value),
+            Value::Array(_) => Err(TrapKind::ExpectedInt),
+            #[cfg(feature = "float")]
+            Value::Float(_) => Err(TrapKind::UnexpectedFloat),
+        }
+    }
+
+    /// The number in `reg` as a float, converting integers.
+    #[cfg(feature = "float")]
+    #[allow(clippy::cast_precision_loss)]
+    fn float(&mut self, reg: Reg) -> Result<f64, TrapKind> {
+        match self.get(reg) {
+            Value::Int(value) => Ok(value as f64),
+            Value::Float(value) => Ok(value.0),
+            Value::Array(_) => Err(TrapKind::ExpectedInt),
+        }
+    }
+
+    fn array_in(&mut self, reg: Reg) -> Result<usize, TrapKind> {
+        match self.get(reg) {
+            Value::Array(array) => Ok(array),
+            Value::Int(_) => Err(TrapKind::ExpectedArray),
+            #[cfg(feature = "float")]
+            Value::Float(_) => Err(TrapKind::UnexpectedFloat),
+        }
+    }
+
+    fn element(&mut self, array: Reg, index: Reg) -> Result<&mut Value, TrapKind> {
+        let array = self.array_in(array)?;
+        let index = self.int(index)?;
+        let elements = &mut self.arrays[array];
+        let len = elements.len();
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| elements.get_mut(index))
+            .ok_or(TrapKind::IndexOutOfBounds { index, len })
+    }
+
+    /// Allocate an array of `len` zeros, returning its id.
+    fn allocate(&mut self, len: usize) -> Result<usize, TrapKind> {
+        let allocated = self.allocated.saturating_add(len);
+        if self
+            .config
+            .max_array_elements
+            .is_some_and(|max| allocated > max)
+        {
+            return Err(TrapKind::AllocationLimit);
+        }
+        self.allocated = allocated;
+        if let Some(gc) = &mut self.gc {
+            if gc.needs_collection(len) {
+                let roots = self.frames.iter().flat_map(|frame| &frame.registers);
+                gc.collect(roots, &mut self.arrays);
+            }
+            gc.allocated(len);
+        }
+        self.arrays.push(vec![Value::Int(0); len]);
+        Ok(self.arrays.len() - 1)
+    }
+
+    fn lookup(&self, label: &str) -> Result<usize, TrapKind> {
+        self.by_name
+            .get(label)
+            .copied()
+            .ok_or_else(|| TrapKind::UnknownLabel(label.to_string()))
+    }
+
+    fn address(&mut self, reg: Reg) -> Result<usize, TrapKind> {
+        let addr = self.int(reg)?;
+        usize::try_from(addr)
+            .ok()
+            .filter(|&id| id < self.blocks.len())
+            .ok_or(TrapKind::InvalidAddress(addr))
+    }
+
+    fn goto(&mut self, block: usize) {
+        let frame = self.top();
+        frame.block = block;
+        frame.index = 0;
+    }
+
+    fn new_frame(&self, block: usize, args: Vec<Value>, return_to: Option<Reg>) -> Frame {
+        let mut registers = vec![Value::Int(0); self.frame_size];
+        for (register, arg) in registers.iter_mut().skip(1).zip(args) {
+            *register = arg;
+        }
+        Frame {
+            registers,
+            block,
+            index: 0,
+            return_to,
+        }
+    }
+
+    fn call(&mut self, block: usize, args: Vec<Value>, return_to: Reg) -> Result<(), TrapKind> {
+        if self
+            .config
+            .max_call_depth
+            .is_some_and(|max| self.frames.len() >= max)
+        {
+            return Err(TrapKind::CallDepthLimit);
+        }
+        let frame = self.new_frame(block, args, Some(return_to));
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Go to the last label of the branch `instr` if `taken`, otherwise the one before it.
+    fn branch(&mut self, instr: &Instruction, taken: bool) -> Result<(), TrapKind> {
+        let targets = instr.operands.len();
+        let index = if taken {
+            targets - 1
+        } else {
+            targets.saturating_sub(2)
+        };
+        let target = instr
+            .operands
+            .get(index)
+            .and_then(Operand::as_label)
+            .ok_or_else(|| TrapKind::MalformedInstruction(instr.to_string()))?;
+        let target = self.lookup(target)?;
+        self.goto(target);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn execute(&mut self, instr: &'a Instruction) -> Result<(), TrapKind> {
+        let malformed = || TrapKind::MalformedInstruction(instr.to_string());
+        let reg = |i: usize| {
+            instr
+                .operands
+                .get(i)
+                .and_then(Operand::as_reg)
+                .ok_or_else(malformed)
+        };
+        let label = |i: usize| {
+            instr
+                .operands
+                .get(i)
+                .and_then(Operand::as_label)
+                .ok_or_else(malformed)
+        };
+        let dest = || instr.dest.ok_or_else(malformed);
+        let args = |machine: &mut Self, from: usize| -> Result<Vec<Value>, TrapKind> {
+            instr.operands[from.min(instr.operands.len())..]
+                .iter()
+                .map(|arg| Ok(machine.get(arg.as_reg().ok_or_else(malformed)?)))
+                .collect()
+        };
+
+        match instr.op {
+            OpCode::Exit => self.frames.clear(),
+            OpCode::Reg => {
+                let value = self.get(reg(0)?);
+                self.set(dest()?, value);
+            }
+            OpCode::Jump => {
+                let target = self.lookup(label(0)?)?;
+                self.goto(target);
+            }
+            OpCode::Call => {
+                let target = self.lookup(label(0)?)?;
+                let args = args(self, 1)?;
+                self.call(target, args, dest()?)?;
+            }
+            OpCode::Addr => {
+                let target = self.lookup(label(0)?)?;
+                self.set(dest()?, Value::Int(Int::try_from(target).unwrap()));
+            }
+            OpCode::DJump => {
+                let target = self.address(reg(0)?)?;
+                self.goto(target);
+            }
+            OpCode::DCall => {
+                let target = self.address(reg(0)?)?;
+                let args = args(self, 1)?;
+                self.call(target, args, dest()?)?;
+            }
+            OpCode::ExternCall => {
+                let function = match instr.operands.first() {
+                    Some(Operand::Extern(name)) => {
+                        self.config.externs.iter().find(|host| host.name == *name)
+                    }
+                    Some(&Operand::Int(index)) => usize::try_from(index)
+                        .ok()
+                        .and_then(|index| self.config.externs.get(index)),
+                    _ => return Err(malformed()),
+                }
+                .map(|host| Rc::clone(&host.function))
+                .ok_or_else(|| TrapKind::UnknownExtern(instr.operands[0].to_string()))?;
+                let args = args(self, 1)?
+                    .into_iter()
+                    .map(|arg| match arg {
+                        Value::Int(value) => Ok(value),
+                        Value::Array(_) => Err(TrapKind::ExpectedInt),
+                        #[cfg(feature = "float")]
+                        Value::Float(_) => Err(TrapKind::UnexpectedFloat),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.set(dest()?, Value::Int(function(&args)));
+            }
+            OpCode::Ret => {
+                let value = self.get(reg(0)?);
+                let callee = self.frames.pop().expect("no frame to return from");
+                if let (Some(return_to), false) = (callee.return_to, self.frames.is_empty()) {
+                    self.set(return_to, value);
+                }
+            }
+            OpCode::Int => {
+                let value = instr
+                    .operands
+                    .first()
+                    .and_then(Operand::as_int)
+                    .ok_or_else(malformed)?;
+                self.set(dest()?, Value::Int(value));
+            }
+            OpCode::Neg => {
+                let value = self.int(reg(0)?)?;
+                self.set(dest()?, Value::Int(value.wrapping_neg()));
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod => {
+                let (lhs, rhs) = (self.int(reg(0)?)?, self.int(reg(1)?)?);
+                let value = match instr.op {
+                    OpCode::Add => lhs.wrapping_add(rhs),
+                    OpCode::Sub => lhs.wrapping_sub(rhs),
+                    OpCode::Mul => lhs.wrapping_mul(rhs),
+                    _ if rhs == 0 => return Err(TrapKind::DivisionByZero),
+                    OpCode::Div => lhs.wrapping_div(rhs),
+                    _ => lhs.wrapping_rem(rhs),
+                };
+                self.set(dest()?, Value::Int(value));
+            }
+            OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                let (lhs, rhs) = (self.int(reg(0)?)?, self.int(reg(1)?)?);
+                self.set(dest()?, Value::Int(bitwise(instr.op, lhs, rhs)));
+            }
+            #[cfg(feature = "float")]
+            OpCode::FInt => {
+                let value = instr
+                    .operands
+                    .first()
+                    .and_then(Operand::as_float)
+                    .ok_or_else(malformed)?;
+                self.set(dest()?, Value::Float(Float(value)));
+            }
+            #[cfg(feature = "float")]
+            OpCode::FAdd | OpCode::FSub | OpCode::FMul | OpCode::FDiv => {
+                let (lhs, rhs) = (self.float(reg(0)?)?, self.float(reg(1)?)?);
+                let value = match instr.op {
+                    OpCode::FAdd => lhs + rhs,
+                    OpCode::FSub => lhs - rhs,
+                    OpCode::FMul => lhs * rhs,
+                    _ => lhs / rhs,
+                };
+                self.set(dest()?, Value::Float(Float(value)));
+            }
+            OpCode::Bb | OpCode::Beq | OpCode::Blt => {
+                let taken = match instr.op {
+                    OpCode::Bb => self.int(reg(0)?)? != 0,
+                    OpCode::Beq => self.get(reg(0)?) == self.get(reg(1)?),
+                    _ => self.int(reg(0)?)? < self.int(reg(1)?)?,
+                };
+                self.branch(instr, taken)?;
+            }
+            #[cfg(feature = "float")]
+            OpCode::FBeq | OpCode::FBlt => {
+                let (lhs, rhs) = (self.float(reg(0)?)?, self.float(reg(1)?)?);
+                #[allow(clippy::float_cmp)]
+                let taken = if instr.op == OpCode::FBeq {
+                    lhs == rhs
+                } else {
+                    lhs < rhs
+                };
+                self.branch(instr, taken)?;
+            }
+            OpCode::Str => {
+                let (Some(Operand::Str(text)), Some(dest)) = (instr.operands.first(), instr.dest)
+                else {
+                    return Err(malformed());
+                };
+                let array = self.allocate(text.len())?;
+                for (element, byte) in self.arrays[array].iter_mut().zip(text.bytes()) {
+                    *element = Value::Int(Int::from(byte));
+                }
+                self.set(dest, Value::Array(array));
+            }
+            OpCode::Arr => {
+                let (len, dest) = (self.int(reg(0)?)?, dest()?);
+                let len = usize::try_from(len).map_err(|_| TrapKind::NegativeArrayLength(len))?;
+                let array = self.allocate(len)?;
+                self.set(dest, Value::Array(array));
+            }
+            OpCode::Set => {
+                let value = self.get(reg(2)?);
+                *self.element(reg(0)?, reg(1)?)? = value;
+            }
+            OpCode::Get => {
+                let value = *self.element(reg(0)?, reg(1)?)?;
+                self.set(dest()?, value);
+            }
+            OpCode::Len => {
+                let array = self.array_in(reg(0)?)?;
+                let len = Int::try_from(self.arrays[array].len()).unwrap();
+                self.set(dest()?, Value::Int(len));
+            }
+            OpCode::Type => {
+                let kind = match self.get(reg(0)?) {
+                    Value::Int(_) => 0,
+                    Value::Array(_) => 1,
+                    #[cfg(feature = "float")]
+                    Value::Float(_) => 2,
+                };
+                self.set(dest()?, Value::Int(kind));
+            }
+            OpCode::PutChar => {
+                let ch = self.int(reg(0)?)?;
+                self.output.push(ch.to_le_bytes()[0]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compile every function that only computes with integers, by its first block.
+fn compile_functions(
+    blocks: &[(usize, &LabelImpl)],
+    by_name: &HashMap<&str, usize>,
+    frame_size: usize,
+) -> HashMap<usize, Function> {
+    let mut compiled = HashMap::new();
+    let mut start = 0;
+    while start < blocks.len() {
+        let func = blocks[start].0;
+        let end = start
+            + blocks[start..]
+                .iter()
+                .take_while(|(f, _)| *f == func)
+                .count();
+        let labels: Vec<&LabelImpl> = blocks[start..end].iter().map(|&(_, label)| label).collect();
+        let lookup = |name: &str| {
+            by_name
+                .get(name)
+                .filter(|&&id| (start..end).contains(&id))
+                .map(|&id| id - start)
+        };
+        if let Some(function) = Function::compile(&labels, lookup, frame_size) {
+            compiled.insert(start, function);
+        }
+        start = end;
+    }
+    compiled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::StackExt;
+    use crate::{AsmBuilder, BuildInstruction, BuilderExt};
+
+    #[test]
+    fn test_run() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(10, 0)
+                .label_call("fib", &[0], 0)
+                .label_call("putn", &[0], 0)
+                .stack_new(1)
+                .string("ok", 2)
+                .stack_push(1, 2)
+                .stack_pop(1, 3)
+                .integer(1, 4)
+                .get_array_index(3, 4, 4)
+                .put_char(4)
+                .char(b'\n', 4)
+                .put_char(4)
+                .exit()
+        });
+        builder.label("fib", |fib_builder| {
+            fib_builder
+                .integer(2, 0)
+                .branch_less_than(1, 0, "fib.then", "fib.else")
+                .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
+                .sub_label("else", |fib_else_builder| {
+                    fib_else_builder
+                        .integer(1, 0)
+                        .sub(1, 0, 1)
+                        .label_call("fib", &[1], 2)
+                        .sub(1, 0, 1)
+                        .label_call("fib", &[1], 3)
+                        .add(2, 3, 0)
+                        .return_(0)
+                })
+        });
+        builder.label("putn", |putn_builder| {
+            putn_builder
+                .branch_boolean(1, "putn.digit", "putn.ret")
+                .sub_label("digit", |putn_digit_builder| {
+                    putn_digit_builder
+                        .integer(10, 0)
+                        .div(1, 0, 2)
+                        .label_call("putn", &[2], 2)
+                        .mod_(1, 0, 1)
+                        .integer(48, 0)
+                        .add(1, 0, 1)
+                        .put_char(1)
+                })
+                .sub_label("ret", |putn_ret_builder| putn_ret_builder.return_(0))
+        });
+
+        let result = run(&builder.finish(), &Config::default());
+        assert_eq!(result.result, Ok(()));
+        assert_eq!(result.output_string(), "55k\n");
+    }
+
+    #[test]
+    fn test_run_bitwise() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(96, 1)
+                .integer(1, 2)
+                .bit_or(1, 2, 3)
+                .put_char(3)
+                .integer(99, 1)
+                .integer(0x7e, 4)
+                .bit_and(1, 4, 3)
+                .put_char(3)
+                .integer(98, 1)
+                .bit_xor(1, 2, 3)
+                .put_char(3)
+                .integer(25, 1)
+                .integer(66, 4)
+                .shift_left(1, 4, 3)
+                .put_char(3)
+                .integer(-404, 1)
+                .integer(2, 4)
+                .shift_right(1, 4, 3)
+                .neg(3, 3)
+                .put_char(3)
+                .exit()
+        });
+        let result = run(&builder.finish(), &Config::default());
+        assert_eq!(result.output_string(), "abcde");
+    }
+
+    #[test]
+    fn test_machine_breakpoints() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(3, 1)
+                .array(1, 2)
+                .integer(7, 3)
+                .label_call("fill", &[2, 3], 0)
+                .exit()
+        });
+        builder.label("fill", |fill_builder| {
+            fill_builder
+                .array_length(1, 3)
+                .sub_label("loop", |fill_loop_builder| {
+                    fill_loop_builder
+                        .integer(1, 0)
+                        .sub(3, 0, 3)
+                        .set_array_index(1, 3, 2)
+                        .branch_boolean(3, "fill.loop", "fill.done")
+                })
+                .sub_label("done", |fill_done_builder| fill_done_builder.return_(1))
+        });
+        let asm = builder.finish();
+        let mut machine = Machine::new(&asm);
+
+        assert_eq!(machine.step(), Ok(true));
+        assert_eq!(machine.registers()[1], Value::Int(3));
+
+        machine
+            .add_breakpoint(Breakpoint::Label("fill.loop".to_string()))
+            .add_breakpoint(Breakpoint::Instruction {
+                label: "main".to_string(),
+                index: 4,
+            });
+        let at = |label: &str, index| {
+            Ok(Stop::Breakpoint(Location {
+                label: label.to_string(),
+                index,
+            }))
+        };
+        assert_eq!(machine.resume(), at("fill.loop", 0));
+        assert_eq!(machine.depth(), 2);
+        assert_eq!(machine.registers()[3], Value::Int(3));
+        assert_eq!(machine.resume(), at("fill.loop", 0));
+        assert_eq!(machine.resume(), at("fill.loop", 0));
+
+        let Value::Array(array) = machine.registers()[1] else {
+            panic!("expected an array in r1");
+        };
+        assert_eq!(
+            machine.array(array),
+            Some(&[Value::Int(0), Value::Int(7), Value::Int(7)][..])
+        );
+
+        assert_eq!(machine.resume(), at("main", 4));
+        assert_eq!(machine.location().unwrap().to_string(), "main+4");
+        assert_eq!(machine.resume(), Ok(Stop::Exited));
+        assert_eq!(machine.location(), None);
+    }
+
+    #[cfg(feature = "float")]
+    #[test]
+    fn test_run_float() {
+        let text = r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- fint 1.5
+    r2 <- int 2
+    r3 <- fmul r1 r2
+    r4 <- fint 3.0
+    fbeq r3 r4 main.done main.equal
+@main.equal
+    r5 <- type r3
+    r6 <- int 63
+    r6 <- add r5 r6
+    putchar r6
+    r7 <- fint -0.5
+    fblt r7 r2 main.done main.less
+@main.less
+    r0 <- int 33
+    putchar r0
+    putchar r3
+@main.done
+    exit
+end";
+        let asm = crate::parse::parse(text).unwrap();
+        assert_eq!(asm.finish(), text);
+
+        let result = run(&crate::parse::parse(text).unwrap(), &Config::default());
+        assert_eq!(result.output_string(), "A!");
+        assert_eq!(
+            result.result.unwrap_err().to_string(),
+            "expected an integer or array, found a float at main.less+2
+    in `putchar r3`
+    with r0 = 33, r1 = 1.5, r2 = 2, r3 = 3.0, r4 = 3.0, r5 = 2, r6 = 65, r7 = -0.5"
+        );
+    }
+
+    #[test]
+    fn test_run_extern() {
+        let text = r"func main
+    r1 <- int 20
+    r2 <- int 3
+    r3 <- xcall max r1 r2
+    putchar r3
+    r3 <- xcall 1
+    putchar r3
+    r3 <- xcall min r1 r2
+    exit
+end";
+        let mut asm = crate::parse::parse(text).unwrap();
+        assert_eq!(asm.main().to_string(), text);
+
+        let mut config = Config::default();
+        config
+            .register_extern("max", |args| args.iter().copied().max().unwrap_or(0) + 45)
+            .register_extern("bang", |_| 33);
+        let result = run(&asm, &config);
+        assert_eq!(result.output_string(), "A!");
+        assert_eq!(
+            result.result.unwrap_err().to_string(),
+            "unknown extern function `min` at main+6
+    in `r3 <- xcall min r1 r2`
+    with r1 = 20, r2 = 3, r3 = 33"
+        );
+    }
+
+    #[test]
+    fn test_run_traps() {
+        let program = |f: fn(&mut crate::builder::LabelBuilder), config: Config| {
+            let mut builder = AsmBuilder::new();
+            builder.main(|main_builder| {
+                f(main_builder);
+                main_builder.label_call("f", &[], 0).exit()
+            });
+            builder.label("f", |f_builder| {
+                f_builder
+                    .integer(0, 1)
+                    .sub_label("end", |end_builder| end_builder)
+            });
+            run(&builder.finish(), &config)
+        };
+        // Only the kind and location, without the instruction and registers.
+        let trap = |result: RunResult| {
+            result
+                .result
+                .unwrap_err()
+                .to_string()
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        let result = program(
+            |builder| {
+                builder.char(b'a', 1).put_char(1).integer(0, 2).div(1, 2, 0);
+            },
+            Config::default(),
+        );
+        assert_eq!(result.output, b"a");
+        assert_eq!(result.steps, 3);
+        assert_eq!(
+            result.result.unwrap_err().to_string(),
+            "division by zero at main+3
+    in `r0 <- div r1 r2`
+    with r1 = 97"
+        );
+
+        let result = program(
+            |builder| {
+                builder
+                    .integer(2, 1)
+                    .array(1, 1)
+                    .integer(2, 2)
+                    .get_array_index(1, 2, 0);
+            },
+            Config::default(),
+        );
+        assert_eq!(
+            result.result.unwrap_err().to_string(),
+            "index 2 out of bounds of array of length 2 at main+3
+    in `r0 <- get r1 r2`
+    with r1 = array #0, r2 = 2"
+        );
+
+        let result = program(|_| {}, Config::default());
+        assert_eq!(trap(result), "fell off the end of `f` at f.end+0");
+
+        let result = program(
+            |builder| {
+                builder.label_jump("main");
+            },
+            Config {
+                max_steps: Some(100),
+                ..Config::default()
+            },
+        );
+        assert_eq!(result.steps, 100);
+        assert_eq!(trap(result), "step limit reached at main+0");
+
+        let result = program(
+            |builder| {
+                builder.string("abc", 1).integer(2, 2).array(2, 2);
+            },
+            Config {
+                max_array_elements: Some(4),
+                ..Config::default()
+            },
+        );
+        assert_eq!(trap(result), "allocation limit reached at main+2");
+
+        let result = program(
+            |builder| {
+                builder.label_call("main", &[], 0);
+            },
+            Config {
+                max_call_depth: Some(10),
+                ..Config::default()
+            },
+        );
+        assert_eq!(trap(result), "call depth limit reached at main+0");
+    }
+}
@@ -0,0 +1,278 @@
+//! Compiling functions that only compute with integers into Rust closures, so calls to small helpers that
+//! run over and over skip decoding their instructions. Enabled with
+//! [`Config::compile`](super::Config::compile).
+//!
+//! A function is compiled if every block only moves, negates and does arithmetic on integers, branches or
+//! jumps to its own blocks, and ends with `ret` or a jump, so it can't have side effects. A call that would
+//! trap or go over the step limit gives up, and the interpreter runs it again from the start to trap
+//! in the same place.
+
+use crate::{
+    asm::{LabelImpl, Line},
+    instr::{bitwise, Instruction, OpCode, Operand},
+    Int,
+};
+
+/// An instruction of a block, which fails where the interpreter would trap.
+type Op = Box<dyn Fn(&mut [Int]) -> Option<()>>;
+
+/// The branch, jump or `ret` ending a block.
+type Exit = Box<dyn Fn(&[Int]) -> Next>;
+
+enum Next {
+    Block(usize),
+    Return(Int),
+}
+
+struct Block {
+    ops: Vec<Op>,
+    exit: Exit,
+    /// The instructions executed by running the block, its exit included.
+    steps: u64,
+}
+
+pub(super) struct Function {
+    blocks: Vec<Block>,
+    frame_size: usize,
+}
+
+impl Function {
+    /// Compile the function with the blocks `labels`, where `lookup` gives the index among them of a label,
+    /// if it has one. Gives `None` if the function does anything else than compute with integers.
+    pub(super) fn compile(
+        labels: &[&LabelImpl],
+        lookup: impl Fn(&str) -> Option<usize>,
+        frame_size: usize,
+    ) -> Option<Function> {
+        let mut blocks = Vec::with_capacity(labels.len());
+        for (index, label) in labels.iter().enumerate() {
+            let mut ops = Vec::new();
+            let mut exit = None;
+            for line in label.lines() {
+                let Line::Instruction(instr) = line else {
+                    return None;
+                };
+                if instr.op.is_terminator() {
+                    exit = Some(terminator(instr, &lookup)?);
+                    break;
+                }
+                ops.push(op(instr)?);
+            }
+            let steps = u64::try_from(ops.len() + usize::from(exit.is_some())).unwrap();
+            let exit = match exit {
+                Some(exit) => exit,
+                // Falling off the end of the last block traps.
+                None if index + 1 == labels.len() => return None,
+                None => Box::new(move |_: &[Int]| Next::Block(index + 1)),
+            };
+            blocks.push(Block { ops, exit, steps });
+        }
+        Some(Function { blocks, frame_size })
+    }
+
+    /// Call the function with `args`, giving the return value and the number of instructions executed, or
+    /// `None` if it would trap or execute more than `max_steps`.
+    pub(super) fn call(&self, args: &[Int], max_steps: u64) -> Option<(Int, u64)> {
+        let mut registers = vec![0; self.frame_size];
+        for (register, &arg) in registers.iter_mut().skip(1).zip(args) {
+            *register = arg;
+        }
+        let (mut block, mut steps) = (0, 0u64);
+        loop {
+            let Block {
+                ops,
+                exit,
+                steps: len,
+            } = &self.blocks[block];
+            steps = steps
+                .checked_add(*len)
+                .filter(|&steps| steps <= max_steps)?;
+            for op in ops {
+                op(&mut registers)?;
+            }
+            match exit(&registers) {
+                Next::Block(next) => block = next,
+                Next::Return(value) => return Some((value, steps)),
+            }
+        }
+    }
+}
+
+fn reg(instr: &Instruction, i: usize) -> Option<usize> {
+    instr
+        .operands
+        .get(i)
+        .and_then(Operand::as_reg)
+        .map(usize::from)
+}
+
+fn op(instr: &Instruction) -> Option<Op> {
+    let dest = usize::from(instr.dest?);
+    let op: Op = match instr.op {
+        OpCode::Int => {
+            let value = instr.operands.first().and_then(Operand::as_int)?;
+            Box::new(move |r| {
+                r[dest] = value;
+                Some(())
+            })
+        }
+        OpCode::Reg => {
+            let src = reg(instr, 0)?;
+            Box::new(move |r| {
+                r[dest] = r[src];
+                Some(())
+            })
+        }
+        OpCode::Neg => {
+            let src = reg(instr, 0)?;
+            Box::new(move |r| {
+                r[dest] = r[src].wrapping_neg();
+                Some(())
+            })
+        }
+        OpCode::Add => binary(instr, dest, |lhs, rhs| Some(lhs.wrapping_add(rhs)))?,
+        OpCode::Sub => binary(instr, dest, |lhs, rhs| Some(lhs.wrapping_sub(rhs)))?,
+        OpCode::Mul => binary(instr, dest, |lhs, rhs| Some(lhs.wrapping_mul(rhs)))?,
+        OpCode::Div => binary(instr, dest, |lhs, rhs| {
+            (rhs != 0).then(|| lhs.wrapping_div(rhs))
+        })?,
+        OpCode::Mod => binary(instr, dest, |lhs, rhs| {
+            (rhs != 0).then(|| lhs.wrapping_rem(rhs))
+        })?,
+        OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+            let op = instr.op;
+            binary(instr, dest, move |lhs, rhs| Some(bitwise(op, lhs, rhs)))?
+        }
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn binary(
+    instr: &Instruction,
+    dest: usize,
+    f: impl Fn(Int, Int) -> Option<Int> + 'static,
+) -> Option<Op> {
+    let (lhs, rhs) = (reg(instr, 0)?, reg(instr, 1)?);
+    Some(Box::new(move |r| {
+        r[dest] = f(r[lhs], r[rhs])?;
+        Some(())
+    }))
+}
+
+fn terminator(instr: &Instruction, lookup: impl Fn(&str) -> Option<usize>) -> Option<Exit> {
+    let label = |i: usize| lookup(instr.operands.get(i)?.as_label()?);
+    let exit: Exit = match instr.op {
+        OpCode::Ret => {
+            let src = reg(instr, 0)?;
+            Box::new(move |r| Next::Return(r[src]))
+        }
+        OpCode::Jump => {
+            let target = label(0)?;
+            Box::new(move |_| Next::Block(target))
+        }
+        OpCode::Bb | OpCode::Beq | OpCode::Blt => {
+            // As in the interpreter, the last label is the one taken and the one before it the other.
+            let targets = instr.operands.len();
+            let (other, taken) = (label(targets.checked_sub(2)?)?, label(targets - 1)?);
+            let lhs = reg(instr, 0)?;
+            let next = move |taken_if: bool| Next::Block(if taken_if { taken } else { other });
+            match instr.op {
+                OpCode::Bb => Box::new(move |r| next(r[lhs] != 0)),
+                OpCode::Beq => {
+                    let rhs = reg(instr, 1)?;
+                    Box::new(move |r| next(r[lhs] == r[rhs]))
+                }
+                _ => {
+                    let rhs = reg(instr, 1)?;
+                    Box::new(move |r| next(r[lhs] < r[rhs]))
+                }
+            }
+        }
+        _ => return None,
+    };
+    Some(exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interp::{run, Config, Machine};
+    use crate::randomize::SplitMix64;
+    use crate::testing::{arbitrary_program, ProgramConfig};
+
+    const PROGRAM: &str = r"func main
+    r1 <- int 20
+    r1 <- call sum r1
+    r2 <- int 10
+    r3 <- int 0
+    r4 <- call div r1 r2
+    r4 <- call div r1 r3
+    exit
+end
+
+func sum
+    r2 <- int 0
+    r3 <- int 1
+@sum.loop
+    bb r1 sum.done sum.step
+@sum.step
+    r2 <- add r2 r1
+    r1 <- sub r1 r3
+    jump sum.loop
+@sum.done
+    ret r2
+end
+
+func div
+    r0 <- div r1 r2
+    ret r0
+end";
+
+    #[test]
+    fn test_compile() {
+        let asm = crate::parse::parse(PROGRAM).unwrap();
+        let interpreted = run(&asm, &Config::default());
+        let config = Config {
+            compile: true,
+            ..Config::default()
+        };
+        assert_eq!(run(&asm, &config), interpreted);
+        assert_eq!(
+            interpreted.result.unwrap_err().to_string(),
+            "division by zero at div+0
+    in `r0 <- div r1 r2`
+    with r1 = 210"
+        );
+
+        // Only the call that doesn't trap runs compiled.
+        let mut machine = Machine::with_config(&asm, config.clone());
+        assert!(machine.resume().is_err());
+        assert_eq!(machine.compiled_calls(), 2);
+
+        // Step limits are hit at the same instruction either way.
+        for max_steps in 0..interpreted.steps {
+            let limit = |compile| Config {
+                max_steps: Some(max_steps),
+                compile,
+                ..Config::default()
+            };
+            assert_eq!(run(&asm, &limit(true)), run(&asm, &limit(false)));
+        }
+    }
+
+    #[test]
+    fn test_compile_arbitrary_programs() {
+        let config = Config {
+            compile: true,
+            ..Config::default()
+        };
+        for seed in 0..50 {
+            let asm = arbitrary_program(&mut SplitMix64::new(seed), &ProgramConfig::default());
+            assert_eq!(
+                run(&asm, &config),
+                run(&asm, &Config::default()),
+                "seed {seed}:\n{asm}"
+            );
+        }
+    }
+}
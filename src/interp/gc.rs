@@ -0,0 +1,245 @@
+//! A model of MiniVM's garbage collector, to see how much garbage generated code makes without a MiniVM
+//! build. Enabled with [`Config::gc`](super::Config::gc).
+
+use super::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// When collections happen, and whether to model where arrays are placed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcConfig {
+    /// How many elements may be allocated after a collection before the next one, which happens right before
+    /// the allocation that would go over it.
+    pub threshold: usize,
+    /// Place arrays in a heap, in the first gap left by collected arrays that they fit in, to measure how
+    /// fragmented it gets.
+    pub model_fragmentation: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1 << 16,
+            model_fragmentation: false,
+        }
+    }
+}
+
+/// What the collector did over a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Arrays allocated, strings included.
+    pub arrays_allocated: usize,
+    pub elements_allocated: usize,
+    pub collections: usize,
+    /// Elements of the arrays that were collected.
+    pub elements_freed: usize,
+    /// The most elements allocated and not yet collected at once.
+    pub peak_elements: usize,
+    /// How large the heap grew, if fragmentation is modelled. It is larger than `peak_elements` when the gaps
+    /// left by collected arrays were too small to reuse.
+    pub heap_size: Option<usize>,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} arrays of {} elements allocated, {} collections freed {} elements, at most {} elements in use",
+            self.arrays_allocated,
+            self.elements_allocated,
+            self.collections,
+            self.elements_freed,
+            self.peak_elements
+        )?;
+        if let Some(heap_size) = self.heap_size {
+            write!(f, ", heap grew to {heap_size} elements")?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the collector during a run.
+pub(super) struct Gc {
+    config: GcConfig,
+    stats: GcStats,
+    since_collection: usize,
+    in_use: usize,
+    collected: Vec<bool>,
+    heap: Option<Heap>,
+}
+
+impl Gc {
+    pub(super) fn new(config: GcConfig) -> Gc {
+        let heap = config.model_fragmentation.then(Heap::default);
+        Self {
+            stats: GcStats {
+                heap_size: heap.as_ref().map(|_| 0),
+                ..GcStats::default()
+            },
+            config,
+            since_collection: 0,
+            in_use: 0,
+            collected: Vec::new(),
+            heap,
+        }
+    }
+
+    pub(super) fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    /// Whether allocating `len` elements has to collect first.
+    pub(super) fn needs_collection(&self, len: usize) -> bool {
+        self.since_collection.saturating_add(len) > self.config.threshold
+    }
+
+    /// Record the allocation of the next array, of `len` elements.
+    pub(super) fn allocated(&mut self, len: usize) {
+        self.stats.arrays_allocated += 1;
+        self.stats.elements_allocated += len;
+        self.since_collection += len;
+        self.in_use += len;
+        self.stats.peak_elements = self.stats.peak_elements.max(self.in_use);
+        self.collected.push(false);
+        if let Some(heap) = &mut self.heap {
+            heap.place(len);
+            self.stats.heap_size = self.stats.heap_size.max(Some(heap.end));
+        }
+    }
+
+    /// Collect every array that can't be reached from `roots`, leaving it empty in `arrays`.
+    pub(super) fn collect<'v>(
+        &mut self,
+        roots: impl Iterator<Item = &'v Value>,
+        arrays: &mut [Vec<Value>],
+    ) {
+        let mut reached = vec![false; arrays.len()];
+        let mut pending: Vec<usize> = roots.filter_map(array_of).collect();
+        while let Some(id) = pending.pop() {
+            if !std::mem::replace(&mut reached[id], true) {
+                pending.extend(arrays[id].iter().filter_map(array_of));
+            }
+        }
+        for (id, array) in arrays.iter_mut().enumerate() {
+            if reached[id] || self.collected[id] {
+                continue;
+            }
+            self.collected[id] = true;
+            self.stats.elements_freed += array.len();
+            self.in_use -= array.len();
+            if let Some(heap) = &mut self.heap {
+                heap.free(id);
+            }
+            *array = Vec::new();
+        }
+        self.stats.collections += 1;
+        self.since_collection = 0;
+    }
+}
+
+fn array_of(value: &Value) -> Option<usize> {
+    if let Value::Array(id) = value {
+        Some(*id)
+    } else {
+        None
+    }
+}
+
+/// Where every array was placed, as its start and length, and the gaps between them.
+#[derive(Default)]
+struct Heap {
+    end: usize,
+    gaps: BTreeMap<usize, usize>,
+    placed: Vec<(usize, usize)>,
+}
+
+impl Heap {
+    fn place(&mut self, len: usize) {
+        let gap = self
+            .gaps
+            .iter()
+            .find(|&(_, &size)| size >= len && len > 0)
+            .map(|(&start, &size)| (start, size));
+        let start = if let Some((start, size)) = gap {
+            self.gaps.remove(&start);
+            if size > len {
+                self.gaps.insert(start + len, size - len);
+            }
+            start
+        } else {
+            self.end += len;
+            self.end - len
+        };
+        self.placed.push((start, len));
+    }
+
+    fn free(&mut self, id: usize) {
+        let (mut start, mut len) = self.placed[id];
+        if len == 0 {
+            return;
+        }
+        if let Some(next) = self.gaps.remove(&(start + len)) {
+            len += next;
+        }
+        if let Some((&before, &size)) = self.gaps.range(..start).next_back() {
+            if before + size == start {
+                self.gaps.remove(&before);
+                (start, len) = (before, len + size);
+            }
+        }
+        if start + len == self.end {
+            self.end = start;
+        } else {
+            self.gaps.insert(start, len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{run, Config};
+
+    #[test]
+    fn test_gc() {
+        // Allocates arrays of 1 to 9 elements, only keeping the last one and an array of 4 made first.
+        let asm = crate::parse::parse(
+            r"func main
+    r1 <- int 1
+    r2 <- int 10
+    r3 <- int 1
+    r5 <- int 4
+    r6 <- arr r5
+@main.loop
+    r4 <- arr r1
+    r1 <- add r1 r3
+    blt r1 r2 main.done main.loop
+@main.done
+    exit
+end",
+        )
+        .unwrap();
+        let gc = |model_fragmentation| {
+            let config = Config {
+                gc: Some(GcConfig {
+                    threshold: 12,
+                    model_fragmentation,
+                }),
+                ..Config::default()
+            };
+            run(&asm, &config).gc.unwrap().to_string()
+        };
+
+        assert_eq!(
+            gc(false),
+            "10 arrays of 49 elements allocated, 5 collections freed 28 elements, at most 21 elements in use"
+        );
+        assert_eq!(
+            gc(true),
+            "10 arrays of 49 elements allocated, 5 collections freed 28 elements, at most 21 elements in use, \
+             heap grew to 25 elements"
+        );
+        assert!(run(&asm, &Config::default()).gc.is_none());
+    }
+}
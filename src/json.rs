@@ -0,0 +1,169 @@
+//! A JSON description of the structure of a program, for tools not written in Rust.
+//!
+//! The schema is stable: fields may be added, but not renamed or removed. A program is an object with
+//!
+//! - `"library"`: the name of the library, or `null` for a program;
+//! - `"entry"`: the lines of the entry block, or `null` for a library;
+//! - `"functions"`: the functions, in the order they are emitted.
+//!
+//! A function has a `"name"`, a `"visibility"` of `"public"` or `"private"`, and `"blocks"`: its body
+//! followed by its sub-labels. A block has the full `"name"` it is jumped to by, like `"fib.loop"`, and its
+//! `"lines"`.
+//!
+//! A line is either `{"raw": text}` for text emitted as-is, or an instruction: an object with the
+//! `"op"` mnemonic, the `"dest"` register index or `null`, and the `"operands"`. Each operand is an object
+//! with a single field, saying what kind of operand it is:
+//!
+//! - `{"reg": 1}` for a register;
+//! - `{"int": -5}` for an integer;
+//! - `{"label": "fib.loop"}` for a label;
+//! - `{"str": "hello"}` for the text of `str`;
+//! - `{"extern": "name"}` for the host function of `xcall`;
+//! - `{"float": 1.5}` for the value of `fint`, or a string like `"NaN"` or `"inf"` if it isn't finite;
+//! - `{"const": "name"}` for a constant that hasn't been substituted.
+
+use crate::asm::{Asm, Label, LabelImpl, Line, Visibility};
+use crate::instr::{Instruction, Operand};
+use std::fmt::Write;
+
+/// Describe `asm` as JSON, on a single line.
+#[must_use]
+pub fn to_json(asm: &Asm) -> String {
+    let mut out = String::new();
+    out.push_str("{\"library\":");
+    match asm.library_id() {
+        Some(id) => write_str(&mut out, id),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"entry\":");
+    if asm.is_library() {
+        out.push_str("null");
+    } else {
+        write_lines(&mut out, asm.entry());
+    }
+    out.push_str(",\"functions\":[");
+    for (i, function) in asm.emitted().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_function(&mut out, function);
+    }
+    out.push_str("]}");
+    out
+}
+
+fn write_function(out: &mut String, function: &Label) {
+    out.push_str("{\"name\":");
+    write_str(out, function.name());
+    let visibility = match function.visibility() {
+        Visibility::Public => "public",
+        Visibility::Private => "private",
+    };
+    write!(out, ",\"visibility\":\"{visibility}\",\"blocks\":[").unwrap();
+    for (i, block) in function.blocks().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        write_str(out, block.name());
+        out.push_str(",\"lines\":");
+        write_lines(out, block);
+        out.push('}');
+    }
+    out.push_str("]}");
+}
+
+fn write_lines(out: &mut String, block: &LabelImpl) {
+    out.push('[');
+    for (i, line) in block.lines().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match line {
+            Line::Instruction(instr) => write_instruction(out, instr),
+            Line::Raw(raw) => {
+                out.push_str("{\"raw\":");
+                write_str(out, raw);
+                out.push('}');
+            }
+        }
+    }
+    out.push(']');
+}
+
+fn write_instruction(out: &mut String, instr: &Instruction) {
+    write!(out, "{{\"op\":\"{}\",\"dest\":", instr.op).unwrap();
+    match instr.dest {
+        Some(dest) => write!(out, "{dest}").unwrap(),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"operands\":[");
+    for (i, operand) in instr.operands.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match operand {
+            Operand::Reg(reg) => write!(out, "{{\"reg\":{reg}}}").unwrap(),
+            Operand::Int(value) => write!(out, "{{\"int\":{value}}}").unwrap(),
+            Operand::Label(label) => write_field(out, "label", label.as_str()),
+            Operand::Str(text) => write_field(out, "str", text),
+            Operand::Extern(name) => write_field(out, "extern", name),
+            #[cfg(feature = "float")]
+            Operand::Float(value) if value.0.is_finite() => {
+                write!(out, "{{\"float\":{:?}}}", value.0).unwrap();
+            }
+            #[cfg(feature = "float")]
+            Operand::Float(value) => write_field(out, "float", &value.to_string()),
+            Operand::Const(name) => write_field(out, "const", name),
+        }
+    }
+    out.push_str("]}");
+}
+
+/// Write an object with the single field `name`, holding the string `value`.
+fn write_field(out: &mut String, name: &str, value: &str) {
+    write!(out, "{{\"{name}\":").unwrap();
+    write_str(out, value);
+    out.push('}');
+}
+
+fn write_str(out: &mut String, text: &str) {
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if ch.is_control() => write!(out, "\\u{:04x}", u32::from(ch)).unwrap(),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_to_json() {
+        let mut builder = AsmBuilder::new();
+        builder
+            .label("greet", |greet_builder| {
+                greet_builder
+                    .private()
+                    .string("say \"hi\"", 1)
+                    .branch_boolean(1, "greet.done", "greet.done")
+                    .sub_label("done", |done_builder| done_builder.return_(1))
+            })
+            .main(|main_builder| main_builder.label_call("greet", &[], 0).exit());
+        let mut asm = builder.finish();
+        asm.main().push_line("; done");
+        assert_eq!(
+            asm.to_json(),
+            r#"{"library":null,"entry":[{"op":"call","dest":0,"operands":[{"label":"main"}]},{"op":"exit","dest":null,"operands":[]}],"functions":[{"name":"greet","visibility":"private","blocks":[{"name":"greet","lines":[{"op":"str","dest":1,"operands":[{"str":"say \"hi\""}]},{"op":"bb","dest":null,"operands":[{"reg":1},{"label":"greet.done"},{"label":"greet.done"}]}]},{"name":"greet.done","lines":[{"op":"ret","dest":null,"operands":[{"reg":1}]}]}]},{"name":"main","visibility":"public","blocks":[{"name":"main","lines":[{"op":"call","dest":0,"operands":[{"label":"greet"}]},{"op":"exit","dest":null,"operands":[]},{"raw":"; done"}]}]}]}"#
+        );
+    }
+}
@@ -1,11 +1,47 @@
 #![warn(clippy::pedantic)]
 
+pub mod analysis;
 pub mod asm;
+pub mod backend;
+#[cfg(feature = "host")]
+pub mod build_support;
 pub mod builder;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "host")]
+pub mod corpus;
+pub mod emit;
 mod ext;
+#[cfg(feature = "host")]
+pub mod harness;
+pub mod instr;
+pub mod instrument;
+#[cfg(feature = "interp")]
+pub mod interp;
+pub mod json;
+pub mod link;
+pub mod lint;
+mod macros;
+pub mod opt;
+pub mod parse;
+pub mod randomize;
+pub mod records;
+pub mod regalloc;
+pub mod runtime;
+pub mod stats;
+pub mod target;
+pub mod template;
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-pub use builder::{AsmBuilder, BuildInstruction};
-pub use ext::BuilderExt;
+pub use builder::{AsmBuilder, BuildError, BuildInstruction, DataRef, FnSig};
+pub use ext::{BuilderExt, RuntimeBuilderExt};
+#[doc(hidden)]
+pub use macros::register as __register;
 
 pub type ArrayLen = u32;
 pub type ArrayIndex = ArrayLen;
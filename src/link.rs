@@ -0,0 +1,332 @@
+//! Merging separately generated modules into one program.
+
+use crate::asm::{Asm, Label, LabelImpl, Line, Visibility};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkError {
+    /// Two modules define a label or sub-label with the same name. Holds the indices of the modules.
+    Collision {
+        name: String,
+        modules: (usize, usize),
+    },
+    /// Every module is a library, so there is no entry point.
+    NoEntryPoint,
+    /// More than one module is a program with its own entry point. Holds the indices of the first two.
+    MultipleEntryPoints(usize, usize),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Collision {
+                name,
+                modules: (first, second),
+            } => write!(f, "`{name}` is defined by modules {first} and {second}"),
+            LinkError::NoEntryPoint => f.write_str("no module has an entry point"),
+            LinkError::MultipleEntryPoints(first, second) => {
+                write!(f, "modules {first} and {second} both have an entry point")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Merge `modules` into one program, keeping the labels of each in order.
+///
+/// Exactly one module must be a program; the others must be [libraries](Asm::new_library). Its entry block
+/// and `main` become those of the result. A [private](Visibility::Private) function that would collide with
+/// a function of another module is renamed after the index of its module, as in `helper_2`.
+///
+/// # Errors
+///
+/// Returns an error if two modules define the same label, or there isn't exactly one program.
+pub fn link(modules: Vec<Asm>) -> Result<Asm, LinkError> {
+    link_prefixed(modules.into_iter().map(|asm| (asm, None)).collect())
+}
+
+/// Like [`link`], first prefixing the labels of each module with the prefix it is paired with, as by
+/// [`Asm::prefix_labels`].
+///
+/// # Errors
+///
+/// Returns an error if two modules define the same label after prefixing, or there isn't exactly one
+/// program.
+pub fn link_prefixed(modules: Vec<(Asm, Option<&str>)>) -> Result<Asm, LinkError> {
+    let mut program = None;
+    for (index, (asm, _)) in modules.iter().enumerate() {
+        if !asm.is_library() {
+            if let Some(first) = program {
+                return Err(LinkError::MultipleEntryPoints(first, index));
+            }
+            program = Some(index);
+        }
+    }
+    let program = program.ok_or(LinkError::NoEntryPoint)?;
+    let mut modules: Vec<Asm> = modules
+        .into_iter()
+        .map(|(mut asm, prefix)| {
+            if let Some(prefix) = prefix {
+                asm.prefix_labels(prefix);
+            }
+            asm
+        })
+        .collect();
+
+    // Public functions keep their names, so they are claimed first.
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    for (index, asm) in modules.iter().enumerate() {
+        let public = asm
+            .iter()
+            .filter(|label| label.visibility() == Visibility::Public);
+        for block in public.flat_map(Label::blocks) {
+            define(&mut defined, block.name(), index)?;
+        }
+    }
+    for (index, asm) in modules.iter_mut().enumerate() {
+        rename_private(asm, index, &mut defined)?;
+    }
+
+    let mut linked = Asm::new();
+    for (index, mut asm) in modules.into_iter().enumerate() {
+        if index == program {
+            *linked.entry_mut().lines_mut() = asm.entry().lines().to_vec();
+            *linked.main() = std::mem::replace(asm.main(), Label::new("main"));
+        }
+        linked.labels_mut().append(asm.labels_mut());
+        linked
+            .call_sites_mut()
+            .extend(std::mem::take(asm.call_sites_mut()));
+        linked.spans_mut().extend(std::mem::take(asm.spans_mut()));
+    }
+    Ok(linked)
+}
+
+fn define(defined: &mut HashMap<String, usize>, name: &str, index: usize) -> Result<(), LinkError> {
+    if let Some(&first) = defined.get(name) {
+        return Err(LinkError::Collision {
+            name: name.to_string(),
+            modules: (first, index),
+        });
+    }
+    defined.insert(name.to_string(), index);
+    Ok(())
+}
+
+/// Rename the private functions of module `index` that collide with a function already `defined`, and
+/// define them.
+fn rename_private(
+    asm: &mut Asm,
+    index: usize,
+    defined: &mut HashMap<String, usize>,
+) -> Result<(), LinkError> {
+    let mut renamed = HashMap::new();
+    let private = asm
+        .labels()
+        .iter()
+        .filter(|label| label.visibility() == Visibility::Private);
+    for label in private {
+        let name = label.name();
+        let collides = label
+            .blocks()
+            .any(|block| defined.contains_key(block.name()));
+        let new_name = if collides {
+            format!("{name}_{index}")
+        } else {
+            name.to_string()
+        };
+        for block in label.blocks() {
+            let block_name = format!("{new_name}{}", &block.name()[name.len()..]);
+            define(defined, &block_name, index)?;
+        }
+        if collides {
+            renamed.insert(name.to_string(), new_name);
+        }
+    }
+    asm.rename_functions(|name| renamed.get(name).cloned());
+    Ok(())
+}
+
+/// Remove the private functions that can't be reached from `main`, a public function, or the entry block,
+/// counting any label mentioned in a raw line as reached.
+pub fn prune(asm: &mut Asm) {
+    let owner: HashMap<&str, &str> = asm
+        .iter()
+        .flat_map(|label| label.blocks().map(|block| (block.name(), label.name())))
+        .collect();
+    let mut reached: HashSet<&str> = asm
+        .iter()
+        .filter(|label| label.visibility() == Visibility::Public)
+        .map(|label| label.name())
+        .collect();
+    let mut pending: Vec<&LabelImpl> = std::iter::once(asm.entry())
+        .chain(
+            asm.iter()
+                .filter(|label| reached.contains(label.name()))
+                .flat_map(Label::blocks),
+        )
+        .collect();
+    while let Some(block) = pending.pop() {
+        for line in block.lines() {
+            let targets: Vec<&str> = match line {
+                Line::Instruction(instr) => instr.targets().collect(),
+                Line::Raw(raw) => raw.split_whitespace().collect(),
+            };
+            for function in targets.into_iter().filter_map(|target| owner.get(target)) {
+                if reached.insert(function) {
+                    let label = asm.iter().find(|label| label.name() == *function);
+                    pending.extend(label.into_iter().flat_map(Label::blocks));
+                }
+            }
+        }
+    }
+    let reached: HashSet<String> = reached.into_iter().map(str::to_string).collect();
+    asm.labels_mut()
+        .retain(|label| reached.contains(label.name()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    fn helper(id: &str) -> Asm {
+        let mut builder = AsmBuilder::new_library(id);
+        builder.label("putn", |putn_builder| {
+            putn_builder
+                .put_char(1)
+                .label_jump("putn.done")
+                .sub_label("done", |done_builder| done_builder.return_(1))
+        });
+        builder.finish()
+    }
+
+    #[test]
+    fn test_link() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(65, 1)
+                .label_call("a_putn", &[1], 0)
+                .label_call("b_putn", &[1], 0)
+                .exit()
+        });
+        let program = builder.finish();
+
+        let linked = link_prefixed(vec![
+            (program, None),
+            (helper("a"), Some("a_")),
+            (helper("b"), Some("b_")),
+        ])
+        .unwrap();
+        assert_eq!(
+            linked.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func a_putn
+    putchar r1
+    jump a_putn.done
+@a_putn.done
+    ret r1
+end
+
+func b_putn
+    putchar r1
+    jump b_putn.done
+@b_putn.done
+    ret r1
+end
+
+func main
+    r1 <- int 65
+    r0 <- call a_putn r1
+    r0 <- call b_putn r1
+    exit
+end"
+        );
+
+        let error = |modules| link(modules).unwrap_err().to_string();
+        assert_eq!(
+            error(vec![AsmBuilder::new().finish(), helper("a"), helper("b")]),
+            "`putn` is defined by modules 1 and 2"
+        );
+        assert_eq!(error(vec![helper("a")]), "no module has an entry point");
+        assert_eq!(
+            error(vec![Asm::new(), helper("a"), Asm::new()]),
+            "modules 0 and 2 both have an entry point"
+        );
+    }
+
+    #[test]
+    fn test_link_private() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(65, 1)
+                .label_call("show_a", &[1], 0)
+                .label_call("show_b", &[1], 0)
+                .exit()
+        });
+        let program = builder.finish();
+
+        let mut a = AsmBuilder::new_library("a");
+        a.label("show_a", |show_builder| {
+            show_builder.label_call("helper", &[1], 0).return_(0)
+        })
+        .label("helper", |helper_builder| {
+            helper_builder.private().put_char(1).return_(1)
+        })
+        .label("unused", |unused_builder| {
+            unused_builder.private().return_(1)
+        });
+        let mut b = AsmBuilder::new_library("b");
+        b.label("show_b", |show_builder| {
+            show_builder.label_call("helper", &[1], 0).return_(0)
+        })
+        .label("helper", |helper_builder| {
+            helper_builder.put_char(1).put_char(1).return_(1)
+        })
+        .export("show_b");
+
+        let mut linked = link(vec![program, a.finish(), b.finish()]).unwrap();
+        prune(&mut linked);
+        assert_eq!(
+            linked.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func show_a
+    r0 <- call helper r1
+    ret r0
+end
+
+func helper
+    putchar r1
+    ret r1
+end
+
+func show_b
+    r0 <- call helper_2 r1
+    ret r0
+end
+
+func helper_2
+    putchar r1
+    putchar r1
+    ret r1
+end
+
+func main
+    r1 <- int 65
+    r0 <- call show_a r1
+    r0 <- call show_b r1
+    exit
+end"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Checks for programs that are valid MiniVM but probably not what was meant to be generated.
+
+use crate::{
+    analysis,
+    asm::{Asm, Label, LabelImpl, Line, Span},
+    builder::Reg,
+    instr::OpCode,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+/// The most arguments a call can pass, in `r1` to `r255`.
+pub const MAX_CALL_ARGS: usize = 255;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The label or sub-label the problem is in, like `fib` or `fib.else`.
+    pub label: String,
+    /// The index of the offending line among the lines of the label, or `None` for the label itself.
+    pub index: Option<usize>,
+    pub kind: LintKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintKind {
+    /// A function that is never called, jumped to or taken the address of, or a sub-label that is never
+    /// jumped to and can't be fallen into.
+    UnusedLabel,
+    /// A register written by an instruction without side effects, and never read afterwards.
+    UnreadWrite(Reg),
+    /// A `ret` in `main`, or whichever function is the [entry point](Asm::entry_point), which returns to
+    /// `@__entry` rather than exiting directly.
+    RetInMain,
+    /// The last block of a function doesn't end in `ret`, `exit` or a jump.
+    FallsOffEnd,
+    /// A call with more than [`MAX_CALL_ARGS`] arguments.
+    TooManyArgs(usize),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "{}+{index}: {}", self.label, self.kind),
+            None => write!(f, "{}: {}", self.label, self.kind),
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Render the diagnostic like a compiler error, with the emitted line it points at and the lines around
+    /// it.
+    #[must_use]
+    pub fn render(&self, asm: &Asm) -> String {
+        self.render_in(asm, &asm.to_string())
+    }
+
+    fn render_in(&self, asm: &Asm, source: &str) -> String {
+        let title = format!("warning: {}", self.kind);
+        let location = match self.index {
+            Some(index) => format!("{}+{index}", self.label),
+            None => self.label.clone(),
+        };
+        match asm.locate(&self.label, self.index) {
+            Some(span) => snippet(source, &span, &title, &location),
+            None => format!("{title}\n  --> {location}"),
+        }
+    }
+}
+
+/// Render every diagnostic of `diagnostics` with [`Diagnostic::render`], separated by empty lines.
+#[must_use]
+pub fn render(asm: &Asm, diagnostics: &[Diagnostic]) -> String {
+    let source = asm.to_string();
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render_in(asm, &source))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Render `title`, followed by the line of `source` that `span` is on with a line of context either side,
+/// and `span` underlined. `location` names where the span is, for readers without the line numbers.
+#[must_use]
+pub fn snippet(source: &str, span: &Span, title: &str, location: &str) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let first = span.line.saturating_sub(1).max(1);
+    let last = (span.line + 1).min(lines.len());
+    let gutter = last.to_string().len();
+
+    let mut out = vec![
+        title.to_string(),
+        format!("{:gutter$}--> {location}, line {}", "", span.line),
+        format!("{:gutter$} |", ""),
+    ];
+    for number in first..=last {
+        let line = lines.get(number - 1).copied().unwrap_or("");
+        out.push(format!("{number:>gutter$} | {line}").trim_end().to_string());
+        if number == span.line {
+            let indent = " ".repeat(span.columns.start);
+            let underline = "^".repeat(span.columns.len().max(1));
+            out.push(format!("{:gutter$} | {indent}{underline}", ""));
+        }
+    }
+    out.join("\n")
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintKind::UnusedLabel => f.write_str("label is never used"),
+            LintKind::UnreadWrite(reg) => write!(f, "r{reg} is written but never read"),
+            LintKind::RetInMain => f.write_str("`ret` in the entry point"),
+            LintKind::FallsOffEnd => f.write_str("control falls off the end of the function"),
+            LintKind::TooManyArgs(count) => {
+                write!(
+                    f,
+                    "call passes {count} arguments, more than {MAX_CALL_ARGS}"
+                )
+            }
+        }
+    }
+}
+
+/// Run every lint over `asm`, returning the diagnostics in output order.
+#[must_use]
+pub fn check(asm: &Asm) -> Vec<Diagnostic> {
+    let used = used_labels(asm);
+    let mut diagnostics = Vec::new();
+    for label in asm.iter() {
+        let is_entry = asm.entry_point() == Some(label.name());
+        check_label(label, is_entry, &used, &mut diagnostics);
+    }
+    diagnostics
+}
+
+fn check_label(
+    label: &Label,
+    is_entry: bool,
+    used: &HashSet<&str>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let unread = unread_writes(label);
+    let mut instr_index = 0;
+    let mut falls_through = false;
+    let mut last = None;
+
+    for block in label.blocks() {
+        let mut diagnostic = |index, kind| {
+            diagnostics.push(Diagnostic {
+                label: block.name().to_string(),
+                index,
+                kind,
+            });
+        };
+        let is_function = block.name() == label.name();
+        if !used.contains(block.name()) && !is_entry && (is_function || !falls_through) {
+            diagnostic(None, LintKind::UnusedLabel);
+        }
+
+        for (index, line) in block.lines().iter().enumerate() {
+            let Line::Instruction(instr) = line else {
+                continue;
+            };
+            if let Some(&Some(reg)) = unread.get(instr_index) {
+                diagnostic(Some(index), LintKind::UnreadWrite(reg));
+            }
+            instr_index += 1;
+
+            if is_entry && instr.op == OpCode::Ret {
+                diagnostic(Some(index), LintKind::RetInMain);
+            }
+            let args = match instr.op {
+                OpCode::Call | OpCode::DCall | OpCode::ExternCall => {
+                    instr.operands.len().saturating_sub(1)
+                }
+                _ => 0,
+            };
+            if args > MAX_CALL_ARGS {
+                diagnostic(Some(index), LintKind::TooManyArgs(args));
+            }
+        }
+
+        falls_through = !ends_in_terminator(block);
+        last = Some(block);
+    }
+
+    if let Some(block) = last.filter(|_| falls_through) {
+        diagnostics.push(Diagnostic {
+            label: block.name().to_string(),
+            index: block.lines().len().checked_sub(1),
+            kind: LintKind::FallsOffEnd,
+        });
+    }
+}
+
+/// Every label referenced by an instruction, or mentioned in a raw line, including those of the entry block.
+fn used_labels(asm: &Asm) -> HashSet<&str> {
+    let mut used = HashSet::new();
+    let blocks = asm.iter().flat_map(Label::blocks);
+    for block in std::iter::once(asm.entry()).chain(blocks) {
+        for line in block.lines() {
+            match line {
+                Line::Instruction(instr) => used.extend(instr.targets()),
+                Line::Raw(raw) => used.extend(raw.split_whitespace()),
+            }
+        }
+    }
+    used
+}
+
+/// For every instruction of `label`, numbered as by [`analysis::liveness`], the register it writes if
+/// nothing reads it. Empty if the label has raw lines, whose reads are unknown.
+fn unread_writes(label: &Label) -> Vec<Option<Reg>> {
+    let has_raw = label.blocks().any(|block| {
+        block
+            .lines()
+            .iter()
+            .any(|line| matches!(line, Line::Raw(_)))
+    });
+    if has_raw {
+        return Vec::new();
+    }
+    let info = analysis::liveness(label);
+    label
+        .blocks()
+        .flat_map(LabelImpl::instructions)
+        .enumerate()
+        .map(|(index, instr)| {
+            let has_effects = matches!(instr.op, OpCode::Call | OpCode::DCall | OpCode::ExternCall);
+            instr
+                .dest
+                .filter(|&dest| !has_effects && !info.live_after(index).contains(dest))
+        })
+        .collect()
+}
+
+/// Whether the last line of `block` is an instruction that never continues to the next one. Raw lines
+/// are assumed to be terminators, as their effect is unknown.
+fn ends_in_terminator(block: &LabelImpl) -> bool {
+    match block.lines().last() {
+        Some(Line::Instruction(instr)) => instr.op.is_terminator(),
+        Some(Line::Raw(_)) => true,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::BuildInstruction;
+    use crate::parse::parse;
+
+    #[test]
+    fn test_check() {
+        let asm = parse(
+            r"func unused
+    ret r1
+end
+
+func f
+    r2 <- int 1
+    bb r1 f.then f.else
+@f.then
+    putchar r2
+@f.else
+    r3 <- add r1 r2
+    ret r3
+@f.orphan
+    r0 <- int 0
+end
+
+func main
+    r1 <- int 1
+    r0 <- call f r1
+    r0 <- int 0
+    ret r0
+end",
+        )
+        .unwrap();
+        let diagnostics: Vec<String> = check(&asm).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            diagnostics.join("\n"),
+            r"unused: label is never used
+f.orphan: label is never used
+f.orphan+0: r0 is written but never read
+f.orphan+0: control falls off the end of the function
+main+3: `ret` in the entry point"
+        );
+        assert_eq!(
+            render(&asm, &check(&asm)[1..3]),
+            r"warning: label is never used
+  --> f.orphan, line 17
+   |
+16 |     ret r3
+17 | @f.orphan
+   |  ^^^^^^^^
+18 |     r0 <- int 0
+
+warning: r0 is written but never read
+  --> f.orphan+0, line 18
+   |
+17 | @f.orphan
+18 |     r0 <- int 0
+   |     ^^^^^^^^^^^
+19 | end"
+        );
+    }
+
+    #[test]
+    fn test_check_custom_entry_point() {
+        let mut builder = crate::AsmBuilder::new();
+        builder
+            .entry_point("start")
+            .label("start", |start_builder| {
+                start_builder.label_call("main", &[], 0).return_(0)
+            })
+            .main(|main_builder| main_builder.integer(0, 0).return_(0));
+        let asm = builder.finish();
+        assert_eq!(asm.entry_point(), Some("start"));
+        let diagnostics: Vec<String> = check(&asm).iter().map(ToString::to_string).collect();
+        assert_eq!(diagnostics.join("\n"), "start+1: `ret` in the entry point");
+    }
+
+    #[test]
+    fn test_check_too_many_args() {
+        let call = |args| format!("    r0 <- call f{}\n", " r1".repeat(args));
+        let asm = parse(&format!(
+            "func f\n    ret r1\nend\n\nfunc main\n    r1 <- int 1\n{}{}    exit\nend",
+            call(MAX_CALL_ARGS),
+            call(MAX_CALL_ARGS + 1)
+        ))
+        .unwrap();
+        let diagnostics: Vec<String> = check(&asm).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            diagnostics.join("\n"),
+            "main+2: call passes 256 arguments, more than 255"
+        );
+    }
+}
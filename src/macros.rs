@@ -0,0 +1,336 @@
+//! The [`minivm_asm!`](crate::minivm_asm) macro, for writing instructions much like the text format.
+
+#![allow(clippy::missing_panics_doc)]
+
+use crate::builder::Reg;
+
+/// Parse a register written as an identifier, like `r12`.
+///
+/// Panics if `name` isn't `r` followed by a register number.
+#[must_use]
+pub fn register(name: &str) -> Reg {
+    name.strip_prefix('r')
+        .and_then(|number| number.parse().ok())
+        .unwrap_or_else(|| panic!("invalid register `{name}`"))
+}
+
+/// Write instructions to a builder in a syntax close to the text format, and evaluate to the builder, so
+/// it can be the body of a closure passed to [`AsmBuilder::main`](crate::AsmBuilder::main) and the like.
+///
+/// Every instruction ends in `;`. Registers are written `r12`, or as a Rust expression in braces, like
+/// `{dest}`. Integers are literals or braced expressions, and `str` takes a string literal or braced
+/// expression. Labels are identifiers, string literals for sub-labels like `"fib.then"`, or braced
+/// expressions. Branches list the label taken if the condition is false first, as in the text format.
+/// Float instructions need the `float` feature.
+#[macro_export]
+macro_rules! minivm_asm {
+    ($builder:expr; $($body:tt)*) => {{
+        let builder = $builder;
+        $crate::__minivm_asm_body!(builder; $($body)*);
+        builder
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_asm_body {
+    ($b:ident;) => {};
+
+    // Calls take any number of arguments, gathered up to the `;`.
+    (@call $b:ident $d:tt $kind:ident $target:tt [$($arg:tt)*] ; $($rest:tt)*) => {
+        $crate::__minivm_asm_body!(@emit_call $b $d $kind $target [$($arg)*]);
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    (@call $b:ident $d:tt $kind:ident $target:tt [$($arg:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::__minivm_asm_body!(@call $b $d $kind $target [$($arg)* $next] $($rest)*);
+    };
+    (@emit_call $b:ident $d:tt label $target:tt [$($arg:tt)*]) => {
+        $b.label_call(
+            $crate::__minivm_label!($target),
+            &[$($crate::__minivm_reg!($arg)),*],
+            $crate::__minivm_reg!($d),
+        );
+    };
+    (@emit_call $b:ident $d:tt reg $target:tt [$($arg:tt)*]) => {
+        $b.dynamic_call(
+            $crate::__minivm_reg!($target),
+            &[$($crate::__minivm_reg!($arg)),*],
+            $crate::__minivm_reg!($d),
+        );
+    };
+    (@emit_call $b:ident $d:tt extern $target:tt [$($arg:tt)*]) => {
+        $b.extern_call(
+            $crate::__minivm_label!($target),
+            &[$($crate::__minivm_reg!($arg)),*],
+            $crate::__minivm_reg!($d),
+        );
+    };
+    ($b:ident; $d:tt <- call $l:tt $($rest:tt)*) => {
+        $crate::__minivm_asm_body!(@call $b $d label $l [] $($rest)*);
+    };
+    ($b:ident; $d:tt <- dcall $r:tt $($rest:tt)*) => {
+        $crate::__minivm_asm_body!(@call $b $d reg $r [] $($rest)*);
+    };
+    ($b:ident; $d:tt <- xcall $f:tt $($rest:tt)*) => {
+        $crate::__minivm_asm_body!(@call $b $d extern $f [] $($rest)*);
+    };
+
+    ($b:ident; exit; $($rest:tt)*) => {
+        $b.exit();
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; jump $l:tt; $($rest:tt)*) => {
+        $b.label_jump($crate::__minivm_label!($l));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; djump $r:tt; $($rest:tt)*) => {
+        $b.dynamic_jump($crate::__minivm_reg!($r));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; ret $r:tt; $($rest:tt)*) => {
+        $b.return_($crate::__minivm_reg!($r));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; putchar $r:tt; $($rest:tt)*) => {
+        $b.put_char($crate::__minivm_reg!($r));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; set $a:tt $i:tt $v:tt; $($rest:tt)*) => {
+        $b.set_array_index(
+            $crate::__minivm_reg!($a),
+            $crate::__minivm_reg!($i),
+            $crate::__minivm_reg!($v),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; bb $r:tt $f:tt $t:tt; $($rest:tt)*) => {
+        $b.branch_boolean(
+            $crate::__minivm_reg!($r),
+            $crate::__minivm_label!($t),
+            $crate::__minivm_label!($f),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; beq $x:tt $y:tt $f:tt $t:tt; $($rest:tt)*) => {
+        $b.branch_equal(
+            $crate::__minivm_reg!($x),
+            $crate::__minivm_reg!($y),
+            $crate::__minivm_label!($t),
+            $crate::__minivm_label!($f),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; blt $x:tt $y:tt $f:tt $t:tt; $($rest:tt)*) => {
+        $b.branch_less_than(
+            $crate::__minivm_reg!($x),
+            $crate::__minivm_reg!($y),
+            $crate::__minivm_label!($t),
+            $crate::__minivm_label!($f),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+
+    ($b:ident; fbeq $x:tt $y:tt $f:tt $t:tt; $($rest:tt)*) => {
+        $b.branch_float_equal(
+            $crate::__minivm_reg!($x),
+            $crate::__minivm_reg!($y),
+            $crate::__minivm_label!($t),
+            $crate::__minivm_label!($f),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; fblt $x:tt $y:tt $f:tt $t:tt; $($rest:tt)*) => {
+        $b.branch_float_less_than(
+            $crate::__minivm_reg!($x),
+            $crate::__minivm_reg!($y),
+            $crate::__minivm_label!($t),
+            $crate::__minivm_label!($f),
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+
+    ($b:ident; $d:tt <- int - $n:literal; $($rest:tt)*) => {
+        $b.integer(-$n, $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- int $n:tt; $($rest:tt)*) => {
+        $b.integer($crate::__minivm_value!($n), $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- fint - $n:literal; $($rest:tt)*) => {
+        $b.float(-$n, $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- fint $n:tt; $($rest:tt)*) => {
+        $b.float($crate::__minivm_value!($n), $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- str $s:tt; $($rest:tt)*) => {
+        $b.string($crate::__minivm_value!($s), $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- addr $l:tt; $($rest:tt)*) => {
+        $b.label_address($crate::__minivm_label!($l), $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- $op:ident $a:tt; $($rest:tt)*) => {
+        $crate::__minivm_asm_unary!($b $op $crate::__minivm_reg!($a), $crate::__minivm_reg!($d));
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+    ($b:ident; $d:tt <- $op:tt $x:tt $y:tt; $($rest:tt)*) => {
+        $crate::__minivm_asm_binary!(
+            $b $op $crate::__minivm_reg!($x), $crate::__minivm_reg!($y), $crate::__minivm_reg!($d)
+        );
+        $crate::__minivm_asm_body!($b; $($rest)*);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_asm_unary {
+    ($b:ident reg $($args:tt)*) => { $b.register_move($($args)*) };
+    ($b:ident neg $($args:tt)*) => { $b.neg($($args)*) };
+    ($b:ident arr $($args:tt)*) => { $b.array($($args)*) };
+    ($b:ident len $($args:tt)*) => { $b.array_length($($args)*) };
+    ($b:ident type $($args:tt)*) => { $b.object_type($($args)*) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_asm_binary {
+    ($b:ident add $($args:tt)*) => { $b.add($($args)*) };
+    ($b:ident sub $($args:tt)*) => { $b.sub($($args)*) };
+    ($b:ident mul $($args:tt)*) => { $b.mul($($args)*) };
+    ($b:ident div $($args:tt)*) => { $b.div($($args)*) };
+    ($b:ident mod $($args:tt)*) => { $b.mod_($($args)*) };
+    ($b:ident band $($args:tt)*) => { $b.bit_and($($args)*) };
+    ($b:ident bor $($args:tt)*) => { $b.bit_or($($args)*) };
+    ($b:ident bxor $($args:tt)*) => { $b.bit_xor($($args)*) };
+    ($b:ident shl $($args:tt)*) => { $b.shift_left($($args)*) };
+    ($b:ident shr $($args:tt)*) => { $b.shift_right($($args)*) };
+    ($b:ident fadd $($args:tt)*) => { $b.float_add($($args)*) };
+    ($b:ident fsub $($args:tt)*) => { $b.float_sub($($args)*) };
+    ($b:ident fmul $($args:tt)*) => { $b.float_mul($($args)*) };
+    ($b:ident fdiv $($args:tt)*) => { $b.float_div($($args)*) };
+    ($b:ident get $($args:tt)*) => { $b.get_array_index($($args)*) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_reg {
+    ({ $e:expr }) => {
+        $e
+    };
+    ($r:ident) => {
+        $crate::__register(stringify!($r))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_value {
+    ({ $e:expr }) => {
+        $e
+    };
+    ($n:literal) => {
+        $n
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __minivm_label {
+    ({ $e:expr }) => {
+        $e
+    };
+    ($l:literal) => {
+        $l
+    };
+    ($l:ident) => {
+        stringify!($l)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_minivm_asm() {
+        let (count, text, tmp) = (3, "hi", 4);
+        let mut builder = AsmBuilder::new();
+        builder
+            .main(|main_builder| {
+                minivm_asm!(main_builder;
+                    r1 <- int {count};
+                    r2 <- str {text};
+                    r3 <- addr step;
+                    r0 <- dcall r3 r1 r2;
+                    r0 <- call {"step"};
+                    r0 <- xcall clock r1;
+                    r0 <- xcall 0;
+                    exit;
+                )
+            })
+            .label("step", |step_builder| {
+                minivm_asm!(step_builder;
+                    {tmp} <- len r2;
+                    {tmp} <- neg {tmp};
+                    r5 <- type r2;
+                    r6 <- int -1;
+                    r6 <- mod r1 r5;
+                    r0 <- get r2 r5;
+                    set r2 r5 r0;
+                    blt r1 r5 "step.done" "step.more";
+                )
+                .sub_label("more", |more_builder| {
+                    minivm_asm!(more_builder;
+                        r1 <- reg r6;
+                        putchar r1;
+                        beq r1 r6 step "step.done";
+                    )
+                })
+                .sub_label("done", |done_builder| {
+                    minivm_asm!(done_builder;
+                        bb r1 "step.more" "step.done";
+                        ret r1;
+                    )
+                })
+            });
+        assert_eq!(
+            builder.finish().to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func step
+    r4 <- len r2
+    r4 <- neg r4
+    r5 <- type r2
+    r6 <- int -1
+    r6 <- mod r1 r5
+    r0 <- get r2 r5
+    set r2 r5 r0
+    blt r1 r5 step.done step.more
+@step.more
+    r1 <- reg r6
+    putchar r1
+    beq r1 r6 step step.done
+@step.done
+    bb r1 step.more step.done
+    ret r1
+end
+
+func main
+    r1 <- int 3
+    r2 <- str :hi
+    r3 <- addr step
+    r0 <- dcall r3 r1 r2
+    r0 <- call step
+    r0 <- xcall clock r1
+    r0 <- xcall 0
+    exit
+end"
+        );
+    }
+}
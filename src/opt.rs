@@ -0,0 +1,2135 @@
+//! Optimization passes over the structured instructions of a label, and of whole programs.
+//!
+//! Passes treat every block of a label as its own basic block, and forget anything they know at raw
+//! lines, whose effect is unknown.
+
+#[cfg(feature = "host")]
+mod dump;
+
+use crate::{
+    analysis,
+    asm::{Asm, Label, LabelImpl, Line, SubLabel, Visibility},
+    builder::{sequential_moves, Reg},
+    instr::{bitwise, Instruction, LabelId, OpCode, Operand},
+    runtime::ArrayCopy,
+    target::{InstructionSet, Target},
+    Int,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Replace arithmetic on registers holding known `int` values with a single `int` load of the result,
+/// and moves of known values with loads of the value.
+///
+/// Arithmetic wraps on overflow, as it does in MiniVM. Division and remainder by zero are left to trap at
+/// runtime.
+pub fn fold_constants(label: &mut Label) {
+    for block in label.blocks_mut() {
+        let mut known: HashMap<Reg, Int> = HashMap::new();
+        for line in block.lines_mut() {
+            let Line::Instruction(instr) = line else {
+                known.clear();
+                continue;
+            };
+            if instr.op != OpCode::Int {
+                if let Some(value) = evaluate(instr, &known) {
+                    *instr = Instruction::new(OpCode::Int, instr.dest, vec![Operand::Int(value)]);
+                }
+            }
+            track_constants(&mut known, instr);
+        }
+    }
+}
+
+/// Replace branches on registers holding known `int` values, and branches whose targets are the same,
+/// with a `jump`, then make jumps and branches to a block that only jumps on go straight to its target.
+pub fn simplify_branches(label: &mut Label) {
+    for block in label.blocks_mut() {
+        let mut known: HashMap<Reg, Int> = HashMap::new();
+        for line in block.lines_mut() {
+            let Line::Instruction(instr) = line else {
+                known.clear();
+                continue;
+            };
+            if let Some(target) = branch_taken(instr, &known) {
+                *instr = Instruction::new(OpCode::Jump, None, vec![Operand::Label(target)]);
+            }
+            track_constants(&mut known, instr);
+        }
+    }
+
+    let forwards: HashMap<LabelId, LabelId> = label
+        .blocks()
+        .filter_map(|block| {
+            let instr = block.lines().first()?.as_instruction()?;
+            let target = instr
+                .targets()
+                .next()
+                .filter(|_| instr.op == OpCode::Jump)?;
+            Some((LabelId::new(block.name()), LabelId::new(target)))
+        })
+        .collect();
+    let thread = |target: &mut LabelId| {
+        let mut seen = vec![target.clone()];
+        while let Some(next) = forwards.get(target) {
+            if seen.contains(next) {
+                break;
+            }
+            seen.push(next.clone());
+            *target = next.clone();
+        }
+    };
+
+    for block in label.blocks_mut() {
+        for line in block.lines_mut() {
+            let Line::Instruction(instr) = line else {
+                continue;
+            };
+            if instr.op != OpCode::Jump && !instr.op.is_branch() {
+                continue;
+            }
+            instr.targets_mut().for_each(thread);
+            let targets: Vec<&str> = instr.targets().collect();
+            if let [if_false, if_true] = targets[..] {
+                if if_false == if_true {
+                    let target = Operand::Label(if_true.into());
+                    *instr = Instruction::new(OpCode::Jump, None, vec![target]);
+                }
+            }
+        }
+    }
+}
+
+/// Replace reads of a register that was copied from another with reads of the original, for as long as
+/// neither is overwritten, then remove moves whose destination is no longer read.
+///
+/// Moves are only removed from labels without raw lines, as what raw lines read is unknown.
+pub fn propagate_copies(label: &mut Label) {
+    for block in label.blocks_mut() {
+        let mut copies: HashMap<Reg, Reg> = HashMap::new();
+        for line in block.lines_mut() {
+            let Line::Instruction(instr) = line else {
+                copies.clear();
+                continue;
+            };
+            for operand in &mut instr.operands {
+                if let Operand::Reg(reg) = operand {
+                    *reg = copies.get(reg).copied().unwrap_or(*reg);
+                }
+            }
+            if let Some(dest) = instr.dest {
+                copies.retain(|&copy, &mut original| copy != dest && original != dest);
+                if let Some(source) = as_move(instr).filter(|&source| source != dest) {
+                    copies.insert(dest, source);
+                }
+            }
+            if instr.op.is_terminator() {
+                copies.clear();
+            }
+        }
+    }
+
+    if has_raw_lines(label) {
+        return;
+    }
+    let liveness = analysis::liveness(label);
+    let mut index = 0;
+    for block in label.blocks_mut() {
+        block.lines_mut().retain(|line| {
+            let instr = line.as_instruction();
+            let dead = instr
+                .filter(|instr| as_move(instr).is_some())
+                .and_then(|instr| instr.dest)
+                .is_some_and(|dest| !liveness.live_after(index).contains(dest));
+            index += 1;
+            !dead
+        });
+    }
+}
+
+/// Merge the source and destination of `reg` moves into one register, across blocks, wherever their values
+/// are never live at once, then remove the moves left copying a register to itself.
+///
+/// Registers that may hold arguments, or be read by a function the label jumps to, keep their numbers. Labels
+/// with raw lines are left alone, as what raw lines read is unknown.
+pub fn coalesce_moves(label: &mut Label) {
+    if has_raw_lines(label) {
+        return;
+    }
+    while let Some((from, to)) = coalescible_move(label) {
+        let rename = |reg: Reg| if reg == from { to } else { reg };
+        for block in label.blocks_mut() {
+            for line in block.lines_mut() {
+                if let Line::Instruction(instr) = line {
+                    *instr = instr.clone().map_registers(rename);
+                }
+            }
+        }
+    }
+    for block in label.blocks_mut() {
+        block.lines_mut().retain(|line| {
+            !line
+                .as_instruction()
+                .is_some_and(|instr| as_move(instr).is_some() && as_move(instr) == instr.dest)
+        });
+    }
+}
+
+/// A move whose registers can be merged, as the register to rename and the one to rename it to.
+fn coalescible_move(label: &Label) -> Option<(Reg, Reg)> {
+    let liveness = analysis::liveness(label);
+    let local: Vec<&str> = label.blocks().map(LabelImpl::name).collect();
+    let instrs: Vec<&Instruction> = label.blocks().flat_map(LabelImpl::instructions).collect();
+
+    let mut pinned = liveness.live_on_entry();
+    for (index, instr) in instrs.iter().enumerate() {
+        let jumps = instr.op == OpCode::Jump || instr.op.is_branch();
+        if instr.op == OpCode::DJump
+            || (jumps && instr.targets().any(|target| !local.contains(&target)))
+        {
+            pinned = pinned.union(liveness.live_at(index));
+        }
+    }
+    // Two registers interfere if one is written while the other is live, except by a move between them.
+    let interfere = |a: Reg, b: Reg| {
+        instrs.iter().enumerate().any(|(index, instr)| {
+            let live = liveness.live_after(index);
+            let source = as_move(instr);
+            match instr.dest {
+                Some(dest) if dest == a => live.contains(b) && source != Some(b),
+                Some(dest) if dest == b => live.contains(a) && source != Some(a),
+                _ => false,
+            }
+        })
+    };
+
+    instrs.iter().find_map(|instr| {
+        let (to, from) = (instr.dest?, as_move(instr)?);
+        if to == from || interfere(to, from) {
+            None
+        } else if !pinned.contains(to) {
+            Some((to, from))
+        } else if !pinned.contains(from) {
+            Some((from, to))
+        } else {
+            None
+        }
+    })
+}
+
+fn has_raw_lines(label: &Label) -> bool {
+    label.blocks().any(|block| {
+        block
+            .lines()
+            .iter()
+            .any(|line| line.as_instruction().is_none())
+    })
+}
+
+/// Reorder the sub-labels of the label so that blocks ending in a `jump` to another block of the label are
+/// followed by it where possible, and remove the jumps that are left going to the next block, such as those
+/// the builders write at the end of a branch. Branches name both their targets, so they can't fall through.
+///
+/// Blocks that already fall through or jump to the next block stay together, and the body of the label stays
+/// first. Labels with raw lines, or whose last block falls off the end of the function, are left alone.
+pub fn layout_blocks(label: &mut Label) {
+    if has_raw_lines(label) {
+        return;
+    }
+    let blocks: Vec<&LabelImpl> = label.blocks().collect();
+    let ends_in_terminator = |block: &LabelImpl| {
+        block
+            .lines()
+            .last()
+            .and_then(Line::as_instruction)
+            .is_some_and(|instr| instr.op.is_terminator())
+    };
+    if !blocks.last().is_some_and(|block| ends_in_terminator(block)) {
+        return;
+    }
+    let index: HashMap<&str, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.name(), i))
+        .collect();
+    // The block each block ends by jumping to, other than the body, which has to stay first.
+    let targets: Vec<Option<usize>> = blocks
+        .iter()
+        .map(|block| {
+            let instr = block.lines().last()?.as_instruction()?;
+            match (instr.op, &instr.operands[..]) {
+                (OpCode::Jump, [Operand::Label(target)]) => index.get(target.as_str()).copied(),
+                _ => None,
+            }
+            .filter(|&target| target != 0)
+        })
+        .collect();
+
+    let len = blocks.len();
+    let mut next: Vec<Option<usize>> = vec![None; len];
+    let mut prev: Vec<Option<usize>> = vec![None; len];
+    for i in (0..len - 1).filter(|&i| !ends_in_terminator(blocks[i])) {
+        (next[i], prev[i + 1]) = (Some(i + 1), Some(i));
+    }
+    let jumps = (0..len).filter_map(|i| Some((i, targets[i]?)));
+    let (in_place, elsewhere): (Vec<_>, Vec<_>) = jumps.partition(|&(from, to)| to == from + 1);
+    for (from, to) in in_place.into_iter().chain(elsewhere) {
+        if next[from].is_some() || prev[to].is_some() {
+            continue;
+        }
+        // Following the start of a chain with its own end would make a cycle.
+        let mut start = from;
+        while let Some(before) = prev[start] {
+            start = before;
+        }
+        if start != to {
+            (next[from], prev[to]) = (Some(to), Some(from));
+        }
+    }
+
+    // Chains are laid out in the order of their first blocks, starting with the body.
+    let mut position = vec![0; len];
+    let mut placed = 0;
+    for start in (0..len).filter(|&i| prev[i].is_none()) {
+        let mut block = Some(start);
+        while let Some(i) = block {
+            position[i] = placed;
+            placed += 1;
+            block = next[i];
+        }
+    }
+    for (i, block) in label.blocks_mut().enumerate() {
+        if targets[i].is_some_and(|target| position[target] == position[i] + 1) {
+            block.lines_mut().pop();
+        }
+    }
+    let mut sub_labels: Vec<(usize, SubLabel)> = std::mem::take(label.sub_labels_mut())
+        .into_iter()
+        .enumerate()
+        .map(|(i, sub_label)| (position[i + 1], sub_label))
+        .collect();
+    sub_labels.sort_by_key(|&(position, _)| position);
+    *label.sub_labels_mut() = sub_labels
+        .into_iter()
+        .map(|(_, sub_label)| sub_label)
+        .collect();
+}
+
+/// Replace `mul`, `div` and `mod` by a constant, loaded with `int` in the same block, with cheaper
+/// instructions, reusing the load when nothing else reads the constant:
+///
+/// - `mul` by 2 or 3 becomes one or two `add`s, and so does `mul` by 4 if `target` has no `shl`.
+/// - `mul` by a larger power of two becomes a `shl`.
+/// - `div` and `mod` by a power of two become a `shr` and a `band`, if the value divided is known not to be
+///   negative, as both round differently for negative values.
+///
+/// Instructions are only used if `target` accepts them. Labels with raw lines are left alone.
+pub fn strength_reduce(label: &mut Label, target: &Target) {
+    if has_raw_lines(label) {
+        return;
+    }
+    let set = target.instruction_set();
+    let liveness = analysis::liveness(label);
+    let mut index = 0;
+    for block in label.blocks_mut() {
+        let lines = block.lines_mut();
+        // Registers holding an `int`, with the line loading it.
+        let mut loads: HashMap<Reg, (Int, usize)> = HashMap::new();
+        let mut non_negative: HashSet<Reg> = HashSet::new();
+        let mut removed = Vec::new();
+        for i in 0..lines.len() {
+            let reduced = reduce(
+                lines,
+                i,
+                &loads,
+                &non_negative,
+                &set,
+                liveness.live_after(index),
+            );
+            if let Some((load, load_reduced, instr)) = reduced {
+                match load_reduced {
+                    Some(load_reduced) => lines[load] = Line::Instruction(load_reduced),
+                    None => removed.push(load),
+                }
+                lines[i] = Line::Instruction(instr);
+            }
+            let Some(instr) = lines[i].as_instruction() else {
+                continue;
+            };
+            track_signs(&mut loads, &mut non_negative, instr, i);
+            index += 1;
+        }
+        for load in removed.into_iter().rev() {
+            lines.remove(load);
+        }
+    }
+}
+
+/// The cheaper form of the `mul`, `div` or `mod` at `i` of `lines`, if it is by a known constant: the line
+/// loading the constant, what to replace the load with, or `None` to remove it, and the new instruction.
+fn reduce(
+    lines: &[Line],
+    i: usize,
+    loads: &HashMap<Reg, (Int, usize)>,
+    non_negative: &HashSet<Reg>,
+    set: &InstructionSet,
+    live_after: analysis::RegSet,
+) -> Option<(usize, Option<Instruction>, Instruction)> {
+    let instr = lines[i].as_instruction()?;
+    let dest = instr.dest?;
+    let [Operand::Reg(lhs), Operand::Reg(rhs)] = instr.operands[..] else {
+        return None;
+    };
+    let operands = match instr.op {
+        OpCode::Mul => vec![(lhs, rhs), (rhs, lhs)],
+        OpCode::Div | OpCode::Mod => vec![(lhs, rhs)],
+        _ => return None,
+    };
+    for (value, constant) in operands {
+        let Some(&(by, load)) = loads.get(&constant) else {
+            continue;
+        };
+        let between = &lines[load + 1..i];
+        let reads_constant = between
+            .iter()
+            .filter_map(Line::as_instruction)
+            .any(|instr| instr.uses().any(|reg| reg == constant));
+        if value == constant
+            || reads_constant
+            || (constant != dest && live_after.contains(constant))
+        {
+            continue;
+        }
+        // Loads replaced by an `add` of `value` move its read earlier, past the instructions between.
+        let can_move_read = between
+            .iter()
+            .filter_map(Line::as_instruction)
+            .all(|instr| {
+                instr.dest != Some(value)
+                    && !matches!(
+                        instr.op,
+                        OpCode::PutChar
+                            | OpCode::Set
+                            | OpCode::Call
+                            | OpCode::DCall
+                            | OpCode::ExternCall
+                    )
+            });
+        let int =
+            |value: Int| Instruction::new(OpCode::Int, Some(constant), vec![Operand::Int(value)]);
+        let double = Instruction::new(
+            OpCode::Add,
+            Some(constant),
+            vec![Operand::Reg(value), Operand::Reg(value)],
+        );
+        let to_dest = |op: OpCode, lhs: Reg, rhs: Reg| {
+            Instruction::new(op, Some(dest), vec![Operand::Reg(lhs), Operand::Reg(rhs)])
+        };
+        let shift = (by > 0 && by & (by - 1) == 0).then(|| Int::from(by.trailing_zeros()));
+        let reduced = match (instr.op, by, shift) {
+            (OpCode::Mul, 2, _) => (None, to_dest(OpCode::Add, value, value)),
+            (OpCode::Mul, 3, _) if can_move_read => {
+                (Some(double), to_dest(OpCode::Add, constant, value))
+            }
+            (OpCode::Mul, 4, _) if can_move_read && !set.supports(OpCode::Shl) => {
+                (Some(double), to_dest(OpCode::Add, constant, constant))
+            }
+            (OpCode::Mul, _, Some(shift)) if shift >= 2 && set.supports(OpCode::Shl) => {
+                (Some(int(shift)), to_dest(OpCode::Shl, value, constant))
+            }
+            (OpCode::Div, _, Some(shift))
+                if non_negative.contains(&value) && set.supports(OpCode::Shr) =>
+            {
+                (Some(int(shift)), to_dest(OpCode::Shr, value, constant))
+            }
+            (OpCode::Mod, _, Some(_))
+                if non_negative.contains(&value) && set.supports(OpCode::BAnd) =>
+            {
+                (Some(int(by - 1)), to_dest(OpCode::BAnd, value, constant))
+            }
+            _ => continue,
+        };
+        return Some((load, reduced.0, reduced.1));
+    }
+    None
+}
+
+/// Update the registers known to hold an `int`, loaded at line `i`, and those known not to be negative,
+/// after `instr`.
+fn track_signs(
+    loads: &mut HashMap<Reg, (Int, usize)>,
+    non_negative: &mut HashSet<Reg>,
+    instr: &Instruction,
+    i: usize,
+) {
+    let Some(dest) = instr.dest else {
+        return;
+    };
+    let reg = |index: usize| instr.operands.get(index).and_then(Operand::as_reg);
+    let is_non_negative = |index: usize| reg(index).is_some_and(|reg| non_negative.contains(&reg));
+    let is_positive = |index: usize| {
+        reg(index)
+            .and_then(|reg| loads.get(&reg))
+            .is_some_and(|&(value, _)| value > 0)
+    };
+    let load = match instr.operands[..] {
+        [Operand::Int(value)] if instr.op == OpCode::Int => Some(value),
+        _ => None,
+    };
+    let dest_non_negative = match instr.op {
+        OpCode::Int => load.is_some_and(|value| value >= 0),
+        OpCode::Len | OpCode::Type => true,
+        OpCode::Reg | OpCode::Shr => is_non_negative(0),
+        OpCode::BAnd => is_non_negative(0) || is_non_negative(1),
+        OpCode::Div | OpCode::Mod => is_non_negative(0) && is_positive(1),
+        _ => false,
+    };
+    loads.remove(&dest);
+    if let Some(value) = load {
+        loads.insert(dest, (value, i));
+    }
+    if dest_non_negative {
+        non_negative.insert(dest);
+    } else {
+        non_negative.remove(&dest);
+    }
+}
+
+/// Replace calls of the label to itself whose result is returned right away with moves of the arguments
+/// into place and a `jump` back to the start of its body, moved into a new `tailrec` sub-label (or
+/// `tailrec1` and so on, if that is taken). Recursion then runs in one frame, however deep it goes.
+///
+/// A call would have started every register other than its arguments out as 0, so those the body reads
+/// before writing are set to 0 again before the `jump`. Labels with raw lines are left alone.
+pub fn tail_recursion_to_loop(label: &mut Label) {
+    if has_raw_lines(label) {
+        return;
+    }
+    let live_on_entry = analysis::liveness(label).live_on_entry();
+    let name = label.name().to_string();
+    let taken = |sub_label: &str| {
+        label
+            .blocks()
+            .any(|block| block.name() == format!("{name}.{sub_label}"))
+    };
+    let mut header_name = "tailrec".to_string();
+    for n in 1.. {
+        if !taken(&header_name) {
+            break;
+        }
+        header_name = format!("tailrec{n}");
+    }
+    let mut header = SubLabel::new(&name, &header_name);
+    let mut rewritten = false;
+    for block in label.blocks_mut() {
+        let lines = block.lines_mut();
+        let mut index = 0;
+        while index + 1 < lines.len() {
+            let Some(args) = self_tail_call(&name, &lines[index], &lines[index + 1]) else {
+                index += 1;
+                continue;
+            };
+            let mut replacement: Vec<Line> = sequential_moves(&args)
+                .into_iter()
+                .map(|(from, to)| {
+                    Line::Instruction(Instruction::new(
+                        OpCode::Reg,
+                        Some(to),
+                        vec![Operand::Reg(from)],
+                    ))
+                })
+                .collect();
+            let not_passed = live_on_entry
+                .iter()
+                .filter(|&reg| reg == 0 || usize::from(reg) > args.len());
+            replacement.extend(not_passed.map(|reg| {
+                Line::Instruction(Instruction::new(
+                    OpCode::Int,
+                    Some(reg),
+                    vec![Operand::Int(0)],
+                ))
+            }));
+            replacement.push(Line::Instruction(Instruction::new(
+                OpCode::Jump,
+                None,
+                vec![Operand::Label(header.name().into())],
+            )));
+            let len = replacement.len();
+            lines.splice(index..index + 2, replacement);
+            index += len;
+            rewritten = true;
+        }
+    }
+    if !rewritten {
+        return;
+    }
+    if let Some(body) = label.blocks_mut().next() {
+        *header.lines_mut() = std::mem::take(body.lines_mut());
+    }
+    label.sub_labels_mut().insert(0, header);
+}
+
+/// The arguments of `call`, if it is a call to `name` whose result `ret` returns.
+fn self_tail_call(name: &str, call: &Line, ret: &Line) -> Option<Vec<Reg>> {
+    let (call, ret) = (call.as_instruction()?, ret.as_instruction()?);
+    let [Operand::Label(callee), args @ ..] = &call.operands[..] else {
+        return None;
+    };
+    if call.op != OpCode::Call
+        || callee != name
+        || ret.op != OpCode::Ret
+        || ret.operands.first()?.as_reg() != call.dest
+    {
+        return None;
+    }
+    args.iter().map(Operand::as_reg).collect()
+}
+
+/// Move `int` loads, and `len`s of arrays a loop doesn't change, out of the natural loops of the label
+/// found by [`analysis::loops`], so they run once before the loop rather than on every iteration.
+///
+/// An instruction is moved if it is the only one of the loop writing its register, the register isn't
+/// read before it on the way around the loop, and it runs on every iteration, before control can leave
+/// the loop. The moved instructions take the place of the header of the loop, so they run however the
+/// loop is entered, and the header moves into a new `{label}.loop{n}` sub-label, which the loop goes back to
+/// instead. Loops are assumed to be entered only through their header, and labels with raw lines are left
+/// alone.
+pub fn hoist_invariants(label: &mut Label) {
+    if has_raw_lines(label) {
+        return;
+    }
+    while let Some((found, invariants)) = find_invariants(label) {
+        hoist(label, &found, &invariants);
+    }
+}
+
+/// The innermost loop of `label` with instructions to hoist, and those instructions, numbered as by
+/// [`analysis::liveness`].
+fn find_invariants(label: &Label) -> Option<(analysis::Loop, Vec<usize>)> {
+    let cfg = analysis::Cfg::new(label);
+    let dominators = cfg.dominators();
+    let liveness = analysis::liveness(label);
+    let blocks: Vec<Vec<&Instruction>> = label
+        .blocks()
+        .map(|block| block.instructions().collect())
+        .collect();
+    let starts: Vec<usize> = blocks
+        .iter()
+        .scan(0, |start, block| {
+            let block_start = *start;
+            *start += block.len();
+            Some(block_start)
+        })
+        .collect();
+
+    let mut loops = analysis::loops(label);
+    loops.sort_by_key(|found| found.blocks.len());
+    for found in loops {
+        if blocks[found.header].is_empty() {
+            continue;
+        }
+        let live_in = liveness.live_at(starts[found.header]);
+        let writes = |reg: Reg| {
+            found
+                .blocks
+                .iter()
+                .flat_map(|&block| &blocks[block])
+                .filter(|instr| instr.dest == Some(reg))
+                .count()
+        };
+        // Blocks that may be the last of an iteration, by going back to the header or leaving the loop.
+        let ends: Vec<usize> = found
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&block| {
+                found.latches.contains(&block)
+                    || cfg.leaves_function(block)
+                    || cfg.successors(block).next().is_none()
+                    || cfg
+                        .successors(block)
+                        .any(|succ| !found.blocks.contains(&succ))
+            })
+            .collect();
+
+        let mut invariants = Vec::new();
+        for &block in &found.blocks {
+            if !ends.iter().all(|&end| dominators[end].contains(&block)) {
+                continue;
+            }
+            for (offset, instr) in blocks[block].iter().enumerate() {
+                let Some(dest) = instr.dest else {
+                    continue;
+                };
+                let invariant = match (instr.op, &instr.operands[..]) {
+                    (OpCode::Int, _) => true,
+                    (OpCode::Len, &[Operand::Reg(array)]) => writes(array) == 0,
+                    _ => false,
+                };
+                if invariant && writes(dest) == 1 && !live_in.contains(dest) {
+                    invariants.push(starts[block] + offset);
+                }
+            }
+        }
+        if !invariants.is_empty() {
+            invariants.sort_unstable();
+            return Some((found, invariants));
+        }
+    }
+    None
+}
+
+/// Move the instructions `invariants` of `label` in front of the loop `found`.
+fn hoist(label: &mut Label, found: &analysis::Loop, invariants: &[usize]) {
+    let name = label.name().to_string();
+    let names: Vec<String> = label
+        .blocks()
+        .map(|block| block.name().to_string())
+        .collect();
+    // There are fewer sub-labels than this, so one of the names is free.
+    let body_name = (1..=names.len())
+        .map(|n| format!("loop{n}"))
+        .find(|sub_label| !names.contains(&format!("{name}.{sub_label}")))
+        .expect("some sub-label name is free");
+    let mut body = SubLabel::new(&name, &body_name);
+    let (header, body_id) = (
+        LabelId::new(&names[found.header]),
+        LabelId::new(body.name()),
+    );
+
+    let mut moved = Vec::new();
+    let mut index = 0;
+    for (i, block) in label.blocks_mut().enumerate() {
+        block.lines_mut().retain(|line| {
+            index += 1;
+            if invariants.contains(&(index - 1)) {
+                moved.push(line.clone());
+                return false;
+            }
+            true
+        });
+        if !found.blocks.contains(&i) {
+            continue;
+        }
+        for instr in block
+            .lines_mut()
+            .iter_mut()
+            .filter_map(Line::as_instruction_mut)
+        {
+            for target in instr.targets_mut().filter(|target| **target == header) {
+                *target = body_id.clone();
+            }
+        }
+        // A block falling into the header has to jump past the moved instructions instead.
+        let ends_in_terminator = block
+            .lines()
+            .last()
+            .and_then(Line::as_instruction)
+            .is_some_and(|instr| instr.op.is_terminator());
+        if i + 1 == found.header && !ends_in_terminator {
+            block.push_instruction(Instruction::new(
+                OpCode::Jump,
+                None,
+                vec![Operand::Label(body_id.clone())],
+            ));
+        }
+    }
+    let header = label
+        .blocks_mut()
+        .nth(found.header)
+        .expect("the header is a block of the label");
+    *body.lines_mut() = std::mem::replace(header.lines_mut(), moved);
+    label.sub_labels_mut().insert(found.header, body);
+}
+
+/// Remove private functions that are the same as an earlier function but for their names, and make the
+/// calls, jumps, branches and addresses referring to them refer to the earlier function instead, as when
+/// several generators inject the same helpers under different names. Repeats until no two functions are
+/// the same, since merging functions can make their callers the same too.
+///
+/// Public functions and `main` are kept, as they may be called from elsewhere. Functions that run off the end
+/// carry on into whichever function follows them, so they and the functions following them are kept too. Raw
+/// lines have to match exactly.
+pub fn dedup_functions(asm: &mut Asm) {
+    loop {
+        let mut survivors: HashMap<String, &Label> = HashMap::new();
+        let mut merged: HashMap<String, String> = HashMap::new();
+        let mut runs_off_end = false;
+        for label in asm.labels() {
+            let follows_run_off = runs_off_end;
+            runs_off_end = !label
+                .blocks()
+                .last()
+                .is_some_and(analysis::ends_in_terminator);
+            if runs_off_end {
+                continue;
+            }
+            let survivor = *survivors.entry(anonymous_text(label)).or_insert(label);
+            if survivor.name() != label.name()
+                && label.visibility() == Visibility::Private
+                && !follows_run_off
+            {
+                merged.extend(
+                    label
+                        .blocks()
+                        .map(|block| block.name().to_string())
+                        .zip(survivor.blocks().map(|block| block.name().to_string())),
+                );
+            }
+        }
+        if merged.is_empty() {
+            return;
+        }
+
+        asm.labels_mut()
+            .retain(|label| !merged.contains_key(label.name()));
+        redirect(asm.entry_mut(), &merged);
+        for block in asm.iter_mut().flat_map(Label::blocks_mut) {
+            redirect(block, &merged);
+        }
+        asm.call_sites_mut()
+            .retain_labels(|label| !merged.contains_key(label));
+        asm.spans_mut()
+            .retain_labels(|label| !merged.contains_key(label));
+    }
+}
+
+/// Make the references of `block` to labels in `merged` refer to what they were merged into.
+fn redirect(block: &mut LabelImpl, merged: &HashMap<String, String>) {
+    for instr in block
+        .lines_mut()
+        .iter_mut()
+        .filter_map(Line::as_instruction_mut)
+    {
+        for target in instr.targets_mut() {
+            if let Some(survivor) = merged.get(target.as_str()) {
+                *target = survivor.as_str().into();
+            }
+        }
+    }
+}
+
+/// The text of `label` with its name left out, as a function and in references to its own sub-labels.
+fn anonymous_text(label: &Label) -> String {
+    let mut label = label.clone();
+    let name = label.name().to_string();
+    for block in label.blocks_mut() {
+        for instr in block
+            .lines_mut()
+            .iter_mut()
+            .filter_map(Line::as_instruction_mut)
+        {
+            for target in instr.targets_mut() {
+                match target.as_str().strip_prefix(name.as_str()) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('.') => *target = rest.into(),
+                    _ => {}
+                }
+            }
+        }
+    }
+    label.rename("");
+    label.to_string()
+}
+
+/// Replace idioms that naive frontends write out one instruction at a time with shorter equivalents: arrays
+/// filled with known printable characters in order become a single `str`, and loops copying an array element
+/// by element become a call to the [`ArrayCopy`] helper, which is added to the program if any are replaced.
+///
+/// Copy loops are left alone if the program has a different function named like the helper. Labels with raw
+/// lines are left alone.
+pub fn recognize_intrinsics(asm: &mut Asm) {
+    let helper = ArrayCopy::label_builder().finish();
+    let existing = asm.iter().find(|label| label.name() == ArrayCopy::NAME);
+    let can_copy = existing.is_none_or(|existing| existing.to_string() == helper.to_string());
+    let mut copied = false;
+    for label in asm.iter_mut() {
+        if has_raw_lines(label) || label.name() == ArrayCopy::NAME {
+            continue;
+        }
+        allocate_strings(label);
+        while let Some(found) = find_copy_loop(label).filter(|_| can_copy) {
+            replace_copy_loop(label, found);
+            copied = true;
+        }
+    }
+    if copied && !asm.iter().any(|label| label.name() == ArrayCopy::NAME) {
+        asm.push_label(helper);
+    }
+}
+
+/// Replace every `arr` of a known length followed by `set`s of known printable characters to each of its
+/// indices in order, with only `int` loads in between, with a `str` of the characters. Loads that are
+/// overwritten before the end of the `set`s are dropped with them.
+fn allocate_strings(label: &mut Label) {
+    for block in label.blocks_mut() {
+        let lines = block.lines_mut();
+        let mut known: HashMap<Reg, Int> = HashMap::new();
+        let mut i = 0;
+        while let Some(Line::Instruction(instr)) = lines.get(i) {
+            if let Some((string, loads, end)) = filled_string(instr, &lines[i + 1..], &known) {
+                lines.splice(i..=i + 1 + end, std::iter::once(string).chain(loads));
+                continue;
+            }
+            track_constants(&mut known, instr);
+            i += 1;
+        }
+    }
+}
+
+/// If `instr` is an `arr` whose elements are all set in `rest`, the `str` replacing it, the loads in between
+/// that have to be kept, and the index in `rest` of the last `set`.
+fn filled_string(
+    instr: &Instruction,
+    rest: &[Line],
+    known: &HashMap<Reg, Int>,
+) -> Option<(Line, Vec<Line>, usize)> {
+    let (OpCode::Arr, Some(array), [Operand::Reg(len)]) =
+        (instr.op, instr.dest, &instr.operands[..])
+    else {
+        return None;
+    };
+    let len = usize::try_from(*known.get(len)?)
+        .ok()
+        .filter(|&len| len > 0)?;
+    let mut known = known.clone();
+    let mut loads: Vec<(Reg, Instruction)> = Vec::new();
+    let mut text = String::new();
+    for (index, instr) in rest.iter().enumerate() {
+        let instr = instr.as_instruction()?;
+        match (instr.op, instr.dest, &instr.operands[..]) {
+            (OpCode::Int, Some(dest), _) if dest != array => {
+                track_constants(&mut known, instr);
+                loads.retain(|&(reg, _)| reg != dest);
+                loads.push((dest, instr.clone()));
+            }
+            (OpCode::Set, None, [Operand::Reg(set), Operand::Reg(at), Operand::Reg(value)])
+                if *set == array
+                    && usize::try_from(known.get(at).copied()?).ok()? == text.len() =>
+            {
+                let byte = u8::try_from(*known.get(value)?)
+                    .ok()
+                    .filter(|&byte| byte == b' ' || byte.is_ascii_graphic())?;
+                text.push(char::from(byte));
+                if text.len() == len {
+                    // The text of a `str` runs to the end of the line, which is trimmed when parsed.
+                    if text.ends_with(' ') {
+                        return None;
+                    }
+                    let string =
+                        Instruction::new(OpCode::Str, Some(array), vec![Operand::Str(text)]);
+                    let loads = loads.into_iter().map(|(_, load)| Line::Instruction(load));
+                    return Some((Line::Instruction(string), loads.collect(), index));
+                }
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// A loop copying an array element by element, found by [`find_copy_loop`].
+struct CopyLoop {
+    /// The block only holding the branch into the loop.
+    header: usize,
+    /// The block copying an element, whose only predecessor is the header.
+    body: usize,
+    exit: LabelId,
+    call: Instruction,
+}
+
+/// Find a block `blt i end exit body`, where `body` gets the element `i` of one array, sets it as element `i`
+/// of another array, adds a register only ever holding 1 to `i` and jumps back, with the element read dead
+/// once the loop exits.
+fn find_copy_loop(label: &Label) -> Option<CopyLoop> {
+    let cfg = analysis::Cfg::new(label);
+    let liveness = analysis::liveness(label);
+    let blocks: Vec<&LabelImpl> = label.blocks().collect();
+    let index: HashMap<&str, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.name(), i))
+        .collect();
+    let mut start = 0;
+    for (header, block) in blocks.iter().enumerate() {
+        let branch = start;
+        start += block.instructions().count();
+        let [Line::Instruction(instr)] = block.lines() else {
+            continue;
+        };
+        let (
+            OpCode::Blt,
+            [Operand::Reg(i), Operand::Reg(end), Operand::Label(exit), Operand::Label(body)],
+        ) = (instr.op, &instr.operands[..])
+        else {
+            continue;
+        };
+        let Some(&body) = index.get(body.as_str()) else {
+            continue;
+        };
+        if body == 0 || body == header || cfg.predecessors(body).any(|pred| pred != header) {
+            continue;
+        }
+        let Some((src, dst, element, one)) = copied_element(blocks[body], block.name(), *i) else {
+            continue;
+        };
+        let regs = [*i, *end, element, one];
+        let distinct = regs
+            .iter()
+            .enumerate()
+            .all(|(n, reg)| !regs[n + 1..].contains(reg) && *reg != src && *reg != dst);
+        let only_one = blocks
+            .iter()
+            .flat_map(|block| block.instructions())
+            .filter(|instr| instr.dest == Some(one))
+            .all(|instr| instr.op == OpCode::Int && instr.operands[..] == [Operand::Int(1)]);
+        if distinct
+            && only_one
+            && !liveness.live_on_entry().contains(one)
+            && !liveness.live_after(branch).contains(element)
+        {
+            let args = [src, dst, *i, *end].map(Operand::Reg);
+            let operands = std::iter::once(Operand::Label(ArrayCopy::NAME.into())).chain(args);
+            return Some(CopyLoop {
+                header,
+                body,
+                exit: exit.clone(),
+                call: Instruction::new(OpCode::Call, Some(*i), operands.collect()),
+            });
+        }
+    }
+    None
+}
+
+/// If `body` copies element `i` of `src` into `dst` through `element`, adds `one` to `i` and jumps to
+/// `header`, the registers `src`, `dst`, `element` and `one`.
+fn copied_element(body: &LabelImpl, header: &str, i: Reg) -> Option<(Reg, Reg, Reg, Reg)> {
+    let instructions: Vec<&Instruction> = body.instructions().collect();
+    let (get, set, add, jump) = match &instructions[..] {
+        [get, set, add, jump] => (get, set, add, jump),
+        [get, set, load, add, jump]
+            if load.op == OpCode::Int && load.operands[..] == [Operand::Int(1)] =>
+        {
+            (get, set, add, jump)
+        }
+        _ => return None,
+    };
+    let (OpCode::Get, Some(element), [Operand::Reg(src), Operand::Reg(at)]) =
+        (get.op, get.dest, &get.operands[..])
+    else {
+        return None;
+    };
+    let (OpCode::Set, [Operand::Reg(dst), Operand::Reg(to), Operand::Reg(value)]) =
+        (set.op, &set.operands[..])
+    else {
+        return None;
+    };
+    let one = match (add.op, add.dest, &add.operands[..]) {
+        (OpCode::Add, Some(sum), [Operand::Reg(a), Operand::Reg(b)]) if sum == i && *a == i => *b,
+        (OpCode::Add, Some(sum), [Operand::Reg(a), Operand::Reg(b)]) if sum == i && *b == i => *a,
+        _ => return None,
+    };
+    let jumps_back = jump.op == OpCode::Jump && jump.targets().eq([header]);
+    let loads_one = instructions.len() == 4 || instructions[2].dest == Some(one);
+    (*at == i && *to == i && *value == element && jumps_back && loads_one)
+        .then_some((*src, *dst, element, one))
+}
+
+/// Replace a copy loop with its call, removing the body.
+fn replace_copy_loop(label: &mut Label, found: CopyLoop) {
+    label.sub_labels_mut().remove(found.body - 1);
+    let header = if found.header > found.body {
+        found.header - 1
+    } else {
+        found.header
+    };
+    let mut lines = vec![Line::Instruction(found.call)];
+    let next = label.blocks().nth(header + 1).map(LabelImpl::name);
+    if next != Some(found.exit.as_str()) {
+        let jump = Instruction::new(OpCode::Jump, None, vec![Operand::Label(found.exit)]);
+        lines.push(Line::Instruction(jump));
+    }
+    if let Some(block) = label.blocks_mut().nth(header) {
+        *block.lines_mut() = lines;
+    }
+}
+
+/// Update the registers known to hold `int` values after `instr`.
+fn track_constants(known: &mut HashMap<Reg, Int>, instr: &Instruction) {
+    if let Some(dest) = instr.dest {
+        match evaluate(instr, known) {
+            Some(value) => known.insert(dest, value),
+            None => known.remove(&dest),
+        };
+    }
+    if instr.op.is_terminator() {
+        known.clear();
+    }
+}
+
+/// The target a branch always goes to, if it only depends on `known` registers.
+fn branch_taken(instr: &Instruction, known: &HashMap<Reg, Int>) -> Option<LabelId> {
+    let [.., Operand::Label(if_false), Operand::Label(if_true)] = &instr.operands[..] else {
+        return None;
+    };
+    let reg = |index: usize| instr.operands.get(index).and_then(Operand::as_reg);
+    let value = |index: usize| known.get(&reg(index)?).copied();
+    let same = reg(0).is_some() && reg(0) == reg(1);
+    let taken = match instr.op {
+        OpCode::Bb => value(0)? != 0,
+        OpCode::Beq => same || value(0)? == value(1)?,
+        OpCode::Blt => !same && value(0)? < value(1)?,
+        _ => return None,
+    };
+    Some(if taken { if_true } else { if_false }.clone())
+}
+
+/// The value `instr` writes to its destination, if it only depends on `known` registers.
+fn evaluate(instr: &Instruction, known: &HashMap<Reg, Int>) -> Option<Int> {
+    instr.dest?;
+    let value = |index: usize| {
+        instr
+            .operands
+            .get(index)
+            .and_then(Operand::as_reg)
+            .and_then(|reg| known.get(&reg).copied())
+    };
+    match instr.op {
+        OpCode::Int => instr.operands.first()?.as_int(),
+        OpCode::Reg => value(0),
+        OpCode::Neg => value(0).map(Int::wrapping_neg),
+        OpCode::Add => Some(value(0)?.wrapping_add(value(1)?)),
+        OpCode::Sub => Some(value(0)?.wrapping_sub(value(1)?)),
+        OpCode::Mul => Some(value(0)?.wrapping_mul(value(1)?)),
+        OpCode::Div => match (value(0)?, value(1)?) {
+            (_, 0) => None,
+            (lhs, rhs) => Some(lhs.wrapping_div(rhs)),
+        },
+        OpCode::Mod => match (value(0)?, value(1)?) {
+            (_, 0) => None,
+            (lhs, rhs) => Some(lhs.wrapping_rem(rhs)),
+        },
+        OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+            Some(bitwise(instr.op, value(0)?, value(1)?))
+        }
+        _ => None,
+    }
+}
+
+/// A rewrite of a fixed number of consecutive instructions, for use with [`Peephole`].
+pub trait Rule {
+    /// How many consecutive instructions the rule looks at.
+    fn window(&self) -> usize;
+
+    /// The instructions to replace `window` with, or `None` to leave it as it is.
+    ///
+    /// A rule must not apply again to its own output, or [`Peephole::run`] never finishes.
+    fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>>;
+}
+
+/// Removes `rX <- reg rX`.
+pub struct SelfMove;
+
+impl Rule for SelfMove {
+    fn window(&self) -> usize {
+        1
+    }
+
+    fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>> {
+        let [instr] = window else { return None };
+        (as_move(instr)? == (instr.dest?)).then(Vec::new)
+    }
+}
+
+/// Replaces an `add` of a register that was just set to `int 0` with a move of the other operand.
+pub struct AddZero;
+
+impl Rule for AddZero {
+    fn window(&self) -> usize {
+        2
+    }
+
+    fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>> {
+        let [zero, add] = window else { return None };
+        if zero.op != OpCode::Int || zero.operands != [Operand::Int(0)] || add.op != OpCode::Add {
+            return None;
+        }
+        let zero_reg = zero.dest?;
+        let other = match add.uses().collect::<Vec<_>>()[..] {
+            [lhs, rhs] if rhs == zero_reg => lhs,
+            [lhs, rhs] if lhs == zero_reg => rhs,
+            _ => return None,
+        };
+        Some(vec![zero.clone(), register_move(other, add.dest?)])
+    }
+}
+
+/// Replaces the second of two `neg`s in a row with a move of the original value.
+pub struct DoubleNeg;
+
+impl Rule for DoubleNeg {
+    fn window(&self) -> usize {
+        2
+    }
+
+    fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>> {
+        let [first, second] = window else { return None };
+        if first.op != OpCode::Neg || second.op != OpCode::Neg {
+            return None;
+        }
+        let (source, negated) = (first.uses().next()?, first.dest?);
+        if second.uses().next()? != negated || source == negated {
+            return None;
+        }
+        let dest = second.dest?;
+        if dest == negated {
+            Some(vec![register_move(source, dest)])
+        } else {
+            Some(vec![first.clone(), register_move(source, dest)])
+        }
+    }
+}
+
+/// Removes a move that is immediately undone, `rY <- reg rX` followed by `rX <- reg rY`, and a move whose
+/// destination is immediately overwritten by another move.
+pub struct RedundantMove;
+
+impl Rule for RedundantMove {
+    fn window(&self) -> usize {
+        2
+    }
+
+    fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>> {
+        let [first, second] = window else { return None };
+        let (from, to) = (as_move(first)?, first.dest?);
+        let (second_from, second_to) = (as_move(second)?, second.dest?);
+        if second_from == to && second_to == from {
+            Some(vec![first.clone()])
+        } else if second_to == to && second_from != to {
+            Some(vec![second.clone()])
+        } else {
+            None
+        }
+    }
+}
+
+fn as_move(instr: &Instruction) -> Option<Reg> {
+    if instr.op == OpCode::Reg {
+        instr.uses().next()
+    } else {
+        None
+    }
+}
+
+fn register_move(from: Reg, to: Reg) -> Instruction {
+    Instruction::new(OpCode::Reg, Some(to), vec![Operand::Reg(from)])
+}
+
+/// Rewrites windows of consecutive instructions with a set of [`Rule`]s until none of them apply.
+pub struct Peephole {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Peephole {
+    /// A peephole optimizer with the built-in rules: [`SelfMove`], [`AddZero`], [`DoubleNeg`], and
+    /// [`RedundantMove`].
+    #[must_use]
+    pub fn new() -> Peephole {
+        let mut peephole = Self::empty();
+        peephole
+            .rule(SelfMove)
+            .rule(AddZero)
+            .rule(DoubleNeg)
+            .rule(RedundantMove);
+        peephole
+    }
+
+    /// A peephole optimizer with no rules.
+    #[must_use]
+    pub fn empty() -> Peephole {
+        Self { rules: Vec::new() }
+    }
+
+    pub fn rule(&mut self, rule: impl Rule + 'static) -> &mut Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    pub fn run(&self, label: &mut Label) {
+        for block in label.blocks_mut() {
+            self.run_block(block);
+        }
+    }
+
+    fn run_block(&self, block: &mut LabelImpl) {
+        let widest = self
+            .rules
+            .iter()
+            .map(|rule| rule.window())
+            .max()
+            .unwrap_or(1);
+        let lines = block.lines_mut();
+        let mut start = 0;
+        while start < lines.len() {
+            let rewrite = self.rules.iter().find_map(|rule| {
+                let end = start + rule.window();
+                let window: Option<Vec<Instruction>> = lines
+                    .get(start..end)?
+                    .iter()
+                    .map(|line| line.as_instruction().cloned())
+                    .collect();
+                Some((end, rule.rewrite(&window?)?))
+            });
+            if let Some((end, replacement)) = rewrite {
+                lines.splice(start..end, replacement.into_iter().map(Line::Instruction));
+                start = start.saturating_sub(widest - 1);
+            } else {
+                start += 1;
+            }
+        }
+    }
+}
+
+impl Default for Peephole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type BoxedPass = Box<dyn FnMut(&mut Asm)>;
+type DumpFn = Box<dyn FnMut(&str, &Asm)>;
+
+/// Runs a pipeline of passes over a program, once or until it stops changing.
+pub struct PassManager {
+    passes: Vec<(String, BoxedPass)>,
+    max_iterations: usize,
+    dump: Option<DumpFn>,
+    #[cfg(feature = "host")]
+    dump_dir: Option<dump::DumpDir>,
+    record_stats: bool,
+}
+
+impl PassManager {
+    /// A pass manager with no passes, that runs them once.
+    #[must_use]
+    pub fn new() -> PassManager {
+        Self {
+            passes: Vec::new(),
+            max_iterations: 1,
+            dump: None,
+            #[cfg(feature = "host")]
+            dump_dir: None,
+            record_stats: false,
+        }
+    }
+
+    /// Add a pass over the whole program, run after those added before it.
+    pub fn pass(&mut self, name: &str, pass: impl FnMut(&mut Asm) + 'static) -> &mut Self {
+        self.passes.push((name.to_string(), Box::new(pass)));
+        self
+    }
+
+    /// Add a pass that is run on every label of the program in turn, like [`fold_constants`].
+    pub fn label_pass(
+        &mut self,
+        name: &str,
+        mut pass: impl FnMut(&mut Label) + 'static,
+    ) -> &mut Self {
+        self.pass(name, move |asm| asm.iter_mut().for_each(&mut pass))
+    }
+
+    /// Rerun the whole pipeline until an iteration leaves the program unchanged, at most `max_iterations`
+    /// times.
+    pub fn to_fixpoint(&mut self, max_iterations: usize) -> &mut Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Count the instructions and labels every pass removes, for the [`PassStats`] returned by
+    /// [`run`](PassManager::run). Otherwise only the iterations are counted.
+    pub fn record_stats(&mut self) -> &mut Self {
+        self.record_stats = true;
+        self
+    }
+
+    /// Call `dump` with the name of every pass and the program right after it ran.
+    pub fn dump_ir(&mut self, dump: impl FnMut(&str, &Asm) + 'static) -> &mut Self {
+        self.dump = Some(Box::new(dump));
+        self
+    }
+
+    pub fn run(&mut self, asm: &mut Asm) -> PassStats {
+        let mut stats = PassStats {
+            iterations: 0,
+            passes: self
+                .passes
+                .iter()
+                .map(|(name, _)| PassRecord {
+                    name: name.clone(),
+                    instructions_removed: 0,
+                    labels_deleted: 0,
+                })
+                .collect(),
+        };
+
+        while stats.iterations < self.max_iterations {
+            stats.iterations += 1;
+            let start = (asm.entry().clone(), asm.iter().cloned().collect::<Vec<_>>());
+            for ((name, pass), record) in self.passes.iter_mut().zip(&mut stats.passes) {
+                if self.record_stats {
+                    let (instructions, labels) = size(asm);
+                    pass(asm);
+                    let (instructions_after, labels_after) = size(asm);
+                    record.instructions_removed += instructions.saturating_sub(instructions_after);
+                    record.labels_deleted += labels.saturating_sub(labels_after);
+                } else {
+                    pass(asm);
+                }
+                if let Some(dump) = &mut self.dump {
+                    dump(name, asm);
+                }
+                #[cfg(feature = "host")]
+                if let Some(dump_dir) = &mut self.dump_dir {
+                    dump_dir.write(name, asm);
+                }
+            }
+            if *asm.entry() == start.0 && asm.iter().eq(&start.1) {
+                break;
+            }
+        }
+        stats
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The number of instructions and of labels and sub-labels of `asm`.
+fn size(asm: &Asm) -> (usize, usize) {
+    let blocks = || asm.iter().flat_map(Label::blocks);
+    let instructions = blocks().map(|block| block.instructions().count()).sum();
+    (instructions, blocks().count())
+}
+
+/// What a [`PassManager`] did to a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassStats {
+    /// How many times the pipeline ran.
+    pub iterations: usize,
+    /// Every pass, in the order they ran, summed over all iterations.
+    pub passes: Vec<PassRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassRecord {
+    pub name: String,
+    pub instructions_removed: usize,
+    /// Labels and sub-labels removed.
+    pub labels_deleted: usize,
+}
+
+impl fmt::Display for PassStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} iterations", self.iterations)?;
+        for pass in &self.passes {
+            write!(
+                f,
+                "\n    {}: {} instructions removed, {} labels deleted",
+                pass.name, pass.instructions_removed, pass.labels_deleted
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LabelBuilder;
+    use crate::target::Version;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_fold_constants() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .integer(6, 2)
+            .integer(7, 3)
+            .mul(2, 3, 0)
+            .neg(0, 4)
+            .register_move(4, 5)
+            .integer(0, 6)
+            .div(0, 6, 7)
+            .add(1, 0, 1)
+            .sub(0, 1, 0)
+            .sub(0, 2, 0)
+            .sub_label("next", |next_builder| next_builder.add(2, 3, 0).return_(0));
+        let mut label = builder.finish();
+
+        fold_constants(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r2 <- int 6
+    r3 <- int 7
+    r0 <- int 42
+    r4 <- int -42
+    r5 <- int -42
+    r6 <- int 0
+    r7 <- div r0 r6
+    r1 <- add r1 r0
+    r0 <- sub r0 r1
+    r0 <- sub r0 r2
+@f.next
+    r0 <- add r2 r3
+    ret r0
+end"
+        );
+    }
+
+    struct SubSelf;
+
+    impl Rule for SubSelf {
+        fn window(&self) -> usize {
+            1
+        }
+
+        fn rewrite(&self, window: &[Instruction]) -> Option<Vec<Instruction>> {
+            let [instr] = window else { return None };
+            let [Operand::Reg(lhs), Operand::Reg(rhs)] = instr.operands[..] else {
+                return None;
+            };
+            (instr.op == OpCode::Sub && lhs == rhs).then(|| {
+                vec![Instruction::new(
+                    OpCode::Int,
+                    instr.dest,
+                    vec![Operand::Int(0)],
+                )]
+            })
+        }
+    }
+
+    #[test]
+    fn test_propagate_copies() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .register_move(1, 2)
+            .register_move(2, 3)
+            .add(3, 3, 0)
+            .register_move(0, 4)
+            .integer(1, 0)
+            .add(4, 0, 5)
+            .register_move(5, 1)
+            .label_jump("f.next")
+            .sub_label("next", |next_builder| next_builder.return_(1));
+        let mut label = builder.finish();
+
+        propagate_copies(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r0 <- add r1 r1
+    r4 <- reg r0
+    r0 <- int 1
+    r5 <- add r4 r0
+    r1 <- reg r5
+    jump f.next
+@f.next
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_coalesce_moves() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .integer(1, 2)
+            .register_move(2, 5)
+            .add(5, 2, 5)
+            .add(5, 2, 0)
+            .register_move(1, 3)
+            .add(3, 2, 3)
+            .label_jump("f.next")
+            .sub_label("next", |next_builder| {
+                next_builder.register_move(3, 4).add(4, 0, 4).return_(4)
+            });
+        let mut label = builder.finish();
+
+        coalesce_moves(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r2 <- int 1
+    r5 <- reg r2
+    r5 <- add r5 r2
+    r0 <- add r5 r2
+    r1 <- add r1 r2
+    jump f.next
+@f.next
+    r1 <- add r1 r0
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_tail_recursion_to_loop() {
+        let mut builder = LabelBuilder::new("putn");
+        builder
+            .integer(10, 3)
+            .branch_less_than(1, 3, "putn.more", "putn.digit")
+            .sub_label("more", |more_builder| {
+                more_builder
+                    .div(1, 3, 4)
+                    .mod_(1, 3, 1)
+                    .label_call("putn", &[4, 1], 0)
+                    .return_(0)
+            })
+            .sub_label("digit", |digit_builder| {
+                digit_builder.put_char(1).return_(1)
+            });
+        let mut label = builder.finish();
+
+        tail_recursion_to_loop(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func putn
+@putn.tailrec
+    r3 <- int 10
+    blt r1 r3 putn.digit putn.more
+@putn.more
+    r4 <- div r1 r3
+    r1 <- mod r1 r3
+    r2 <- reg r1
+    r1 <- reg r4
+    jump putn.tailrec
+@putn.digit
+    putchar r1
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_tail_recursion_to_loop_resets_registers() {
+        // `r2` starts out as 0 in every call, and `f.tailrec` is already taken.
+        let build = |builder: &mut crate::AsmBuilder| {
+            builder
+                .label("f", |f_builder| {
+                    f_builder
+                        .add(2, 1, 2)
+                        .branch_boolean(1, "f.more", "f.tailrec")
+                        .sub_label("tailrec", |tailrec_builder| tailrec_builder.return_(2))
+                        .sub_label("more", |more_builder| {
+                            more_builder
+                                .integer(1, 3)
+                                .sub(1, 3, 1)
+                                .label_call("f", &[1], 0)
+                                .return_(0)
+                        })
+                })
+                .main(|main_builder| {
+                    main_builder
+                        .integer(3, 1)
+                        .label_call("f", &[1], 1)
+                        .integer(48, 2)
+                        .add(1, 2, 1)
+                        .put_char(1)
+                        .exit()
+                });
+        };
+        let mut builder = crate::AsmBuilder::new();
+        build(&mut builder);
+        let mut asm = builder.finish();
+        #[cfg(feature = "interp")]
+        let expected = crate::interp::run(&asm, &crate::interp::Config::default()).output_string();
+
+        asm.iter_mut().for_each(tail_recursion_to_loop);
+        assert_eq!(
+            asm.labels()[0].to_string(),
+            r"func f
+@f.tailrec1
+    r2 <- add r2 r1
+    bb r1 f.tailrec f.more
+@f.tailrec
+    ret r2
+@f.more
+    r3 <- int 1
+    r1 <- sub r1 r3
+    r2 <- int 0
+    jump f.tailrec1
+end"
+        );
+        #[cfg(feature = "interp")]
+        assert_eq!(
+            crate::interp::run(&asm, &crate::interp::Config::default()).output_string(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_hoist_invariants() {
+        let mut builder = LabelBuilder::new("sum");
+        builder
+            .integer(0, 2)
+            .integer(0, 0)
+            .sub_label("loop", |loop_builder| {
+                loop_builder
+                    .integer(1, 5)
+                    .array_length(1, 3)
+                    .branch_less_than(2, 3, "sum.body", "sum.done")
+            })
+            .sub_label("body", |body_builder| {
+                body_builder
+                    .get_array_index(1, 2, 4)
+                    .add(0, 4, 0)
+                    .integer(0, 4)
+                    .add(2, 5, 2)
+                    .label_jump("sum.loop")
+            })
+            .sub_label("done", |done_builder| done_builder.return_(0));
+        let mut label = builder.finish();
+
+        hoist_invariants(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func sum
+    r2 <- int 0
+    r0 <- int 0
+@sum.loop
+    r5 <- int 1
+    r3 <- len r1
+@sum.loop1
+    blt r2 r3 sum.done sum.body
+@sum.body
+    r4 <- get r1 r2
+    r0 <- add r0 r4
+    r4 <- int 0
+    r2 <- add r2 r5
+    jump sum.loop1
+@sum.done
+    ret r0
+end"
+        );
+
+        let mut builder = LabelBuilder::new("spin");
+        builder.integer(7, 1).put_char(1).label_jump("spin");
+        let mut label = builder.finish();
+
+        hoist_invariants(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func spin
+    r1 <- int 7
+@spin.loop1
+    putchar r1
+    jump spin.loop1
+end"
+        );
+    }
+
+    #[test]
+    fn test_recognize_intrinsics() {
+        let mut asm = crate::parse::parse(
+            r"func main
+    r1 <- int 3
+    r2 <- arr r1
+    r3 <- int 0
+    r4 <- int 104
+    set r2 r3 r4
+    r3 <- int 1
+    r4 <- int 105
+    set r2 r3 r4
+    r3 <- int 2
+    r4 <- int 33
+    set r2 r3 r4
+    r5 <- arr r1
+    r6 <- int 0
+@main.copy
+    blt r6 r1 main.done main.elem
+@main.elem
+    r7 <- get r2 r6
+    set r5 r6 r7
+    r8 <- int 1
+    r6 <- add r6 r8
+    jump main.copy
+@main.done
+    r6 <- int 0
+    r7 <- get r5 r6
+    putchar r7
+    exit
+end",
+        )
+        .unwrap();
+
+        recognize_intrinsics(&mut asm);
+        assert_eq!(
+            asm.finish(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func array_copy
+    r5 <- int 1
+@array_copy.loop
+    blt r3 r4 array_copy.done array_copy.elem
+@array_copy.elem
+    r6 <- get r1 r3
+    set r2 r3 r6
+    r3 <- add r3 r5
+    jump array_copy.loop
+@array_copy.done
+    ret r3
+end
+
+func main
+    r1 <- int 3
+    r2 <- str :hi!
+    r3 <- int 2
+    r4 <- int 33
+    r5 <- arr r1
+    r6 <- int 0
+@main.copy
+    r6 <- call array_copy r2 r5 r6 r1
+@main.done
+    r6 <- int 0
+    r7 <- get r5 r6
+    putchar r7
+    exit
+end"
+        );
+    }
+
+    #[test]
+    fn test_layout_blocks() {
+        let mut label = crate::parse::parse(
+            r"func f
+    r2 <- int 10
+    jump f.loop
+@f.done
+    ret r1
+@f.loop
+    r1 <- add r1 r2
+    blt r1 r2 f.done f.body
+@f.body
+    putchar r1
+    jump f.loop
+end
+
+func main
+    exit
+end",
+        )
+        .unwrap()
+        .labels()[0]
+            .clone();
+
+        layout_blocks(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r2 <- int 10
+@f.loop
+    r1 <- add r1 r2
+    blt r1 r2 f.done f.body
+@f.done
+    ret r1
+@f.body
+    putchar r1
+    jump f.loop
+end"
+        );
+    }
+
+    #[test]
+    fn test_strength_reduce() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .array_length(1, 2)
+            .integer(8, 3)
+            .mul(2, 3, 4)
+            .integer(2, 3)
+            .mul(3, 4, 5)
+            .integer(3, 3)
+            .put_char(1)
+            .mul(5, 3, 6)
+            .integer(4, 3)
+            .div(2, 3, 7)
+            .integer(16, 3)
+            .mod_(2, 3, 8)
+            .integer(4, 3)
+            .div(6, 3, 9)
+            .integer(4, 3)
+            .mul(9, 3, 9)
+            .return_(9);
+        let label = builder.finish();
+
+        let mut reduced = label.clone();
+        strength_reduce(&mut reduced, &Target::MiniVm(Version::new(0, 2)));
+        assert_eq!(
+            reduced.finish(),
+            r"func f
+    r2 <- len r1
+    r3 <- int 3
+    r4 <- shl r2 r3
+    r5 <- add r4 r4
+    r3 <- int 3
+    putchar r1
+    r6 <- mul r5 r3
+    r3 <- int 2
+    r7 <- shr r2 r3
+    r3 <- int 15
+    r8 <- band r2 r3
+    r3 <- int 4
+    r9 <- div r6 r3
+    r3 <- int 2
+    r9 <- shl r9 r3
+    ret r9
+end"
+        );
+
+        let mut reduced = label;
+        strength_reduce(&mut reduced, &Target::MiniVm(Version::new(0, 1)));
+        assert_eq!(
+            reduced.lines()[1..3]
+                .iter()
+                .chain(&reduced.lines()[13..])
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "r3 <- int 8",
+                "r4 <- mul r2 r3",
+                "r3 <- add r9 r9",
+                "r9 <- add r3 r3",
+                "ret r9"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedup_functions() {
+        let mut builder = AsmBuilder::new();
+        for name in ["count_a", "count_b"] {
+            builder.label(name, |count_builder| {
+                count_builder
+                    .private()
+                    .branch_if(1, format!("{name}.done"))
+                    .label_call(name, &[1], 1)
+                    .sub_label("done", |done_builder| done_builder.return_(1))
+            });
+        }
+        for (name, callee) in [("run_a", "count_a"), ("run_b", "count_b")] {
+            builder.label(name, |run_builder| {
+                run_builder.private().label_call(callee, &[1], 0).return_(0)
+            });
+        }
+        builder
+            .label("count_c", |count_builder| {
+                count_builder
+                    .branch_if(1, "count_c.done")
+                    .label_call("count_c", &[1], 1)
+                    .sub_label("done", |done_builder| done_builder.return_(1))
+            })
+            .main(|main_builder| {
+                main_builder
+                    .label_call("run_a", &[], 0)
+                    .label_call("run_b", &[], 0)
+                    .label_address("count_b.done", 1)
+                    .exit()
+            });
+        let mut asm = builder.finish();
+
+        dedup_functions(&mut asm);
+        assert_eq!(
+            asm.to_string(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func count_a
+    bb r1 count_a.cont1 count_a.done
+@count_a.cont1
+    r1 <- call count_a r1
+@count_a.done
+    ret r1
+end
+
+func run_a
+    r0 <- call count_a r1
+    ret r0
+end
+
+func count_c
+    bb r1 count_c.cont1 count_c.done
+@count_c.cont1
+    r1 <- call count_c r1
+@count_c.done
+    ret r1
+end
+
+func main
+    r0 <- call run_a
+    r0 <- call run_a
+    r1 <- addr count_a.done
+    exit
+end"
+        );
+    }
+
+    #[test]
+    fn test_dedup_functions_running_off_the_end() {
+        let mut builder = AsmBuilder::new();
+        for (name, next) in [("fall_a", "next_a"), ("fall_b", "next_b")] {
+            builder
+                .label(name, |fall_builder| fall_builder.private().integer(1, 1))
+                .label(next, |next_builder| {
+                    next_builder.private().put_char(1).return_(1)
+                });
+        }
+        builder.main(|main_builder| {
+            main_builder
+                .label_call("fall_a", &[], 0)
+                .label_call("fall_b", &[], 0)
+                .label_call("next_a", &[], 0)
+                .label_call("next_b", &[], 0)
+                .exit()
+        });
+        let mut asm = builder.finish();
+        let text = asm.to_string();
+
+        dedup_functions(&mut asm);
+        assert_eq!(asm.to_string(), text);
+    }
+
+    #[test]
+    fn test_simplify_branches() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .integer(1, 0)
+            .branch_boolean(0, "f.a", "f.b")
+            .sub_label("a", |a_builder| {
+                a_builder.integer(2, 2).branch_less_than(2, 0, "f.c", "f.d")
+            })
+            .sub_label("b", |b_builder| b_builder.branch_equal(1, 1, "f.c", "f.d"))
+            .sub_label("c", |c_builder| c_builder.label_jump("f.e"))
+            .sub_label("d", |d_builder| {
+                d_builder.branch_less_than(1, 2, "f.c", "f.e")
+            })
+            .sub_label("e", |e_builder| e_builder.label_jump("g"));
+        let mut label = builder.finish();
+
+        simplify_branches(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r0 <- int 1
+    jump f.a
+@f.a
+    r2 <- int 2
+    blt r2 r0 f.d g
+@f.b
+    jump g
+@f.c
+    jump g
+@f.d
+    jump g
+@f.e
+    jump g
+end"
+        );
+    }
+
+    #[test]
+    fn test_pass_manager() {
+        let mut builder = crate::AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .integer(2, 1)
+                .register_move(1, 2)
+                .add(2, 2, 3)
+                .register_move(3, 0)
+                .return_(0)
+        });
+        let mut asm = builder.finish();
+
+        let dumps = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut manager = PassManager::new();
+        manager
+            .label_pass("propagate_copies", propagate_copies)
+            .label_pass("fold_constants", fold_constants)
+            .pass("drop_unused", |asm| {
+                asm.labels_mut().retain(|label| label.name() != "unused");
+            })
+            .to_fixpoint(10)
+            .record_stats()
+            .dump_ir({
+                let dumps = dumps.clone();
+                move |name, asm| {
+                    dumps
+                        .borrow_mut()
+                        .push(format!("{name}\n{}", asm.iter().last().unwrap()));
+                }
+            });
+        asm.push_label(Label::new("unused"));
+        let stats = manager.run(&mut asm);
+
+        assert_eq!(
+            stats.to_string(),
+            "2 iterations
+    propagate_copies: 2 instructions removed, 0 labels deleted
+    fold_constants: 0 instructions removed, 0 labels deleted
+    drop_unused: 0 instructions removed, 1 labels deleted"
+        );
+        assert_eq!(dumps.borrow().len(), 6);
+        assert_eq!(
+            dumps.borrow()[1],
+            r"fold_constants
+func main
+    r1 <- int 2
+    r3 <- int 4
+    ret r3
+end"
+        );
+    }
+
+    #[test]
+    fn test_peephole() {
+        let mut builder = LabelBuilder::new("f");
+        builder
+            .register_move(1, 1)
+            .integer(0, 2)
+            .add(1, 2, 3)
+            .neg(3, 4)
+            .neg(4, 4)
+            .neg(1, 5)
+            .neg(5, 6)
+            .register_move(6, 7)
+            .register_move(7, 6)
+            .register_move(1, 8)
+            .register_move(2, 8)
+            .sub(8, 8, 9)
+            .return_(9);
+        let mut label = builder.finish();
+
+        let mut peephole = Peephole::new();
+        peephole.rule(SubSelf);
+        peephole.run(&mut label);
+        assert_eq!(
+            label.finish(),
+            r"func f
+    r2 <- int 0
+    r3 <- reg r1
+    r4 <- reg r3
+    r5 <- neg r1
+    r6 <- reg r1
+    r7 <- reg r6
+    r8 <- reg r2
+    r9 <- int 0
+    ret r9
+end"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Writing the program to files between passes, which needs a filesystem.
+
+use super::PassManager;
+use crate::asm::Asm;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The directory [`PassManager::dump_after_each`] writes into, inside the one it is given.
+const DUMP_DIR: &str = "minivm-passes";
+
+pub(super) struct DumpDir {
+    dir: PathBuf,
+    count: usize,
+    error: Option<io::Error>,
+}
+
+impl DumpDir {
+    pub(super) fn write(&mut self, name: &str, asm: &Asm) {
+        self.count += 1;
+        let path = (self.dir).join(format!(
+            "{:03}-{name}.{}",
+            self.count,
+            crate::corpus::EXTENSION
+        ));
+        if let Err(error) = fs::write(path, asm.to_string()) {
+            self.error.get_or_insert(error);
+        }
+    }
+}
+
+impl PassManager {
+    /// Write the program to a file after every pass, named for when and which pass ran, as in
+    /// `003-fold_constants.vasm`, so that a program the passes break can be bisected by comparing the files.
+    /// The files go in a `minivm-passes` directory inside `dir`, from which the files of an earlier dump are
+    /// removed first. Dumps are written alongside any callback of [`dump_ir`](PassManager::dump_ir).
+    ///
+    /// The passes carry on if a file can't be written; the first such error is kept for
+    /// [`take_dump_error`](PassManager::take_dump_error).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory can't be created, or a file of an earlier dump can't be removed.
+    pub fn dump_after_each(&mut self, dir: impl AsRef<Path>) -> io::Result<&mut Self> {
+        let dir = dir.as_ref().join(DUMP_DIR);
+        fs::create_dir_all(&dir)?;
+        for path in crate::corpus::files_with_extension(&dir, crate::corpus::EXTENSION)? {
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default();
+            let is_dump = name.split_once('-').is_some_and(|(count, _)| {
+                !count.is_empty() && count.bytes().all(|b| b.is_ascii_digit())
+            });
+            if is_dump {
+                fs::remove_file(path)?;
+            }
+        }
+        self.dump_dir = Some(DumpDir {
+            dir,
+            count: 0,
+            error: None,
+        });
+        Ok(self)
+    }
+
+    /// The first error writing a file of [`dump_after_each`](PassManager::dump_after_each) since the last
+    /// call, if any.
+    pub fn take_dump_error(&mut self) -> Option<io::Error> {
+        self.dump_dir.as_mut()?.error.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opt::{fold_constants, propagate_copies};
+
+    #[test]
+    fn test_dump_after_each() {
+        let dir = std::env::temp_dir().join(format!("minivm-passes-{}", std::process::id()));
+        let mut asm = crate::parse::parse(
+            "func main\n    r1 <- int 2\n    r2 <- reg r1\n    r0 <- add r2 r2\n    exit\nend",
+        )
+        .unwrap();
+        let dumps = dir.join(DUMP_DIR);
+        fs::create_dir_all(&dumps).unwrap();
+        fs::write(dumps.join("009-stale.vasm"), "").unwrap();
+        fs::write(dumps.join("kept.vasm"), "").unwrap();
+        fs::write(dir.join("001-mine.vasm"), "").unwrap();
+
+        let mut manager = PassManager::new();
+        manager
+            .label_pass("propagate_copies", propagate_copies)
+            .label_pass("fold_constants", fold_constants)
+            .to_fixpoint(10)
+            .dump_after_each(&dir)
+            .unwrap();
+        manager.run(&mut asm);
+        assert!(manager.take_dump_error().is_none());
+
+        assert!(dir.join("001-mine.vasm").exists());
+        let mut files: Vec<String> = fs::read_dir(&dumps)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "001-propagate_copies.vasm",
+                "002-fold_constants.vasm",
+                "003-propagate_copies.vasm",
+                "004-fold_constants.vasm",
+                "kept.vasm"
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dumps.join("001-propagate_copies.vasm")).unwrap(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func main
+    r1 <- int 2
+    r0 <- add r1 r1
+    exit
+end"
+        );
+
+        // A dump that can't be written is reported, without stopping the passes.
+        fs::remove_dir_all(&dumps).unwrap();
+        manager.run(&mut asm);
+        assert_eq!(
+            manager.take_dump_error().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(manager.take_dump_error().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+//! Reading programs back from the MiniVM text format.
+
+#[cfg(feature = "float")]
+use crate::instr::Float;
+use crate::{
+    asm::{Asm, Label, LabelImpl, SubLabel},
+    instr::{Instruction, Interner, OpCode, Operand},
+    target::Version,
+    Int,
+};
+use std::fmt;
+
+const ENTRY_LABEL: &str = "@__entry";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The 1-based line the error was found on.
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnknownOpCode(String),
+    /// A register past `r255`.
+    InvalidRegister(String),
+    /// A line that can't appear where it does, like an instruction outside of a function.
+    UnexpectedLine(String),
+    /// A sub-label that doesn't belong to the function it is in.
+    ForeignSubLabel {
+        function: String,
+        sub_label: String,
+    },
+    /// A function that is still open at the end of the text.
+    UnterminatedFunction(String),
+    DuplicateMain,
+    MissingMain,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownOpCode(op) => write!(f, "unknown opcode `{op}`"),
+            ParseErrorKind::InvalidRegister(reg) => write!(f, "invalid register `{reg}`"),
+            ParseErrorKind::UnexpectedLine(line) => write!(f, "unexpected line `{line}`"),
+            ParseErrorKind::ForeignSubLabel {
+                function,
+                sub_label,
+            } => write!(f, "sub-label `{sub_label}` is not part of `{function}`"),
+            ParseErrorKind::UnterminatedFunction(name) => {
+                write!(f, "function `{name}` is missing its `end`")
+            }
+            ParseErrorKind::DuplicateMain => f.write_str("`main` is defined more than once"),
+            ParseErrorKind::MissingMain => f.write_str("`main` is not defined"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a whole program, as emitted by [`Asm::finish`].
+///
+/// If the `@__entry` block is left out, the program gets the standard one, calling `main`. `main` may only be
+/// left out if the entry block doesn't call it. Instructions are parsed into their structured form, so
+/// formatting is normalized when the program is emitted again. A [version header](Asm::finish_with_header)
+/// on the first line is read into the program.
+///
+/// # Errors
+///
+/// Returns the first line that isn't valid MiniVM, or that the program model can't represent.
+pub fn parse(text: &str) -> Result<Asm, ParseError> {
+    parse_into(Asm::new(), text)
+}
+
+/// Parse a [library](Asm::new_library) named `id`, made of functions only.
+///
+/// # Errors
+///
+/// Returns the first line that isn't valid MiniVM, or that a library can't contain, like `func main` or the
+/// `@__entry` block.
+pub fn parse_library(id: &str, text: &str) -> Result<Asm, ParseError> {
+    parse_into(Asm::new_library(id), text)
+}
+
+fn parse_into(mut asm: Asm, text: &str) -> Result<Asm, ParseError> {
+    let mut main = None;
+    let mut has_entry = false;
+    let mut in_entry = false;
+    let mut function: Option<(Label, usize)> = None;
+    let mut first = true;
+    let mut names = Interner::default();
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let error = |kind| ParseError { line: number, kind };
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if std::mem::take(&mut first) {
+            if let Some(version) = Version::from_header(trimmed) {
+                asm.set_version(Some(version));
+                continue;
+            }
+        }
+
+        if let Some((label, start)) = function.take_if(|_| trimmed == "end") {
+            if label.name() != "main" {
+                asm.push_label(label);
+            } else if main.replace(label).is_some() {
+                return Err(ParseError {
+                    line: start,
+                    kind: ParseErrorKind::DuplicateMain,
+                });
+            }
+        } else if let Some((label, _)) = &mut function {
+            if let Some(name) = trimmed.strip_prefix('@') {
+                let sub_label = name
+                    .strip_prefix(label.name())
+                    .and_then(|name| name.strip_prefix('.'))
+                    .ok_or_else(|| {
+                        error(ParseErrorKind::ForeignSubLabel {
+                            function: label.name().to_string(),
+                            sub_label: name.to_string(),
+                        })
+                    })?;
+                let sub_label = SubLabel::new(label.name(), sub_label);
+                label.push_sub_label(sub_label);
+            } else {
+                let mut instr = parse_instruction(line).map_err(error)?;
+                names.share(&mut instr);
+                let block: &mut LabelImpl = match label.sub_labels_mut().last_mut() {
+                    Some(sub_label) => sub_label,
+                    None => label,
+                };
+                block.push_instruction(instr);
+            }
+        } else if let Some(name) = trimmed.strip_prefix("func ") {
+            if asm.is_library() && name.trim() == "main" {
+                return Err(error(ParseErrorKind::UnexpectedLine(trimmed.to_string())));
+            }
+            in_entry = false;
+            function = Some((Label::new(name.trim()), number));
+        } else if trimmed == ENTRY_LABEL && !has_entry && !asm.is_library() {
+            asm.entry_mut().lines_mut().clear();
+            has_entry = true;
+            in_entry = true;
+        } else if in_entry {
+            let mut instr = parse_instruction(line).map_err(error)?;
+            names.share(&mut instr);
+            asm.entry_mut().push_instruction(instr);
+        } else {
+            return Err(error(ParseErrorKind::UnexpectedLine(trimmed.to_string())));
+        }
+    }
+
+    let end = text.lines().count();
+    if let Some((label, _)) = function {
+        return Err(ParseError {
+            line: end,
+            kind: ParseErrorKind::UnterminatedFunction(label.name().to_string()),
+        });
+    }
+    if asm.is_library() {
+        return Ok(asm);
+    }
+    let calls_main = asm
+        .entry()
+        .instructions()
+        .any(|instr| instr.targets().any(|target| target == "main"));
+    match main {
+        Some(main) => *asm.main() = main,
+        None if calls_main => {
+            return Err(ParseError {
+                line: end,
+                kind: ParseErrorKind::MissingMain,
+            })
+        }
+        None => {}
+    }
+    Ok(asm)
+}
+
+/// Parse a single instruction, `[rX <- ]op operand...`.
+///
+/// Operands are told apart by their form alone: `rN` is a register, a number is an integer, and `:` starts
+/// a string that runs to the end of the line. Anything else is a label.
+///
+/// # Errors
+///
+/// Returns an error if the opcode is unknown or a register is out of range.
+pub fn parse_instruction(line: &str) -> Result<Instruction, ParseErrorKind> {
+    let line = line.trim_start().trim_end_matches(['\r', '\n']);
+    let (dest, rest) = match line.split_once("<-") {
+        Some((dest, rest)) if is_register(dest.trim()) => (Some(dest.trim()), rest.trim_start()),
+        _ => (None, line),
+    };
+    let dest = dest.map(parse_register).transpose()?;
+
+    let (op, mut rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let op =
+        OpCode::from_mnemonic(op).ok_or_else(|| ParseErrorKind::UnknownOpCode(op.to_string()))?;
+
+    let mut operands = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(text) = rest.strip_prefix(':') {
+            operands.push(Operand::Str(text.to_string()));
+            break;
+        }
+        let Some(token) = rest.split_whitespace().next() else {
+            break;
+        };
+        rest = &rest[token.len()..];
+        let operand = if is_register(token) {
+            Operand::Reg(parse_register(token)?)
+        } else if let Ok(value) = token.parse::<Int>() {
+            Operand::Int(value)
+        } else if op == OpCode::ExternCall && operands.is_empty() {
+            // The function of `xcall` is named like a label, but isn't one.
+            Operand::Extern(token.to_string())
+        } else {
+            Operand::Label(token.into())
+        };
+        // Only `fint` takes a float, so `inf` and `NaN` stay labels elsewhere.
+        #[cfg(feature = "float")]
+        let operand = match token.parse() {
+            Ok(value) if op == OpCode::FInt => Operand::Float(Float(value)),
+            _ => operand,
+        };
+        operands.push(operand);
+    }
+    Ok(Instruction::new(op, dest, operands))
+}
+
+fn is_register(token: &str) -> bool {
+    token
+        .strip_prefix('r')
+        .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn parse_register(token: &str) -> Result<u8, ParseErrorKind> {
+    token[1..]
+        .parse()
+        .map_err(|_| ParseErrorKind::InvalidRegister(token.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let text = r"@__entry
+    r0 <- call main
+    exit
+
+func putn
+    bb r1 putn.ret putn.digit
+@putn.digit
+    r0 <- int -10
+    r0 <- div r1 r0
+    r0 <- call putn r0
+    putchar r1
+@putn.ret
+    r0 <- str :hello, world
+    ret r0
+end
+
+func main
+    r0 <- int 35
+    r0 <- call putn r0
+    exit
+end";
+        let asm = parse(text).unwrap();
+        assert_eq!(asm.labels().len(), 1);
+        assert_eq!(asm.finish(), text);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("func main\n    r0 <- nop r1\nend"),
+            "line 2: unknown opcode `nop`"
+        );
+        assert_eq!(
+            error("func main\n    r256 <- int 0\nend"),
+            "line 2: invalid register `r256`"
+        );
+        assert_eq!(
+            error("func f\n@g.then\nend"),
+            "line 2: sub-label `g.then` is not part of `f`"
+        );
+        assert_eq!(error("func f\nend"), "line 2: `main` is not defined");
+
+        let library_error = |text| parse_library("lib", text).unwrap_err().to_string();
+        assert_eq!(
+            library_error("func main\n    exit\nend"),
+            "line 1: unexpected line `func main`"
+        );
+        assert_eq!(
+            library_error("@__entry\n    exit"),
+            "line 1: unexpected line `@__entry`"
+        );
+        assert!(parse_library("lib", "func f\n    ret r1\nend")
+            .unwrap()
+            .is_library());
+    }
+
+    #[test]
+    fn test_parse_version_header() {
+        let text = r"# minivm 0.2
+@__entry
+    r0 <- call main
+    exit
+
+func main
+    exit
+end";
+        let asm = parse(text).unwrap();
+        assert_eq!(asm.version(), Some(Version::new(0, 2)));
+        assert!(!asm.to_string().starts_with('#'));
+        assert_eq!(asm.finish_with_header(), text);
+
+        assert_eq!(parse("func main\n    exit\nend").unwrap().version(), None);
+        assert_eq!(
+            parse("func main\n    exit\nend\n# minivm 0.2")
+                .unwrap_err()
+                .to_string(),
+            "line 4: unexpected line `# minivm 0.2`"
+        );
+    }
+
+    #[test]
+    fn test_parse_custom_entry() {
+        let text = r"@__entry
+    r1 <- int 3
+    r0 <- call start r1
+    exit
+
+func start
+    putchar r1
+    ret r1
+end";
+        let asm = parse(text).unwrap();
+        assert!(!asm.has_standard_entry());
+        assert_eq!(asm.finish(), text);
+    }
+}
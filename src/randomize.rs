@@ -0,0 +1,296 @@
+//! Seeded, semantics-preserving randomization of program layout.
+//!
+//! Used to check that consumers of generated programs don't depend on the order of functions and blocks,
+//! or on which registers hold which values.
+
+use crate::{
+    analysis::{self, RegSet},
+    asm::{Asm, Label, LabelImpl, Line},
+    builder::Reg,
+    instr::{Instruction, OpCode, Operand},
+};
+use std::collections::HashMap;
+
+/// Shuffle the functions of `asm` (other than `main`, which stays last), the sub-labels of every function,
+/// and the registers used by every function. The same seed always produces the same layout.
+///
+/// Fallthrough from one block to the next is replaced by an explicit `jump`. If a function runs off its end,
+/// its last block stays last, and the functions keep their order. Registers that may hold arguments on
+/// entry are never renamed, nor are the registers of functions that share their frame through jumps between
+/// functions or label addresses, or that contain raw lines.
+pub fn layout(asm: &mut Asm, seed: u64) {
+    let mut rng = SplitMix64::new(seed);
+
+    let addressed: Vec<String> = asm
+        .iter()
+        .flat_map(Label::blocks)
+        .flat_map(LabelImpl::instructions)
+        .filter(|instr| instr.op == OpCode::Addr)
+        .flat_map(Instruction::targets)
+        .map(str::to_string)
+        .collect();
+    let jump_targets: Vec<String> = asm
+        .iter()
+        .flat_map(|label| {
+            let names = block_names(label);
+            label
+                .blocks()
+                .flat_map(LabelImpl::instructions)
+                .filter(|instr| instr.op != OpCode::Call && instr.op != OpCode::Addr)
+                .flat_map(Instruction::targets)
+                .filter(move |target| !names.iter().any(|name| name == target))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut falls_off_end = false;
+    for label in asm.iter_mut() {
+        let names = block_names(label);
+        let shares_frame = names
+            .iter()
+            .any(|name| addressed.contains(name) || jump_targets.contains(name));
+        if !shares_frame && is_self_contained(label, &names) {
+            rename_registers(label, &mut rng);
+        }
+        if make_fallthrough_explicit(label) {
+            rng.shuffle(label.sub_labels_mut());
+        } else {
+            // The last block runs into whatever follows the function, so it has to stay last.
+            falls_off_end = true;
+            let sub_labels = label.sub_labels_mut();
+            let len = sub_labels.len().saturating_sub(1);
+            rng.shuffle(&mut sub_labels[..len]);
+        }
+    }
+
+    if !falls_off_end {
+        rng.shuffle(asm.labels_mut());
+    }
+}
+
+fn block_names(label: &Label) -> Vec<String> {
+    label
+        .blocks()
+        .map(|block| block.name().to_string())
+        .collect()
+}
+
+/// Whether the function has no raw lines, and control never leaves it other than by returning.
+fn is_self_contained(label: &Label, names: &[String]) -> bool {
+    label
+        .blocks()
+        .flat_map(LabelImpl::lines)
+        .all(|line| match line {
+            Line::Raw(_) => false,
+            Line::Instruction(instr) => match instr.op {
+                OpCode::DJump => false,
+                op if op == OpCode::Jump || op.is_branch() => instr
+                    .targets()
+                    .all(|target| names.iter().any(|name| name == target)),
+                _ => true,
+            },
+        })
+}
+
+fn ends_in_terminator(block: &LabelImpl) -> bool {
+    matches!(block.lines().last(), Some(Line::Instruction(instr)) if instr.op.is_terminator())
+}
+
+/// Append a `jump` to every block that falls through into the next one.
+/// Returns whether the last block ends in a terminator.
+fn make_fallthrough_explicit(label: &mut Label) -> bool {
+    let names = block_names(label);
+    let mut blocks: Vec<&mut LabelImpl> = label.blocks_mut().collect();
+    let last = blocks.len() - 1;
+    for (i, block) in blocks.iter_mut().enumerate().take(last) {
+        if !ends_in_terminator(block) {
+            let jump = Instruction::new(
+                OpCode::Jump,
+                None,
+                vec![Operand::Label(names[i + 1].as_str().into())],
+            );
+            block.push_instruction(jump);
+        }
+    }
+    ends_in_terminator(blocks[last])
+}
+
+fn rename_registers(label: &mut Label, rng: &mut SplitMix64) {
+    let live_on_entry = analysis::liveness(label).live_on_entry();
+    let mut renamable = RegSet::new();
+    for instr in label.blocks().flat_map(LabelImpl::instructions) {
+        for reg in instr.dest.into_iter().chain(instr.uses()) {
+            if !live_on_entry.contains(reg) {
+                renamable.insert(reg);
+            }
+        }
+    }
+
+    let from: Vec<Reg> = renamable.iter().collect();
+    let mut to = from.clone();
+    rng.shuffle(&mut to);
+    let mapping: HashMap<Reg, Reg> = from.into_iter().zip(to).collect();
+    let rename = |reg: &mut Reg| {
+        if let Some(&renamed) = mapping.get(reg) {
+            *reg = renamed;
+        }
+    };
+
+    for block in label.blocks_mut() {
+        for line in block.lines_mut() {
+            if let Line::Instruction(instr) = line {
+                if let Some(dest) = &mut instr.dest {
+                    rename(dest);
+                }
+                for operand in &mut instr.operands {
+                    if let Operand::Reg(reg) = operand {
+                        rename(reg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A small, seedable pseudo-random number generator. Not suitable for cryptography.
+#[derive(Clone, Debug)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    #[must_use]
+    pub fn new(seed: u64) -> SplitMix64 {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`. Panics if `bound` is 0.
+    pub fn below(&mut self, bound: usize) -> usize {
+        let bound = u64::try_from(bound).unwrap_or(u64::MAX);
+        usize::try_from(self.next_u64() % bound).unwrap_or(0)
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    fn putn() -> Asm {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .integer(35, 0)
+                .label_call("putn", &[0], 0)
+                .exit()
+        });
+
+        builder.label("putn", |putn_builder| {
+            putn_builder
+                .branch_boolean(1, "putn.digit", "putn.ret")
+                .sub_label("digit", |putn_digit_builder| {
+                    putn_digit_builder
+                        .integer(10, 0)
+                        .div(1, 0, 0)
+                        .label_call("putn", &[0], 0)
+                        .integer(10, 0)
+                        .mod_(1, 0, 1)
+                        .integer(48, 0)
+                        .add(1, 0, 1)
+                        .put_char(1)
+                })
+                .sub_label("ret", |putn_ret_builder| {
+                    putn_ret_builder.integer(0, 0).return_(0)
+                })
+        });
+
+        builder.finish()
+    }
+
+    #[test]
+    fn test_layout_is_reproducible() {
+        let mut first = putn();
+        let mut second = putn();
+        layout(&mut first, 42);
+        layout(&mut second, 42);
+
+        assert_eq!(first.finish(), second.finish());
+    }
+
+    #[test]
+    fn test_layout_makes_fallthrough_explicit() {
+        let mut asm = putn();
+        layout(&mut asm, 7);
+
+        let putn = &asm.labels()[0];
+        let digit = putn
+            .sub_labels()
+            .iter()
+            .find(|sub_label| sub_label.name() == "putn.digit")
+            .unwrap();
+        assert_eq!(digit.lines().last().unwrap().to_string(), "jump putn.ret");
+    }
+
+    #[test]
+    fn test_layout_keeps_arguments_in_place() {
+        for seed in 0..16 {
+            let mut asm = putn();
+            layout(&mut asm, seed);
+
+            let putn = &asm.labels()[0];
+            assert_eq!(putn.lines()[0].to_string(), "bb r1 putn.ret putn.digit");
+        }
+    }
+
+    #[test]
+    fn test_layout_keeps_last_block_falling_off_end() {
+        let build = || {
+            let mut builder = AsmBuilder::new();
+            builder.main(|main_builder| main_builder.label_call("f", &[], 0).exit());
+            builder.label("f", |f_builder| {
+                f_builder
+                    .integer(65, 3)
+                    .put_char(3)
+                    .integer(66, 4)
+                    .sub_label("b", |b_builder| b_builder.put_char(4).integer(67, 5))
+                    .sub_label("c", |c_builder| c_builder.put_char(5))
+            });
+            builder.finish()
+        };
+        for seed in 0..16 {
+            let mut asm = build();
+            layout(&mut asm, seed);
+
+            let f = asm.iter().find(|label| label.name() == "f").unwrap();
+            assert_eq!(f.sub_labels().last().unwrap().name(), "f.c");
+            #[cfg(feature = "interp")]
+            {
+                let config = crate::interp::Config {
+                    max_steps: Some(1000),
+                    ..crate::interp::Config::default()
+                };
+                let result = crate::interp::run(&asm, &config);
+                assert_eq!(result.output_string(), "ABC");
+                assert_eq!(
+                    result.result.unwrap_err().kind,
+                    crate::interp::TrapKind::FellOffEnd("f".to_string())
+                );
+            }
+        }
+    }
+}
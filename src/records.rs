@@ -0,0 +1,156 @@
+#![allow(clippy::module_name_repetitions, clippy::missing_panics_doc)]
+
+use crate::{
+    builder::{BuildInstruction, Reg},
+    ArrayIndex, ArrayLen, Int,
+};
+
+/// Layout of a record: an array with one element per named field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    name: String,
+    fields: Vec<String>,
+}
+
+impl Record {
+    /// Panics if a field name is repeated.
+    #[must_use]
+    pub fn new<I>(name: &str, fields: I) -> Record
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let fields: Vec<String> = fields.into_iter().map(Into::into).collect();
+        for (i, field) in fields.iter().enumerate() {
+            assert!(
+                !fields[..i].contains(field),
+                "duplicate field `{field}` in record `{name}`"
+            );
+        }
+        Self {
+            name: name.to_string(),
+            fields,
+        }
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[must_use]
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    #[must_use]
+    pub fn len(&self) -> ArrayLen {
+        ArrayLen::try_from(self.fields.len()).expect("too many fields in record")
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    #[must_use]
+    pub fn field_index(&self, field: &str) -> Option<ArrayIndex> {
+        let index = self.fields.iter().position(|f| f == field)?;
+        Some(ArrayIndex::try_from(index).expect("too many fields in record"))
+    }
+
+    fn index_of(&self, field: &str) -> Int {
+        match self.field_index(field) {
+            Some(index) => Int::from(index),
+            None => panic!("record `{}` has no field `{field}`", self.name),
+        }
+    }
+}
+
+/// Builder methods for accessing [`Record`]s by field name.
+pub trait RecordExt: BuildInstruction {
+    /// Store a new record with the layout `record` into `rX`.
+    #[track_caller]
+    fn alloc_record(&mut self, record: &Record, to: Reg) -> &mut Self {
+        self.integer(Int::from(record.len()), to).array(to, to)
+    }
+
+    /// Store into `rX` the field `field` of the record in `rY`.
+    ///
+    /// Panics if `record` has no such field, or if `rX` and `rY` are the same register.
+    #[track_caller]
+    fn get_field(&mut self, record: &Record, rec: Reg, field: &str, to: Reg) -> &mut Self {
+        assert_ne!(
+            rec, to,
+            "cannot load a field into the record's own register"
+        );
+        self.integer(record.index_of(field), to)
+            .get_array_index(rec, to, to)
+    }
+
+    /// Store `rZ` into the field `field` of the record in `rX`, using `rW` to hold the field's index.
+    ///
+    /// Panics if `record` has no such field, or if `rW` is the same register as `rX` or `rZ`.
+    #[track_caller]
+    fn set_field(
+        &mut self,
+        record: &Record,
+        rec: Reg,
+        field: &str,
+        value: Reg,
+        scratch: Reg,
+    ) -> &mut Self {
+        assert!(
+            scratch != rec && scratch != value,
+            "scratch register must differ from the record and value registers"
+        );
+        self.integer(record.index_of(field), scratch)
+            .set_array_index(rec, scratch, value)
+    }
+}
+
+impl<T: BuildInstruction> RecordExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LabelBuilder;
+
+    #[test]
+    fn test_record_builder_build() {
+        let point = Record::new("point", ["x", "y"]);
+
+        let mut builder = LabelBuilder::new("swap_point");
+        builder
+            .get_field(&point, 1, "x", 2)
+            .get_field(&point, 1, "y", 3)
+            .alloc_record(&point, 0)
+            .set_field(&point, 0, "x", 3, 4)
+            .set_field(&point, 0, "y", 2, 4)
+            .return_(0);
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func swap_point
+    r2 <- int 0
+    r2 <- get r1 r2
+    r3 <- int 1
+    r3 <- get r1 r3
+    r0 <- int 2
+    r0 <- arr r0
+    r4 <- int 0
+    set r0 r4 r3
+    r4 <- int 1
+    set r0 r4 r2
+    ret r0
+end"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "record `point` has no field `z`")]
+    fn test_get_unknown_field_panics() {
+        let point = Record::new("point", ["x", "y"]);
+        LabelBuilder::new("f").get_field(&point, 1, "z", 0);
+    }
+}
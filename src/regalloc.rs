@@ -0,0 +1,679 @@
+//! Functions written against unlimited virtual registers, allocated onto MiniVM registers.
+//!
+//! Allocation is a linear scan over live intervals computed from block liveness. Values that don't fit
+//! in the register budget are spilled to an array created on entry, and reloaded around each use.
+
+#![allow(clippy::module_name_repetitions, clippy::missing_panics_doc)]
+
+use crate::{
+    asm::{self, UserSpan},
+    builder::{
+        impl_build_instruction, label_operand, reg_operands, sequential_moves, Deferred, Lbl, Reg,
+    },
+    instr::{Instruction, OpCode, Operand, Register},
+    runtime::{RequireRuntime, Runtime},
+    BuildInstruction, Int,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// A virtual register, written `vN`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VReg(u32);
+
+impl Register for VReg {
+    const PREFIX: char = 'v';
+
+    fn index(self) -> u32 {
+        self.0
+    }
+}
+
+struct Block {
+    name: Option<String>,
+    instructions: Vec<Instruction<VReg>>,
+}
+
+/// Builds a function over [`VReg`]s, allocating MiniVM registers for them once finished.
+///
+/// Parameters are found in [`param`](VirtualRegBuilder::param) registers rather than `r1..rN`.
+pub struct VirtualRegBuilder {
+    name: String,
+    arity: u8,
+    next: u32,
+    blocks: Vec<Block>,
+    registers: u16,
+    fallthroughs: usize,
+    deferred: Deferred,
+}
+
+impl VirtualRegBuilder {
+    #[must_use]
+    pub fn new(name: &str, arity: u8) -> VirtualRegBuilder {
+        Self {
+            name: name.to_string(),
+            arity,
+            next: u32::from(arity),
+            blocks: vec![Block {
+                name: None,
+                instructions: Vec::new(),
+            }],
+            registers: 256,
+            fallthroughs: 0,
+            deferred: Deferred::default(),
+        }
+    }
+
+    /// The register holding argument `index`, counting from zero.
+    ///
+    /// Panics if the function has no such argument.
+    #[must_use]
+    pub fn param(&self, index: u8) -> VReg {
+        assert!(
+            index < self.arity,
+            "`{}` has only {} parameters",
+            self.name,
+            self.arity
+        );
+        VReg(u32::from(index))
+    }
+
+    /// A register that hasn't been used yet.
+    pub fn fresh(&mut self) -> VReg {
+        let reg = VReg(self.next);
+        self.next += 1;
+        reg
+    }
+
+    /// Only allocate registers `r0` to `r{count - 1}`. Defaults to all 256 registers.
+    pub fn register_budget(&mut self, count: u16) -> &mut Self {
+        self.registers = count.min(256);
+        self
+    }
+
+    pub fn sub_label<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: for<'a> FnOnce(&'a mut VirtualRegBuilder) -> &'a mut VirtualRegBuilder,
+    {
+        self.blocks.push(Block {
+            name: Some(name.to_string()),
+            instructions: Vec::new(),
+        });
+        f(self)
+    }
+
+    /// The full name of the sub-label `name` of the function, like `fib.then` for `then`, to branch to
+    /// without repeating the function's name.
+    #[must_use]
+    pub fn local(&self, name: &str) -> String {
+        format!("{}.{name}", self.name)
+    }
+
+    /// Allocate registers and finish the function.
+    ///
+    /// Any [`Runtime`] required by the function is discarded, and tail calls are not checked;
+    /// use [`AsmBuilder::virtual_label`](crate::AsmBuilder::virtual_label) for both.
+    ///
+    /// Panics if the register budget is too small, even with spilling, or the function reads a
+    /// [constant](crate::BuildInstruction::integer_const), which only [`AsmBuilder`](crate::AsmBuilder) can
+    /// substitute.
+    #[must_use]
+    pub fn finish(self) -> asm::Label {
+        let label = self.finish_with_deferred().0;
+        crate::builder::assert_no_consts(&label);
+        label
+    }
+
+    pub(crate) fn finish_with_deferred(self) -> (asm::Label, Deferred) {
+        let label =
+            Allocator::new(self.name, self.arity, self.next, self.registers).run(self.blocks);
+        (label, self.deferred)
+    }
+
+    /// Spans would not survive register allocation, which moves and adds instructions.
+    #[allow(clippy::unused_self)]
+    fn set_span(&mut self, _span: Option<UserSpan>) {}
+
+    fn write_instruction(&mut self, op: OpCode, dest: Option<VReg>, operands: Vec<Operand<VReg>>) {
+        let block = self.blocks.last_mut().expect("function has an entry block");
+        block.instructions.push(instruction(op, dest, operands));
+    }
+
+    /// Written as a `jump` that also reads its arguments, and lowered once they have registers.
+    fn write_tail_call(&mut self, label: Lbl, args: &[VReg]) {
+        let operands = std::iter::once(label_operand(label))
+            .chain(reg_operands(args))
+            .collect();
+        self.write_instruction(OpCode::Jump, None, operands);
+        self.deferred.tail_calls.push(label.to_string());
+    }
+
+    /// The name of the next sub-label to fall through to.
+    fn fallthrough(&mut self) -> String {
+        self.fallthroughs += 1;
+        format!("{}.cont{}", self.name, self.fallthroughs)
+    }
+
+    fn start_fallthrough(&mut self, name: &str) {
+        self.blocks.push(Block {
+            name: Some(name[self.name.len() + 1..].to_string()),
+            instructions: Vec::new(),
+        });
+    }
+}
+
+fn is_tail_call<R: Register>(instr: &Instruction<R>) -> bool {
+    instr.op == OpCode::Jump && instr.uses().next().is_some()
+}
+
+fn instruction(op: OpCode, dest: Option<VReg>, operands: Vec<Operand<VReg>>) -> Instruction<VReg> {
+    Instruction { op, dest, operands }
+}
+
+impl RequireRuntime for VirtualRegBuilder {
+    fn require_runtime<R: Runtime + 'static>(&mut self, runtime: R) -> &mut Self {
+        self.deferred.require_runtime(Box::new(runtime));
+        self
+    }
+}
+
+impl_build_instruction![VirtualRegBuilder => VReg];
+
+struct Allocator {
+    name: String,
+    arity: u8,
+    next: u32,
+    registers: u16,
+    /// Registers that are never spilled: the incoming arguments, the spill array, and reloaded values.
+    unspillable: HashSet<VReg>,
+    spill_array: Option<VReg>,
+    slots: HashMap<VReg, Int>,
+}
+
+impl Allocator {
+    fn new(name: String, arity: u8, next: u32, registers: u16) -> Allocator {
+        assert!(
+            registers > u16::from(arity),
+            "not enough registers for the parameters of `{name}`"
+        );
+        Self {
+            name,
+            arity,
+            next,
+            registers,
+            unspillable: (0..u32::from(arity)).map(VReg).collect(),
+            spill_array: None,
+            slots: HashMap::new(),
+        }
+    }
+
+    fn fresh(&mut self, spillable: bool) -> VReg {
+        let reg = VReg(self.next);
+        self.next += 1;
+        if !spillable {
+            self.unspillable.insert(reg);
+        }
+        reg
+    }
+
+    fn run(mut self, mut blocks: Vec<Block>) -> asm::Label {
+        // Arguments only stay in `r1..rN` long enough to be moved into ordinary, spillable registers.
+        let copies: Vec<VReg> = (0..self.arity).map(|_| self.fresh(true)).collect();
+        for block in &mut blocks {
+            for instr in std::mem::take(&mut block.instructions) {
+                let instr = instr.map_registers(|reg| {
+                    let param = usize::try_from(reg.0).map_or(None, |i| copies.get(i));
+                    param.copied().unwrap_or(reg)
+                });
+                block.instructions.push(instr);
+            }
+        }
+        let prologue = copies.iter().zip(0..).map(|(&copy, param)| {
+            instruction(OpCode::Reg, Some(copy), vec![Operand::Reg(VReg(param))])
+        });
+        blocks[0].instructions.splice(0..0, prologue);
+
+        let assignment = loop {
+            let intervals = self.intervals(&blocks);
+            match self.linear_scan(&blocks, &intervals) {
+                Ok(assignment) => break assignment,
+                Err(spilled) => self.spill(&mut blocks, &spilled),
+            }
+        };
+
+        if self.spill_array.is_some() {
+            let len = Int::try_from(self.slots.len()).expect("too many spill slots");
+            blocks[0].instructions[0].operands[0] = Operand::Int(len);
+        }
+        self.lower(blocks, &assignment)
+    }
+
+    fn block_names(&self, blocks: &[Block]) -> Vec<String> {
+        blocks
+            .iter()
+            .map(|block| match &block.name {
+                Some(name) => format!("{}.{name}", self.name),
+                None => self.name.clone(),
+            })
+            .collect()
+    }
+
+    /// The first and last position at which each register may be live. Instruction `k` reads its operands
+    /// at `2k + 1` and writes its destination at `2k + 2`; arguments are written at `0`.
+    fn intervals(&self, blocks: &[Block]) -> BTreeMap<VReg, (usize, usize)> {
+        let names = self.block_names(blocks);
+        let mut uses = vec![BTreeSet::new(); blocks.len()];
+        let mut defs = vec![BTreeSet::new(); blocks.len()];
+        let mut successors = vec![Vec::new(); blocks.len()];
+        for (i, block) in blocks.iter().enumerate() {
+            for instr in &block.instructions {
+                for reg in instr.uses() {
+                    if !defs[i].contains(&reg) {
+                        uses[i].insert(reg);
+                    }
+                }
+                defs[i].extend(instr.dest);
+                if instr.op.is_branch() || (instr.op == OpCode::Jump && !is_tail_call(instr)) {
+                    successors[i].extend(
+                        instr
+                            .targets()
+                            .filter_map(|target| names.iter().position(|name| name == target)),
+                    );
+                }
+            }
+            let falls_through =
+                !matches!(block.instructions.last(), Some(instr) if instr.op.is_terminator());
+            if falls_through && i + 1 < blocks.len() {
+                successors[i].push(i + 1);
+            }
+        }
+
+        let mut live_in = uses.clone();
+        let mut live_out = vec![BTreeSet::new(); blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..blocks.len()).rev() {
+                let out: BTreeSet<VReg> = successors[i]
+                    .iter()
+                    .flat_map(|&succ| live_in[succ].iter().copied())
+                    .collect();
+                let live: BTreeSet<VReg> = out
+                    .iter()
+                    .filter(|reg| !defs[i].contains(reg))
+                    .chain(&uses[i])
+                    .copied()
+                    .collect();
+                live_out[i] = out;
+                if live != live_in[i] {
+                    live_in[i] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut intervals = BTreeMap::new();
+        let mut extend = |reg: VReg, pos: usize| {
+            let (start, end) = intervals.entry(reg).or_insert((pos, pos));
+            *start = pos.min(*start);
+            *end = pos.max(*end);
+        };
+        for param in 0..u32::from(self.arity) {
+            extend(VReg(param), 0);
+        }
+        let mut k = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            if block.instructions.is_empty() {
+                continue;
+            }
+            for &reg in &live_in[i] {
+                extend(reg, 2 * k + 1);
+            }
+            for instr in &block.instructions {
+                for reg in instr.uses() {
+                    extend(reg, 2 * k + 1);
+                }
+                if let Some(dest) = instr.dest {
+                    extend(dest, 2 * k + 2);
+                }
+                k += 1;
+            }
+            for &reg in &live_out[i] {
+                extend(reg, 2 * k);
+            }
+        }
+        intervals
+    }
+
+    /// Assign a register to every interval, or return the registers that have to be spilled first.
+    fn linear_scan(
+        &self,
+        blocks: &[Block],
+        intervals: &BTreeMap<VReg, (usize, usize)>,
+    ) -> Result<HashMap<VReg, Reg>, Vec<VReg>> {
+        let hints: HashMap<VReg, VReg> = blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .filter(|instr| instr.op == OpCode::Reg)
+            .filter_map(|instr| Some((instr.dest?, instr.uses().next()?)))
+            .collect();
+
+        let mut order: Vec<(usize, usize, VReg)> = intervals
+            .iter()
+            .map(|(&reg, &(start, end))| (start, end, reg))
+            .collect();
+        order.sort_unstable();
+
+        let mut free: BTreeSet<Reg> = (0..self.registers)
+            .map(|reg| Reg::try_from(reg).expect("at most 256 registers"))
+            .collect();
+        let mut active: Vec<(usize, VReg)> = Vec::new();
+        let mut assignment = HashMap::new();
+        let mut spilled = Vec::new();
+        for (start, end, reg) in order {
+            active.retain(|&(active_end, active_reg)| {
+                let expired = active_end < start;
+                if expired {
+                    free.insert(assignment[&active_reg]);
+                }
+                !expired
+            });
+
+            let phys = if reg.0 < u32::from(self.arity) {
+                let param = Reg::try_from(reg.0 + 1).expect("at most 255 parameters");
+                free.take(&param)
+            } else {
+                hints
+                    .get(&reg)
+                    .and_then(|hint| assignment.get(hint))
+                    .and_then(|hint| free.take(hint))
+                    .or_else(|| free.pop_first())
+            };
+            let phys = if let Some(phys) = phys {
+                phys
+            } else {
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, active_reg))| !self.unspillable.contains(active_reg))
+                    .max_by_key(|(_, &(active_end, _))| active_end);
+                let spillable = !self.unspillable.contains(&reg);
+                match victim {
+                    Some((i, &(active_end, victim))) if active_end > end || !spillable => {
+                        active.remove(i);
+                        spilled.push(victim);
+                        assignment
+                            .remove(&victim)
+                            .expect("active registers are assigned")
+                    }
+                    _ if spillable => {
+                        spilled.push(reg);
+                        continue;
+                    }
+                    _ => panic!("not enough registers to allocate `{}`", self.name),
+                }
+            };
+            assignment.insert(reg, phys);
+            active.push((end, reg));
+        }
+
+        if spilled.is_empty() {
+            Ok(assignment)
+        } else {
+            Err(spilled)
+        }
+    }
+
+    /// Keep `spilled` in the spill array, loading it into a new register before each read
+    /// and storing it after each write.
+    fn spill(&mut self, blocks: &mut [Block], spilled: &[VReg]) {
+        let array = if let Some(array) = self.spill_array {
+            array
+        } else {
+            let array = self.fresh(false);
+            let create = [
+                instruction(OpCode::Int, Some(array), vec![Operand::Int(0)]),
+                instruction(OpCode::Arr, Some(array), vec![Operand::Reg(array)]),
+            ];
+            blocks[0].instructions.splice(0..0, create);
+            *self.spill_array.insert(array)
+        };
+        for &reg in spilled {
+            let slot = Int::try_from(self.slots.len()).expect("too many spill slots");
+            self.slots.insert(reg, slot);
+        }
+
+        for block in blocks {
+            for mut instr in std::mem::take(&mut block.instructions) {
+                let mut reloaded: Vec<(VReg, VReg)> = Vec::new();
+                for operand in &mut instr.operands {
+                    let Operand::Reg(reg) = operand else { continue };
+                    if !spilled.contains(reg) {
+                        continue;
+                    }
+                    let load =
+                        if let Some(&(_, load)) = reloaded.iter().find(|(from, _)| from == reg) {
+                            load
+                        } else {
+                            let load = self.fresh(false);
+                            block.instructions.extend([
+                                instruction(
+                                    OpCode::Int,
+                                    Some(load),
+                                    vec![Operand::Int(self.slots[reg])],
+                                ),
+                                instruction(
+                                    OpCode::Get,
+                                    Some(load),
+                                    vec![Operand::Reg(array), Operand::Reg(load)],
+                                ),
+                            ]);
+                            reloaded.push((*reg, load));
+                            load
+                        };
+                    *reg = load;
+                }
+
+                match instr.dest {
+                    Some(dest) if spilled.contains(&dest) => {
+                        let value = self.fresh(false);
+                        let index = self.fresh(false);
+                        instr.dest = Some(value);
+                        block.instructions.extend([
+                            instr,
+                            instruction(
+                                OpCode::Int,
+                                Some(index),
+                                vec![Operand::Int(self.slots[&dest])],
+                            ),
+                            instruction(
+                                OpCode::Set,
+                                None,
+                                vec![
+                                    Operand::Reg(array),
+                                    Operand::Reg(index),
+                                    Operand::Reg(value),
+                                ],
+                            ),
+                        ]);
+                    }
+                    _ => block.instructions.push(instr),
+                }
+            }
+        }
+    }
+
+    fn lower(&self, blocks: Vec<Block>, assignment: &HashMap<VReg, Reg>) -> asm::Label {
+        let mut label = asm::Label::new(&self.name);
+        for block in blocks {
+            let mut lines = Vec::new();
+            for instr in block.instructions {
+                let instr = instr.map_registers(|reg| assignment[&reg]);
+                if is_tail_call(&instr) {
+                    let args: Vec<Reg> = instr.uses().collect();
+                    for (from, to) in sequential_moves(&args) {
+                        lines.push(Instruction::new(
+                            OpCode::Reg,
+                            Some(to),
+                            vec![Operand::Reg(from)],
+                        ));
+                    }
+                    lines.push(Instruction::new(
+                        OpCode::Jump,
+                        None,
+                        vec![instr.operands[0].clone()],
+                    ));
+                } else if !(instr.op == OpCode::Reg && instr.uses().eq(instr.dest)) {
+                    lines.push(instr);
+                }
+            }
+
+            match block.name {
+                None => {
+                    for instr in lines {
+                        label.push_instruction(instr);
+                    }
+                }
+                Some(name) => {
+                    let mut sub_label = asm::SubLabel::new(&self.name, &name);
+                    for instr in lines {
+                        sub_label.push_instruction(instr);
+                    }
+                    label.push_sub_label(sub_label);
+                }
+            }
+        }
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fib() -> VirtualRegBuilder {
+        let mut builder = VirtualRegBuilder::new("fib", 1);
+        let n = builder.param(0);
+        let [two, one, a, b] = [(); 4].map(|()| builder.fresh());
+        builder
+            .integer(2, two)
+            .branch_less_than(n, two, "fib.then", "fib.else")
+            .sub_label("then", |then| then.return_(n))
+            .sub_label("else", |else_| {
+                else_
+                    .integer(1, one)
+                    .sub(n, one, a)
+                    .sub(a, one, b)
+                    .label_call("fib", &[a], a)
+                    .label_call("fib", &[b], b)
+                    .add(a, b, a)
+                    .return_(a)
+            });
+        builder
+    }
+
+    #[test]
+    fn test_virtual_reg_builder_build() {
+        assert_eq!(
+            fib().finish().finish(),
+            r"func fib
+    r0 <- int 2
+    blt r1 r0 fib.else fib.then
+@fib.then
+    ret r1
+@fib.else
+    r0 <- int 1
+    r1 <- sub r1 r0
+    r0 <- sub r1 r0
+    r1 <- call fib r1
+    r0 <- call fib r0
+    r1 <- add r1 r0
+    ret r1
+end"
+        );
+    }
+
+    #[test]
+    fn test_spill_to_array() {
+        let mut builder = VirtualRegBuilder::new("sum", 1);
+        let n = builder.param(0);
+        let [one, two, three, four] = [(); 4].map(|()| builder.fresh());
+        builder
+            .register_budget(4)
+            .integer(1, one)
+            .integer(2, two)
+            .integer(3, three)
+            .integer(4, four)
+            .add(one, n, one)
+            .add(two, n, two)
+            .add(three, n, three)
+            .add(four, n, four)
+            .add(one, two, one)
+            .add(one, three, one)
+            .add(one, four, one)
+            .return_(one);
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"func sum
+    r0 <- int 4
+    r0 <- arr r0
+    r2 <- int 1
+    r3 <- int 0
+    set r0 r3 r2
+    r2 <- int 2
+    r3 <- int 3
+    set r0 r3 r2
+    r2 <- int 3
+    r3 <- int 2
+    set r0 r3 r2
+    r2 <- int 4
+    r3 <- int 1
+    set r0 r3 r2
+    r2 <- int 0
+    r2 <- get r0 r2
+    r2 <- add r2 r1
+    r3 <- int 0
+    set r0 r3 r2
+    r2 <- int 3
+    r2 <- get r0 r2
+    r2 <- add r2 r1
+    r3 <- int 3
+    set r0 r3 r2
+    r2 <- int 2
+    r2 <- get r0 r2
+    r2 <- add r2 r1
+    r3 <- int 2
+    set r0 r3 r2
+    r2 <- int 1
+    r2 <- get r0 r2
+    r1 <- add r2 r1
+    r2 <- int 1
+    set r0 r2 r1
+    r1 <- int 0
+    r1 <- get r0 r1
+    r2 <- int 3
+    r2 <- get r0 r2
+    r1 <- add r1 r2
+    r2 <- int 0
+    set r0 r2 r1
+    r1 <- int 0
+    r1 <- get r0 r1
+    r2 <- int 2
+    r2 <- get r0 r2
+    r1 <- add r1 r2
+    r2 <- int 0
+    set r0 r2 r1
+    r1 <- int 0
+    r1 <- get r0 r1
+    r2 <- int 1
+    r2 <- get r0 r2
+    r1 <- add r1 r2
+    r2 <- int 0
+    set r0 r2 r1
+    r1 <- int 0
+    r1 <- get r0 r1
+    ret r1
+end"
+        );
+    }
+}
@@ -0,0 +1,393 @@
+#![allow(clippy::module_name_repetitions, clippy::missing_panics_doc)]
+
+use crate::{
+    builder::{AsmBuilder, BuildInstruction, LabelBuilder, Reg},
+    BuilderExt, Int,
+};
+
+/// A set of helper functions that is injected into a program at most once.
+///
+/// Runtimes are requested from label builders using [`RequireRuntime::require_runtime`],
+/// and are emitted by [`AsmBuilder::finish`] after all other labels. They are `Send` so that label builders
+/// requiring them are too.
+pub trait Runtime: Send {
+    /// Name identifying the runtime. Two runtimes with the same name are assumed to be identical.
+    fn name(&self) -> &str;
+
+    /// Build the functions making up the runtime.
+    fn inject(&self, builder: &mut AsmBuilder);
+}
+
+pub trait RequireRuntime {
+    /// Mark `runtime` as used by the program, so that it gets injected when the program is finished.
+    fn require_runtime<R: Runtime + 'static>(&mut self, runtime: R) -> &mut Self;
+
+    /// Whether [`assert_true`](crate::RuntimeBuilderExt::assert_true) writes its check, rather than the
+    /// assertions being [stripped](AsmBuilder::strip_assertions).
+    fn keeps_assertions(&self) -> bool {
+        true
+    }
+}
+
+/// Array-backed stack.
+///
+/// A stack is an array of length 2 holding the number of elements and the backing array,
+/// which doubles in size whenever it is full.
+pub struct Stack;
+
+impl Stack {
+    pub const NEW: &'static str = "stack_new";
+    pub const PUSH: &'static str = "stack_push";
+    pub const POP: &'static str = "stack_pop";
+
+    const INITIAL_CAPACITY: i64 = 4;
+}
+
+impl Runtime for Stack {
+    fn name(&self) -> &'static str {
+        "stack"
+    }
+
+    fn inject(&self, builder: &mut AsmBuilder) {
+        builder.label(Stack::NEW, |new| {
+            new.integer(2, 0)
+                .array(0, 1)
+                .integer(0, 0)
+                .set_array_index(1, 0, 0)
+                .integer(Stack::INITIAL_CAPACITY, 2)
+                .array(2, 2)
+                .integer(1, 0)
+                .set_array_index(1, 0, 2)
+                .return_(1)
+        });
+
+        builder.label(Stack::PUSH, |push| {
+            push.integer(0, 0)
+                .get_array_index(1, 0, 3)
+                .integer(1, 0)
+                .get_array_index(1, 0, 4)
+                .array_length(4, 5)
+                .branch_less_than(3, 5, "stack_push.store", "stack_push.grow")
+                .sub_label("grow", |grow| {
+                    grow.add(5, 5, 6).array(6, 6).integer(0, 7).integer(1, 8)
+                })
+                .sub_label("copy", |copy| {
+                    copy.branch_less_than(7, 5, "stack_push.copy_elem", "stack_push.copied")
+                })
+                .sub_label("copy_elem", |copy_elem| {
+                    copy_elem
+                        .get_array_index(4, 7, 9)
+                        .set_array_index(6, 7, 9)
+                        .add(7, 8, 7)
+                        .label_jump("stack_push.copy")
+                })
+                .sub_label("copied", |copied| {
+                    copied
+                        .integer(1, 0)
+                        .set_array_index(1, 0, 6)
+                        .register_move(6, 4)
+                })
+                .sub_label("store", |store| {
+                    store
+                        .set_array_index(4, 3, 2)
+                        .integer(1, 0)
+                        .add(3, 0, 3)
+                        .integer(0, 0)
+                        .set_array_index(1, 0, 3)
+                        .return_(1)
+                })
+        });
+
+        builder.label(Stack::POP, |pop| {
+            pop.integer(0, 0)
+                .get_array_index(1, 0, 2)
+                .integer(1, 3)
+                .sub(2, 3, 2)
+                .set_array_index(1, 0, 2)
+                .get_array_index(1, 3, 3)
+                .get_array_index(3, 2, 4)
+                .return_(4)
+        });
+    }
+}
+
+/// Copy of a range of elements between arrays.
+///
+/// The function takes the source and destination arrays and the start and end of the range, copies every
+/// element from the start up to but not including the end, and returns the larger of the start and end,
+/// as a loop counting up from the start would leave it.
+pub struct ArrayCopy;
+
+impl ArrayCopy {
+    pub const NAME: &'static str = "array_copy";
+
+    pub(crate) fn label_builder() -> LabelBuilder {
+        let mut copy = LabelBuilder::new(ArrayCopy::NAME);
+        copy.integer(1, 5)
+            .sub_label("loop", |loop_| {
+                loop_.branch_less_than(3, 4, "array_copy.elem", "array_copy.done")
+            })
+            .sub_label("elem", |elem| {
+                elem.get_array_index(1, 3, 6)
+                    .set_array_index(2, 3, 6)
+                    .add(3, 5, 3)
+                    .label_jump("array_copy.loop")
+            })
+            .sub_label("done", |done| done.return_(3));
+        copy
+    }
+}
+
+impl Runtime for ArrayCopy {
+    fn name(&self) -> &'static str {
+        ArrayCopy::NAME
+    }
+
+    fn inject(&self, builder: &mut AsmBuilder) {
+        builder.push_label_builder(ArrayCopy::label_builder());
+    }
+}
+
+/// Constructor for closures capturing a fixed number of registers.
+///
+/// A closure is an array holding the address of its function followed by the captured values.
+/// The constructor takes the address and the values as arguments, and returns the closure.
+pub struct Closure {
+    captured: Reg,
+    name: String,
+}
+
+impl Closure {
+    /// Panics if `captured` is more than 253, as the constructor needs a register for each value.
+    #[must_use]
+    pub fn new(captured: usize) -> Closure {
+        let captured = Reg::try_from(captured)
+            .ok()
+            .filter(|&captured| captured <= Reg::MAX - 2)
+            .expect("too many captured registers");
+        Self {
+            captured,
+            name: format!("closure_new_{captured}"),
+        }
+    }
+}
+
+impl Runtime for Closure {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn inject(&self, builder: &mut AsmBuilder) {
+        // r1 holds the address, and r2 onwards hold the captured values.
+        let index = self.captured + 2;
+        builder.label(&self.name, |new| {
+            new.integer(Int::from(self.captured) + 1, index)
+                .array(index, 0);
+            for value in 1..index {
+                new.integer(Int::from(value - 1), index)
+                    .set_array_index(0, index, value);
+            }
+            new.return_(0)
+        });
+    }
+}
+
+/// A check that a register isn't zero, failing with a fixed message.
+///
+/// The check is a function taking the register's value and returning it unchanged, or printing the message
+/// after [`Assert::MARKER`] on a line of its own and exiting.
+pub struct Assert {
+    message: String,
+    name: String,
+}
+
+impl Assert {
+    /// What every failed assertion prints first, for a harness to recognize it by.
+    pub const MARKER: &'static str = "assertion failed: ";
+
+    #[must_use]
+    pub fn new(message: &str) -> Assert {
+        // FNV-1a, so every message gets its own function, under a name that is a valid label.
+        let hash = message
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        Self {
+            message: message.to_string(),
+            name: format!("__assert_{hash:016x}"),
+        }
+    }
+}
+
+impl Runtime for Assert {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn inject(&self, builder: &mut AsmBuilder) {
+        let ok = format!("{}.ok", self.name);
+        let fail = format!("{}.fail", self.name);
+        builder.label(&self.name, |check| {
+            check
+                .branch_boolean(1, &ok, &fail)
+                .sub_label("fail", |fail| {
+                    fail.put_str(&format!("{}{}\n", Assert::MARKER, self.message), 0)
+                        .exit()
+                })
+                .sub_label("ok", |ok| ok.return_(1))
+        });
+    }
+}
+
+/// Builder methods for working with [`Stack`]s.
+pub trait StackExt: BuildInstruction + RequireRuntime {
+    /// Store a new, empty stack into `rX`.
+    #[track_caller]
+    fn stack_new(&mut self, to: Reg) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.require_runtime(Stack).label_call(Stack::NEW, &[], to)
+    }
+
+    /// Push the contents of `rY` onto the stack in `rX`.
+    #[track_caller]
+    fn stack_push(&mut self, stack: Reg, value: Reg) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.require_runtime(Stack)
+            .label_call(Stack::PUSH, &[stack, value], stack)
+    }
+
+    /// Pop the top of the stack in `rY` into `rX`. Popping an empty stack is an out of bounds access.
+    #[track_caller]
+    fn stack_pop(&mut self, stack: Reg, to: Reg) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self.require_runtime(Stack)
+            .label_call(Stack::POP, &[stack], to)
+    }
+}
+
+impl<T: BuildInstruction + RequireRuntime> StackExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_runtime_is_injected_once() {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .stack_new(0)
+                .integer(104, 1)
+                .stack_push(0, 1)
+                .label_call("print_top", &[0], 1)
+                .exit()
+        });
+
+        builder.label("print_top", |print_top_builder| {
+            print_top_builder
+                .stack_pop(1, 2)
+                .put_char(2)
+                .stack_push(1, 2)
+                .return_(2)
+        });
+
+        let asm = builder.finish().finish();
+        assert_eq!(asm.matches("func stack_new").count(), 1);
+        assert_eq!(asm.matches("func stack_push").count(), 1);
+        assert_eq!(asm.matches("func stack_pop").count(), 1);
+    }
+
+    #[test]
+    fn test_stack_runtime_build() {
+        let mut builder = AsmBuilder::new();
+
+        builder.main(|main_builder| {
+            main_builder
+                .stack_new(0)
+                .integer(104, 1)
+                .stack_push(0, 1)
+                .stack_pop(0, 1)
+                .put_char(1)
+                .exit()
+        });
+
+        assert_eq!(
+            builder.finish().finish(),
+            r"@__entry
+    r0 <- call main
+    exit
+
+func stack_new
+    r0 <- int 2
+    r1 <- arr r0
+    r0 <- int 0
+    set r1 r0 r0
+    r2 <- int 4
+    r2 <- arr r2
+    r0 <- int 1
+    set r1 r0 r2
+    ret r1
+end
+
+func stack_push
+    r0 <- int 0
+    r3 <- get r1 r0
+    r0 <- int 1
+    r4 <- get r1 r0
+    r5 <- len r4
+    blt r3 r5 stack_push.grow stack_push.store
+@stack_push.grow
+    r6 <- add r5 r5
+    r6 <- arr r6
+    r7 <- int 0
+    r8 <- int 1
+@stack_push.copy
+    blt r7 r5 stack_push.copied stack_push.copy_elem
+@stack_push.copy_elem
+    r9 <- get r4 r7
+    set r6 r7 r9
+    r7 <- add r7 r8
+    jump stack_push.copy
+@stack_push.copied
+    r0 <- int 1
+    set r1 r0 r6
+    r4 <- reg r6
+@stack_push.store
+    set r4 r3 r2
+    r0 <- int 1
+    r3 <- add r3 r0
+    r0 <- int 0
+    set r1 r0 r3
+    ret r1
+end
+
+func stack_pop
+    r0 <- int 0
+    r2 <- get r1 r0
+    r3 <- int 1
+    r2 <- sub r2 r3
+    set r1 r0 r2
+    r3 <- get r1 r3
+    r4 <- get r3 r2
+    ret r4
+end
+
+func main
+    r0 <- call stack_new
+    r1 <- int 104
+    r0 <- call stack_push r0 r1
+    r1 <- call stack_pop r0
+    putchar r1
+    exit
+end",
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Size statistics of programs, and checks on how they change.
+
+use crate::{
+    asm::{Asm, Label, LabelImpl},
+    builder::Reg,
+    instr::OpCode,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AsmStats {
+    /// Functions in output order, `main` being last.
+    pub functions: Vec<FunctionStats>,
+    /// How many structured instructions of each opcode the program has.
+    pub opcodes: BTreeMap<OpCode, usize>,
+    /// The length of the emitted program, in bytes.
+    pub output_size: usize,
+}
+
+impl AsmStats {
+    #[must_use]
+    pub fn new(asm: &Asm) -> AsmStats {
+        let mut opcodes = BTreeMap::new();
+        for instr in asm
+            .iter()
+            .flat_map(Label::blocks)
+            .flat_map(LabelImpl::instructions)
+        {
+            *opcodes.entry(instr.op).or_default() += 1;
+        }
+        Self {
+            functions: asm.iter().map(FunctionStats::new).collect(),
+            opcodes,
+            output_size: asm.to_string().len(),
+        }
+    }
+
+    #[must_use]
+    pub fn opcode(&self, op: OpCode) -> usize {
+        self.opcodes.get(&op).copied().unwrap_or(0)
+    }
+
+    /// The number of sub-labels across all functions.
+    #[must_use]
+    pub fn sub_labels(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.sub_labels)
+            .sum()
+    }
+
+    #[must_use]
+    pub fn function(&self, name: &str) -> Option<&FunctionStats> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    #[must_use]
+    pub fn instructions(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.instructions)
+            .sum()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    /// Lines in the function's blocks, raw lines included.
+    pub instructions: usize,
+    pub sub_labels: usize,
+    /// The highest register read or written by a structured instruction, if any.
+    pub highest_register: Option<Reg>,
+}
+
+impl FunctionStats {
+    fn new(label: &Label) -> FunctionStats {
+        let highest_register = label
+            .blocks()
+            .flat_map(LabelImpl::instructions)
+            .flat_map(|instr| instr.dest.into_iter().chain(instr.uses()))
+            .max();
+        Self {
+            name: label.name().to_string(),
+            instructions: label.blocks().map(|block| block.lines().len()).sum(),
+            sub_labels: label.sub_labels().len(),
+            highest_register,
+        }
+    }
+}
+
+/// How much generated code may grow before a [`SizeGate`] fails.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SizePolicy {
+    /// Largest allowed growth of the whole program, in percent of its baseline instruction count.
+    pub max_total_growth_percent: usize,
+    /// Largest allowed growth of any one function, in instructions.
+    pub max_function_growth: Option<usize>,
+}
+
+/// The instruction count of a program or function before and after a change.
+/// Functions that were added or removed count as having no instructions on the other side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SizeDelta {
+    pub baseline: usize,
+    pub current: usize,
+}
+
+impl SizeDelta {
+    /// How many instructions were added, if the size grew.
+    #[must_use]
+    pub fn growth(self) -> usize {
+        self.current.saturating_sub(self.baseline)
+    }
+}
+
+impl fmt::Display for SizeDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.baseline, self.current)?;
+        if self.current >= self.baseline {
+            write!(f, " (+{})", self.current - self.baseline)
+        } else {
+            write!(f, " (-{})", self.baseline - self.current)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionDelta {
+    pub name: String,
+    pub delta: SizeDelta,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The whole program grew by more than the policy's percentage.
+    Total(SizeDelta),
+    /// The named function grew by more than the policy's instruction count.
+    Function(FunctionDelta),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GateResult {
+    pub total: SizeDelta,
+    /// Every function whose size changed, in the order of the current program then removed functions.
+    pub functions: Vec<FunctionDelta>,
+    pub violations: Vec<Violation>,
+}
+
+impl GateResult {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for GateResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.passed() { "passed" } else { "failed" };
+        write!(f, "size gate {verdict}: {} instructions", self.total)?;
+        for function in &self.functions {
+            let violates = self
+                .violations
+                .contains(&Violation::Function(function.clone()));
+            let marker = if violates { " (over limit)" } else { "" };
+            write!(f, "\n    {}: {}{marker}", function.name, function.delta)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the size of generated code against a baseline, e.g. from a build script.
+pub struct SizeGate;
+
+impl SizeGate {
+    #[must_use]
+    pub fn check(baseline: &AsmStats, current: &AsmStats, policy: &SizePolicy) -> GateResult {
+        let total = SizeDelta {
+            baseline: baseline.instructions(),
+            current: current.instructions(),
+        };
+
+        let size = |stats: &AsmStats, name: &str| {
+            stats
+                .function(name)
+                .map_or(0, |function| function.instructions)
+        };
+        let removed = baseline
+            .functions
+            .iter()
+            .filter(|function| current.function(&function.name).is_none());
+        let functions: Vec<FunctionDelta> = current
+            .functions
+            .iter()
+            .chain(removed)
+            .map(|function| FunctionDelta {
+                name: function.name.clone(),
+                delta: SizeDelta {
+                    baseline: size(baseline, &function.name),
+                    current: size(current, &function.name),
+                },
+            })
+            .filter(|function| function.delta.baseline != function.delta.current)
+            .collect();
+
+        let mut violations = Vec::new();
+        if total.current * 100 > total.baseline * (100 + policy.max_total_growth_percent) {
+            violations.push(Violation::Total(total));
+        }
+        if let Some(max_growth) = policy.max_function_growth {
+            violations.extend(
+                functions
+                    .iter()
+                    .filter(|function| function.delta.growth() > max_growth)
+                    .cloned()
+                    .map(Violation::Function),
+            );
+        }
+
+        GateResult {
+            total,
+            functions,
+            violations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    fn program(fib_len: usize) -> Asm {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.label_call("fib", &[], 0).exit());
+        builder.label("fib", |fib_builder| {
+            for _ in 1..fib_len {
+                fib_builder.integer(0, 0);
+            }
+            fib_builder.return_(0)
+        });
+        builder.label("putn", |putn_builder| putn_builder.return_(1));
+        builder.finish()
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.label_call("fib", &[3], 0).exit());
+        builder.label("fib", |fib_builder| {
+            fib_builder
+                .integer(2, 0)
+                .branch_less_than(1, 0, "fib.then", "fib.else")
+                .sub_label("then", |fib_then_builder| fib_then_builder.return_(1))
+                .sub_label("else", |fib_else_builder| {
+                    fib_else_builder.integer(1, 0).sub(1, 0, 5).return_(5)
+                })
+        });
+        let asm = builder.finish();
+        let stats = asm.stats();
+
+        assert_eq!(stats.output_size, asm.to_string().len());
+        assert_eq!(stats.opcode(OpCode::Int), 2);
+        assert_eq!(stats.opcode(OpCode::Ret), 2);
+        assert_eq!(stats.opcode(OpCode::Jump), 0);
+        assert_eq!(stats.opcodes.values().sum::<usize>(), stats.instructions());
+        assert_eq!(stats.sub_labels(), 2);
+        assert_eq!(
+            stats.function("fib"),
+            Some(&FunctionStats {
+                name: "fib".to_string(),
+                instructions: 6,
+                sub_labels: 2,
+                highest_register: Some(5),
+            })
+        );
+        assert_eq!(stats.function("main").unwrap().highest_register, Some(3));
+    }
+
+    #[test]
+    fn test_size_gate() {
+        let baseline = program(8).stats();
+        let policy = SizePolicy {
+            max_total_growth_percent: 10,
+            max_function_growth: Some(2),
+        };
+
+        assert!(SizeGate::check(&baseline, &program(9).stats(), &policy).passed());
+
+        let mut current = program(11);
+        current.labels_mut().remove(1);
+        assert_eq!(
+            SizeGate::check(&baseline, &current.stats(), &policy).to_string(),
+            "size gate failed: 11 -> 13 (+2) instructions
+    fib: 8 -> 11 (+3) (over limit)
+    putn: 1 -> 0 (-1)"
+        );
+    }
+}
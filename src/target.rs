@@ -0,0 +1,236 @@
+//! Releases of MiniVM, and the instructions each of them accepts.
+
+use crate::{
+    asm::{Asm, Label},
+    instr::OpCode,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// A release of MiniVM, like `0.2`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+const HEADER_PREFIX: &str = "# minivm ";
+
+impl Version {
+    #[must_use]
+    pub fn new(major: u32, minor: u32) -> Version {
+        Self { major, minor }
+    }
+
+    /// The line [`Asm::finish_with_header`] writes before a program generated for this release, like
+    /// `# minivm 0.2`.
+    #[must_use]
+    pub fn header(self) -> String {
+        format!("{HEADER_PREFIX}{self}")
+    }
+
+    /// The release named by `line`, if it is a [header](Version::header).
+    #[must_use]
+    pub fn from_header(line: &str) -> Option<Version> {
+        let (major, minor) = line.trim().strip_prefix(HEADER_PREFIX)?.split_once('.')?;
+        Some(Version::new(major.parse().ok()?, minor.parse().ok()?))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The VM a program is meant to run on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// A MiniVM release. `0.1` has the original instructions and only the standard entry block, `0.2` adds
+    /// the bitwise instructions and custom entry blocks, and `0.3` adds the float instructions.
+    MiniVm(Version),
+    /// A VM with its own set of instructions, like a fork of MiniVM.
+    Custom(InstructionSet),
+}
+
+impl Target {
+    /// The instructions and entry blocks the target accepts.
+    #[must_use]
+    pub fn instruction_set(&self) -> InstructionSet {
+        let version = match self {
+            Target::MiniVm(version) => *version,
+            Target::Custom(set) => return set.clone(),
+        };
+        let mut set = InstructionSet::new(OpCode::ALL.iter().copied().filter(|&op| {
+            let since = match op {
+                OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                    Version::new(0, 2)
+                }
+                #[cfg(feature = "float")]
+                OpCode::FInt
+                | OpCode::FAdd
+                | OpCode::FSub
+                | OpCode::FMul
+                | OpCode::FDiv
+                | OpCode::FBeq
+                | OpCode::FBlt => Version::new(0, 3),
+                _ => Version::new(0, 1),
+            };
+            version >= since
+        }));
+        if version >= Version::new(0, 2) {
+            set = set.with_custom_entry();
+        }
+        set
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::MiniVm(version) => write!(f, "MiniVM {version}"),
+            Target::Custom(_) => f.write_str("the custom target"),
+        }
+    }
+}
+
+/// The instructions a VM accepts, and whether it can start from a custom entry block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InstructionSet {
+    opcodes: BTreeSet<OpCode>,
+    custom_entry: bool,
+}
+
+impl InstructionSet {
+    /// Accept `opcodes`, and only the standard entry block.
+    #[must_use]
+    pub fn new(opcodes: impl IntoIterator<Item = OpCode>) -> InstructionSet {
+        Self {
+            opcodes: opcodes.into_iter().collect(),
+            custom_entry: false,
+        }
+    }
+
+    /// Also accept [custom entry blocks](crate::AsmBuilder::entry_point).
+    #[must_use]
+    pub fn with_custom_entry(mut self) -> InstructionSet {
+        self.custom_entry = true;
+        self
+    }
+
+    #[must_use]
+    pub fn supports(&self, op: OpCode) -> bool {
+        self.opcodes.contains(&op)
+    }
+
+    #[must_use]
+    pub fn supports_custom_entry(&self) -> bool {
+        self.custom_entry
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetError {
+    /// An instruction the target doesn't accept, in the named label or sub-label.
+    Instruction {
+        target: String,
+        label: String,
+        op: OpCode,
+    },
+    /// A custom entry block, on a target that only accepts the standard one.
+    CustomEntry { target: String },
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetError::Instruction { target, label, op } => {
+                write!(f, "`{op}` in `{label}` is not supported by {target}")
+            }
+            TargetError::CustomEntry { target } => {
+                write!(f, "custom entry blocks are not supported by {target}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+/// Check that `target` accepts every instruction of `asm`, and its entry block. Raw lines are not checked.
+///
+/// # Errors
+///
+/// Returns the first instruction the target doesn't accept, or the entry block if it can't be used.
+pub fn check(asm: &Asm, target: &Target) -> Result<(), TargetError> {
+    let set = target.instruction_set();
+    if !asm.is_library() && !asm.has_standard_entry() && !set.supports_custom_entry() {
+        return Err(TargetError::CustomEntry {
+            target: target.to_string(),
+        });
+    }
+    let entry = Some(asm.entry()).filter(|_| !asm.is_library());
+    for block in entry.into_iter().chain(asm.iter().flat_map(Label::blocks)) {
+        if let Some(instr) = block.instructions().find(|instr| !set.supports(instr.op)) {
+            return Err(TargetError::Instruction {
+                target: target.to_string(),
+                label: block.name().to_string(),
+                op: instr.op,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildError, BuildInstruction};
+
+    #[test]
+    fn test_target() {
+        let build = |target: Target| {
+            let mut builder = AsmBuilder::with_target(target);
+            builder.main(|main_builder| main_builder.bit_xor(1, 2, 1).exit());
+            builder.finish_checked().map(|_| ())
+        };
+        assert_eq!(
+            build(Target::MiniVm(Version::new(0, 1)))
+                .unwrap_err()
+                .to_string(),
+            "`bxor` in `main` is not supported by MiniVM 0.1"
+        );
+        assert_eq!(build(Target::MiniVm(Version::new(0, 2))), Ok(()));
+        assert_eq!(
+            AsmBuilder::with_target(Target::MiniVm(Version::new(0, 2)))
+                .finish()
+                .version(),
+            Some(Version::new(0, 2))
+        );
+
+        let custom = InstructionSet::new([OpCode::Call, OpCode::Exit, OpCode::BXor]);
+        assert_eq!(build(Target::Custom(custom.clone())), Ok(()));
+
+        let mut builder = AsmBuilder::with_target(Target::Custom(custom));
+        builder
+            .entry_point("start")
+            .label("start", |start_builder| start_builder.exit());
+        assert_eq!(
+            builder.finish_checked().unwrap_err(),
+            BuildError::Target(TargetError::CustomEntry {
+                target: "the custom target".to_string()
+            })
+        );
+    }
+
+    #[cfg(feature = "float")]
+    #[test]
+    fn test_target_float() {
+        let mut builder = AsmBuilder::with_target(Target::MiniVm(Version::new(0, 2)));
+        builder.main(|main_builder| main_builder.float(1.5, 1).exit());
+        assert_eq!(
+            builder.finish_checked().unwrap_err().to_string(),
+            "`fint` in `main` is not supported by MiniVM 0.2"
+        );
+    }
+}
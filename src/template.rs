@@ -0,0 +1,228 @@
+//! Snippets of instructions with placeholders, parsed once and expanded many times.
+
+use crate::{
+    instr::Instruction,
+    parse::{self, ParseErrorKind},
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Instructions in the text format, one per line, where `{name}` is replaced by the value bound to `name`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    /// Every non-empty line, along with its 1-based line number.
+    lines: Vec<(usize, Vec<Piece>)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Placeholder(String),
+}
+
+/// The values of the placeholders of a [`Template`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bindings(HashMap<String, String>);
+
+impl Bindings {
+    #[must_use]
+    pub fn new() -> Bindings {
+        Self::default()
+    }
+
+    /// Bind `name` to `value`, written as it is displayed.
+    #[must_use]
+    pub fn bind(mut self, name: &str, value: impl fmt::Display) -> Bindings {
+        self.0.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A `{` or `}` that isn't part of a placeholder or doubled, on the given line.
+    UnmatchedBrace(usize),
+    /// A placeholder whose name isn't made of letters, digits and underscores.
+    InvalidPlaceholder { line: usize, name: String },
+    /// A placeholder with no value bound to it.
+    Unbound(String),
+    /// A line that isn't an instruction once expanded.
+    Instruction { line: usize, kind: ParseErrorKind },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnmatchedBrace(line) => write!(f, "line {line}: unmatched brace"),
+            TemplateError::InvalidPlaceholder { line, name } => {
+                write!(f, "line {line}: invalid placeholder `{{{name}}}`")
+            }
+            TemplateError::Unbound(name) => write!(f, "no value bound to `{name}`"),
+            TemplateError::Instruction { line, kind } => write!(f, "line {line}: {kind}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Template {
+    /// Parse `text`, leaving the instructions themselves to be checked once expanded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a brace is unmatched or a placeholder name is invalid.
+    pub fn parse(text: &str) -> Result<Template, TemplateError> {
+        let mut lines = Vec::new();
+        for (number, line) in (1..).zip(text.lines()) {
+            if !line.trim().is_empty() {
+                lines.push((number, parse_line(line.trim(), number)?));
+            }
+        }
+        Ok(Self { lines })
+    }
+
+    /// The names of the placeholders, in order of first appearance.
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> + '_ {
+        let mut seen = Vec::new();
+        self.lines
+            .iter()
+            .flat_map(|(_, pieces)| pieces)
+            .filter_map(move |piece| match piece {
+                Piece::Placeholder(name) if !seen.contains(&name) => {
+                    seen.push(name);
+                    Some(name.as_str())
+                }
+                _ => None,
+            })
+    }
+
+    /// Replace every placeholder with its value in `bindings`, and parse the resulting instructions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a placeholder is unbound, or a line isn't an instruction once expanded.
+    pub fn expand(&self, bindings: &Bindings) -> Result<Vec<Instruction>, TemplateError> {
+        let mut instructions = Vec::with_capacity(self.lines.len());
+        for (line, pieces) in &self.lines {
+            let mut text = String::new();
+            for piece in pieces {
+                match piece {
+                    Piece::Text(part) => text.push_str(part),
+                    Piece::Placeholder(name) => text.push_str(
+                        bindings
+                            .0
+                            .get(name)
+                            .ok_or_else(|| TemplateError::Unbound(name.clone()))?,
+                    ),
+                }
+            }
+            let instr = parse::parse_instruction(&text)
+                .map_err(|kind| TemplateError::Instruction { line: *line, kind })?;
+            instructions.push(instr);
+        }
+        Ok(instructions)
+    }
+}
+
+fn parse_line(line: &str, number: usize) -> Result<Vec<Piece>, TemplateError> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or(TemplateError::UnmatchedBrace(number))?;
+                let name = &rest[..end];
+                if name.is_empty() || !name.chars().all(|ch| ch.is_alphanumeric() || ch == '_') {
+                    return Err(TemplateError::InvalidPlaceholder {
+                        line: number,
+                        name: name.to_string(),
+                    });
+                }
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+                pieces.push(Piece::Placeholder(name.to_string()));
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(TemplateError::UnmatchedBrace(number)),
+            _ => text.push(ch),
+        }
+    }
+    pieces.push(Piece::Text(text));
+    pieces.retain(|piece| !matches!(piece, Piece::Text(text) if text.is_empty()));
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsmBuilder;
+
+    #[test]
+    fn test_template() {
+        let swap = Template::parse(
+            r"r{tmp} <- reg r{a}
+            r{a} <- reg r{b}
+
+            r{b} <- reg r{tmp}",
+        )
+        .unwrap();
+        assert_eq!(swap.placeholders().collect::<Vec<_>>(), ["tmp", "a", "b"]);
+
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .instantiate(
+                    &swap,
+                    &Bindings::new().bind("a", 1).bind("b", 2).bind("tmp", 3),
+                )
+                .instantiate(
+                    &swap,
+                    &Bindings::new().bind("a", 4).bind("b", 5).bind("tmp", 3),
+                )
+        });
+        assert_eq!(
+            builder.finish().main().to_string(),
+            r"func main
+    r3 <- reg r1
+    r1 <- reg r2
+    r2 <- reg r3
+    r3 <- reg r4
+    r4 <- reg r5
+    r5 <- reg r3
+end"
+        );
+
+        let error = |text, bindings: &Bindings| match Template::parse(text) {
+            Ok(template) => template.expand(bindings).unwrap_err().to_string(),
+            Err(error) => error.to_string(),
+        };
+        assert_eq!(
+            error("r{a <- int 1", &Bindings::new()),
+            "line 1: unmatched brace"
+        );
+        assert_eq!(
+            error("r{} <- int 1", &Bindings::new()),
+            "line 1: invalid placeholder `{}`"
+        );
+        assert_eq!(
+            error("r{a} <- int 1", &Bindings::new()),
+            "no value bound to `a`"
+        );
+        assert_eq!(
+            error("\nr1 <- {op} r2", &Bindings::new().bind("op", "nop")),
+            "line 2: unknown opcode `nop`"
+        );
+    }
+}
@@ -0,0 +1,510 @@
+//! Helpers for testing code that generates programs.
+
+#![allow(clippy::missing_panics_doc)]
+
+#[cfg(feature = "host")]
+pub mod corpus;
+
+use crate::{
+    asm::{Asm, Label, LabelImpl, SubLabel},
+    builder::Reg,
+    instr::{Instruction, OpCode, Operand},
+    randomize::SplitMix64,
+    AsmBuilder, Int,
+};
+
+/// Something that can be compared against expected assembly with [`assert_asm_eq!`](crate::assert_asm_eq).
+pub trait AsmText {
+    fn asm_text(self) -> String;
+}
+
+impl AsmText for AsmBuilder {
+    fn asm_text(self) -> String {
+        self.finish().to_string()
+    }
+}
+
+macro_rules! impl_asm_text_for_display {
+    [$($ty:ty),* $(,)?] => {
+        $(
+            impl AsmText for $ty {
+                fn asm_text(self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+impl_asm_text_for_display![Asm, &Asm, Label, &Label, SubLabel, &SubLabel, String, &String, &str];
+
+/// Assert that a program, label, or builder emits `expected`, ignoring differences in whitespace and
+/// blank lines. On mismatch, panics with a line-by-line diff, colored unless `NO_COLOR` is set.
+#[macro_export]
+macro_rules! assert_asm_eq {
+    ($actual:expr, $expected:expr $(,)?) => {{
+        let actual = $crate::testing::AsmText::asm_text($actual);
+        let color = ::std::env::var_os("NO_COLOR").is_none();
+        if let Some(diff) = $crate::testing::diff(&$expected, &actual, color) {
+            panic!("assembly differs (- expected, + actual):\n{diff}");
+        }
+    }};
+}
+
+/// The non-blank lines of `text`, with runs of whitespace collapsed to single spaces and leading and
+/// trailing whitespace removed. The text of a `str` is left as it is, spaces and all.
+#[must_use]
+pub fn normalize(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| {
+            let collapse = |part: &str| part.split_whitespace().collect::<Vec<_>>().join(" ");
+            match line.split_once(':') {
+                Some((head, literal)) if head.split_whitespace().last() == Some("str") => {
+                    format!("{} :{literal}", collapse(head))
+                }
+                _ => collapse(line),
+            }
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// A line-by-line diff of the normalized `expected` and `actual`, or `None` if they are the same.
+/// Lines only in `expected` start with `-`, and those only in `actual` with `+`.
+#[must_use]
+pub fn diff(expected: &str, actual: &str, color: bool) -> Option<String> {
+    let expected = normalize(expected);
+    let actual = normalize(actual);
+    if expected == actual {
+        return None;
+    }
+
+    let (removed, added, reset) = if color {
+        ("\x1b[31m", "\x1b[32m", "\x1b[0m")
+    } else {
+        ("", "", "")
+    };
+    let mut lines: Vec<String> = shortest_edit(&expected, &actual)
+        .into_iter()
+        .map(|edit| match edit {
+            Edit::Keep(i) => format!("  {}", expected[i]),
+            Edit::Remove(i) => format!("{removed}- {}{reset}", expected[i]),
+            Edit::Add(j) => format!("{added}+ {}{reset}", actual[j]),
+        })
+        .collect();
+    lines.reverse();
+    Some(lines.join("\n"))
+}
+
+/// A step of [`shortest_edit`], with the index of its line in the side it comes from.
+enum Edit {
+    Keep(usize),
+    Remove(usize),
+    Add(usize),
+}
+
+/// The fewest lines to remove from `old` and add to turn it into `new`, last edit first, found with Myers'
+/// algorithm. It takes O((N + M)·D) time and O(D²) space for D differing lines, so programs that are
+/// mostly the same are cheap to compare however long they are.
+fn shortest_edit(old: &[String], new: &[String]) -> Vec<Edit> {
+    let (old_len, new_len) = (old.len(), new.len());
+    // Diagonal `k = x - y` is stored at `k + offset`, which keeps indices positive.
+    let offset = old_len + new_len + 1;
+    let mut furthest = vec![0; 2 * offset + 1];
+    // The furthest points before each round, on diagonals `-d - 1` to `d + 1`.
+    let mut trace = Vec::new();
+    'search: for d in 0..=old_len + new_len {
+        trace.push(furthest[offset - d - 1..=offset + d + 1].to_vec());
+        for k in (offset - d..=offset + d).step_by(2) {
+            let down = k == offset - d || (k != offset + d && furthest[k - 1] < furthest[k + 1]);
+            let mut x = if down {
+                furthest[k + 1]
+            } else {
+                furthest[k - 1] + 1
+            };
+            let mut y = x + offset - k;
+            while x < old_len && y < new_len && old[x] == new[y] {
+                x += 1;
+                y += 1;
+            }
+            furthest[k] = x;
+            if x >= old_len && y >= new_len {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (old_len, new_len);
+    for (d, before) in trace.iter().enumerate().rev() {
+        if d == 0 {
+            edits.extend((0..x).rev().map(Edit::Keep));
+            break;
+        }
+        let at = |k: usize| before[k + d + 1 - offset];
+        let k = x + offset - y;
+        let down = k == offset - d || (k != offset + d && at(k - 1) < at(k + 1));
+        let prev_k = if down { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x + offset - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Keep(x));
+        }
+        if down {
+            edits.push(Edit::Add(prev_y));
+        } else {
+            edits.push(Edit::Remove(prev_x));
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits
+}
+
+/// Options for [`arbitrary_program`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProgramConfig {
+    /// Functions besides `main`.
+    pub functions: usize,
+    /// Registers every function may use, at least 4.
+    pub registers: u8,
+    /// Statements in every block.
+    pub statements: usize,
+    /// The most times any loop runs.
+    pub max_loop_iterations: Int,
+}
+
+impl Default for ProgramConfig {
+    fn default() -> Self {
+        Self {
+            functions: 4,
+            registers: 8,
+            statements: 6,
+            max_loop_iterations: 4,
+        }
+    }
+}
+
+/// Generate a random program that always exits without trapping.
+///
+/// Functions only call functions defined after them, loops count down from a constant, divisors are
+/// non-zero constants, and arrays are only indexed in bounds, so every program terminates. Registers
+/// other than those of `config` are never used, and every label that is referenced is defined.
+///
+/// Panics if `config.registers` is less than 4.
+pub fn arbitrary_program(rng: &mut SplitMix64, config: &ProgramConfig) -> Asm {
+    assert!(config.registers >= 4, "programs need at least 4 registers");
+    let values = usize::from(config.registers) - 2;
+    let arities: Vec<usize> = (0..config.functions)
+        .map(|_| rng.below(values.min(3) + 1))
+        .collect();
+
+    let mut asm = Asm::new();
+    for function in 0..config.functions {
+        let label = Generator::new(rng, config, &arities, function).function();
+        asm.push_label(label);
+    }
+    *asm.main() = Generator::new(rng, config, &arities, config.functions).function();
+    asm
+}
+
+/// Builds a single function, using `r0` for constants and arrays, the last register as a loop counter, and
+/// the others for values.
+struct Generator<'a> {
+    rng: &'a mut SplitMix64,
+    config: &'a ProgramConfig,
+    arities: &'a [usize],
+    /// The index of the function, `main` being past the others.
+    function: usize,
+    label: Label,
+    sub_labels: usize,
+}
+
+impl<'a> Generator<'a> {
+    fn new(
+        rng: &'a mut SplitMix64,
+        config: &'a ProgramConfig,
+        arities: &'a [usize],
+        function: usize,
+    ) -> Generator<'a> {
+        let name = Self::name(arities, function);
+        Self {
+            rng,
+            config,
+            arities,
+            function,
+            label: Label::new(&name),
+            sub_labels: 0,
+        }
+    }
+
+    fn name(arities: &[usize], function: usize) -> String {
+        if function == arities.len() {
+            "main".to_string()
+        } else {
+            format!("f{function}")
+        }
+    }
+
+    fn function(mut self) -> Label {
+        for _ in 0..self.config.statements {
+            match self.rng.below(8) {
+                0 => self.if_else(),
+                1 => self.counted_loop(),
+                _ => self.statement(),
+            }
+        }
+        if self.function == self.arities.len() {
+            self.emit(OpCode::Exit, None, vec![]);
+        } else {
+            let value = self.value();
+            self.emit(OpCode::Ret, None, vec![Operand::Reg(value)]);
+        }
+        self.label
+    }
+
+    fn if_else(&mut self) {
+        let (then, otherwise, join) = (self.block(), self.block(), self.block());
+        let (lhs, rhs) = (self.value(), self.value());
+        let op = [OpCode::Beq, OpCode::Blt][self.rng.below(2)];
+        self.emit(
+            op,
+            None,
+            vec![
+                Operand::Reg(lhs),
+                Operand::Reg(rhs),
+                self.target(&otherwise),
+                self.target(&then),
+            ],
+        );
+
+        self.start(&then);
+        self.statements();
+        self.emit(OpCode::Jump, None, vec![self.target(&join)]);
+        self.start(&otherwise);
+        self.statements();
+        self.start(&join);
+    }
+
+    fn counted_loop(&mut self) {
+        let (body, end) = (self.block(), self.block());
+        let counter = self.config.registers - 1;
+        let iterations = self.constant(1..=self.config.max_loop_iterations);
+        self.emit(OpCode::Int, Some(counter), vec![Operand::Int(iterations)]);
+
+        self.start(&body);
+        self.statements();
+        self.emit(OpCode::Int, Some(0), vec![Operand::Int(1)]);
+        self.emit(
+            OpCode::Sub,
+            Some(counter),
+            vec![Operand::Reg(counter), Operand::Reg(0)],
+        );
+        self.emit(
+            OpCode::Bb,
+            None,
+            vec![Operand::Reg(counter), self.target(&end), self.target(&body)],
+        );
+        self.start(&end);
+    }
+
+    fn statements(&mut self) {
+        for _ in 0..=self.rng.below(self.config.statements) {
+            self.statement();
+        }
+    }
+
+    fn statement(&mut self) {
+        let dest = self.value();
+        let reg = |reg: Reg| Operand::Reg(reg);
+        match self.rng.below(9) {
+            0 => {
+                let value = self.constant(-100..=100);
+                self.emit(OpCode::Int, Some(dest), vec![Operand::Int(value)]);
+            }
+            1 => {
+                let op = [
+                    OpCode::Add,
+                    OpCode::Sub,
+                    OpCode::Mul,
+                    OpCode::BAnd,
+                    OpCode::BOr,
+                    OpCode::BXor,
+                    OpCode::Shl,
+                    OpCode::Shr,
+                ][self.rng.below(8)];
+                let (lhs, rhs) = (self.value(), self.value());
+                self.emit(op, Some(dest), vec![reg(lhs), reg(rhs)]);
+            }
+            2 => {
+                let divisor = self.constant(1..=9) * [1, -1][self.rng.below(2)];
+                let op = [OpCode::Div, OpCode::Mod][self.rng.below(2)];
+                let lhs = self.value();
+                self.emit(OpCode::Int, Some(0), vec![Operand::Int(divisor)]);
+                self.emit(op, Some(dest), vec![reg(lhs), reg(0)]);
+            }
+            3 => {
+                let op = [OpCode::Neg, OpCode::Reg, OpCode::Type][self.rng.below(3)];
+                let from = self.value();
+                self.emit(op, Some(dest), vec![reg(from)]);
+            }
+            4 => {
+                let value = self.value();
+                self.emit(OpCode::PutChar, None, vec![reg(value)]);
+            }
+            5 => {
+                let len = self.constant(1..=4);
+                let value = self.value();
+                self.emit(OpCode::Int, Some(0), vec![Operand::Int(len)]);
+                self.emit(OpCode::Arr, Some(0), vec![reg(0)]);
+                let index = self.constant(0..=len - 1);
+                self.emit(OpCode::Int, Some(dest), vec![Operand::Int(index)]);
+                self.emit(OpCode::Set, None, vec![reg(0), reg(dest), reg(value)]);
+                let index = self.constant(0..=len - 1);
+                self.emit(OpCode::Int, Some(dest), vec![Operand::Int(index)]);
+                self.emit(OpCode::Get, Some(dest), vec![reg(0), reg(dest)]);
+            }
+            _ if self.function + 1 < self.arities.len() => {
+                let callee =
+                    self.function + 1 + self.rng.below(self.arities.len() - self.function - 1);
+                let name = Self::name(self.arities, callee);
+                let args: Vec<Operand> = (0..self.arities[callee])
+                    .map(|_| reg(self.value()))
+                    .collect();
+                if self.rng.below(2) == 0 {
+                    self.emit(
+                        OpCode::Call,
+                        Some(dest),
+                        [vec![Operand::Label(name.into())], args].concat(),
+                    );
+                } else {
+                    self.emit(OpCode::Addr, Some(0), vec![Operand::Label(name.into())]);
+                    self.emit(OpCode::DCall, Some(dest), [vec![reg(0)], args].concat());
+                }
+            }
+            _ => {
+                let value = self.value();
+                self.emit(OpCode::Add, Some(dest), vec![reg(value), reg(value)]);
+            }
+        }
+    }
+
+    /// A random register for values.
+    fn value(&mut self) -> Reg {
+        let values = usize::from(self.config.registers) - 2;
+        Reg::try_from(1 + self.rng.below(values)).unwrap()
+    }
+
+    fn constant(&mut self, range: std::ops::RangeInclusive<Int>) -> Int {
+        let span = usize::try_from(range.end() - range.start() + 1).unwrap();
+        range.start() + Int::try_from(self.rng.below(span)).unwrap()
+    }
+
+    /// The name of a new sub-label.
+    fn block(&mut self) -> String {
+        self.sub_labels += 1;
+        format!("b{}", self.sub_labels)
+    }
+
+    fn target(&self, block: &str) -> Operand {
+        Operand::Label(format!("{}.{block}", self.label.name()).into())
+    }
+
+    /// Continue in a new sub-label, falling through from the current block.
+    fn start(&mut self, block: &str) {
+        let sub_label = SubLabel::new(self.label.name(), block);
+        self.label.push_sub_label(sub_label);
+    }
+
+    fn emit(&mut self, op: OpCode, dest: Option<Reg>, operands: Vec<Operand>) {
+        let block: &mut LabelImpl = match self.label.sub_labels_mut().last_mut() {
+            Some(sub_label) => sub_label,
+            None => &mut self.label,
+        };
+        block.push_instruction(Instruction::new(op, dest, operands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BuildInstruction;
+
+    #[test]
+    fn test_assert_asm_eq() {
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| main_builder.integer(1, 0).put_char(0).exit());
+        assert_asm_eq!(
+            builder,
+            "@__entry
+                r0   <- call main
+                exit
+
+            func main
+                r0 <- int 1
+                putchar r0
+                exit
+            end   "
+        );
+    }
+
+    #[test]
+    fn test_arbitrary_program() {
+        let config = ProgramConfig::default();
+        for seed in 0..50 {
+            let asm = arbitrary_program(&mut SplitMix64::new(seed), &config);
+            let again = arbitrary_program(&mut SplitMix64::new(seed), &config);
+            assert_eq!(asm.to_string(), again.to_string());
+            assert_eq!(
+                crate::parse::parse(&asm.to_string()).unwrap().to_string(),
+                asm.to_string()
+            );
+
+            let stats = asm.stats();
+            assert_eq!(stats.functions.len(), config.functions + 1);
+            assert!(stats
+                .functions
+                .iter()
+                .all(|function| function.highest_register < Some(config.registers)));
+
+            #[cfg(feature = "interp")]
+            {
+                let result = crate::interp::run(&asm, &crate::interp::Config::default());
+                assert_eq!(result.result, Ok(()), "seed {seed}:\n{asm}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff("func f\n  ret r0\nend", "func f\nret  r0\nend", true),
+            None
+        );
+        assert_eq!(
+            diff(
+                "func f\n    r0 <- int 1\n    ret r0\nend",
+                "func f\n    r0 <- int 2\n    ret r0\n    exit\nend",
+                false
+            )
+            .unwrap(),
+            "  func f
+- r0 <- int 1
++ r0 <- int 2
+  ret r0
++ exit
+  end"
+        );
+        assert_eq!(
+            diff("exit", "ret r0", true).unwrap(),
+            "\x1b[31m- exit\x1b[0m\n\x1b[32m+ ret r0\x1b[0m"
+        );
+        assert_eq!(
+            diff("r1 <- str :a  b", "r1  <-  str :a b", false).unwrap(),
+            "- r1 <- str :a  b\n+ r1 <- str :a b"
+        );
+        assert_eq!(diff("", "exit", false).unwrap(), "+ exit");
+        assert_eq!(diff("exit", "", false).unwrap(), "- exit");
+    }
+}
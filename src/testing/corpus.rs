@@ -0,0 +1,195 @@
+//! Regression corpora of programs, checked against their expected output.
+//!
+//! Every `name.minivm` file in a corpus must parse and be emitted again unchanged, modulo whitespace. If a
+//! `name.out` file sits next to it, the program is also run with the [interpreter](crate::interp), which
+//! must exit with exactly that output; without the `interp` feature, these files are ignored.
+
+use super::{diff, normalize};
+use crate::corpus::files_with_extension;
+use crate::parse::{self, ParseError};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The extension of the programs [`run_dir`] reads.
+pub const EXTENSION: &str = "minivm";
+/// The extension of the files holding the expected output of programs.
+pub const OUTPUT_EXTENSION: &str = "out";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorpusReport {
+    /// Every program of the corpus, sorted by path.
+    pub files: Vec<FileResult>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    ParseError(ParseError),
+    /// The program was emitted differently than it was written, as shown by the diff.
+    NotRoundTripped(String),
+    #[cfg(feature = "interp")]
+    Trapped(crate::interp::Trap),
+    OutputMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl CorpusReport {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &FileResult> + '_ {
+        self.files
+            .iter()
+            .filter(|file| file.outcome != Outcome::Passed)
+    }
+}
+
+impl fmt::Display for CorpusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failures().count();
+        write!(
+            f,
+            "{} programs: {} passed, {failed} failed",
+            self.files.len(),
+            self.files.len() - failed
+        )?;
+        for file in self.failures() {
+            write!(f, "\n    {}: ", file.path.display())?;
+            match &file.outcome {
+                Outcome::Passed => {}
+                Outcome::ParseError(error) => write!(f, "{error}")?,
+                Outcome::NotRoundTripped(diff) => {
+                    f.write_str("emitted differently")?;
+                    for line in diff.lines() {
+                        write!(f, "\n        {line}")?;
+                    }
+                }
+                #[cfg(feature = "interp")]
+                Outcome::Trapped(trap) => {
+                    write!(f, "trapped: {}", trap.to_string().replace('\n', "\n    "))?;
+                }
+                Outcome::OutputMismatch { expected, actual } => write!(
+                    f,
+                    "expected output {:?}, got {:?}",
+                    String::from_utf8_lossy(expected),
+                    String::from_utf8_lossy(actual)
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check every `.minivm` program directly inside `dir`.
+///
+/// # Errors
+///
+/// Returns an error if the directory or one of its files can't be read.
+pub fn run_dir(dir: impl AsRef<Path>) -> io::Result<CorpusReport> {
+    let mut files = Vec::new();
+    for path in files_with_extension(dir.as_ref(), EXTENSION)? {
+        let text = fs::read_to_string(&path)?;
+        let expected = match fs::read(path.with_extension(OUTPUT_EXTENSION)) {
+            Ok(expected) => Some(expected),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+        let outcome = check(&text, expected.as_deref());
+        files.push(FileResult { path, outcome });
+    }
+    Ok(CorpusReport { files })
+}
+
+fn check(text: &str, expected: Option<&[u8]>) -> Outcome {
+    let asm = match parse::parse(text) {
+        Ok(asm) => asm,
+        Err(error) => return Outcome::ParseError(error),
+    };
+    let emitted = asm.to_string();
+    if normalize(&emitted) != normalize(text) {
+        return Outcome::NotRoundTripped(diff(text, &emitted, false).unwrap_or_default());
+    }
+
+    #[cfg(feature = "interp")]
+    if let Some(expected) = expected {
+        let result = crate::interp::run(&asm, &crate::interp::Config::default());
+        if let Err(trap) = result.result {
+            return Outcome::Trapped(trap);
+        }
+        if result.output != expected {
+            return Outcome::OutputMismatch {
+                expected: expected.to_vec(),
+                actual: result.output,
+            };
+        }
+    }
+    #[cfg(not(feature = "interp"))]
+    let _ = expected;
+    Outcome::Passed
+}
+
+#[cfg(all(test, feature = "interp"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_dir() {
+        let dir = std::env::temp_dir().join(format!("minivm-golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = |body: &str| {
+            format!("@__entry\n    r0 <- call main\n    exit\n\nfunc main\n{body}\nend")
+        };
+        let hi = program(
+            "    r0 <- int 104\n    putchar r0\n    r0 <- int 105\n    putchar r0\n    exit",
+        );
+        fs::write(dir.join("a.minivm"), &hi).unwrap();
+        fs::write(dir.join("a.out"), "hi").unwrap();
+        fs::write(dir.join("b.minivm"), &hi).unwrap();
+        fs::write(dir.join("b.out"), "ho").unwrap();
+        fs::write(dir.join("c.minivm"), "func main\n    exit\nend").unwrap();
+        fs::write(
+            dir.join("d.minivm"),
+            program("    r0 <- int 0\n    r0 <- div r0 r0"),
+        )
+        .unwrap();
+        fs::write(dir.join("d.out"), "").unwrap();
+        fs::write(dir.join("e.minivm"), program("    exit")).unwrap();
+
+        let report = run_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            format!(
+                r#"5 programs: 2 passed, 3 failed
+    {}: expected output "ho", got "hi"
+    {}: emitted differently
+        + @__entry
+        + r0 <- call main
+        + exit
+          func main
+          exit
+          end
+    {}: trapped: division by zero at main+1
+        in `r0 <- div r0 r0`"#,
+                path("b.minivm"),
+                path("c.minivm"),
+                path("d.minivm")
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,727 @@
+//! A terminal viewer for programs: a list of their functions, the instructions or control-flow graph of the
+//! selected one, and the [interpreter](crate::interp) stepping through them.
+//!
+//! [`Viewer`] holds the state of the viewer and renders it as text, so it can be driven without a terminal.
+//! [`run`] shows it in the terminal until it is quit.
+
+use crate::{
+    analysis::{Cfg, EdgeKind, Target},
+    asm::{Asm, Label, Line},
+    builder::Reg,
+    interp::{Machine, Value},
+};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    queue, style, terminal,
+};
+use std::io::{self, Write};
+
+/// How many instructions [`Command::Continue`] executes at most, so that a program that never reaches a
+/// breakpoint doesn't freeze the viewer.
+const CONTINUE_STEPS: u64 = 1_000_000;
+
+/// The width of the list of functions.
+const FUNCTIONS_WIDTH: usize = 20;
+
+/// The keys of every command, shown at the bottom of the screen.
+const HELP: &str =
+    "n/p function  j/k line  c graph  b breakpoint  s step  r continue  R restart  q quit";
+
+/// Something the viewer can be asked to do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    NextFunction,
+    PreviousFunction,
+    /// Move the cursor to the next line of the selected function.
+    CursorDown,
+    /// Move the cursor to the previous line of the selected function.
+    CursorUp,
+    /// Switch between the instructions of the selected function and its control-flow graph.
+    ToggleGraph,
+    /// Set or clear a breakpoint on the instruction under the cursor.
+    ToggleBreakpoint,
+    /// Execute one instruction.
+    Step,
+    /// Execute instructions until the program reaches a breakpoint, exits or traps.
+    Continue,
+    /// Run the program again from the start, keeping the breakpoints.
+    Restart,
+}
+
+/// How a piece of the screen is shown.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Style {
+    Normal,
+    /// The selected function, or the line under the cursor.
+    Selected,
+    /// A block or function the instruction under the cursor may go to.
+    Target,
+    /// The instruction about to be executed, or the function or block it is in.
+    Current,
+}
+
+/// Text shown in one style.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Span {
+    fn new(text: String, style: Style) -> Span {
+        Self { text, style }
+    }
+}
+
+/// A line of the function view: the name of a block, or a line of one, by their indices.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Row {
+    Block(usize),
+    Line(usize, usize),
+}
+
+impl Row {
+    fn block(self) -> usize {
+        match self {
+            Row::Block(block) | Row::Line(block, _) => block,
+        }
+    }
+}
+
+fn rows(label: &Label) -> Vec<Row> {
+    label
+        .blocks()
+        .enumerate()
+        .flat_map(|(block, lines)| {
+            std::iter::once(Row::Block(block))
+                .chain((0..lines.lines().len()).map(move |index| Row::Line(block, index)))
+        })
+        .collect()
+}
+
+/// The state of the viewer: what is selected, and the program being run.
+pub struct Viewer<'a> {
+    asm: &'a Asm,
+    functions: Vec<&'a Label>,
+    selected: usize,
+    cursor: usize,
+    graph: bool,
+    /// Breakpoints, by block name and line index.
+    breakpoints: Vec<(String, usize)>,
+    /// `None` for a library, which can't be run.
+    machine: Option<Machine<'a>>,
+    status: String,
+}
+
+impl<'a> Viewer<'a> {
+    /// A viewer of `asm`, with its first function selected and the program about to start.
+    #[must_use]
+    pub fn new(asm: &'a Asm) -> Viewer<'a> {
+        let mut viewer = Self {
+            asm,
+            functions: asm.iter().collect(),
+            selected: 0,
+            cursor: 0,
+            graph: false,
+            breakpoints: Vec::new(),
+            machine: None,
+            status: String::new(),
+        };
+        viewer.restart();
+        viewer
+    }
+
+    /// The program being run, if it isn't a library.
+    #[must_use]
+    pub fn machine(&self) -> Option<&Machine<'a>> {
+        self.machine.as_ref()
+    }
+
+    pub fn apply(&mut self, command: Command) {
+        let rows = self.selected_rows().len();
+        match command {
+            Command::NextFunction | Command::PreviousFunction if self.functions.is_empty() => {}
+            Command::NextFunction => self.select((self.selected + 1) % self.functions.len(), 0),
+            Command::PreviousFunction => {
+                let count = self.functions.len();
+                self.select((self.selected + count - 1) % count, 0);
+            }
+            Command::CursorDown => self.cursor = (self.cursor + 1).min(rows.saturating_sub(1)),
+            Command::CursorUp => self.cursor = self.cursor.saturating_sub(1),
+            Command::ToggleGraph => self.graph = !self.graph,
+            Command::ToggleBreakpoint => self.toggle_breakpoint(),
+            Command::Step => self.run(1),
+            Command::Continue => self.run(CONTINUE_STEPS),
+            Command::Restart => self.restart(),
+        }
+    }
+
+    fn select(&mut self, function: usize, cursor: usize) {
+        self.selected = function;
+        self.cursor = cursor;
+    }
+
+    fn selected_rows(&self) -> Vec<Row> {
+        self.functions
+            .get(self.selected)
+            .map_or_else(Vec::new, |label| rows(label))
+    }
+
+    /// The block and line under the cursor, if it is on a line.
+    fn cursor_line(&self) -> Option<(&'a str, usize)> {
+        let label = self.functions.get(self.selected)?;
+        match *self.selected_rows().get(self.cursor)? {
+            Row::Line(block, index) => Some((label.blocks().nth(block)?.name(), index)),
+            Row::Block(_) => None,
+        }
+    }
+
+    /// The labels the instruction under the cursor may go to.
+    fn cursor_targets(&self) -> Vec<&'a str> {
+        let Some((block, index)) = self.cursor_line() else {
+            return Vec::new();
+        };
+        let label = self.functions[self.selected];
+        label
+            .blocks()
+            .find(|b| b.name() == block)
+            .and_then(|b| b.lines().get(index))
+            .and_then(Line::as_instruction)
+            .map_or_else(Vec::new, |instr| instr.targets().collect())
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let Some((block, index)) = self.cursor_line() else {
+            return;
+        };
+        let at = (block.to_string(), index);
+        match self
+            .breakpoints
+            .iter()
+            .position(|breakpoint| *breakpoint == at)
+        {
+            Some(i) => {
+                self.breakpoints.remove(i);
+            }
+            None => self.breakpoints.push(at),
+        }
+    }
+
+    fn restart(&mut self) {
+        if self.asm.is_library() {
+            self.status = "a library has no entry point to run".to_string();
+            return;
+        }
+        self.machine = Some(Machine::new(self.asm));
+        self.status = "ready".to_string();
+        self.follow();
+    }
+
+    /// Execute up to `steps` instructions, stopping early at a breakpoint.
+    fn run(&mut self, steps: u64) {
+        let Some(machine) = &mut self.machine else {
+            return;
+        };
+        if machine.location().is_none() {
+            self.status = "the program has exited".to_string();
+            return;
+        }
+        self.status = if steps == 1 {
+            "stepped".to_string()
+        } else {
+            format!("paused after {steps} steps")
+        };
+        for _ in 0..steps {
+            match machine.step() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.status = "exited".to_string();
+                    break;
+                }
+                Err(trap) => {
+                    let text = trap.to_string();
+                    self.status = text.lines().next().unwrap_or_default().to_string();
+                    break;
+                }
+            }
+            let at = machine
+                .location()
+                .map(|location| (location.label, location.index));
+            if steps > 1 && at.is_some_and(|at| self.breakpoints.contains(&at)) {
+                self.status = "stopped at a breakpoint".to_string();
+                break;
+            }
+        }
+        self.follow();
+    }
+
+    /// The function and row of the instruction about to be executed, if it is in a function.
+    fn current(&self) -> Option<(usize, usize)> {
+        let location = self.machine.as_ref()?.location()?;
+        self.functions
+            .iter()
+            .enumerate()
+            .find_map(|(function, label)| {
+                let block = label.blocks().position(|b| b.name() == location.label)?;
+                let row = rows(label)
+                    .iter()
+                    .position(|&row| row == Row::Line(block, location.index))?;
+                Some((function, row))
+            })
+    }
+
+    /// Select the function and line about to be executed.
+    fn follow(&mut self) {
+        if let Some((function, row)) = self.current() {
+            self.select(function, row);
+        }
+    }
+
+    /// The screen, `width` columns by `height` rows: the functions on the left, the selected one on the right,
+    /// and the state of the program at the bottom.
+    #[must_use]
+    pub fn render(&self, width: usize, height: usize) -> Vec<Vec<Span>> {
+        let main_height = height.saturating_sub(5);
+        let pane_width = width.saturating_sub(FUNCTIONS_WIDTH + 3);
+        let functions = self.render_functions();
+        let pane = if self.graph {
+            self.render_graph()
+        } else {
+            self.render_function()
+        };
+        // Scroll the pane so that the cursor stays in view.
+        let cursor_row = pane
+            .iter()
+            .position(|span| span.style == Style::Selected)
+            .unwrap_or(0);
+        let skip = (cursor_row + 1).saturating_sub(main_height);
+
+        let mut screen = Vec::with_capacity(height);
+        let mut pane = pane.into_iter().skip(skip);
+        let mut functions = functions.into_iter();
+        for _ in 0..main_height {
+            let left = functions
+                .next()
+                .unwrap_or_else(|| Span::new(String::new(), Style::Normal));
+            let right = pane
+                .next()
+                .unwrap_or_else(|| Span::new(String::new(), Style::Normal));
+            screen.push(vec![
+                Span::new(fit(&left.text, FUNCTIONS_WIDTH), left.style),
+                Span::new(" | ".to_string(), Style::Normal),
+                Span::new(fit(&right.text, pane_width), right.style),
+            ]);
+        }
+        let footer = [
+            "-".repeat(width),
+            self.render_position(),
+            self.render_registers(),
+            self.render_output(width),
+            HELP.to_string(),
+        ];
+        for line in footer.into_iter().take(height - main_height) {
+            screen.push(vec![Span::new(fit(&line, width), Style::Normal)]);
+        }
+        screen
+    }
+
+    fn render_functions(&self) -> Vec<Span> {
+        let current = self.current().map(|(function, _)| function);
+        let targets = self.cursor_targets();
+        self.functions
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let marker = if Some(i) == current { '>' } else { ' ' };
+                let style = if i == self.selected {
+                    Style::Selected
+                } else if targets.contains(&label.name()) {
+                    Style::Target
+                } else {
+                    Style::Normal
+                };
+                Span::new(format!("{marker} {}", label.name()), style)
+            })
+            .collect()
+    }
+
+    fn render_function(&self) -> Vec<Span> {
+        let Some(label) = self.functions.get(self.selected) else {
+            return Vec::new();
+        };
+        let blocks: Vec<_> = label.blocks().collect();
+        let current = self
+            .current()
+            .filter(|&(function, _)| function == self.selected)
+            .map(|(_, row)| row);
+        let targets = self.cursor_targets();
+        rows(label)
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let (text, targeted) = match row {
+                    Row::Block(block) => {
+                        let name = blocks[block].name();
+                        (format!("  {name}:"), targets.contains(&name))
+                    }
+                    Row::Line(block, index) => {
+                        let name = blocks[block].name();
+                        let breakpoint = self
+                            .breakpoints
+                            .iter()
+                            .any(|(b, at)| b == name && *at == index);
+                        let marker = match (breakpoint, current == Some(i)) {
+                            (true, true) => "*>",
+                            (true, false) => "* ",
+                            (false, true) => " >",
+                            (false, false) => "  ",
+                        };
+                        (
+                            format!("{marker}    {}", blocks[block].lines()[index]),
+                            false,
+                        )
+                    }
+                };
+                let style = if current == Some(i) {
+                    Style::Current
+                } else if i == self.cursor {
+                    Style::Selected
+                } else if targeted {
+                    Style::Target
+                } else {
+                    Style::Normal
+                };
+                Span::new(text, style)
+            })
+            .collect()
+    }
+
+    /// Every block of the selected function and the edges leaving it, like `[0] fib` followed by
+    /// `--true--> [2] fib.then`.
+    fn render_graph(&self) -> Vec<Span> {
+        let Some(label) = self.functions.get(self.selected) else {
+            return Vec::new();
+        };
+        let cfg = Cfg::new(label);
+        let cursor_block = self.selected_rows().get(self.cursor).map(|row| row.block());
+        let current_block = self
+            .current()
+            .filter(|&(function, _)| function == self.selected)
+            .and_then(|(_, row)| rows(label).get(row).map(|row| row.block()));
+        let targets = self.cursor_targets();
+        let mut spans = Vec::new();
+        for i in 0..cfg.len() {
+            let name = cfg.block(i).name();
+            let style = if current_block == Some(i) {
+                Style::Current
+            } else if cursor_block == Some(i) {
+                Style::Selected
+            } else if targets.contains(&name) {
+                Style::Target
+            } else {
+                Style::Normal
+            };
+            spans.push(Span::new(format!("  [{i}] {name}"), style));
+            for edge in cfg.edges().iter().filter(|edge| edge.from == i) {
+                let kind = match edge.kind {
+                    EdgeKind::Jump => "jump",
+                    EdgeKind::BranchTrue => "true",
+                    EdgeKind::BranchFalse => "false",
+                    EdgeKind::Fallthrough => "fallthrough",
+                };
+                let to = match edge.to {
+                    Target::Block(to) => format!("[{to}] {}", cfg.block(to).name()),
+                    Target::External(name) => name.to_string(),
+                    Target::Dynamic => "(dynamic)".to_string(),
+                };
+                spans.push(Span::new(format!("    --{kind}--> {to}"), Style::Normal));
+            }
+        }
+        spans
+    }
+
+    fn render_position(&self) -> String {
+        match &self.machine {
+            Some(machine) => {
+                let at = machine
+                    .location()
+                    .map_or_else(|| "-".to_string(), |location| location.to_string());
+                format!(
+                    "{}  |  at {at}  |  {} steps  |  depth {}",
+                    self.status,
+                    machine.steps(),
+                    machine.depth()
+                )
+            }
+            None => self.status.clone(),
+        }
+    }
+
+    /// The registers of the current frame that don't hold the integer 0 they start out as.
+    fn render_registers(&self) -> String {
+        let registers = self.machine.as_ref().map_or(&[][..], Machine::registers);
+        let set: Vec<String> = (0..=Reg::MAX)
+            .zip(registers)
+            .filter(|&(_, value)| *value != Value::Int(0))
+            .map(|(reg, value)| format!("r{reg} = {value}"))
+            .collect();
+        format!("registers: {}", set.join("  "))
+    }
+
+    /// The end of the output, with line breaks written as `\n`, so that it fits on one line.
+    fn render_output(&self, width: usize) -> String {
+        let output = self.machine.as_ref().map_or(&[][..], Machine::output);
+        let text = String::from_utf8_lossy(output).replace('\n', "\\n");
+        let room = width.saturating_sub("output: ".len());
+        let skip = text.chars().count().saturating_sub(room);
+        format!("output: {}", text.chars().skip(skip).collect::<String>())
+    }
+}
+
+/// `text` cut or padded with spaces to `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut text: String = text.chars().take(width).collect();
+    let len = text.chars().count();
+    text.extend(std::iter::repeat_n(' ', width - len));
+    text
+}
+
+/// The command of `key`, or `None` if it quits the viewer or does nothing.
+fn command(key: &KeyEvent) -> Option<Command> {
+    Some(match key.code {
+        KeyCode::Char('n') | KeyCode::Tab | KeyCode::Right => Command::NextFunction,
+        KeyCode::Char('p') | KeyCode::BackTab | KeyCode::Left => Command::PreviousFunction,
+        KeyCode::Char('j') | KeyCode::Down => Command::CursorDown,
+        KeyCode::Char('k') | KeyCode::Up => Command::CursorUp,
+        KeyCode::Char('c') => Command::ToggleGraph,
+        KeyCode::Char('b') => Command::ToggleBreakpoint,
+        KeyCode::Char('s') => Command::Step,
+        KeyCode::Char('r') => Command::Continue,
+        KeyCode::Char('R') => Command::Restart,
+        _ => return None,
+    })
+}
+
+/// Puts the terminal back the way it was, even if the viewer panics.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter(out: &mut impl Write) -> io::Result<RawTerminal> {
+        terminal::enable_raw_mode()?;
+        queue!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+        out.flush()?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        // There is nowhere left to report a failure to restore the terminal.
+        let _ = queue!(out, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = out.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn draw(out: &mut impl Write, screen: &[Vec<Span>]) -> io::Result<()> {
+    queue!(out, terminal::Clear(terminal::ClearType::All))?;
+    for (y, line) in (0..).zip(screen) {
+        queue!(out, cursor::MoveTo(0, y))?;
+        for span in line {
+            let content = style::style(&span.text);
+            let content = match span.style {
+                Style::Normal => content,
+                Style::Selected => style::Stylize::reverse(content),
+                Style::Target => style::Stylize::yellow(content),
+                Style::Current => style::Stylize::bold(style::Stylize::green(content)),
+            };
+            queue!(out, style::PrintStyledContent(content))?;
+        }
+    }
+    out.flush()
+}
+
+/// Show `asm` in the terminal, taking over the whole screen, until `q` or escape is pressed.
+///
+/// # Errors
+///
+/// Returns an error if the terminal can't be set up, drawn to or read from.
+pub fn run(asm: &Asm) -> io::Result<()> {
+    let mut viewer = Viewer::new(asm);
+    let mut out = io::stdout();
+    let _terminal = RawTerminal::enter(&mut out)?;
+    loop {
+        let (width, height) = terminal::size()?;
+        draw(
+            &mut out,
+            &viewer.render(usize::from(width), usize::from(height)),
+        )?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+            return Ok(());
+        }
+        if let Some(command) = command(&key) {
+            viewer.apply(command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsmBuilder, BuildInstruction};
+
+    fn text(screen: &[Vec<Span>]) -> Vec<String> {
+        screen
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|span| span.text.as_str())
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn styled(screen: &[Vec<Span>], style: Style) -> Vec<String> {
+        screen
+            .iter()
+            .flatten()
+            .filter(|span| span.style == style)
+            .map(|span| span.text.trim().to_string())
+            .collect()
+    }
+
+    fn program() -> Asm {
+        let mut builder = AsmBuilder::new();
+        builder
+            .main(|main_builder| {
+                main_builder
+                    .integer(2, 1)
+                    .label_call("count", &[1], 0)
+                    .exit()
+            })
+            .label("count", |count_builder| {
+                count_builder
+                    .branch_boolean(1, "count.print", "count.done")
+                    .sub_label("print", |print_builder| {
+                        print_builder
+                            .integer(48, 2)
+                            .add(1, 2, 2)
+                            .put_char(2)
+                            .integer(1, 2)
+                            .sub(1, 2, 1)
+                            .label_jump("count")
+                    })
+                    .sub_label("done", |done_builder| done_builder.return_(1))
+            });
+        builder.finish()
+    }
+
+    #[test]
+    fn test_viewer_render() {
+        let asm = program();
+        let viewer = Viewer::new(&asm);
+        let screen = viewer.render(72, 10);
+        assert_eq!(
+            text(&screen),
+            [
+                "  count              |   main:",
+                "> main               |  >    r1 <- int 2",
+                "                     |       r0 <- call count r1",
+                "                     |       exit",
+                "                     |",
+                "------------------------------------------------------------------------",
+                "ready  |  at main+0  |  0 steps  |  depth 1",
+                "registers:",
+                "output:",
+                "n/p function  j/k line  c graph  b breakpoint  s step  r continue  R res",
+            ]
+        );
+        assert_eq!(styled(&screen, Style::Selected), ["> main"]);
+        assert_eq!(styled(&screen, Style::Current), [">    r1 <- int 2"]);
+    }
+
+    #[test]
+    fn test_viewer_targets() {
+        let asm = program();
+        let mut viewer = Viewer::new(&asm);
+        viewer.apply(Command::PreviousFunction);
+        viewer.apply(Command::CursorDown);
+        let screen = viewer.render(72, 20);
+        assert_eq!(
+            styled(&screen, Style::Selected),
+            ["count", "bb r1 count.done count.print"]
+        );
+        assert_eq!(
+            styled(&screen, Style::Target),
+            ["count.print:", "count.done:"]
+        );
+
+        viewer.apply(Command::ToggleGraph);
+        let screen = viewer.render(72, 12);
+        assert_eq!(
+            text(&screen)[..7],
+            [
+                "  count              |   [0] count",
+                "> main               |     --false--> [2] count.done",
+                "                     |     --true--> [1] count.print",
+                "                     |   [1] count.print",
+                "                     |     --jump--> [0] count",
+                "                     |   [2] count.done",
+                "                     |",
+            ]
+        );
+        assert_eq!(styled(&screen, Style::Selected), ["count", "[0] count"]);
+        assert_eq!(
+            styled(&screen, Style::Target),
+            ["[1] count.print", "[2] count.done"]
+        );
+    }
+
+    #[test]
+    fn test_viewer_stepping() {
+        let asm = program();
+        let mut viewer = Viewer::new(&asm);
+        viewer.apply(Command::Step);
+        viewer.apply(Command::Step);
+        let screen = viewer.render(72, 12);
+        assert_eq!(styled(&screen, Style::Selected), ["> count"]);
+        assert_eq!(
+            styled(&screen, Style::Current),
+            [">    bb r1 count.done count.print"]
+        );
+        assert_eq!(text(&screen)[9], "registers: r1 = 2");
+
+        // Stop at `putchar` on every pass through the loop.
+        for _ in 0..4 {
+            viewer.apply(Command::CursorDown);
+        }
+        viewer.apply(Command::ToggleBreakpoint);
+        viewer.apply(Command::Continue);
+        viewer.apply(Command::Continue);
+        let screen = viewer.render(72, 12);
+        assert_eq!(styled(&screen, Style::Current), ["*>    putchar r2"]);
+        assert_eq!(
+            text(&screen)[8],
+            "stopped at a breakpoint  |  at count.print+2  |  12 steps  |  depth 2"
+        );
+        assert_eq!(text(&screen)[10], "output: 2");
+
+        viewer.apply(Command::Continue);
+        let screen = viewer.render(72, 12);
+        assert_eq!(text(&screen)[8], "exited  |  at -  |  19 steps  |  depth 0");
+        assert_eq!(text(&screen)[10], "output: 21");
+
+        viewer.apply(Command::Restart);
+        assert_eq!(viewer.machine().unwrap().steps(), 0);
+    }
+}
@@ -0,0 +1,414 @@
+//! Checking that instructions have the operands their opcode takes, for instructions built by hand rather
+//! than with [`BuildInstruction`](crate::BuildInstruction), which always gets them right, and that control
+//! only leaves a block through a terminator.
+
+use crate::{
+    asm::{Asm, Label, Line},
+    instr::{Instruction, OpCode, Operand},
+    lint::MAX_CALL_ARGS,
+    parse::parse_instruction,
+};
+use std::fmt;
+
+/// What an operand has to be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    Reg,
+    Int,
+    /// A label or sub-label, like the target of a jump or branch.
+    Label,
+    /// The text of `str`.
+    Str,
+    /// The function of `xcall`, by name or by its index among the host functions.
+    Extern,
+    #[cfg(feature = "float")]
+    Float,
+}
+
+impl OperandKind {
+    fn accepts<R>(self, operand: &Operand<R>) -> bool {
+        match (self, operand) {
+            (OperandKind::Reg, Operand::Reg(_))
+            | (OperandKind::Int, Operand::Int(_))
+            | (OperandKind::Label, Operand::Label(_))
+            | (OperandKind::Str, Operand::Str(_))
+            | (OperandKind::Extern, Operand::Extern(_)) => true,
+            (OperandKind::Extern, &Operand::Int(index)) => index >= 0,
+            #[cfg(feature = "float")]
+            (OperandKind::Float, Operand::Float(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperandKind::Reg => "a register",
+            OperandKind::Int => "an integer",
+            OperandKind::Label => "a label",
+            OperandKind::Str => "a string",
+            OperandKind::Extern => "a host function",
+            #[cfg(feature = "float")]
+            OperandKind::Float => "a float",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// An instruction without a register to write, for an opcode that writes one.
+    MissingDest(OpCode),
+    /// An instruction with a register to write, for an opcode that doesn't write one.
+    UnexpectedDest(OpCode),
+    /// Fewer operands than the opcode takes; `expected` is the kind of the first one missing.
+    MissingOperand { op: OpCode, expected: OperandKind },
+    /// More operands than the opcode takes.
+    ExtraOperands {
+        op: OpCode,
+        expected: usize,
+        found: usize,
+    },
+    /// An operand of the wrong kind, counting from 0.
+    WrongOperand {
+        op: OpCode,
+        index: usize,
+        expected: OperandKind,
+        found: String,
+    },
+    /// A call with more arguments than the callee has registers to receive them in, as they go to `r1`
+    /// onwards.
+    TooManyArguments { op: OpCode, found: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::MissingDest(op) => write!(f, "`{op}` needs a register to write"),
+            VerifyError::UnexpectedDest(op) => write!(f, "`{op}` doesn't write a register"),
+            VerifyError::MissingOperand { op, expected } => {
+                write!(f, "`{op}` is missing {expected}")
+            }
+            VerifyError::ExtraOperands {
+                op,
+                expected,
+                found,
+            } => write!(f, "`{op}` takes {expected} operands, but has {found}"),
+            VerifyError::WrongOperand {
+                op,
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "operand {index} of `{op}` should be {expected}, not `{found}`"
+            ),
+            VerifyError::TooManyArguments { op, found } => write!(
+                f,
+                "`{op}` passes {found} arguments, but only {MAX_CALL_ARGS} registers can receive them"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Whether `op` writes a register, the operands it takes, and the kind of any number of operands after them.
+fn shape(op: OpCode) -> (bool, &'static [OperandKind], Option<OperandKind>) {
+    use OperandKind::{Extern, Int, Label, Reg, Str};
+
+    match op {
+        OpCode::Exit => (false, &[], None),
+        OpCode::Reg | OpCode::Neg | OpCode::Arr | OpCode::Len | OpCode::Type => {
+            (true, &[Reg], None)
+        }
+        OpCode::Jump => (false, &[Label], None),
+        OpCode::Call => (true, &[Label], Some(Reg)),
+        OpCode::Addr => (true, &[Label], None),
+        OpCode::DJump | OpCode::Ret | OpCode::PutChar => (false, &[Reg], None),
+        OpCode::DCall => (true, &[Reg], Some(Reg)),
+        OpCode::ExternCall => (true, &[Extern], Some(Reg)),
+        OpCode::Int => (true, &[Int], None),
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Mod
+        | OpCode::BAnd
+        | OpCode::BOr
+        | OpCode::BXor
+        | OpCode::Shl
+        | OpCode::Shr
+        | OpCode::Get => (true, &[Reg, Reg], None),
+        #[cfg(feature = "float")]
+        OpCode::FAdd | OpCode::FSub | OpCode::FMul | OpCode::FDiv => (true, &[Reg, Reg], None),
+        OpCode::Bb => (false, &[Reg, Label, Label], None),
+        OpCode::Beq | OpCode::Blt => (false, &[Reg, Reg, Label, Label], None),
+        #[cfg(feature = "float")]
+        OpCode::FBeq | OpCode::FBlt => (false, &[Reg, Reg, Label, Label], None),
+        OpCode::Str => (true, &[Str], None),
+        #[cfg(feature = "float")]
+        OpCode::FInt => (true, &[OperandKind::Float], None),
+        OpCode::Set => (false, &[Reg, Reg, Reg], None),
+    }
+}
+
+/// Check that `instr` writes a register if and only if its opcode does, and has the operands the opcode
+/// takes: `set` takes three registers, `str` its text, and branches their condition followed by two labels.
+///
+/// # Errors
+///
+/// Returns the first way the instruction doesn't match its opcode.
+pub fn check_instruction(instr: &Instruction) -> Result<(), VerifyError> {
+    let op = instr.op;
+    let (writes, fixed, rest) = shape(op);
+    match (writes, instr.dest) {
+        (true, None) => return Err(VerifyError::MissingDest(op)),
+        (false, Some(_)) => return Err(VerifyError::UnexpectedDest(op)),
+        _ => {}
+    }
+    if let Some(&expected) = fixed.get(instr.operands.len()) {
+        return Err(VerifyError::MissingOperand { op, expected });
+    }
+    if rest.is_none() && instr.operands.len() > fixed.len() {
+        return Err(VerifyError::ExtraOperands {
+            op,
+            expected: fixed.len(),
+            found: instr.operands.len(),
+        });
+    }
+    let kinds = fixed.iter().copied().chain(rest.into_iter().cycle());
+    for (index, (operand, expected)) in instr.operands.iter().zip(kinds).enumerate() {
+        if !expected.accepts(operand) {
+            return Err(VerifyError::WrongOperand {
+                op,
+                index,
+                expected,
+                found: operand.to_string(),
+            });
+        }
+    }
+    let args = instr.operands.len() - fixed.len();
+    if rest.is_some() && args > MAX_CALL_ARGS {
+        return Err(VerifyError::TooManyArguments { op, found: args });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// An instruction right after a terminator, at `index` among the lines of the named label or sub-label.
+    Unreachable {
+        label: String,
+        index: usize,
+        after: OpCode,
+    },
+    /// A label or sub-label that doesn't end in a terminator, so control runs on into the next sub-label,
+    /// or the next function if it is the last one.
+    MissingTerminator { label: String },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::Unreachable {
+                label,
+                index,
+                after,
+            } => write!(f, "{label}+{index} is unreachable, after `{after}`"),
+            BlockError::MissingTerminator { label } => write!(
+                f,
+                "`{label}` doesn't end in `ret`, `exit`, a jump or a branch"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Check that every block of `asm`, including the entry block, ends in a terminator, and has no instructions
+/// after one. Raw lines are read as instructions where they are, and otherwise could be anything, like a
+/// label, so they end a block and may start another.
+///
+/// # Errors
+///
+/// Returns the first block that doesn't end in a terminator, or has an unreachable instruction.
+pub fn check_blocks(asm: &Asm) -> Result<(), BlockError> {
+    for block in std::iter::once(asm.entry()).chain(asm.iter().flat_map(Label::blocks)) {
+        let label = || block.name().to_string();
+        // The opcode of the last instruction, or `None` if there isn't one or it is an unknown raw line.
+        let mut last = None;
+        let mut ends_unknown = false;
+        for (index, line) in block.lines().iter().enumerate() {
+            let Some(ops) = opcodes(line) else {
+                (last, ends_unknown) = (None, true);
+                continue;
+            };
+            for op in ops {
+                if let Some(after) = last.filter(|last: &OpCode| last.is_terminator()) {
+                    return Err(BlockError::Unreachable {
+                        label: label(),
+                        index,
+                        after,
+                    });
+                }
+                (last, ends_unknown) = (Some(op), false);
+            }
+        }
+        if !ends_unknown && !last.is_some_and(OpCode::is_terminator) {
+            return Err(BlockError::MissingTerminator { label: label() });
+        }
+    }
+    Ok(())
+}
+
+/// The opcodes of the instructions of `line`, or `None` if it is a raw line that isn't only instructions.
+fn opcodes(line: &Line) -> Option<Vec<OpCode>> {
+    match line {
+        Line::Instruction(instr) => Some(vec![instr.op]),
+        Line::Raw(raw) => raw
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_instruction(line).ok().map(|instr| instr.op))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse::parse, AsmBuilder, BuildInstruction};
+
+    #[test]
+    fn test_check_instruction() {
+        let check = |line| match check_instruction(&parse_instruction(line).unwrap()) {
+            Ok(()) => "ok".to_string(),
+            Err(error) => error.to_string(),
+        };
+        let lines = [
+            "r0 <- call fib r1 r2",
+            "set r1 r2 r3",
+            "set r1 r2 5",
+            "set r1 r2",
+            "ret r1 r2",
+            "r1 <- putchar r1",
+            "r1 <- str :hi there",
+            "r1 <- str hi",
+            "bb r1 f.yes",
+            "blt r1 r2 r3 f.yes",
+            "r1 <- xcall print r1",
+            "r1 <- xcall -1",
+            "r1 <- int",
+        ];
+        let report = lines
+            .iter()
+            .map(|line| format!("{line}: {}", check(line)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            report,
+            r"r0 <- call fib r1 r2: ok
+set r1 r2 r3: ok
+set r1 r2 5: operand 2 of `set` should be a register, not `5`
+set r1 r2: `set` is missing a register
+ret r1 r2: `ret` takes 1 operands, but has 2
+r1 <- putchar r1: `putchar` doesn't write a register
+r1 <- str :hi there: ok
+r1 <- str hi: operand 0 of `str` should be a string, not `hi`
+bb r1 f.yes: `bb` is missing a label
+blt r1 r2 r3 f.yes: operand 2 of `blt` should be a label, not `r3`
+r1 <- xcall print r1: ok
+r1 <- xcall -1: operand 0 of `xcall` should be a host function, not `-1`
+r1 <- int: `int` is missing an integer"
+        );
+
+        let args = (0..256).map(|_| Operand::Reg(1));
+        let call = Instruction::new(
+            OpCode::Call,
+            Some(0),
+            std::iter::once(Operand::Label("f".into()))
+                .chain(args)
+                .collect(),
+        );
+        assert_eq!(
+            check_instruction(&call).unwrap_err().to_string(),
+            "`call` passes 256 arguments, but only 255 registers can receive them"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder.main(|main_builder| {
+            main_builder
+                .instruction(Instruction::new(OpCode::Set, None, vec![Operand::Reg(1)]))
+                .exit()
+        });
+        assert_eq!(
+            builder.finish_checked().unwrap_err().to_string(),
+            "`set r1` in `main` is invalid: `set` is missing a register"
+        );
+    }
+
+    #[test]
+    fn test_check_blocks() {
+        let check = |text| match check_blocks(&parse(text).unwrap()) {
+            Ok(()) => "ok".to_string(),
+            Err(error) => error.to_string(),
+        };
+        assert_eq!(
+            check(
+                r"func main
+    bb r1 main.then main.else
+@main.then
+    ret r1
+@main.else
+    r1 <- int 0
+    ret r1
+end"
+            ),
+            "ok"
+        );
+        assert_eq!(
+            check(
+                r"func main
+    bb r1 main.then main.else
+@main.then
+    putchar r1
+@main.else
+    ret r1
+end"
+            ),
+            "`main.then` doesn't end in `ret`, `exit`, a jump or a branch"
+        );
+        assert_eq!(
+            check(
+                "func main
+    exit
+    putchar r1
+    exit
+end"
+            ),
+            "main+1 is unreachable, after `exit`"
+        );
+        assert_eq!(
+            check(
+                "func f
+    putchar r1
+end
+
+func main
+    exit
+end"
+            ),
+            "`f` doesn't end in `ret`, `exit`, a jump or a branch"
+        );
+
+        let mut builder = AsmBuilder::new();
+        builder
+            .strict(true)
+            .main(|main_builder| main_builder.label_call("f", &[], 0).exit())
+            .label("f", |f_builder| f_builder.integer(1, 1).put_char(1));
+        assert_eq!(
+            builder.finish_checked().unwrap_err().to_string(),
+            "`f` doesn't end in `ret`, `exit`, a jump or a branch"
+        );
+    }
+}
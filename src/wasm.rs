@@ -0,0 +1,116 @@
+//! An entry point for JavaScript, to assemble and run programs in the browser.
+//!
+//! Build for `wasm32-unknown-unknown` with the `wasm` feature. The modules that need processes or files are
+//! behind the `host` feature, which is off by default.
+
+use crate::{interp, parse};
+use serde::{Deserialize, Serialize};
+
+/// How many instructions a program may execute if the request doesn't say, so that a program that never
+/// exits doesn't hang the page.
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// How many array elements a program may allocate if the request doesn't say.
+const DEFAULT_MAX_ARRAY_ELEMENTS: usize = 1 << 24;
+
+#[derive(Deserialize)]
+struct Request {
+    /// The program, in the text format.
+    source: String,
+    /// How many instructions may be executed.
+    #[serde(default)]
+    max_steps: Option<u64>,
+    /// How many array elements may be allocated, strings included.
+    #[serde(default)]
+    max_array_elements: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    /// Everything written with `putchar`, with invalid UTF-8 replaced.
+    output: String,
+    steps: u64,
+    /// Why the program couldn't be parsed or stopped before exiting, if it did.
+    error: Option<String>,
+}
+
+/// Parse and run the program described by `source_json`, an object with the program's `"source"` text and
+/// optional `"max_steps"` and `"max_array_elements"` limits. Programs are limited to 10,000,000 steps and
+/// 16,777,216 array elements by default.
+///
+/// Returns an object with the `"output"` of the program, how many `"steps"` it ran for, and the `"error"`
+/// that stopped it, or `null` if it exited.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn build_and_run(source_json: &str) -> String {
+    let response = match run(source_json) {
+        Ok(response) => response,
+        Err(error) => Response {
+            output: String::new(),
+            steps: 0,
+            error: Some(error),
+        },
+    };
+    serde_json::to_string(&response).expect("responses are always valid JSON")
+}
+
+fn run(source_json: &str) -> Result<Response, String> {
+    let request: Request =
+        serde_json::from_str(source_json).map_err(|error| format!("invalid request: {error}"))?;
+    let asm = parse::parse(&request.source).map_err(|error| error.to_string())?;
+    let result = interp::run(&asm, &config(&request));
+    Ok(Response {
+        output: result.output_string(),
+        steps: result.steps,
+        error: result.result.err().map(|trap| trap.to_string()),
+    })
+}
+
+/// The limits of `request`, or the defaults for those it leaves out.
+fn config(request: &Request) -> interp::Config {
+    interp::Config {
+        max_steps: Some(request.max_steps.unwrap_or(DEFAULT_MAX_STEPS)),
+        max_array_elements: Some(
+            request
+                .max_array_elements
+                .unwrap_or(DEFAULT_MAX_ARRAY_ELEMENTS),
+        ),
+        ..interp::Config::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_and_run() {
+        assert_eq!(
+            build_and_run(
+                r#"{"source": "func main\n    r1 <- int 72\n    putchar r1\n    exit\nend"}"#
+            ),
+            r#"{"output":"H","steps":3,"error":null}"#
+        );
+        assert_eq!(
+            build_and_run(
+                r#"{"source": "func main\n@main.loop\n    jump main.loop\nend", "max_steps": 5}"#
+            ),
+            r#"{"output":"","steps":5,"error":"step limit reached at main.loop+0\n    in `jump main.loop`"}"#
+        );
+        assert!(build_and_run(
+            r#"{"source": "func main\n    r1 <- int 10\n    r1 <- arr r1\n    exit\nend", "max_array_elements": 5}"#
+        )
+        .contains(r#""error":"allocation limit reached"#));
+        assert_eq!(
+            build_and_run(r#"{"source": "func main\n    nop\nend"}"#),
+            r#"{"output":"","steps":0,"error":"line 2: unknown opcode `nop`"}"#
+        );
+        assert!(build_and_run("[]").contains(r#""error":"invalid request: "#));
+
+        let request: Request = serde_json::from_str(r#"{"source": ""}"#).unwrap();
+        let config = config(&request);
+        assert_eq!(config.max_steps, Some(DEFAULT_MAX_STEPS));
+        assert_eq!(config.max_array_elements, Some(DEFAULT_MAX_ARRAY_ELEMENTS));
+    }
+}